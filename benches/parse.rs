@@ -0,0 +1,93 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::hint::black_box;
+
+use betwixt_parse::parsers::{
+    betwixt, code, properties as extract_props, section, LineParser, MarkdownParsers,
+};
+use betwixt_parse::{Document, Strictness, BETWIXT_TOKEN, CLOSE_TOKEN};
+
+// A document with many nested sections, each with a few properties and code blocks
+fn many_sections(sections: usize) -> Vec<u8> {
+    let mut doc = Vec::new();
+    for idx in 0..sections {
+        let level = idx % 4 + 1;
+        doc.extend_from_slice(
+            format!("{} Section {}\n\nSome prose.\n", "#".repeat(level), idx).as_bytes(),
+        );
+        doc.extend_from_slice(
+            format!(
+                "<?btxt+rust filename='src/mod{}.rs' mode='append' tag='t{}' ?>\n",
+                idx,
+                idx % 3
+            )
+            .as_bytes(),
+        );
+        doc.extend_from_slice(
+            format!("```rust block{}\nfn f{}() {{}}\n```\n", idx, idx).as_bytes(),
+        );
+        doc.extend_from_slice(b"```python\nprint('hi')\n```\n");
+    }
+    doc
+}
+
+// A document made of one code block, lines long
+fn huge_block(lines: usize) -> Vec<u8> {
+    let mut doc = b"# Huge\n<?btxt filename='huge.rs' mode='overwrite' ?>\n```rust\n".to_vec();
+    for idx in 0..lines {
+        doc.extend_from_slice(format!("let x{} = {};\n", idx, idx).as_bytes());
+    }
+    doc.extend_from_slice(b"```\n");
+    doc
+}
+
+fn parsers<'a>() -> MarkdownParsers<impl LineParser<'a>, impl LineParser<'a>, impl LineParser<'a>> {
+    MarkdownParsers {
+        code: code("```", "```"),
+        section: section('#'),
+        betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+        strict: Strictness::Deny,
+    }
+}
+
+fn document(c: &mut Criterion) {
+    let mut group = c.benchmark_group("from_contents");
+    for (name, doc) in [
+        ("many_sections", many_sections(2_000)),
+        ("huge_block", huge_block(50_000)),
+    ] {
+        group.throughput(Throughput::Bytes(doc.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| Document::from_contents(black_box(&doc), parsers()).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn properties(c: &mut Criterion) {
+    let all = b"filename='src/main.rs' mode='insert[// here]' tag='build' pre='fn main() {' \
+post='}' cmd='cargo run' runner='ssh:host' format='rustfmt' code='print()' ignore=false";
+    // the permutation parser does the most work when properties are in reverse order
+    let reversed =
+        b"ignore=false code='print()' format='rustfmt' runner='ssh:host' cmd='cargo run' \
+post='}' pre='fn main() {' tag='build' mode='insert[// here]' filename='src/main.rs'";
+    c.bench_function("properties/all", |b| {
+        b.iter(|| extract_props(black_box(&all[..])).unwrap())
+    });
+    c.bench_function("properties/reversed", |b| {
+        b.iter(|| extract_props(black_box(&reversed[..])).unwrap())
+    });
+}
+
+fn terminator(c: &mut Criterion) {
+    let doc = huge_block(50_000);
+    // skip the heading and betwixt block, straight to the fence
+    let start = doc.windows(3).position(|w| w == b"```").unwrap();
+    let fenced = &doc[start..];
+    let parser = code("```", "```");
+    c.bench_function("code/terminator", |b| {
+        b.iter(|| parser(black_box(fenced)).unwrap())
+    });
+}
+
+criterion_group!(benches, document, properties, terminator);
+criterion_main!(benches);
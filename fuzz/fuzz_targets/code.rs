@@ -0,0 +1,10 @@
+#![no_main]
+
+use betwixt_parse::parsers::code;
+use libfuzzer_sys::fuzz_target;
+
+// A fenced code block starting the input
+fuzz_target!(|data: &[u8]| {
+    let _ = code("```", "```")(data);
+    let _ = code("~~~", "~~~")(data);
+});
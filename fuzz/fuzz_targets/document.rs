@@ -0,0 +1,23 @@
+#![no_main]
+
+use betwixt_parse::tangle::{self, TangleOptions};
+use betwixt_parse::{parse, render, FlavorBuilder, Strictness};
+use libfuzzer_sys::fuzz_target;
+
+// A whole document, then everything done with one once parsed
+fuzz_target!(|data: &[u8]| {
+    for flavor in [
+        FlavorBuilder::github(),
+        FlavorBuilder::github().strict(Strictness::Warn),
+        FlavorBuilder::nested(),
+    ] {
+        let Ok(doc) = parse(data, flavor) else {
+            continue;
+        };
+        let _ = doc.describe(&doc.root);
+        let selected: Vec<usize> = (0..doc.code_blocks.len()).collect();
+        let _ = tangle::plan(&doc, &selected, &TangleOptions::default());
+        let _ = render::render(data, &doc);
+        let _ = render::format(data, &doc);
+    }
+});
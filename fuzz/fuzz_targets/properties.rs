@@ -0,0 +1,11 @@
+#![no_main]
+
+use betwixt_parse::parsers::{betwixt, properties};
+use betwixt_parse::{BETWIXT_TOKEN, CLOSE_TOKEN};
+use libfuzzer_sys::fuzz_target;
+
+// The body of a betwixt block, and a whole one
+fuzz_target!(|data: &[u8]| {
+    let _ = properties(data);
+    let _ = betwixt(BETWIXT_TOKEN, CLOSE_TOKEN)(data);
+});
@@ -0,0 +1,28 @@
+/* The C ABI of betwixt, built with `cargo build --release --no-default-features --features ffi`.
+ *
+ * Strings are nul terminated utf8. Every string betwixt returns must be released with
+ * betwixt_free. Functions that can fail take an `error` out parameter which, when it isn't
+ * null, is set to a description of the failure. */
+#ifndef BETWIXT_H
+#define BETWIXT_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Parse a markdown document, returning a json array of its code blocks, or null if it
+ * can't be parsed. */
+char *betwixt_parse_json(const char *markdown, char **error);
+
+/* Tangle a markdown document into dir, which must exist. Returns 0 once every file is
+ * written, and -1 otherwise. Neither formatters nor commands are run. */
+int betwixt_tangle(const char *markdown, const char *dir, char **error);
+
+/* Release a string returned by betwixt. Null is ignored. */
+void betwixt_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
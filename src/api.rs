@@ -0,0 +1,44 @@
+use std::collections::HashMap;
+
+use crate::{Document, DocumentError, FlavorBuilder, Properties};
+
+// Parse a document written in the markdown dialect of flavor. This is the stable way to
+// read documents: the parsers behind it, and `Document::from_contents`, are only for
+// building parsers of dialects flavors can't describe
+pub fn parse(contents: &[u8], flavor: FlavorBuilder) -> Result<Document<'_>, DocumentError> {
+    Document::from_contents(contents, flavor.build())
+}
+
+// Parse a document like `parse`, with defaults for the properties of its code blocks by
+// language, see `Document::from_contents_with_defaults`
+pub fn parse_with_defaults<'a>(
+    contents: &'a [u8],
+    flavor: FlavorBuilder,
+    defaults: &HashMap<&'a [u8], Properties<'a>>,
+) -> Result<Document<'a>, DocumentError> {
+    Document::from_contents_with_defaults(contents, flavor.build(), defaults)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BETWIXT_COM_TOKEN, CLOSE_COM_TOKEN};
+
+    #[test]
+    fn test_parse() {
+        let markdown = &b"# Facade
+<!--btxt filename='main.rs' -->
+```rust
+fn main() {}
+```
+"[..];
+        let doc = parse(markdown, FlavorBuilder::github()).unwrap();
+        assert_eq!(doc.code_blocks[0].properties.filename, None);
+        let flavor = FlavorBuilder::github().tokens(BETWIXT_COM_TOKEN, CLOSE_COM_TOKEN);
+        let doc = parse(markdown, flavor).unwrap();
+        assert_eq!(
+            doc.code_blocks[0].properties.filename,
+            Some(&b"main.rs"[..])
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::tangle::{path, FileOutput, Link};
+
+// Writing what is tangled to a single archive instead of loose files, e.g. to distribute a
+// project template. Files are archived as they are planned: those appended to hold only what
+// was appended, and nothing is formatted or assembled
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl Format {
+    // The format an archive is written in, going by its extension
+    pub fn of(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Format::TarGz)
+        } else if name.ends_with(".tar") {
+            Some(Format::Tar)
+        } else if name.ends_with(".zip") {
+            Some(Format::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+// Write outputs to an archive at archive, in the format its extension names
+pub fn write(archive: &Path, outputs: &[FileOutput]) -> Result<(), ArchiveError> {
+    let format = Format::of(archive).ok_or_else(|| ArchiveError::Format(archive.into()))?;
+    let file = File::create(archive).map_err(ArchiveError::Io)?;
+    match format {
+        Format::Tar => tar(file, outputs).map(|_| ()).map_err(ArchiveError::Io),
+        Format::TarGz => {
+            let encoder = tar(GzEncoder::new(file, Compression::default()), outputs)
+                .map_err(ArchiveError::Io)?;
+            encoder.finish().map(|_| ()).map_err(ArchiveError::Io)
+        }
+        Format::Zip => zip(file, outputs),
+    }
+}
+
+fn tar<W: Write>(writer: W, outputs: &[FileOutput]) -> io::Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    for output in outputs {
+        let name = path(&output.filename).map_err(io::Error::other)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        match output.link {
+            Some(link) => {
+                header.set_entry_type(match link {
+                    Link::Symbolic => tar::EntryType::Symlink,
+                    Link::Hard => tar::EntryType::Link,
+                });
+                header.set_size(0);
+                let target = path(&output.contents).map_err(io::Error::other)?;
+                builder.append_link(&mut header, name, target)?;
+            }
+            None => {
+                header.set_size(output.contents.len() as u64);
+                builder.append_data(&mut header, name, &output.contents[..])?;
+            }
+        }
+    }
+    builder.into_inner()
+}
+
+fn zip(file: File, outputs: &[FileOutput]) -> Result<(), ArchiveError> {
+    let mut zip = ZipWriter::new(file);
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .unix_permissions(0o644);
+    for output in outputs {
+        let name = String::from_utf8_lossy(&output.filename);
+        match output.link {
+            Some(Link::Symbolic) => {
+                let target = String::from_utf8_lossy(&output.contents);
+                zip.add_symlink(name, target, options)?;
+            }
+            Some(Link::Hard) => return Err(ArchiveError::HardLink(name.into_owned())),
+            None => {
+                zip.start_file(name, options)?;
+                zip.write_all(&output.contents).map_err(ArchiveError::Io)?;
+            }
+        }
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    // an archive whose extension isn't that of a format betwixt writes
+    Format(PathBuf),
+    Io(io::Error),
+    Zip(zip::result::ZipError),
+    // a file that is a hard link, which zip archives can't hold
+    HardLink(String),
+}
+
+impl From<zip::result::ZipError> for ArchiveError {
+    fn from(err: zip::result::ZipError) -> Self {
+        ArchiveError::Zip(err)
+    }
+}
+
+impl Error for ArchiveError {}
+
+impl Display for ArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArchiveError::Format(path) => write!(
+                f,
+                "unknown archive format for {}, expected .tar, .tar.gz, .tgz or .zip",
+                path.display()
+            ),
+            ArchiveError::Io(err) => write!(f, "failed writing archive: {}", err),
+            ArchiveError::Zip(err) => write!(f, "failed writing zip archive: {}", err),
+            ArchiveError::HardLink(name) => {
+                write!(f, "{} is a hard link, which zip archives can't hold", name)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+    use std::io::Read;
+
+    #[test]
+    fn test_archive() {
+        assert_eq!(Format::of(Path::new("out.TGZ")), Some(Format::TarGz));
+        assert_eq!(Format::of(Path::new("out.tar")), Some(Format::Tar));
+        assert_eq!(Format::of(Path::new("out.rar")), None);
+
+        let output = |filename: &'static [u8], contents: &[u8], link| FileOutput {
+            filename: Cow::Borrowed(filename),
+            append: false,
+            prepend: false,
+            create: false,
+            contents: contents.to_vec(),
+            format: None,
+            assemble: None,
+            blocks: Vec::new(),
+            link,
+            shebang: None,
+        };
+        let outputs = vec![
+            output(b"v2/main.rs", b"fn main() {}\n", None),
+            output(b"latest", b"v2", Some(Link::Symbolic)),
+        ];
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        write(&dir.join("out.tar.gz"), &outputs).unwrap();
+        let gz = File::open(dir.join("out.tar.gz")).unwrap();
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(gz));
+        let mut entries = archive.entries().unwrap();
+        let mut main = entries.next().unwrap().unwrap();
+        assert_eq!(main.path().unwrap(), Path::new("v2/main.rs"));
+        let mut contents = String::new();
+        main.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "fn main() {}\n");
+        drop(main);
+        let latest = entries.next().unwrap().unwrap();
+        assert_eq!(latest.link_name().unwrap().unwrap(), Path::new("v2"));
+
+        write(&dir.join("out.zip"), &outputs).unwrap();
+        let mut zip = zip::ZipArchive::new(File::open(dir.join("out.zip")).unwrap()).unwrap();
+        let mut contents = String::new();
+        zip.by_name("v2/main.rs")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "fn main() {}\n");
+        assert!(zip.by_name("latest").unwrap().is_symlink());
+
+        let hard = [output(b"main.rs", b"v2/main.rs", Some(Link::Hard))];
+        assert!(matches!(
+            write(&dir.join("hard.zip"), &hard),
+            Err(ArchiveError::HardLink(_))
+        ));
+        assert!(matches!(
+            write(&dir.join("out.rar"), &outputs),
+            Err(ArchiveError::Format(_))
+        ));
+    }
+}
@@ -0,0 +1,89 @@
+use serde::Serialize;
+
+use crate::tangle::{self, FileOutput, TangleError, TangleOptions};
+use crate::{Document, DocumentError, FlavorBuilder};
+
+// What the bindings to other languages share. Documents are read as github markdown, and
+// tangled files are handed back to the caller rather than written, except through the C ABI
+
+// A code block of a document, with the properties that apply to it
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct Block {
+    pub id: Option<String>,
+    pub lang: Option<String>,
+    pub contents: String,
+    pub filename: Option<String>,
+    pub mode: Option<String>,
+    pub tag: Option<String>,
+}
+
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+pub(crate) fn document(markdown: &str) -> Result<Document<'_>, DocumentError> {
+    crate::parse(markdown.as_bytes(), FlavorBuilder::github())
+}
+
+pub(crate) fn blocks(doc: &Document) -> Vec<Block> {
+    doc.code_blocks
+        .iter()
+        .map(|block| Block {
+            id: block.part.id.map(lossy),
+            lang: block.part.lang.map(lossy),
+            contents: lossy(block.part.contents),
+            filename: block.properties.filename.map(lossy),
+            mode: block.properties.mode.as_ref().map(|mode| mode.to_string()),
+            tag: block.properties.tag.map(lossy),
+        })
+        .collect()
+}
+
+// Plan tangling a document. Only blocks with a filename are tangled
+pub(crate) fn plan<'a>(doc: &Document<'a>) -> Result<Vec<FileOutput<'a>>, TangleError> {
+    let selected: Vec<usize> = (0..doc.code_blocks.len())
+        .filter(|&idx| !doc.filenames(idx).is_empty())
+        .collect();
+    tangle::plan(doc, &selected, &TangleOptions::default())
+}
+
+// The files a document tangles to, as (filename, contents) in the order they are first
+// written to
+#[cfg_attr(not(any(feature = "wasm", feature = "python")), allow(dead_code))]
+pub(crate) fn files(doc: &Document) -> Result<Vec<(String, String)>, TangleError> {
+    Ok(plan(doc)?
+        .iter()
+        .map(|output| (lossy(&output.filename), lossy(&output.contents)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MARKDOWN: &str = "# Playground
+<?btxt filename='main.rs' mode='overwrite' ?>
+```rust main
+fn main() {}
+```
+# Elsewhere
+```rust
+// not tangled
+```
+";
+
+    #[test]
+    fn test_blocks() {
+        let doc = document(MARKDOWN).unwrap();
+        let blocks = blocks(&doc);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].id.as_deref(), Some("main"));
+        assert_eq!(blocks[0].filename.as_deref(), Some("main.rs"));
+        assert_eq!(blocks[0].mode.as_deref(), Some("overwrite"));
+        assert_eq!(blocks[1].filename, None);
+        assert_eq!(
+            files(&doc).unwrap(),
+            vec![("main.rs".to_owned(), "fn main() {}\n".to_owned())]
+        );
+    }
+}
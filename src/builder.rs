@@ -0,0 +1,136 @@
+use crate::properties::Properties;
+use crate::render::render_directive;
+use crate::{Directive, Document, DocumentError, FlavorBuilder, BETWIXT_TOKEN, CLOSE_TOKEN};
+
+// Build a document in code, for tools that generate literate documents and for tests of
+// what is done with documents. The document is written out as github markdown and parsed,
+// so it is exactly what parsing the markdown would give, e.g.
+//
+//   let builder = DocumentBuilder::new()
+//       .section("Intro", 1)
+//       .block("rust main", "fn main() {}\n", &props);
+//   let doc = builder.build()?;
+#[derive(Clone, Debug, Default)]
+pub struct DocumentBuilder {
+    markdown: String,
+}
+
+impl DocumentBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Start a section, nested in the last one started with a lower level
+    pub fn section(mut self, heading: &str, level: usize) -> Self {
+        self.line(&format!("{} {}", "#".repeat(level), heading));
+        self
+    }
+
+    // Set properties for the rest of the section and the sections nested in it, or only for
+    // its code blocks in lang, as a betwixt block would
+    pub fn properties(mut self, lang: Option<&str>, properties: &Properties) -> Self {
+        let directive = Directive {
+            span: 0..0,
+            lang: lang.map(str::as_bytes),
+            properties: properties.clone(),
+        };
+        let rendered = render_directive(&directive, BETWIXT_TOKEN, CLOSE_TOKEN);
+        self.line(&String::from_utf8_lossy(&rendered));
+        self
+    }
+
+    // Add a code block. lang is everything following the opening fence, e.g. `rust main` for
+    // a rust block with the id `main`. The block's own properties are written after it on the
+    // same line, so their values can't span lines; set those with `properties` instead
+    pub fn block(mut self, lang: &str, contents: &str, properties: &Properties) -> Self {
+        let mut fence = format!("```{}", lang);
+        for (name, value) in crate::render::properties(properties) {
+            fence = format!("{} {}={}", fence, name, String::from_utf8_lossy(&value));
+        }
+        self.line(&fence);
+        self.markdown.push_str(contents);
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            self.markdown.push('\n');
+        }
+        self.line("```");
+        self
+    }
+
+    // Add prose, which betwixt ignores
+    pub fn text(mut self, text: &str) -> Self {
+        self.line(text);
+        self
+    }
+
+    // The document as markdown
+    pub fn markdown(&self) -> &str {
+        &self.markdown
+    }
+
+    pub fn build(&self) -> Result<Document<'_>, DocumentError> {
+        crate::parse(self.markdown.as_bytes(), FlavorBuilder::github())
+    }
+
+    fn line(&mut self, line: &str) {
+        self.markdown.push_str(line);
+        self.markdown.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tangle::{plan, TangleOptions};
+
+    #[test]
+    fn test_builder() {
+        let go: Properties =
+            serde_json::from_str(r#"{"filename": "main.go", "mode": "append", "file-post": "}"}"#)
+                .unwrap();
+        let mut file_pre = Properties::default();
+        file_pre
+            .set("file-pre", b"package main\nfunc main() {\n")
+            .unwrap();
+        let tagged: Properties = serde_json::from_str(r#"{"tag": "demo"}"#).unwrap();
+        let builder = DocumentBuilder::new()
+            .section("Go", 1)
+            .properties(Some("go"), &go)
+            .properties(Some("go"), &file_pre)
+            .text("Say hello")
+            .block("go hello", "fmt.Println(\"hello\")", &tagged);
+        assert_eq!(
+            builder.markdown(),
+            "# Go
+<?btxt+go filename='main.go' mode='append' file-post='}' ?>
+<?btxt+go
+  file-pre=|||package main
+func main() {
+||| ?>
+Say hello
+```go hello tag='demo'
+fmt.Println(\"hello\")
+```
+"
+        );
+        let doc = builder.build().unwrap();
+        let block = &doc.code_blocks[0];
+        assert_eq!(block.part.id, Some(&b"hello"[..]));
+        assert_eq!(block.properties.tag, Some(&b"demo"[..]));
+        let outputs = plan(&doc, &[0], &TangleOptions::default()).unwrap();
+        assert_eq!(
+            outputs[0].contents,
+            b"package main\nfunc main() {\nfmt.Println(\"hello\")\n}".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_deserialize_properties() {
+        let props: Properties =
+            serde_json::from_str(r#"{"filename": "a.rs", "mode": "overwrite", "ignore": true}"#)
+                .unwrap();
+        assert_eq!(props.filename, Some(&b"a.rs"[..]));
+        assert_eq!(props.ignore, Some(true));
+        assert!(serde_json::from_str::<Properties>(r#"{"mode": "sideways"}"#).is_err());
+        assert!(serde_json::from_str::<Properties>(r#"{"bogus": "1"}"#).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::tangle::FileOutput;
+
+// The hashes of what was last tangled to each file, so files whose contents haven't changed
+// aren't written (or formatted) again. Their modification times are left alone, and build
+// systems watching them don't rebuild anything.
+//
+// The cache is kept in `.betwixt/cache` in the output directory, a line per file of the hash
+// in hex and the file's name, separated by a tab
+pub const CACHE_FILE: &str = ".betwixt/cache";
+
+#[derive(Debug, Default)]
+pub struct Cache {
+    path: PathBuf,
+    hashes: BTreeMap<String, u64>,
+}
+
+impl Cache {
+    // Load the cache of the output directory dir. A missing cache is an empty one
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let path = dir.join(CACHE_FILE);
+        match Cache::open(&path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Cache {
+                path,
+                hashes: BTreeMap::new(),
+            }),
+            cache => cache,
+        }
+    }
+
+    // Read the cache at path, which must be there, as the manifest of what a run tangled
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let path = path.to_owned();
+        let contents = fs::read_to_string(&path)?;
+        // lines that can't be read are dropped, which only costs writing their file again
+        let hashes = contents
+            .lines()
+            .filter_map(|line| {
+                let (hash, file) = line.split_once('\t')?;
+                Some((file.to_owned(), u64::from_str_radix(hash, 16).ok()?))
+            })
+            .collect();
+        Ok(Cache { path, hashes })
+    }
+
+    // Whether contents are what was last tangled to file
+    pub fn unchanged(&self, file: &str, contents: &[u8]) -> bool {
+        self.hashes.get(file) == Some(&hash(contents))
+    }
+
+    pub fn record(&mut self, file: &str, contents: &[u8]) {
+        self.hashes.insert(file.to_owned(), hash(contents));
+    }
+
+    // How tangling outputs would differ from what the cache records: the files whose
+    // contents would change, in the order of outputs, those it doesn't have, and then those
+    // it has that nothing tangles to anymore. Appended and prepended files always change
+    pub fn diff(&self, outputs: &[FileOutput]) -> Vec<(Change, String)> {
+        let mut changes = Vec::new();
+        let mut tangled = HashSet::new();
+        for output in outputs {
+            let file = String::from_utf8_lossy(&output.filename).into_owned();
+            let change = match self.hashes.get(&file) {
+                None => Change::Added,
+                Some(_) if output.append || output.prepend => Change::Changed,
+                Some(&hash) if hash != self::hash(&output.contents) => Change::Changed,
+                Some(_) => {
+                    tangled.insert(file);
+                    continue;
+                }
+            };
+            tangled.insert(file.clone());
+            changes.push((change, file));
+        }
+        for file in self.hashes.keys() {
+            if !tangled.contains(file) {
+                changes.push((Change::Orphaned, file.clone()));
+            }
+        }
+        changes
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let contents: String = self
+            .hashes
+            .iter()
+            .map(|(file, hash)| format!("{:016x}\t{}\n", hash, file))
+            .collect();
+        fs::write(&self.path, contents)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    // tangled now, but not recorded
+    Added,
+    // tangled now with different contents than were recorded
+    Changed,
+    // recorded, but nothing tangles to it anymore
+    Orphaned,
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // padded, so changes can be listed in a column
+        f.pad(match self {
+            Change::Added => "added",
+            Change::Changed => "changed",
+            Change::Orphaned => "orphaned",
+        })
+    }
+}
+
+// 64 bit FNV-1a. The cache outlives any one build of betwixt, so the hash must never change,
+// which std's hashers don't promise
+pub fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache() {
+        assert_eq!(hash(b""), 0xcbf29ce484222325);
+        assert_eq!(hash(b"a"), 0xaf63dc4c8601ec8c);
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("cache");
+        let mut cache = Cache::load(&dir).unwrap();
+        assert!(!cache.unchanged("main.rs", b"fn main() {}\n"));
+        cache.record("main.rs", b"fn main() {}\n");
+        cache.save().unwrap();
+
+        let cache = Cache::load(&dir).unwrap();
+        assert!(cache.unchanged("main.rs", b"fn main() {}\n"));
+        assert!(!cache.unchanged("main.rs", b"fn main() { todo!() }\n"));
+        assert!(!cache.unchanged("lib.rs", b"fn main() {}\n"));
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(Cache::open(&dir.join(CACHE_FILE)).is_err());
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut cache = Cache::default();
+        for (file, contents) in [
+            ("a.rs", "a\n"),
+            ("b.rs", "b\n"),
+            ("c.rs", "c\n"),
+            ("d.rs", "d\n"),
+        ] {
+            cache.record(file, contents.as_bytes());
+        }
+        let output = |file: &'static str, contents: &'static str, append| FileOutput {
+            filename: file.as_bytes().into(),
+            append,
+            prepend: false,
+            create: false,
+            contents: contents.as_bytes().to_vec(),
+            format: None,
+            assemble: None,
+            blocks: Vec::new(),
+            link: None,
+            shebang: None,
+        };
+        let outputs = [
+            output("e.rs", "e\n", false),
+            output("b.rs", "b2\n", false),
+            output("a.rs", "a\n", false),
+            output("d.rs", "d\n", true),
+        ];
+        assert_eq!(
+            cache.diff(&outputs),
+            vec![
+                (Change::Added, "e.rs".into()),
+                (Change::Changed, "b.rs".into()),
+                (Change::Changed, "d.rs".into()),
+                (Change::Orphaned, "c.rs".into()),
+            ]
+        );
+    }
+}
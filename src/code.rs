@@ -1,11 +1,14 @@
 use std::fmt::Debug;
+use std::ops::Range;
 
-use nom::bytes::complete::{tag, take_until, take_while};
-use nom::character::complete::{alpha1, newline, space0};
+use memchr::{memchr, memchr2, memchr_iter, memmem};
+use nom::branch::alt;
+use nom::bytes::complete::{tag, take_until, take_while1};
+use nom::character::complete::{line_ending, space0};
 use nom::character::is_alphanumeric;
-use nom::combinator::opt;
+use nom::combinator::{eof, opt, verify};
 use nom::sequence::tuple;
-use nom::{IResult, InputLength, InputTake, Parser};
+use nom::{IResult, Parser};
 
 use crate::LineParseError;
 
@@ -15,6 +18,10 @@ use super::{LineParseResult, ScanResult};
 pub struct Code<'a> {
     pub properties: Properties<'a>,
     pub part: CodePart<'a>,
+    // the bytes of the document the block was parsed from, fences included
+    pub span: Range<usize>,
+    // the bytes of the document holding the block's contents
+    pub contents_span: Range<usize>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -22,24 +29,101 @@ pub struct CodePart<'a> {
     pub contents: &'a [u8],
     pub lang: Option<&'a [u8]>,
     pub id: Option<&'a [u8]>,
-	pub prop_line: Option<&'a [u8]>
+    // a path written in place of the id, e.g. ```rust src/lib.rs, the block's filename with
+    // `infer-filename`
+    pub path: Option<&'a [u8]>,
+    pub prop_line: Option<&'a [u8]>,
+    // marked as never to be tangled, with NOTANGLE or the id `_`
+    pub notangle: bool,
 }
 
-// Locate the index at which point a parser succeeded (returned Ok).
-fn locate_parser_match<I, O, P, E>(mut parser: P) -> impl FnMut(I) -> Option<usize>
+// The marker for a block that is never tangled, following its id or in place of it, e.g.
+// ```rust !notangle
+pub const NOTANGLE: &[u8] = b"!notangle";
+
+// Locate the first line of the input at which a parser succeeds, returning the index the
+// line starts at. Only line starts are tried, found with memchr, so searching is linear
+// in the length of the input rather than retrying the parser at every byte.
+fn locate_line_match<'a, O, P>(mut parser: P) -> impl FnMut(&'a [u8]) -> Option<usize>
 where
-    P: Parser<I, O, E>,
-    I: InputLength + InputTake,
+    P: Parser<&'a [u8], O, nom::error::Error<&'a [u8]>>,
 {
-    move |i: I| {
-        for idx in 0..i.input_len() {
-            match parser.parse(i.take_split(idx).0) {
-                Ok(_) => return Some(idx),
-                Err(_) => {}
-            }
+    move |i: &'a [u8]| {
+        std::iter::once(0)
+            .chain(memchr_iter(b'\n', i).map(|idx| idx + 1))
+            .find(|&idx| parser.parse(&i[idx..]).is_ok())
+    }
+}
+
+// Whether c can be part of a language identifier, like those of objective-c, c++, c#,
+// shell_session or vb.net
+pub(crate) fn is_lang_char(c: u8) -> bool {
+    is_alphanumeric(c) || b"-_+.#".contains(&c)
+}
+
+// Whether c can be part of a block id, like setup-db or hello_rust
+fn is_id_char(c: u8) -> bool {
+    is_alphanumeric(c) || c == b'-' || c == b'_'
+}
+
+// Whether word is written like a path rather than an id or a property, e.g. `src/lib.rs`
+fn is_path(word: &[u8]) -> bool {
+    word.iter().any(|c| b"/.".contains(c)) && !word.iter().any(|c| b"='\"|!".contains(c))
+}
+
+// The first word of raw, and what follows it
+fn first_word(raw: &[u8]) -> (&[u8], &[u8]) {
+    let end = memchr2(b' ', b'\t', raw).unwrap_or(raw.len());
+    (&raw[..end], &raw[end..])
+}
+
+// What follows the language in a fence's info string
+struct Info<'a> {
+    id: Option<&'a [u8]>,
+    path: Option<&'a [u8]>,
+    // the rest, which holds the block's properties
+    prop_line: Option<&'a [u8]>,
+    notangle: bool,
+}
+
+// Split what follows the language in a fence's info string into the block's id or path, the
+// rest, which holds the block's properties, and whether the block is marked notangle.
+// The id is the first word, optionally marked with a #, e.g. `#setup-db`. A first word
+// written like a path is the block's path instead, and any other that isn't a valid id leaves
+// the block without either. The id `_` names no block, and marks it notangle
+fn info(raw: &[u8]) -> Info<'_> {
+    let mut info = Info {
+        id: None,
+        path: None,
+        prop_line: None,
+        notangle: false,
+    };
+    if raw.is_empty() {
+        return info;
+    }
+    let (word, rest) = first_word(raw);
+    let id = word.strip_prefix(b"#").unwrap_or(word);
+    let raw = if id == b"_" {
+        info.prop_line = Some(rest);
+        info.notangle = true;
+        return info;
+    } else if is_path(word) {
+        info.path = Some(word);
+        rest
+    } else if id.is_empty() || !id.iter().all(|&c| is_id_char(c)) {
+        raw
+    } else {
+        info.id = Some(id);
+        rest
+    };
+    match first_word(raw.trim_ascii_start()) {
+        (NOTANGLE, rest) => {
+            info.prop_line = Some(rest);
+            info.notangle = true;
         }
-        None
+        _ => info.prop_line = Some(raw),
     }
+    info
 }
 
 pub fn code<'a>(
@@ -49,37 +133,61 @@ pub fn code<'a>(
     move |i: &[u8]| {
         let (input, (_, lang, _, raw_id, _)) = tuple((
             tag(code_start),
-            opt(alpha1),
+            opt(verify(take_while1(is_lang_char), |lang: &[u8]| {
+                is_alphanumeric(lang[0])
+            })),
             space0,
             take_until("\n"),
             tag("\n"),
         ))(i)?;
-        let (id, prop_line) = if raw_id.len() > 0 {
-			let line = take_while(is_alphanumeric)(raw_id)?;
-            (Some(line.1), Some(line.0))
-        } else {
-            (None, None)
-        };
-        let mut terminator = locate_parser_match(tuple((
+        // documents saved on windows end lines with \r\n
+        let raw_id = raw_id.strip_suffix(b"\r").unwrap_or(raw_id);
+        let Info {
+            id,
+            path,
+            prop_line,
+            notangle,
+        } = info(raw_id);
+        // the closing fence may end the document without a newline
+        let mut terminator = locate_line_match(tuple((
             tag(code_end),
             space0::<&'a [u8], nom::error::Error<&'a [u8]>>,
-            newline,
+            alt((line_ending, eof)),
         )));
         let end_idx = match terminator(input) {
             Some(result) => result,
             None => return Ok((input, LineParseResult::PartialMatch)),
         };
-        let (excess, _) =
-            take_until::<&str, &'a [u8], nom::error::Error<&'a [u8]>>("\n")(&input[end_idx..])
-                .unwrap();
+        let excess = &input[end_idx..];
+        let excess = &excess[memchr(b'\n', excess).unwrap_or(excess.len())..];
         Ok((
             excess,
             LineParseResult::Matched(ScanResult::Code(CodePart {
                 id,
+                path,
                 contents: &input[..end_idx],
                 lang,
-				prop_line
+                prop_line,
+                notangle,
             })),
         ))
     }
 }
+
+// Parse out a comment starting a line, e.g. `<!-- -->` in markdown. Nothing in it is
+// looked at, so a commented out code block isn't tangled
+pub fn comment<'a>(
+    open: &'static str,
+    close: &'static str,
+) -> impl Fn(&'a [u8]) -> IResult<&'a [u8], LineParseResult<'a>, LineParseError> {
+    move |i: &[u8]| {
+        let (input, _) = tag(open)(i)?;
+        match memmem::find(input, close.as_bytes()) {
+            Some(idx) => Ok((
+                &input[idx + close.len()..],
+                LineParseResult::Matched(ScanResult::Skipped),
+            )),
+            None => Ok((input, LineParseResult::PartialMatch)),
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+// Comment syntax of the languages betwixt knows how to annotate, keyed by the language
+// of a code block's fence
+const LINE_COMMENTS: &[(&str, &str)] = &[
+    ("bash", "#"),
+    ("c", "//"),
+    ("cpp", "//"),
+    ("cs", "//"),
+    ("go", "//"),
+    ("haskell", "--"),
+    ("java", "//"),
+    ("javascript", "//"),
+    ("js", "//"),
+    ("kotlin", "//"),
+    ("lua", "--"),
+    ("make", "#"),
+    ("perl", "#"),
+    ("php", "//"),
+    ("python", "#"),
+    ("py", "#"),
+    ("r", "#"),
+    ("ruby", "#"),
+    ("rust", "//"),
+    ("scala", "//"),
+    ("sh", "#"),
+    ("shell", "#"),
+    ("sql", "--"),
+    ("swift", "//"),
+    ("toml", "#"),
+    ("ts", "//"),
+    ("typescript", "//"),
+    ("yaml", "#"),
+    ("zsh", "#"),
+];
+
+// Languages with block comments, the only kind some of them have
+const BLOCK_COMMENTS: &[(&str, &str, &str)] = &[
+    ("c", "/*", "*/"),
+    ("cpp", "/*", "*/"),
+    ("cs", "/*", "*/"),
+    ("css", "/*", "*/"),
+    ("go", "/*", "*/"),
+    ("haskell", "{-", "-}"),
+    ("html", "<!--", "-->"),
+    ("java", "/*", "*/"),
+    ("javascript", "/*", "*/"),
+    ("js", "/*", "*/"),
+    ("kotlin", "/*", "*/"),
+    ("lua", "--[[", "]]"),
+    ("markdown", "<!--", "-->"),
+    ("md", "<!--", "-->"),
+    ("ocaml", "(*", "*)"),
+    ("php", "/*", "*/"),
+    ("rust", "/*", "*/"),
+    ("scala", "/*", "*/"),
+    ("sql", "/*", "*/"),
+    ("swift", "/*", "*/"),
+    ("ts", "/*", "*/"),
+    ("typescript", "/*", "*/"),
+    ("xml", "<!--", "-->"),
+];
+
+// How comments are written in a language: a token starting a line comment, and the tokens
+// opening and closing a block comment, either of which it may lack
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Syntax {
+    pub line: Option<String>,
+    pub block: Option<(String, String)>,
+}
+
+impl Syntax {
+    // text as a comment, as a line comment when the language has them. None when the
+    // language has neither kind
+    pub fn comment(&self, text: &str) -> Option<String> {
+        match (&self.line, &self.block) {
+            (Some(line), _) => Some(format!("{} {}", line, text)),
+            (None, Some((open, close))) => Some(format!("{} {} {}", open, text, close)),
+            (None, None) => None,
+        }
+    }
+}
+
+// The comment syntax of each language: betwixt's own, with that of languages configured in
+// `betwixt.toml` added or replacing it, e.g.
+//
+//   [comments]
+//   nix = { line = "#" }
+//   svelte = { block = ["<!--", "-->"] }
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(transparent)]
+pub struct Comments {
+    languages: BTreeMap<String, Syntax>,
+}
+
+impl Comments {
+    // The comment syntax of lang, if it is configured or betwixt knows it
+    pub fn syntax(&self, lang: &[u8]) -> Option<Syntax> {
+        let configured = std::str::from_utf8(lang)
+            .ok()
+            .and_then(|lang| self.languages.get(lang));
+        match configured {
+            Some(syntax) => Some(syntax.clone()),
+            None => syntax(lang),
+        }
+    }
+}
+
+// The comment syntax betwixt knows for lang
+pub fn syntax(lang: &[u8]) -> Option<Syntax> {
+    let syntax = Syntax {
+        line: line_comment(lang).map(str::to_owned),
+        block: BLOCK_COMMENTS
+            .iter()
+            .find(|(name, _, _)| name.as_bytes() == lang)
+            .map(|(_, open, close)| (open.to_string(), close.to_string())),
+    };
+    if syntax == Syntax::default() {
+        None
+    } else {
+        Some(syntax)
+    }
+}
+
+// The token that starts a line comment in lang, if betwixt knows it
+pub fn line_comment(lang: &[u8]) -> Option<&'static str> {
+    LINE_COMMENTS
+        .iter()
+        .find(|(name, _)| name.as_bytes() == lang)
+        .map(|(_, token)| *token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comments() {
+        let comments: Comments = toml::from_str(
+            "nix = { line = '#' }
+rust = { block = ['/*', '*/'] }
+",
+        )
+        .unwrap();
+        let comment = |lang: &[u8]| comments.syntax(lang)?.comment("btxt:begin 1");
+        assert_eq!(comment(b"nix").as_deref(), Some("# btxt:begin 1"));
+        // configured languages replace what betwixt knows of them
+        assert_eq!(comment(b"rust").as_deref(), Some("/* btxt:begin 1 */"));
+        assert_eq!(comment(b"python").as_deref(), Some("# btxt:begin 1"));
+        assert_eq!(comment(b"css").as_deref(), Some("/* btxt:begin 1 */"));
+        assert_eq!(comment(b"brainfuck"), None);
+        assert!(toml::from_str::<Comments>("nix = { lne = '#' }").is_err());
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt::Display;
+#[cfg(feature = "fs")]
+use std::fs;
+use std::io;
+#[cfg(feature = "fs")]
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::comments::Comments;
+use crate::exec::Policy;
+use crate::properties::{Properties, PropertyError};
+
+pub const CONFIG_FILENAME: &str = "betwixt.toml";
+
+// Project level configuration, read from a `betwixt.toml`
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub exec: Policy,
+    pub hooks: Hooks,
+    // comment syntax for languages betwixt doesn't know, or to use instead of its own
+    pub comments: Comments,
+    // properties of code blocks in each language, below any the document sets, e.g.
+    //
+    //   [lang.rust]
+    //   filename = "src/main.rs"
+    //   mode = "append"
+    pub lang: BTreeMap<String, BTreeMap<String, Setting>>,
+}
+
+// A property's value in the config, where booleans needn't be quoted
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum Setting {
+    Text(String),
+    Bool(bool),
+}
+
+impl Setting {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Setting::Text(text) => text.as_bytes(),
+            Setting::Bool(true) => b"true",
+            Setting::Bool(false) => b"false",
+        }
+    }
+}
+
+// Commands run before and after tangling writes any files. Hooks come from the
+// project's config rather than the document, so they run without confirmation,
+// but are still subject to the exec policy
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(contents: &str) -> Result<Self, ConfigError> {
+        let config: Self = toml::from_str(contents).map_err(ConfigError::Parse)?;
+        config.defaults()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    #[cfg(feature = "fs")]
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        contents.parse()
+    }
+
+    // The properties of `[lang]`, by language, for `Document::from_contents_with_defaults`. Only properties
+    // of how blocks are tangled can be set there: blocks are ignored, or made from `code`, as
+    // the document is parsed
+    pub fn defaults(&self) -> Result<HashMap<&[u8], Properties<'_>>, ConfigError> {
+        let mut defaults = HashMap::new();
+        for (lang, settings) in self.lang.iter() {
+            let mut props = Properties::default();
+            for (name, value) in settings.iter() {
+                if name == "ignore" || name == "code" {
+                    return Err(ConfigError::Property(
+                        lang.clone(),
+                        PropertyError::Unknown(name.clone()),
+                    ));
+                }
+                props
+                    .set(name, value.as_bytes())
+                    .map_err(|err| ConfigError::Property(lang.clone(), err))?;
+            }
+            defaults.insert(lang.as_bytes(), props);
+        }
+        Ok(defaults)
+    }
+
+    // Load the `betwixt.toml` in dir, if there is one. A missing file is the default config
+    #[cfg(feature = "fs")]
+    pub fn discover(dir: &Path) -> Result<Self, ConfigError> {
+        let path = dir.join(CONFIG_FILENAME);
+        if path.is_file() {
+            Self::from_file(&path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    // a property of a language's defaults, and what is wrong with it
+    Property(String, PropertyError),
+}
+
+impl Error for ConfigError {}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "unable to read config: {}", err),
+            ConfigError::Parse(err) => write!(f, "invalid config: {}", err),
+            ConfigError::Property(lang, err) => write!(f, "invalid config for {}: {}", lang, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::from_str(
+            "[exec]
+allow = ['cargo', 'rustc']
+deny = ['rm', 'curl | sh']
+",
+        )
+        .unwrap();
+        assert_eq!(config.exec.allow, vec!["cargo", "rustc"]);
+        assert_eq!(config.exec.deny, vec!["rm", "curl | sh"]);
+        assert_eq!(config.hooks, Hooks::default());
+        let config = Config::from_str(
+            "hooks.before = ['cargo clean']
+hooks.after = ['cargo check', 'cargo test']
+",
+        )
+        .unwrap();
+        assert_eq!(config.hooks.before, vec!["cargo clean"]);
+        assert_eq!(config.hooks.after, vec!["cargo check", "cargo test"]);
+        assert_eq!(Config::from_str("").unwrap(), Config::default());
+        assert!(
+            Config::from_str("[exec]\nalow = []").is_err(),
+            "unknown keys should be rejected"
+        );
+    }
+
+    #[test]
+    fn test_language_defaults() {
+        let config = Config::from_str(
+            "[lang.rust]
+filename = 'src/main.rs'
+mode = 'append'
+trim-trailing = true
+",
+        )
+        .unwrap();
+        let defaults = config.defaults().unwrap();
+        let doc = crate::parse_with_defaults(
+            b"# Defaults
+```rust
+fn main() {}
+```
+```rust filename='src/lib.rs'
+```
+```python
+```
+",
+            crate::FlavorBuilder::github(),
+            &defaults,
+        )
+        .unwrap();
+        let props = |idx: usize| &doc.code_blocks[idx].properties;
+        assert_eq!(props(0).filename, Some(&b"src/main.rs"[..]));
+        assert_eq!(props(0).trim_trailing, Some(true));
+        // what the document sets wins
+        assert_eq!(props(1).filename, Some(&b"src/lib.rs"[..]));
+        assert_eq!(props(1).mode, Some(crate::TangleMode::Append));
+        assert_eq!(props(2).filename, None);
+        let rust = doc.root.properties.get_code_props(Some(b"rust"));
+        assert_eq!(rust.filename, Some(&b"src/main.rs"[..]));
+        // even a betwixt block for every language wins
+        let doc = crate::parse_with_defaults(
+            b"<?btxt mode='overwrite' ?>\n```rust\n```\n",
+            crate::FlavorBuilder::github(),
+            &defaults,
+        )
+        .unwrap();
+        assert_eq!(
+            doc.code_blocks[0].properties.mode,
+            Some(crate::TangleMode::Overwrite)
+        );
+        // defaults are checked along with the document
+        let config = Config::from_str("[lang.rust]\nmode = 'insert-after[fn main]'").unwrap();
+        let defaults = config.defaults().unwrap();
+        let parsed = crate::parse_with_defaults(
+            b"```rust filename='src/main.rs'\n```\n",
+            crate::FlavorBuilder::github(),
+            &defaults,
+        );
+        assert!(matches!(parsed, Err(crate::DocumentError::Conflict(..))));
+        assert!(Config::from_str("[lang.rust]\nmode = 'sideways'").is_err());
+        assert!(Config::from_str("[lang.rust]\nignore = true").is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+
+use crate::tangle::{MARKER_BEGIN, MARKER_END};
+use crate::Document;
+
+// A block's contents as found between markers in a tangled file
+#[derive(Debug, PartialEq)]
+pub struct MarkedBlock<'t> {
+    pub block: usize,
+    pub id: Option<&'t [u8]>,
+    pub contents: &'t [u8],
+}
+
+// Find every region of a tangled file surrounded by betwixt markers
+pub fn marked_blocks(tangled: &[u8]) -> Result<Vec<MarkedBlock<'_>>, DetangleError> {
+    let mut blocks = Vec::new();
+    let mut open: Option<(usize, Option<&[u8]>, usize)> = None;
+    let mut pos = 0;
+    for line in tangled.split_inclusive(|&c| c == b'\n') {
+        let line_start = pos;
+        pos += line.len();
+        match (&open, marker(line)) {
+            (None, Some((MARKER_BEGIN, block, id))) => open = Some((block, id, pos)),
+            (Some((open_block, id, start)), Some((MARKER_END, block, _))) => {
+                if *open_block != block {
+                    return Err(DetangleError::UnterminatedMarker(*open_block));
+                }
+                blocks.push(MarkedBlock {
+                    block,
+                    id: *id,
+                    contents: &tangled[*start..line_start],
+                });
+                open = None;
+            }
+            (Some((open_block, _, _)), Some((MARKER_BEGIN, _, _))) => {
+                return Err(DetangleError::UnterminatedMarker(*open_block))
+            }
+            (None, Some((_, block, _))) => return Err(DetangleError::UnexpectedEnd(block)),
+            _ => {}
+        }
+    }
+    match open {
+        Some((block, _, _)) => Err(DetangleError::UnterminatedMarker(block)),
+        None => Ok(blocks),
+    }
+}
+
+// Parse a marker line, e.g. `// btxt:begin 4 hellorust`, into the marker kind, block
+// index and block id. Anything before the marker must be a comment token
+fn marker(line: &[u8]) -> Option<(&'static str, usize, Option<&[u8]>)> {
+    let line = line.trim_ascii();
+    let (kind, at) = [MARKER_BEGIN, MARKER_END].iter().find_map(|kind| {
+        line.windows(kind.len())
+            .position(|w| w == kind.as_bytes())
+            .map(|at| (*kind, at))
+    })?;
+    let comment = line[..at].trim_ascii();
+    if comment.is_empty() || comment.iter().any(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    let mut words = line[at + kind.len()..]
+        .split(|c| c.is_ascii_whitespace())
+        .filter(|w| !w.is_empty());
+    let block = std::str::from_utf8(words.next()?).ok()?.parse().ok()?;
+    // what follows the index of a block comment is the token closing it, not an id
+    let id = words
+        .next()
+        .filter(|w| w.iter().any(|c| c.is_ascii_alphanumeric()));
+    Some((kind, block, id))
+}
+
+// Replace the contents of each code block with its contents from the tangled files,
+// returning the updated document and the indexes of the blocks that changed
+pub fn detangle(
+    source: &[u8],
+    doc: &Document,
+    tangled: &[&[u8]],
+) -> Result<(Vec<u8>, Vec<usize>), DetangleError> {
+    let mut edits: HashMap<usize, &[u8]> = HashMap::new();
+    for file in tangled {
+        for marked in marked_blocks(file)? {
+            let block = doc
+                .code_blocks
+                .get(marked.block)
+                .filter(|block| block.properties.code.is_none())
+                .ok_or(DetangleError::UnknownBlock(marked.block))?;
+            if block.part.id != marked.id {
+                return Err(DetangleError::MismatchedBlock(marked.block));
+            }
+            match edits.get(&marked.block) {
+                Some(existing) if *existing != marked.contents => {
+                    return Err(DetangleError::ConflictingEdits(marked.block))
+                }
+                _ => edits.insert(marked.block, marked.contents),
+            };
+        }
+    }
+    let mut changed: Vec<usize> = edits
+        .iter()
+        .filter(|(idx, contents)| doc.code_blocks[**idx].part.contents != **contents)
+        .map(|(idx, _)| *idx)
+        .collect();
+    changed.sort();
+    let mut output = Vec::with_capacity(source.len());
+    let mut pos = 0;
+    for &idx in changed.iter() {
+        let span = doc.code_blocks[idx].contents_span.clone();
+        output.extend_from_slice(&source[pos..span.start]);
+        output.extend_from_slice(edits[&idx]);
+        if !edits[&idx].is_empty() && !edits[&idx].ends_with(b"\n") {
+            // the closing fence has to stay on its own line
+            output.push(b'\n');
+        }
+        pos = span.end;
+    }
+    output.extend_from_slice(&source[pos..]);
+    Ok((output, changed))
+}
+
+#[derive(Debug)]
+pub enum DetangleError {
+    UnterminatedMarker(usize),
+    UnexpectedEnd(usize),
+    UnknownBlock(usize),
+    MismatchedBlock(usize),
+    ConflictingEdits(usize),
+}
+
+impl Error for DetangleError {}
+
+impl Display for DetangleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DetangleError::UnterminatedMarker(idx) => {
+                write!(f, "marker for block {} is never closed", idx)
+            }
+            DetangleError::UnexpectedEnd(idx) => {
+                write!(f, "end marker for block {} without a begin marker", idx)
+            }
+            DetangleError::UnknownBlock(idx) => {
+                write!(f, "block {} does not exist in the document", idx)
+            }
+            DetangleError::MismatchedBlock(idx) => write!(
+                f,
+                "block {} has a different id in the document, it has changed since tangling",
+                idx
+            ),
+            DetangleError::ConflictingEdits(idx) => {
+                write!(f, "block {} was edited differently in multiple places", idx)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strictness::{Lints, Strictness};
+    use crate::tangle::{plan, TangleOptions};
+    use crate::{betwixt, code, section, MarkdownParsers, BETWIXT_TOKEN, CLOSE_TOKEN};
+
+    #[test]
+    fn test_detangle() {
+        let markdown = &b"# Detangle
+<?btxt filename='main.rs' mode='overwrite' pre='fn main() {' post='}' ?>
+```rust greet
+    println!(\"hello\");
+```
+<?btxt filename='main.py' ?>
+```python
+print('unchanged')
+```
+"[..];
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let doc = Document::from_contents(markdown, parsers).unwrap();
+        let options = TangleOptions {
+            lints: Lints::strict(),
+            markers: true,
+            ..Default::default()
+        };
+        let outputs = plan(&doc, &[0, 1], &options).unwrap();
+        let edited = String::from_utf8(outputs[0].contents.clone())
+            .unwrap()
+            .replace("hello", "goodbye");
+        assert_eq!(
+            edited,
+            "fn main() {
+// btxt:begin 0 greet
+    println!(\"goodbye\");
+// btxt:end 0
+}"
+        );
+        let (updated, changed) =
+            detangle(markdown, &doc, &[edited.as_bytes(), &outputs[1].contents]).unwrap();
+        assert_eq!(changed, vec![0]);
+        assert_eq!(
+            String::from_utf8(updated).unwrap(),
+            String::from_utf8_lossy(markdown).replace("hello", "goodbye")
+        );
+        assert!(matches!(
+            detangle(markdown, &doc, &[b"# btxt:begin 1\nprint('x')\n"]),
+            Err(DetangleError::UnterminatedMarker(1))
+        ));
+        assert!(matches!(
+            detangle(markdown, &doc, &[b"# btxt:begin 1 greet\n# btxt:end 1\n"]),
+            Err(DetangleError::MismatchedBlock(1))
+        ));
+        // block comments close after the index, or the id when there is one
+        let marked = marked_blocks(b"<!-- btxt:begin 2 -->\n<p>\n<!-- btxt:end 2 -->\n").unwrap();
+        assert_eq!(marked[0].id, None);
+        assert_eq!(marked[0].contents, b"<p>\n");
+    }
+}
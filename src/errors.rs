@@ -0,0 +1,105 @@
+use std::error::Error;
+use std::fmt::{Debug, Display};
+use std::ops::Range;
+
+// Why a document couldn't be parsed or changed. Errors found in the document carry the
+// byte range of the document they were found at; blocks inserted since have none
+#[derive(Debug)]
+pub enum DocumentError {
+    InvalidMatch(InvalidMatchDetails),
+    DuplicateID(String, Option<Range<usize>>),
+    PropertiesWithoutID,
+    // what is wrong with a code block's properties
+    InvalidProperties(String, Option<Range<usize>>),
+    // properties of a block that can't be tangled together, and why
+    Conflict(String, Option<Range<usize>>),
+    // what an Observer refused, and why
+    Rejected(String, Option<Range<usize>>),
+}
+
+impl DocumentError {
+    // The bytes of the document the error is about, if it is about any
+    pub fn span(&self) -> Option<Range<usize>> {
+        match self {
+            DocumentError::InvalidMatch(details) => Some(details.span.clone()),
+            DocumentError::DuplicateID(_, span)
+            | DocumentError::InvalidProperties(_, span)
+            | DocumentError::Conflict(_, span)
+            | DocumentError::Rejected(_, span) => span.clone(),
+            DocumentError::PropertiesWithoutID => None,
+        }
+    }
+}
+
+impl Error for DocumentError {}
+
+impl Display for DocumentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match &self {
+                DocumentError::InvalidMatch(im) => format!("{}", im),
+                DocumentError::DuplicateID(id, _) => format!("re-used code block id: {}", id),
+                DocumentError::PropertiesWithoutID => {
+                    "code block properties must follow the block's id".into()
+                }
+                DocumentError::InvalidProperties(why, _) => {
+                    format!("invalid code block properties: {}", why)
+                }
+                DocumentError::Conflict(why, _) => format!("conflicting properties: {}", why),
+                DocumentError::Rejected(why, _) => format!("rejected: {}", why),
+            }
+        )
+    }
+}
+
+#[cfg(feature = "miette")]
+impl miette::Diagnostic for DocumentError {
+    fn code<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        Some(Box::new(match self {
+            DocumentError::InvalidMatch(_) | DocumentError::InvalidProperties(..) => {
+                "betwixt::invalid_properties"
+            }
+            DocumentError::DuplicateID(..) => "betwixt::duplicate_id",
+            DocumentError::PropertiesWithoutID => "betwixt::properties_without_id",
+            DocumentError::Conflict(..) => "betwixt::conflicting_properties",
+            DocumentError::Rejected(..) => "betwixt::rejected",
+        }))
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = miette::LabeledSpan> + '_>> {
+        let label = match self {
+            DocumentError::DuplicateID(..) => "already used by an earlier block",
+            DocumentError::Conflict(..) => "can't be tangled as it is",
+            DocumentError::Rejected(..) => "rejected",
+            _ => "invalid property",
+        };
+        let span = self.span()?;
+        Some(Box::new(std::iter::once(
+            miette::LabeledSpan::new_with_span(Some(label.into()), span),
+        )))
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidMatchDetails {
+    pub(crate) line_start: usize,
+    pub(crate) line_end: usize,
+    // what is wrong with the properties
+    pub(crate) reason: String,
+    // the bytes of the document holding the offending property
+    pub(crate) span: Range<usize>,
+}
+
+impl Error for InvalidMatchDetails {}
+
+impl Display for InvalidMatchDetails {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid properties from line {} to line {}: {}",
+            self.line_start, self.line_end, self.reason,
+        )
+    }
+}
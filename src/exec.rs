@@ -0,0 +1,1198 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt::Display;
+use std::io;
+#[cfg(feature = "fs")]
+use std::io::{BufRead, BufReader, Read};
+use std::ops::RangeInclusive;
+use std::path::Path;
+#[cfg(feature = "fs")]
+use std::process::{Child, Command, Stdio};
+#[cfg(feature = "fs")]
+use std::str::from_utf8;
+#[cfg(feature = "fs")]
+use std::sync::mpsc;
+#[cfg(feature = "fs")]
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "fs")]
+use std::time::Instant;
+
+use memchr::memmem;
+use nom::branch::alt;
+use nom::bytes::complete::{tag, take_while1};
+use nom::character::is_space;
+use nom::combinator::{all_consuming, map, verify};
+use nom::sequence::preceded;
+use nom::IResult;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::properties::format_duration;
+use crate::Code;
+
+// Where the commands of a code block are executed. Every runner receives the same
+// command string and the files tangled for the block, and is responsible for making
+// those files available wherever the command actually runs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Runner<'a> {
+    // Run commands directly on this machine, from the output directory. Each command between
+    // `&&` is split on whitespace and run as a program, except on Windows, where it is given to
+    // `cmd /C` so built-ins and quoted paths work as they do in a console
+    #[default]
+    Local,
+    // Run the whole command with `cmd /C`, which interprets it, `&&` included
+    Cmd,
+    // Run the whole command with PowerShell, `powershell` on Windows and `pwsh` elsewhere
+    PowerShell,
+    // Copy tangled files to a remote machine over ssh and run the commands there.
+    // The destination is anything `ssh` accepts, e.g. `user@host` or a host alias, but never
+    // starts with `-`, which ssh and scp would take as an option, e.g. `-oProxyCommand=..`
+    Ssh(&'a [u8]),
+}
+
+impl<'a> Runner<'a> {
+    pub(crate) fn from_bytes(b: &[u8]) -> IResult<&[u8], Runner<'_>> {
+        let local = map(tag("local"), |_| Runner::Local);
+        let cmd = map(tag("cmd"), |_| Runner::Cmd);
+        let powershell = map(tag("powershell"), |_| Runner::PowerShell);
+        let ssh = map(
+            preceded(
+                tag("ssh:"),
+                verify(take_while1(|c| !is_space(c)), |dest: &[u8]| {
+                    !dest.starts_with(b"-")
+                }),
+            ),
+            Runner::Ssh,
+        );
+        all_consuming(alt((local, cmd, powershell, ssh)))(b)
+    }
+
+    // Execute cmd with this runner, returning the stdout of the last command run.
+    // `files` are the (relative) paths tangled for the block being executed. Nothing
+    // is run (or copied) unless the policy permits the command
+    #[cfg(feature = "fs")]
+    pub fn run(&self, cmd: &str, files: &[&Path], policy: &Policy) -> Result<Vec<u8>, ExecError> {
+        self.stream(cmd, files, &Env::default(), policy, None, &mut |_, _| {})
+    }
+
+    // Execute cmd like `run`, with the environment of the block it is run for, passing each
+    // line the commands write to stdout or stderr to forward as soon as it is written, for
+    // commands that take long enough to be watched. cmd is run as given, placeholders should
+    // already be expanded with `Env::expand`. Whatever is still running once timeout has
+    // passed, copying files included, is killed
+    #[cfg(feature = "fs")]
+    pub fn stream(
+        &self,
+        cmd: &str,
+        files: &[&Path],
+        env: &Env,
+        policy: &Policy,
+        timeout: Option<Duration>,
+        forward: &mut dyn FnMut(Stream, &[u8]),
+    ) -> Result<Vec<u8>, ExecError> {
+        policy.check(cmd)?;
+        let deadline = timeout.map(|timeout| (Instant::now() + timeout, timeout));
+        match self {
+            Runner::Local => {
+                let mut output = Vec::new();
+                for cmd in cmd.split("&&") {
+                    let (mut command, program) = local(cmd).ok_or(ExecError::EmptyCommand)?;
+                    output = checked(command.envs(env.vars()), program, deadline, forward)?;
+                }
+                Ok(output)
+            }
+            Runner::Cmd => {
+                let mut command = Command::new("cmd");
+                command.arg("/C");
+                raw_arg(&mut command, cmd);
+                checked(command.envs(env.vars()), "cmd", deadline, forward)
+            }
+            Runner::PowerShell => checked(
+                Command::new(POWERSHELL)
+                    .args(["-NoProfile", "-NonInteractive", "-Command", cmd])
+                    .envs(env.vars()),
+                POWERSHELL,
+                deadline,
+                forward,
+            ),
+            Runner::Ssh(dest) => {
+                let dest = from_utf8(dest).map_err(|_| ExecError::InvalidDestination)?;
+                for file in files {
+                    // ensure the relative directory exists on the remote before copying
+                    if let Some(parent) = file.parent().filter(|p| !p.as_os_str().is_empty()) {
+                        checked(
+                            Command::new("ssh")
+                                .arg("--")
+                                .arg(dest)
+                                .arg("mkdir")
+                                .arg("-p")
+                                .arg(parent),
+                            "ssh",
+                            deadline,
+                            &mut |_, _| {},
+                        )?;
+                    }
+                    let mut target = dest.to_owned();
+                    target.push(':');
+                    target.push_str(&file.to_string_lossy());
+                    checked(
+                        Command::new("scp")
+                            .arg("-q")
+                            .arg("--")
+                            .arg(file)
+                            .arg(target),
+                        "scp",
+                        deadline,
+                        &mut |_, _| {},
+                    )?;
+                }
+                // the remote shell is responsible for interpreting the command, including `&&`,
+                // so the environment is exported ahead of it for every command it runs
+                let mut remote = String::new();
+                for (name, value) in env.vars() {
+                    remote.push_str(&format!(
+                        "export {}='{}'; ",
+                        name,
+                        value.replace('\'', r"'\''")
+                    ));
+                }
+                remote.push_str(cmd);
+                checked(
+                    Command::new("ssh").arg("--").arg(dest).arg(remote),
+                    "ssh",
+                    deadline,
+                    forward,
+                )
+            }
+        }
+    }
+}
+
+impl<'a> Display for Runner<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Runner::Local => write!(f, "local"),
+            Runner::Cmd => write!(f, "cmd"),
+            Runner::PowerShell => write!(f, "powershell"),
+            Runner::Ssh(dest) => write!(f, "ssh:{}", String::from_utf8_lossy(dest)),
+        }
+    }
+}
+
+// What a block's command is run for. Commands are given each of these the block has as the
+// environment variables BTXT_FILE, BTXT_ID, BTXT_LANG and BTXT_TAG, and `{file}` in a cmd
+// is replaced by the file the block tangles to, e.g. `cmd='python {file}'`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Env {
+    pub file: Option<String>,
+    pub id: Option<String>,
+    pub lang: Option<String>,
+    pub tag: Option<String>,
+}
+
+impl Env {
+    pub fn of(block: &Code) -> Self {
+        let string = |value: Option<&[u8]>| value.map(|v| String::from_utf8_lossy(v).into_owned());
+        Env {
+            // a block tangled to several files runs with the first
+            file: string(block.properties.filenames().next()),
+            id: string(block.part.id),
+            lang: string(block.part.lang),
+            tag: string(block.properties.tag),
+        }
+    }
+
+    // The environment variables for those set
+    pub fn vars(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("BTXT_FILE", &self.file),
+            ("BTXT_ID", &self.id),
+            ("BTXT_LANG", &self.lang),
+            ("BTXT_TAG", &self.tag),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value.as_deref()?)))
+        .collect()
+    }
+
+    // cmd with its placeholders replaced. Using `{file}` for a block without a filename is
+    // an error
+    pub fn expand(&self, cmd: &str) -> Result<String, ExecError> {
+        if !cmd.contains(FILE_PLACEHOLDER) {
+            return Ok(cmd.to_owned());
+        }
+        match &self.file {
+            Some(file) => Ok(cmd.replace(FILE_PLACEHOLDER, file)),
+            None => Err(ExecError::NoFile(cmd.to_owned())),
+        }
+    }
+}
+
+const FILE_PLACEHOLDER: &str = "{file}";
+
+// Keep at most max_lines lines and max_bytes bytes of what a command wrote, ending what is kept
+// with a line saying how much was cut, so a runaway command can't bloat what it is kept in.
+// Bytes are cut at the start of a character, never in the middle of one
+pub fn truncate(
+    output: &[u8],
+    max_lines: Option<usize>,
+    max_bytes: Option<usize>,
+) -> Cow<'_, [u8]> {
+    let mut limit = Limit::new(max_lines, max_bytes);
+    let mut kept = Vec::with_capacity(output.len());
+    for line in output.split_inclusive(|&b| b == b'\n') {
+        kept.extend_from_slice(&limit.keep(line));
+    }
+    match limit.marker() {
+        Some(marker) => {
+            kept.extend_from_slice(marker.as_bytes());
+            Cow::Owned(kept)
+        }
+        None => Cow::Borrowed(output),
+    }
+}
+
+// The limits of truncate, for output kept a line at a time as a command writes it
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Limit {
+    max_lines: Option<usize>,
+    max_bytes: Option<usize>,
+    lines: usize,
+    bytes: usize,
+    cut: usize,
+}
+
+impl Limit {
+    pub fn new(max_lines: Option<usize>, max_bytes: Option<usize>) -> Self {
+        Limit {
+            max_lines,
+            max_bytes,
+            ..Default::default()
+        }
+    }
+
+    // What is kept of the next line a command wrote, which is empty once a limit is reached
+    pub fn keep<'l>(&mut self, line: &'l [u8]) -> Cow<'l, [u8]> {
+        let mut end = line.len();
+        if self.cut > 0 || self.max_lines.is_some_and(|max| self.lines >= max) {
+            end = 0;
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            end = end.min(max_bytes.saturating_sub(self.bytes));
+            while end > 0 && end < line.len() && line[end] & 0xc0 == 0x80 {
+                end -= 1;
+            }
+        }
+        self.lines += 1;
+        self.bytes += end;
+        self.cut += line.len() - end;
+        if end == line.len() {
+            return Cow::Borrowed(line);
+        }
+        let mut kept = line[..end].to_vec();
+        if !kept.is_empty() {
+            kept.push(b'\n');
+        }
+        Cow::Owned(kept)
+    }
+
+    // The line saying how much was cut, if anything was
+    pub fn marker(&self) -> Option<String> {
+        match self.cut {
+            0 => None,
+            1 => Some("[truncated 1 byte]\n".into()),
+            cut => Some(format!("[truncated {} bytes]\n", cut)),
+        }
+    }
+}
+
+// output without the ansi escape sequences in it: control sequences such as colors and cursor
+// movement (`ESC [ ... m`), operating system commands such as titles and links (`ESC ] ...`,
+// ended by BEL or `ESC \`), and the other escapes, such as `ESC 7` or `ESC ( B`. An ESC that
+// doesn't start a whole sequence is kept, along with whatever follows it
+pub fn strip_ansi(output: &[u8]) -> Cow<'_, [u8]> {
+    if memchr::memchr(ESC, output).is_none() {
+        return Cow::Borrowed(output);
+    }
+    let mut stripped = Vec::with_capacity(output.len());
+    let mut i = 0;
+    while i < output.len() {
+        if output[i] != ESC {
+            stripped.push(output[i]);
+            i += 1;
+            continue;
+        }
+        match escape_len(&output[i..]) {
+            Some(len) => i += len,
+            None => {
+                stripped.push(ESC);
+                i += 1;
+            }
+        }
+    }
+    Cow::Owned(stripped)
+}
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+// The length of the escape sequence seq starts with, or None if it is cut short or has bytes
+// that can't be part of it
+fn escape_len(seq: &[u8]) -> Option<usize> {
+    // the bytes from start that are within, then a last byte that must be in end
+    let ending = |start: usize, within: RangeInclusive<u8>, end: RangeInclusive<u8>| {
+        let len = seq[start..]
+            .iter()
+            .take_while(|b| within.contains(b))
+            .count();
+        let last = *seq.get(start + len)?;
+        end.contains(&last).then_some(start + len + 1)
+    };
+    match seq.get(1)? {
+        // parameters and intermediates, up to the final byte
+        b'[' => ending(2, 0x20..=0x3f, 0x40..=0x7e),
+        b']' => {
+            let rest = &seq[2..];
+            let bel = memchr::memchr(BEL, rest).map(|end| end + 1);
+            let st = memmem::find(rest, &[ESC, b'\\']).map(|end| end + 2);
+            let end = match (bel, st) {
+                (Some(bel), Some(st)) => bel.min(st),
+                (end, None) | (None, end) => end?,
+            };
+            Some(end + 2)
+        }
+        // any intermediates, e.g. `ESC ( B`, then the final byte
+        _ => ending(1, 0x20..=0x2f, 0x30..=0x7e),
+    }
+}
+
+pub const REDACTED: &str = "[redacted]";
+
+// Secrets kept out of what commands write, replaced with REDACTED wherever they are shown or
+// kept: as output is forwarded, in recordings, in errors and in reports
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Redactor {
+    // longest first, so a secret containing another is redacted whole
+    secrets: Vec<Vec<u8>>,
+}
+
+impl Redactor {
+    // The secrets of a block: each of the comma separated values of its `redact` property,
+    // e.g. `redact='${TOKEN},password'`, where `${NAME}` is the value of the environment
+    // variable NAME, and the value of every variable in vars whose name matches one of
+    // patterns, the `redact` regexes of the exec policy
+    pub fn new<'v>(
+        redact: Option<&[u8]>,
+        patterns: &[String],
+        vars: impl IntoIterator<Item = (&'v str, &'v str)>,
+    ) -> Result<Self, ExecError> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|_| ExecError::InvalidPattern(pattern.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let vars: Vec<(&str, &str)> = vars.into_iter().collect();
+        let mut secrets: Vec<Vec<u8>> = Vec::new();
+        for value in redact.unwrap_or_default().split(|&b| b == b',') {
+            let value = value.trim_ascii();
+            let name = value
+                .strip_prefix(b"${")
+                .and_then(|value| value.strip_suffix(b"}"));
+            match name {
+                Some(name) => secrets.extend(
+                    vars.iter()
+                        .filter(|(var, _)| var.as_bytes() == name)
+                        .map(|(_, value)| value.as_bytes().to_vec()),
+                ),
+                None => secrets.push(value.to_vec()),
+            }
+        }
+        for (name, value) in vars.iter() {
+            if patterns.iter().any(|pattern| pattern.is_match(name)) {
+                secrets.push(value.as_bytes().to_vec());
+            }
+        }
+        // an empty secret would be found everywhere
+        secrets.retain(|secret| !secret.is_empty());
+        secrets.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+        secrets.dedup();
+        Ok(Redactor { secrets })
+    }
+
+    // text with every secret in it replaced
+    pub fn redact<'t>(&self, text: &'t [u8]) -> Cow<'t, [u8]> {
+        let mut text = Cow::Borrowed(text);
+        for secret in self.secrets.iter() {
+            if memmem::find(&text, secret).is_none() {
+                continue;
+            }
+            let mut redacted = Vec::with_capacity(text.len());
+            let mut last = 0;
+            for start in memmem::find_iter(&text, secret) {
+                // occurrences overlapping one already redacted are part of it
+                if start < last {
+                    continue;
+                }
+                redacted.extend_from_slice(&text[last..start]);
+                redacted.extend_from_slice(REDACTED.as_bytes());
+                last = start + secret.len();
+            }
+            redacted.extend_from_slice(&text[last..]);
+            text = Cow::Owned(redacted);
+        }
+        text
+    }
+
+    pub fn redact_str(&self, text: &str) -> String {
+        String::from_utf8_lossy(&self.redact(text.as_bytes())).into_owned()
+    }
+
+    // err with the secrets in what a failing command wrote to stderr redacted
+    pub fn error(&self, err: ExecError) -> ExecError {
+        match err {
+            ExecError::Failed(program, code, stderr) => {
+                ExecError::Failed(program, code, self.redact_str(&stderr))
+            }
+            err => err,
+        }
+    }
+}
+
+// The output of a command a line was written to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+// Run a formatter over a tangled file. The file path is passed as the final argument
+// to the formatter command, e.g. `rustfmt --edition 2021` runs `rustfmt --edition 2021 <file>`
+#[cfg(feature = "fs")]
+pub fn format(cmd: &str, file: &Path, policy: &Policy) -> Result<(), ExecError> {
+    policy.check(cmd)?;
+    let (mut command, program) = local(cmd).ok_or(ExecError::EmptyCommand)?;
+    checked(command.arg(file), program, None, &mut |_, _| {})?;
+    Ok(())
+}
+
+// Run the commands assembling a tangled file over file, the file as it is before it is put
+// in place. `{file}` in them is replaced by its path, and commands without it are given the
+// path as their final argument, like formatters, e.g. `gofmt -w` or `sort -o {file} {file}`.
+// Each is run like a command of the local runner
+#[cfg(feature = "fs")]
+pub fn assemble(cmd: &str, file: &Path, policy: &Policy) -> Result<(), ExecError> {
+    policy.check(cmd)?;
+    for cmd in cmd.split("&&") {
+        if cmd.contains(FILE_PLACEHOLDER) {
+            let cmd = cmd.replace(FILE_PLACEHOLDER, &file.to_string_lossy());
+            let (mut command, program) = local(&cmd).ok_or(ExecError::EmptyCommand)?;
+            checked(&mut command, program, None, &mut |_, _| {})?;
+        } else {
+            let (mut command, program) = local(cmd).ok_or(ExecError::EmptyCommand)?;
+            checked(command.arg(file), program, None, &mut |_, _| {})?;
+        }
+    }
+    Ok(())
+}
+
+// Make attempt until it succeeds or has been retried `retries` times, waiting delay before each
+// retry, returning the last attempt's result and how many attempts were made. Only commands
+// that exit with an error or time out are retried, those denied or that can't be started would
+// fail the same way again. attempt is given the number of the attempt, from 1
+#[cfg(feature = "fs")]
+pub fn retry<T>(
+    retries: u32,
+    delay: Duration,
+    mut attempt: impl FnMut(u32) -> Result<T, ExecError>,
+) -> (Result<T, ExecError>, u32) {
+    let mut attempts = 1;
+    loop {
+        match attempt(attempts) {
+            Err(ExecError::Failed(..) | ExecError::TimedOut(..)) if attempts <= retries => {
+                thread::sleep(delay);
+                attempts += 1;
+            }
+            result => return (result, attempts),
+        }
+    }
+}
+
+// Restrictions on which commands may be executed. A command is denied if any deny
+// rule matches it, and, when the allow list is non-empty, if any program it invokes
+// is not in the allow list.
+//
+// Rules are compared word by word: a rule matches if its words appear in the command
+// in the same order (`curl | sh` matches `curl -fsSL https://x | sh`). Words match a
+// program by name, regardless of path, so `rm` also matches `/bin/rm`.
+#[derive(Debug, Default, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    // regexes matching the names of environment variables whose values are redacted from
+    // what every command writes, e.g. `TOKEN|SECRET|PASSWORD`, see Redactor
+    pub redact: Vec<String>,
+}
+
+impl Policy {
+    pub fn check(&self, cmd: &str) -> Result<(), ExecError> {
+        let cmd_words = words(cmd);
+        for rule in self.deny.iter() {
+            let mut remaining = cmd_words.iter();
+            if words(rule)
+                .iter()
+                .all(|rule| remaining.any(|word| word_matches(word, rule)))
+            {
+                return Err(ExecError::Denied(cmd.to_owned(), rule.clone()));
+            }
+        }
+        if !self.allow.is_empty() {
+            for program in programs(&cmd_words) {
+                if !self
+                    .allow
+                    .iter()
+                    .any(|allowed| word_matches(program, allowed))
+                {
+                    return Err(ExecError::NotAllowed(program.to_owned()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+const SEPARATORS: [&str; 4] = ["&&", "||", "|", ";"];
+
+// split a command into words, with shell separators always standing alone
+fn words(cmd: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    for mut word in cmd.split_whitespace() {
+        while !word.is_empty() {
+            match word.find(['|', ';', '&']) {
+                Some(0) => {
+                    let sep = SEPARATORS
+                        .iter()
+                        .find(|sep| word.starts_with(*sep))
+                        .map_or(1, |sep| sep.len());
+                    words.push(&word[..sep]);
+                    word = &word[sep..];
+                }
+                Some(idx) => {
+                    words.push(&word[..idx]);
+                    word = &word[idx..];
+                }
+                None => {
+                    words.push(word);
+                    word = "";
+                }
+            }
+        }
+    }
+    words
+}
+
+// the first word of every command in a list of words
+fn programs<'w>(words: &[&'w str]) -> Vec<&'w str> {
+    let mut programs = Vec::new();
+    let mut start = true;
+    for word in words {
+        if SEPARATORS.contains(word) || *word == "&" {
+            start = true;
+        } else if start {
+            programs.push(*word);
+            start = false;
+        }
+    }
+    programs
+}
+
+fn word_matches(word: &str, rule: &str) -> bool {
+    word == rule || Path::new(word).file_name().is_some_and(|name| name == rule)
+}
+
+#[cfg(all(feature = "fs", windows))]
+const POWERSHELL: &str = "powershell";
+#[cfg(all(feature = "fs", not(windows)))]
+const POWERSHELL: &str = "pwsh";
+
+// The process running one command of a local cmd, and the program it runs. Scripts are run by
+// their interpreter, `.ps1` by PowerShell and `.bat` or `.cmd` by cmd, and on Windows anything
+// else is run by `cmd /C` as well. None when the command is empty
+#[cfg(feature = "fs")]
+fn local(cmd: &str) -> Option<(Command, &str)> {
+    let cmd = cmd.trim();
+    let (program, args) = cmd.split_once(char::is_whitespace).unwrap_or((cmd, ""));
+    if program.is_empty() {
+        return None;
+    }
+    let extension = Path::new(program)
+        .extension()
+        .map(|extension| extension.to_ascii_lowercase());
+    let mut command;
+    if extension
+        .as_ref()
+        .is_some_and(|extension| extension == "ps1")
+    {
+        command = Command::new(POWERSHELL);
+        command.args([
+            "-NoProfile",
+            "-NonInteractive",
+            "-ExecutionPolicy",
+            "Bypass",
+            "-File",
+        ]);
+        command.arg(program).args(args.split_whitespace());
+    } else if cfg!(windows)
+        || extension.is_some_and(|extension| extension == "bat" || extension == "cmd")
+    {
+        command = Command::new("cmd");
+        command.arg("/C");
+        raw_arg(&mut command, cmd);
+    } else {
+        command = Command::new(program);
+        command.args(args.split_whitespace());
+    }
+    Some((command, program))
+}
+
+// Pass arg to cmd as it is written. cmd doesn't parse its command line the way other programs
+// do, so on Windows the quoting Command adds to arguments would be given to the command
+#[cfg(feature = "fs")]
+fn raw_arg(command: &mut Command, arg: &str) {
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        command.raw_arg(arg);
+    }
+    #[cfg(not(windows))]
+    command.args(arg.split_whitespace());
+}
+
+// run a command to completion, treating a non-zero exit as an error. Each line it writes
+// is passed to forward as it is read. With a deadline, the time it is killed at and the
+// timeout that is from, a command still running then is killed and timed out
+#[cfg(feature = "fs")]
+fn checked(
+    command: &mut Command,
+    program: &str,
+    deadline: Option<(Instant, Duration)>,
+    forward: &mut dyn FnMut(Stream, &[u8]),
+) -> Result<Vec<u8>, ExecError> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| ExecError::Spawn(program.to_owned(), err))?;
+    // both are read at once, in the order lines arrive, so the command never blocks on a
+    // full pipe
+    let (send, lines) = mpsc::channel();
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let readers = [
+        read_lines(stdout, Stream::Stdout, send.clone()),
+        read_lines(stderr, Stream::Stderr, send),
+    ];
+    let (mut output, mut errors) = (Vec::new(), Vec::new());
+    let timed_out = |child: &mut Child, timeout| {
+        // the readers are left to finish on their own, whatever the command started may
+        // still hold its output open
+        let _ = child.kill();
+        let _ = child.wait();
+        ExecError::TimedOut(program.to_owned(), timeout)
+    };
+    loop {
+        let (stream, line) = match deadline {
+            Some((at, timeout)) => {
+                match lines.recv_timeout(at.saturating_duration_since(Instant::now())) {
+                    Ok(line) => line,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        return Err(timed_out(&mut child, timeout))
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            None => match lines.recv() {
+                Ok(line) => line,
+                Err(_) => break,
+            },
+        };
+        forward(stream, &line);
+        match stream {
+            Stream::Stdout => output.extend_from_slice(&line),
+            Stream::Stderr => errors.extend_from_slice(&line),
+        }
+    }
+    for reader in readers {
+        let _ = reader.join();
+    }
+    let spawn = |err| ExecError::Spawn(program.to_owned(), err);
+    // a command can close its output and keep running
+    let status = match deadline {
+        Some((at, timeout)) => loop {
+            match child.try_wait().map_err(spawn)? {
+                Some(status) => break status,
+                None if Instant::now() >= at => return Err(timed_out(&mut child, timeout)),
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        },
+        None => child.wait().map_err(spawn)?,
+    };
+    if !status.success() {
+        return Err(ExecError::Failed(
+            program.to_owned(),
+            status.code(),
+            String::from_utf8_lossy(&errors).into_owned(),
+        ));
+    }
+    Ok(output)
+}
+
+// send each line read from a command's output until it is closed
+#[cfg(feature = "fs")]
+fn read_lines(
+    output: impl Read + Send + 'static,
+    stream: Stream,
+    send: mpsc::Sender<(Stream, Vec<u8>)>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut output = BufReader::new(output);
+        loop {
+            let mut line = Vec::new();
+            match output.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if send.send((stream, line)).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[derive(Debug)]
+pub enum ExecError {
+    EmptyCommand,
+    InvalidDestination,
+    Denied(String, String),
+    NotAllowed(String),
+    Spawn(String, io::Error),
+    Failed(String, Option<i32>, String),
+    // the command was still running once its block's timeout had passed, and was killed
+    TimedOut(String, Duration),
+    // the command uses `{file}`, and its block has no filename
+    NoFile(String),
+    // a `redact` pattern of the exec policy isn't a valid regex
+    InvalidPattern(String),
+}
+
+impl Error for ExecError {}
+
+impl Display for ExecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExecError::EmptyCommand => write!(f, "empty command"),
+            ExecError::InvalidDestination => write!(f, "remote destination is not valid utf8"),
+            ExecError::Denied(cmd, rule) => {
+                write!(f, "command '{}' is denied by rule '{}'", cmd, rule)
+            }
+            ExecError::NotAllowed(program) => {
+                write!(f, "program '{}' is not in the allow list", program)
+            }
+            ExecError::NoFile(cmd) => {
+                write!(
+                    f,
+                    "command '{}' uses {{file}}, but its block has no filename",
+                    cmd
+                )
+            }
+            ExecError::InvalidPattern(pattern) => {
+                write!(f, "redact pattern '{}' is not a valid regex", pattern)
+            }
+            ExecError::Spawn(program, err) => write!(f, "failed to start {}: {}", program, err),
+            ExecError::TimedOut(program, timeout) => write!(
+                f,
+                "{} was killed after running for {}",
+                program,
+                format_duration(*timeout)
+            ),
+            ExecError::Failed(program, code, stderr) => {
+                match code {
+                    Some(code) => write!(f, "{} exited with status {}", program, code)?,
+                    None => write!(f, "{} was terminated by a signal", program)?,
+                }
+                match stderr.trim_end() {
+                    "" => Ok(()),
+                    stderr => write!(f, ": {}", stderr),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runner_from_bytes() {
+        let parsed = Runner::from_bytes(b"local");
+        assert!(matches!(parsed.unwrap().1, Runner::Local));
+        let parsed = Runner::from_bytes(b"ssh:deploy@example.com");
+        assert_eq!(parsed.unwrap().1, Runner::Ssh(b"deploy@example.com"));
+        assert!(
+            Runner::from_bytes(b"ssh:").is_err(),
+            "ssh runner requires a destination"
+        );
+        assert!(
+            Runner::from_bytes(b"ssh:user@host extra").is_err(),
+            "destinations may not contain spaces"
+        );
+        assert!(
+            Runner::from_bytes(b"ssh:-oProxyCommand=touch${IFS}pwned").is_err(),
+            "destinations may not be options"
+        );
+        assert!(crate::extract_props(b" runner='ssh:-F/tmp/config' cmd='make'").is_err());
+        assert!(Runner::from_bytes(b"docker").is_err());
+        assert_eq!(Runner::from_bytes(b"cmd").unwrap().1, Runner::Cmd);
+        assert_eq!(
+            Runner::from_bytes(b"powershell").unwrap().1,
+            Runner::PowerShell
+        );
+        assert_eq!(Runner::PowerShell.to_string(), "powershell");
+        let (_, props) = crate::extract_props(b" runner='ssh:ops@build' cmd='make'").unwrap();
+        assert_eq!(props.runner, Some(Runner::Ssh(b"ops@build")));
+    }
+
+    #[test]
+    #[cfg(all(feature = "fs", unix))]
+    fn test_local_runner() {
+        let output = Runner::Local
+            .run("echo first && echo second", &[], &Policy::default())
+            .unwrap();
+        assert_eq!(output, b"second\n");
+        let mut lines = Vec::new();
+        let output = Runner::Local
+            .stream(
+                "printf a\\nb\\n && ls /nonexistent-betwixt",
+                &[],
+                &Env::default(),
+                &Policy::default(),
+                None,
+                &mut |stream, line| lines.push((stream, line.to_vec())),
+            )
+            .unwrap_err();
+        assert!(matches!(output, ExecError::Failed(_, Some(_), _)));
+        assert_eq!(
+            lines[..2],
+            [
+                (Stream::Stdout, b"a\n".to_vec()),
+                (Stream::Stdout, b"b\n".to_vec())
+            ]
+        );
+        assert_eq!(lines[2].0, Stream::Stderr);
+        assert!(matches!(
+            Runner::Local.run("false", &[], &Policy::default()),
+            Err(ExecError::Failed(_, Some(1), _))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn test_interpreters() {
+        let (command, program) = local(" scripts/setup.ps1 -Force ").unwrap();
+        assert_eq!(program, "scripts/setup.ps1");
+        assert_eq!(command.get_program(), POWERSHELL);
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            [
+                "-NoProfile",
+                "-NonInteractive",
+                "-ExecutionPolicy",
+                "Bypass",
+                "-File",
+                "scripts/setup.ps1",
+                "-Force"
+            ]
+        );
+        let (command, program) = local("build.BAT release").unwrap();
+        assert_eq!(program, "build.BAT");
+        assert_eq!(command.get_program(), "cmd");
+        let (command, _) = local("cargo build").unwrap();
+        assert_eq!(
+            command.get_program(),
+            if cfg!(windows) { "cmd" } else { "cargo" }
+        );
+        assert!(local("  ").is_none());
+    }
+
+    #[test]
+    #[cfg(all(feature = "fs", windows))]
+    fn test_windows_runner() {
+        // built-ins and quoted paths only work when cmd interprets the command
+        let output = Runner::Local
+            .run(
+                r#"echo first && dir /b "C:\Program Files" > nul && echo second"#,
+                &[],
+                &Policy::default(),
+            )
+            .unwrap();
+        assert_eq!(output, b"second\r\n");
+        let output = Runner::Cmd
+            .run("echo one&& echo two", &[], &Policy::default())
+            .unwrap();
+        assert_eq!(output, b"one\r\ntwo\r\n");
+    }
+
+    #[test]
+    #[cfg(all(feature = "fs", unix))]
+    fn test_assemble() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("list.txt");
+        std::fs::write(&file, "b\na\n").unwrap();
+        let policy = Policy::default();
+        // sort -c is given the file, and fails unless it is sorted
+        assert!(matches!(
+            assemble("sort -c", &file, &policy),
+            Err(ExecError::Failed(..))
+        ));
+        assemble("sort -o {file} {file} && sort -c", &file, &policy).unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), b"a\nb\n");
+        assert!(matches!(
+            assemble(" && sort -c", &file, &policy),
+            Err(ExecError::EmptyCommand)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn test_retry() {
+        let failing = || ExecError::Failed("curl".into(), Some(7), String::new());
+        let (result, attempts) = retry(3, Duration::ZERO, |attempt| match attempt {
+            3 => Ok(attempt),
+            _ => Err(failing()),
+        });
+        assert_eq!((result.unwrap(), attempts), (3, 3));
+        let (result, attempts) = retry(2, Duration::ZERO, |_| Err::<(), _>(failing()));
+        assert!(matches!(result, Err(ExecError::Failed(_, Some(7), _))));
+        assert_eq!(attempts, 3);
+        // denied commands would be denied again
+        let (result, attempts) = retry(2, Duration::ZERO, |_| {
+            Err::<(), _>(ExecError::NotAllowed("curl".into()))
+        });
+        assert!(matches!(result, Err(ExecError::NotAllowed(_))));
+        assert_eq!(attempts, 1);
+        let (result, attempts) = retry(1, Duration::ZERO, |_| {
+            Err::<(), _>(ExecError::TimedOut("curl".into(), Duration::from_secs(1)))
+        });
+        assert!(matches!(result, Err(ExecError::TimedOut(..))));
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    #[cfg(all(feature = "fs", unix))]
+    fn test_timeout() {
+        let started = Instant::now();
+        let mut lines = Vec::new();
+        let err = Runner::Local
+            .stream(
+                "echo started && sleep 10",
+                &[],
+                &Env::default(),
+                &Policy::default(),
+                Some(Duration::from_millis(200)),
+                &mut |_, line| lines.push(line.to_vec()),
+            )
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(err.to_string(), "sleep was killed after running for 200ms");
+        assert_eq!(lines, [b"started\n".to_vec()]);
+        let output = Runner::Local
+            .stream(
+                "echo quick",
+                &[],
+                &Env::default(),
+                &Policy::default(),
+                Some(Duration::from_secs(10)),
+                &mut |_, _| {},
+            )
+            .unwrap();
+        assert_eq!(output, b"quick\n");
+    }
+
+    #[test]
+    fn test_truncate() {
+        let output = b"one\ntwo\nthree\n";
+        assert!(matches!(
+            truncate(output, Some(3), Some(14)),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(
+            truncate(output, Some(2), None),
+            &b"one\ntwo\n[truncated 6 bytes]\n"[..]
+        );
+        assert_eq!(
+            truncate(output, Some(2), Some(5)),
+            &b"one\nt\n[truncated 9 bytes]\n"[..]
+        );
+        assert_eq!(
+            truncate(output, Some(0), None),
+            &b"[truncated 14 bytes]\n"[..]
+        );
+        assert_eq!(truncate(b"x", Some(0), None), &b"[truncated 1 byte]\n"[..]);
+        // characters aren't split
+        assert_eq!(
+            truncate("né\n".as_bytes(), None, Some(2)),
+            &b"n\n[truncated 3 bytes]\n"[..]
+        );
+
+        // a line at a time, nothing is kept once a limit is reached
+        let mut limit = Limit::new(Some(2), Some(6));
+        let kept: Vec<_> = ["one\n", "two\n", "three\n"]
+            .iter()
+            .map(|line| limit.keep(line.as_bytes()).into_owned())
+            .collect();
+        assert_eq!(kept, [&b"one\n"[..], b"tw\n", b""]);
+        assert_eq!(limit.marker().unwrap(), "[truncated 8 bytes]\n");
+        assert_eq!(Limit::new(None, None).marker(), None);
+    }
+
+    #[test]
+    fn test_strip_ansi() {
+        assert!(matches!(strip_ansi(b"plain"), Cow::Borrowed(_)));
+        assert_eq!(
+            strip_ansi(b"\x1b[1;32mok\x1b[0m done\x1b[2K"),
+            &b"ok done"[..]
+        );
+        assert_eq!(
+            strip_ansi(b"\x1b]0;title\x07\x1b]8;;https://example.com\x1b\\link\x1b]8;;\x1b\\"),
+            &b"link"[..]
+        );
+        assert_eq!(
+            strip_ansi(b"\x1b(Bsaved\x1b7 \x1bcdone"),
+            &b"saved done"[..]
+        );
+        // what isn't a whole sequence is kept as it is
+        assert_eq!(strip_ansi(b"cut \x1b[31"), &b"cut \x1b[31"[..]);
+        assert_eq!(strip_ansi(b"cut \x1b["), &b"cut \x1b["[..]);
+        assert_eq!(strip_ansi(b"\x1b[1\n2m"), &b"\x1b[1\n2m"[..]);
+        assert_eq!(
+            strip_ansi(b"\x1b\nline\x1b]0;title"),
+            &b"\x1b\nline\x1b]0;title"[..]
+        );
+        assert_eq!(strip_ansi(b"end\x1b"), &b"end\x1b"[..]);
+    }
+
+    #[test]
+    fn test_redactor() {
+        let vars = [
+            ("API_TOKEN", "s3cr3t"),
+            ("DB_PASSWORD", "hunter2"),
+            ("HOME", "/home/me"),
+            ("EMPTY_TOKEN", ""),
+        ];
+        let redactor = Redactor::new(
+            Some(b"${HOME}, password ,${UNSET}"),
+            &["TOKEN$".into()],
+            vars,
+        )
+        .unwrap();
+        assert_eq!(
+            redactor.redact(b"token s3cr3t for /home/me, password hunter2"),
+            &b"token [redacted] for [redacted], [redacted] hunter2"[..]
+        );
+        assert!(matches!(
+            redactor.redact(b"nothing secret"),
+            Cow::Borrowed(_)
+        ));
+        let err = redactor.error(ExecError::Failed(
+            "curl".into(),
+            Some(22),
+            "bad token s3cr3t".into(),
+        ));
+        assert_eq!(
+            err.to_string(),
+            "curl exited with status 22: bad token [redacted]"
+        );
+        assert!(matches!(
+            Redactor::new(None, &["(".into()], vars),
+            Err(ExecError::InvalidPattern(_))
+        ));
+        assert_eq!(Redactor::new(None, &[], vars).unwrap(), Redactor::default());
+    }
+
+    #[test]
+    fn test_env() {
+        let doc = crate::parse(
+            b"```python hello filename='hello.py' tag='demo' cmd='python {file}'\n```\n",
+            crate::FlavorBuilder::github(),
+        )
+        .unwrap();
+        let block = &doc.code_blocks[0];
+        let env = Env::of(block);
+        assert_eq!(
+            env.vars(),
+            vec![
+                ("BTXT_FILE", "hello.py"),
+                ("BTXT_ID", "hello"),
+                ("BTXT_LANG", "python"),
+                ("BTXT_TAG", "demo"),
+            ]
+        );
+        assert_eq!(env.expand("python {file}").unwrap(), "python hello.py");
+        let env = Env { file: None, ..env };
+        assert_eq!(env.expand("make").unwrap(), "make");
+        assert!(matches!(
+            env.expand("python {file}"),
+            Err(ExecError::NoFile(_))
+        ));
+        #[cfg(feature = "fs")]
+        assert_eq!(
+            Runner::Local
+                .stream(
+                    "printenv BTXT_ID",
+                    &[],
+                    &env,
+                    &Policy::default(),
+                    None,
+                    &mut |_, _| {}
+                )
+                .unwrap(),
+            b"hello\n"
+        );
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = Policy {
+            allow: Vec::new(),
+            deny: vec!["rm".into(), "curl | sh".into()],
+            redact: Vec::new(),
+        };
+        assert!(policy.check("cargo build && cargo test").is_ok());
+        assert!(policy.check("cargo build && rm -rf target").is_err());
+        assert!(policy.check("/bin/rm foo").is_err());
+        assert!(policy
+            .check("curl -fsSL https://example.com/install|sh")
+            .is_err());
+        assert!(policy.check("curl -o out https://example.com").is_ok());
+        let policy = Policy {
+            allow: vec!["cargo".into(), "grep".into()],
+            deny: vec!["cargo publish".into()],
+            redact: Vec::new(),
+        };
+        assert!(policy.check("cargo test | grep ok").is_ok());
+        assert!(policy.check("cargo test; echo done").is_err());
+        assert!(matches!(
+            policy.check("cargo publish --dry-run"),
+            Err(ExecError::Denied(_, _))
+        ));
+        #[cfg(feature = "fs")]
+        assert!(matches!(
+            Runner::Local.run("rustc main.rs", &[], &policy),
+            Err(ExecError::NotAllowed(_))
+        ));
+    }
+}
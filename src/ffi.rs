@@ -0,0 +1,147 @@
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::path::Path;
+use std::ptr;
+
+use crate::bindings::{blocks, document, plan};
+
+// A C ABI, for editors and other programs that embed betwixt rather than running the cli.
+// See include/betwixt.h. Strings are nul terminated utf8, and every string returned must be
+// released with betwixt_free. Functions that can fail take an `error` out parameter, which
+// is set to a description of the failure when it isn't null
+
+// Hand a string to the caller, who frees it with betwixt_free. Strings with nuls, which C
+// can't represent, are cut short at the first
+fn give(s: String) -> *mut c_char {
+    let s = match CString::new(s) {
+        Ok(s) => s,
+        Err(err) => {
+            let end = err.nul_position();
+            CString::new(&err.into_vec()[..end]).expect("nul removed")
+        }
+    };
+    s.into_raw()
+}
+
+unsafe fn fail(error: *mut *mut c_char, err: impl ToString) {
+    if !error.is_null() {
+        *error = give(err.to_string());
+    }
+}
+
+unsafe fn borrow<'a>(s: *const c_char, name: &str) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err(format!("{} is null", name));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| format!("{} is not valid utf8", name))
+}
+
+// Parse a document, returning its code blocks as a json array, or null if it can't be parsed
+//
+// # Safety
+// markdown must be a nul terminated string, and error null or valid to write to
+#[no_mangle]
+pub unsafe extern "C" fn betwixt_parse_json(
+    markdown: *const c_char,
+    error: *mut *mut c_char,
+) -> *mut c_char {
+    let json = borrow(markdown, "markdown").and_then(|markdown| {
+        let doc = document(markdown).map_err(|err| err.to_string())?;
+        serde_json::to_string(&blocks(&doc)).map_err(|err| err.to_string())
+    });
+    match json {
+        Ok(json) => give(json),
+        Err(err) => {
+            fail(error, err);
+            ptr::null_mut()
+        }
+    }
+}
+
+// Tangle a document into dir, which must exist. Returns 0 once every file is written, and -1
+// otherwise. Only blocks with a filename are tangled, and neither formatters nor commands
+// are run
+//
+// # Safety
+// markdown and dir must be nul terminated strings, and error null or valid to write to
+#[no_mangle]
+pub unsafe extern "C" fn betwixt_tangle(
+    markdown: *const c_char,
+    dir: *const c_char,
+    error: *mut *mut c_char,
+) -> c_int {
+    let written = borrow(markdown, "markdown").and_then(|markdown| {
+        let dir = Path::new(borrow(dir, "dir")?);
+        let doc = document(markdown).map_err(|err| err.to_string())?;
+        for output in plan(&doc).map_err(|err| err.to_string())?.iter() {
+            output.write(dir).map_err(|err| err.to_string())?;
+        }
+        Ok(())
+    });
+    match written {
+        Ok(()) => 0,
+        Err(err) => {
+            fail(error, err);
+            -1
+        }
+    }
+}
+
+// Release a string returned by betwixt. Null is ignored
+//
+// # Safety
+// s must be null or a string returned by betwixt that hasn't been freed already
+#[no_mangle]
+pub unsafe extern "C" fn betwixt_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_ffi() {
+        let markdown = CString::new(
+            "# FFI
+<?btxt filename='ffi.rs' mode='overwrite' ?>
+```rust
+fn main() {}
+```
+",
+        )
+        .unwrap();
+        let mut error = ptr::null_mut();
+        unsafe {
+            let json = betwixt_parse_json(markdown.as_ptr(), &mut error);
+            assert!(error.is_null());
+            let blocks: serde_json::Value =
+                serde_json::from_slice(CStr::from_ptr(json).to_bytes()).unwrap();
+            assert_eq!(blocks[0]["filename"], "ffi.rs");
+            betwixt_free(json);
+
+            let invalid = CString::new("<?btxt bogus=1 ?>\n").unwrap();
+            assert!(betwixt_parse_json(invalid.as_ptr(), &mut error).is_null());
+            assert!(!error.is_null());
+            betwixt_free(error);
+            assert!(betwixt_parse_json(ptr::null(), ptr::null_mut()).is_null());
+
+            let tmp = tempfile::tempdir().unwrap();
+
+            let dir = tmp.path();
+            let c_dir = CString::new(dir.to_str().unwrap()).unwrap();
+            assert_eq!(
+                betwixt_tangle(markdown.as_ptr(), c_dir.as_ptr(), ptr::null_mut()),
+                0
+            );
+            assert_eq!(
+                fs::read_to_string(dir.join("ffi.rs")).unwrap(),
+                "fn main() {}\n"
+            );
+        }
+    }
+}
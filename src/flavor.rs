@@ -0,0 +1,209 @@
+use crate::properties::{betwixt_with, CustomProperty};
+use crate::{
+    code, comment, section_with, LineParser, MarkdownParsers, Strictness, BETWIXT_TOKEN,
+    CLOSE_TOKEN,
+};
+
+// The markdown syntax a document is written in: how code blocks are fenced, how
+// headings are marked and how betwixt blocks are opened and closed
+#[derive(Clone, Debug)]
+pub struct FlavorBuilder {
+    code_start: &'static str,
+    code_end: &'static str,
+    section_mark: char,
+    // whether headings need a space after their marks
+    heading_space: bool,
+    open: &'static str,
+    close: &'static str,
+    // the comment syntax whose contents are skipped, if any
+    comments: Option<(&'static str, &'static str)>,
+    strict: Strictness,
+    custom: Vec<CustomProperty>,
+}
+
+impl Default for FlavorBuilder {
+    fn default() -> Self {
+        FlavorBuilder::github()
+    }
+}
+
+impl FlavorBuilder {
+    // markdown used by github and many others
+    pub fn github() -> Self {
+        FlavorBuilder {
+            code_start: "```",
+            code_end: "```",
+            section_mark: '#',
+            heading_space: true,
+            open: BETWIXT_TOKEN,
+            close: CLOSE_TOKEN,
+            comments: Some(("<!--", "-->")),
+            strict: Strictness::Deny,
+            custom: Vec::new(),
+        }
+    }
+
+    // markdown embedded in a markdown code block, fenced with ''' so it doesn't end the
+    // block it is in
+    pub fn nested() -> Self {
+        FlavorBuilder {
+            code_start: "'''",
+            code_end: "'''",
+            ..FlavorBuilder::github()
+        }
+    }
+
+    pub fn fence(mut self, start: &'static str, end: &'static str) -> Self {
+        self.code_start = start;
+        self.code_end = end;
+        self
+    }
+
+    pub fn section_mark(mut self, mark: char) -> Self {
+        self.section_mark = mark;
+        self
+    }
+
+    // Accept headings without a space after their marks, e.g. `##Title`
+    pub fn heading_space(mut self, required: bool) -> Self {
+        self.heading_space = required;
+        self
+    }
+
+    pub fn tokens(mut self, open: &'static str, close: &'static str) -> Self {
+        self.open = open;
+        self.close = close;
+        self
+    }
+
+    // Skip everything in comments opened and closed by these tokens, rather than tangling
+    // code blocks that are commented out. Betwixt blocks written as comments are still read
+    pub fn comments(mut self, open: &'static str, close: &'static str) -> Self {
+        self.comments = Some((open, close));
+        self
+    }
+
+    // Look into comments as if they weren't there
+    pub fn no_comments(mut self) -> Self {
+        self.comments = None;
+        self
+    }
+
+    // how betwixt blocks with invalid properties are handled
+    pub fn strict(mut self, strict: Strictness) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn custom_property(mut self, property: CustomProperty) -> Self {
+        self.custom.push(property);
+        self
+    }
+
+    pub fn build<'a>(
+        self,
+    ) -> MarkdownParsers<impl LineParser<'a>, impl LineParser<'a>, impl LineParser<'a>> {
+        let betwixt = betwixt_with(self.open, self.close, self.custom);
+        let comment = self.comments.map(|(open, close)| comment(open, close));
+        MarkdownParsers {
+            code: code(self.code_start, self.code_end),
+            section: section_with(self.section_mark, self.heading_space),
+            // betwixt blocks can be written as comments, so are looked for first
+            betwixt: move |i| match (betwixt(i), &comment) {
+                (Err(nom::Err::Error(_)), Some(comment)) => comment(i),
+                (result, _) => result,
+            },
+            strict: self.strict,
+        }
+    }
+}
+
+// The parameters are placeholders, the parsers built are named by the return types
+impl MarkdownParsers<(), (), ()> {
+    pub fn github<'a>(
+    ) -> MarkdownParsers<impl LineParser<'a>, impl LineParser<'a>, impl LineParser<'a>> {
+        FlavorBuilder::github().build()
+    }
+
+    pub fn nested<'a>(
+    ) -> MarkdownParsers<impl LineParser<'a>, impl LineParser<'a>, impl LineParser<'a>> {
+        FlavorBuilder::nested().build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Document, BETWIXT_COM_TOKEN, CLOSE_COM_TOKEN};
+
+    #[test]
+    fn test_flavors() {
+        let github = &b"# Github
+<?btxt filename='main.rs' ?>
+```rust
+fn main() {}
+```
+"[..];
+        let doc = Document::from_contents(github, MarkdownParsers::github()).unwrap();
+        assert_eq!(doc.code_blocks[0].part.contents, b"fn main() {}\n");
+        let nested = String::from_utf8_lossy(github).replace("```", "'''");
+        let doc = Document::from_contents(nested.as_bytes(), MarkdownParsers::nested()).unwrap();
+        assert_eq!(
+            doc.code_blocks[0].properties.filename,
+            Some(&b"main.rs"[..])
+        );
+
+        let custom = &b"= Custom
+<!--btxt filename='main.rs' -->
+~~~rust
+fn main() {}
+~~~
+"[..];
+        let parsers = FlavorBuilder::github()
+            .fence("~~~", "~~~")
+            .section_mark('=')
+            .tokens(BETWIXT_COM_TOKEN, CLOSE_COM_TOKEN)
+            .build();
+        let doc = Document::from_contents(custom, parsers).unwrap();
+        assert_eq!(doc.root.children[0].part.heading, Some(&b"Custom"[..]));
+        assert_eq!(
+            doc.code_blocks[0].properties.filename,
+            Some(&b"main.rs"[..])
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        let markdown = &b"# Comments
+<!-- an old example
+```rust
+fn old() {}
+```
+# Not a heading
+-->
+<!--btxt filename='main.rs' -->
+```rust
+fn main() {}
+```
+<!-- ```rust
+fn inline() {}
+``` --> trailing text
+"[..];
+        let parsers = FlavorBuilder::github()
+            .tokens(BETWIXT_COM_TOKEN, CLOSE_COM_TOKEN)
+            .build();
+        let doc = Document::from_contents(markdown, parsers).unwrap();
+        assert_eq!(doc.root.children.len(), 1);
+        assert_eq!(doc.code_blocks.len(), 1);
+        assert_eq!(doc.code_blocks[0].part.contents, b"fn main() {}\n");
+        assert_eq!(
+            doc.code_blocks[0].properties.filename,
+            Some(&b"main.rs"[..])
+        );
+
+        let parsers = FlavorBuilder::github().no_comments().build();
+        let doc = Document::from_contents(markdown, parsers).unwrap();
+        assert_eq!(doc.root.children.len(), 2);
+        assert_eq!(doc.code_blocks.len(), 2);
+    }
+}
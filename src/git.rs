@@ -0,0 +1,329 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::iter;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+// Working alongside git, for projects where documents are the source and the files tangled
+// from them are generated
+
+pub const IGNORE_FILE: &str = ".gitignore";
+const IGNORE_HEADER: &str = "# tangled by betwixt";
+
+// The .gitignore that should list file: the nearest one in the directories above it, up to
+// the root of the repository it is in. When there is none, the one that would be at the
+// root of the repository, or next to the file outside of one
+pub fn ignore_file(file: &Path) -> PathBuf {
+    let parent = file.parent().unwrap_or(Path::new("."));
+    for dir in parent.ancestors() {
+        let ignore = dir.join(IGNORE_FILE);
+        if ignore.is_file() {
+            return ignore;
+        }
+        if dir.join(".git").exists() {
+            return ignore;
+        }
+    }
+    parent.join(IGNORE_FILE)
+}
+
+// Make sure each of files (absolute paths) is listed in the .gitignore nearest to it,
+// appending those that aren't. Returns the .gitignore files changed
+pub fn ignore(files: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let mut by_ignore: BTreeMap<PathBuf, Vec<String>> = BTreeMap::new();
+    for file in files {
+        let ignore = ignore_file(file);
+        let dir = ignore.parent().unwrap_or(Path::new("."));
+        let relative = file.strip_prefix(dir).unwrap_or(file);
+        // anchored to the .gitignore's directory, so other files of the same name are kept
+        let entry = format!("/{}", relative.to_string_lossy().replace('\\', "/"));
+        by_ignore.entry(ignore).or_default().push(entry);
+    }
+    let mut changed = Vec::new();
+    for (ignore, entries) in by_ignore {
+        let contents = match fs::read_to_string(&ignore) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        let listed: Vec<&str> = contents.lines().map(str::trim).collect();
+        let mut missing: Vec<String> = entries
+            .into_iter()
+            .filter(|entry| !listed.contains(&entry.as_str()) && !listed.contains(&&entry[1..]))
+            .collect();
+        missing.dedup();
+        if missing.is_empty() {
+            continue;
+        }
+        let mut updated = contents.clone();
+        if !updated.is_empty() && !updated.ends_with('\n') {
+            updated.push('\n');
+        }
+        if !listed.contains(&IGNORE_HEADER) {
+            updated.push_str(IGNORE_HEADER);
+            updated.push('\n');
+        }
+        for entry in missing {
+            updated.push_str(&entry);
+            updated.push('\n');
+        }
+        fs::write(&ignore, updated)?;
+        changed.push(ignore);
+    }
+    Ok(changed)
+}
+
+// The files git has uncommitted changes to, of those given. Files git doesn't track, and
+// files outside of any repository, have none
+pub fn uncommitted(files: &[PathBuf]) -> Result<Vec<PathBuf>, GitError> {
+    let mut dirty = Vec::new();
+    for file in files {
+        let Some(name) = file.file_name() else {
+            continue;
+        };
+        if !file.exists() {
+            continue;
+        }
+        let output = git(
+            dir(file),
+            &[
+                "status".as_ref(),
+                "--porcelain".as_ref(),
+                "--untracked-files=no".as_ref(),
+                "--".as_ref(),
+                name,
+            ],
+        )?;
+        if !output.status.success() {
+            if String::from_utf8_lossy(&output.stderr).contains("not a git repository") {
+                continue;
+            }
+            return Err(failed(output));
+        }
+        if !output.stdout.is_empty() {
+            dirty.push(file.clone());
+        }
+    }
+    Ok(dirty)
+}
+
+// Commit files, and only them, with message, staging them first. Other changes, staged or
+// not, are left as they were. Returns whether there was anything to commit
+pub fn commit(files: &[PathBuf], message: &str) -> Result<bool, GitError> {
+    let Some(first) = files.first() else {
+        return Ok(false);
+    };
+    let dir = dir(first);
+    // a git command, limited to files
+    fn args<'a>(command: &'a [&'a str], files: &'a [PathBuf]) -> Vec<&'a OsStr> {
+        command
+            .iter()
+            .map(OsStr::new)
+            .chain(iter::once(OsStr::new("--")))
+            .chain(files.iter().map(|file| file.as_os_str()))
+            .collect()
+    }
+    let output = git(dir, &args(&["add"], files))?;
+    if !output.status.success() {
+        return Err(failed(output));
+    }
+    let output = git(dir, &args(&["diff", "--cached", "--quiet"], files))?;
+    if output.status.success() {
+        return Ok(false);
+    }
+    let output = git(dir, &args(&["commit", "--quiet", "-m", message], files))?;
+    if !output.status.success() {
+        return Err(failed(output));
+    }
+    Ok(true)
+}
+
+// The lines of file, counting from 1, changed since the revision rev, including changes not
+// yet committed. Lines removed are marked by the lines either side of them
+pub fn changed_lines(file: &Path, rev: &str) -> Result<Vec<Range<usize>>, GitError> {
+    let name = file.file_name().unwrap_or(file.as_os_str());
+    let output = git(
+        dir(file),
+        &[
+            "diff".as_ref(),
+            "--unified=0".as_ref(),
+            "--no-color".as_ref(),
+            rev.as_ref(),
+            "--".as_ref(),
+            name,
+        ],
+    )?;
+    if !output.status.success() {
+        return Err(failed(output));
+    }
+    Ok(hunks(&String::from_utf8_lossy(&output.stdout)))
+}
+
+// The new lines of each hunk of a diff, from headers like `@@ -12,3 +14,2 @@`
+fn hunks(diff: &str) -> Vec<Range<usize>> {
+    diff.lines()
+        .filter_map(|line| line.strip_prefix("@@ "))
+        .filter_map(|header| header.split(' ').find_map(|part| part.strip_prefix('+')))
+        .filter_map(|new| {
+            let (start, len): (usize, usize) = match new.split_once(',') {
+                Some((start, len)) => (start.parse().ok()?, len.parse().ok()?),
+                None => (new.parse().ok()?, 1),
+            };
+            Some(match len {
+                // removed after line start
+                0 => start.max(1)..start + 2,
+                len => start..start + len,
+            })
+        })
+        .collect()
+}
+
+// The directory a file is in
+fn dir(file: &Path) -> &Path {
+    file.parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+}
+
+fn git(dir: &Path, args: &[&OsStr]) -> Result<Output, GitError> {
+    Command::new("git")
+        // errors are told apart by their messages, which must be in english
+        .env("LC_ALL", "C")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .map_err(GitError::Spawn)
+}
+
+fn failed(output: Output) -> GitError {
+    GitError::Failed(
+        output.status.code(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+    )
+}
+
+#[derive(Debug)]
+pub enum GitError {
+    Spawn(io::Error),
+    Failed(Option<i32>, String),
+    // files that would be overwritten, but have uncommitted changes
+    Uncommitted(Vec<PathBuf>),
+}
+
+impl Error for GitError {}
+
+impl Display for GitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GitError::Spawn(err) => write!(f, "failed to start git: {}", err),
+            GitError::Failed(code, stderr) => {
+                match code {
+                    Some(code) => write!(f, "git exited with status {}", code)?,
+                    None => write!(f, "git was terminated by a signal")?,
+                }
+                match stderr.trim_end() {
+                    "" => Ok(()),
+                    stderr => write!(f, ": {}", stderr),
+                }
+            }
+            GitError::Uncommitted(files) => {
+                let files: Vec<String> = files
+                    .iter()
+                    .map(|file| file.to_string_lossy().into_owned())
+                    .collect();
+                write!(
+                    f,
+                    "refusing to overwrite files with uncommitted changes: {}",
+                    files.join(", ")
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ignore() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let src = root.join("src");
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::create_dir_all(&src).unwrap();
+        fs::write(root.join(IGNORE_FILE), "target/\n/src/lib.rs").unwrap();
+        let files = vec![
+            src.join("main.rs"),
+            src.join("lib.rs"),
+            root.join("build.rs"),
+        ];
+        assert_eq!(ignore(&files).unwrap(), vec![root.join(IGNORE_FILE)]);
+        assert_eq!(
+            fs::read_to_string(root.join(IGNORE_FILE)).unwrap(),
+            "target/\n/src/lib.rs\n# tangled by betwixt\n/src/main.rs\n/build.rs\n"
+        );
+        // already listed files are left alone
+        assert!(ignore(&files).unwrap().is_empty());
+
+        // a nested .gitignore is nearer than the repository's
+        fs::write(src.join(IGNORE_FILE), "").unwrap();
+        assert_eq!(ignore_file(&src.join("main.rs")), src.join(IGNORE_FILE));
+    }
+
+    #[test]
+    fn test_hunks() {
+        let diff = "diff --git a/README.md b/README.md
+--- a/README.md
++++ b/README.md
+@@ -3 +3 @@ heading
+-old
++new
+@@ -10,2 +10,3 @@
+@@ -20,4 +22,0 @@
+";
+        assert_eq!(hunks(diff), vec![3..4, 10..13, 22..24]);
+    }
+
+    // Run git in dir for a test, which must succeed
+    fn run(dir: &Path, args: &[&str]) {
+        let args: Vec<&OsStr> = args.iter().map(OsStr::new).collect();
+        let output = git(dir, &args).unwrap();
+        assert!(output.status.success(), "{}", failed(output));
+    }
+
+    #[test]
+    fn test_commit() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let (committed, changed, untracked) =
+            (root.join("a.rs"), root.join("b.rs"), root.join("c.rs"));
+        let files = vec![committed.clone(), changed.clone(), untracked.clone()];
+        fs::write(&committed, "a").unwrap();
+        fs::write(&changed, "b").unwrap();
+        assert!(uncommitted(&files).unwrap().is_empty(), "not a repository");
+
+        run(root, &["init", "-q"]);
+        run(root, &["config", "user.name", "betwixt"]);
+        run(root, &["config", "user.email", "betwixt@example.com"]);
+        run(root, &["add", "a.rs", "b.rs"]);
+        run(root, &["commit", "-qm", "init"]);
+        fs::write(&changed, "changed").unwrap();
+        fs::write(&untracked, "c").unwrap();
+        assert_eq!(uncommitted(&files).unwrap(), vec![changed.clone()]);
+
+        // only the files given are committed
+        let changed = [changed];
+        assert!(commit(&changed, "tangle").unwrap());
+        assert!(uncommitted(&files).unwrap().is_empty());
+        let status = git(root, &["status".as_ref(), "--porcelain".as_ref()]).unwrap();
+        assert_eq!(status.stdout, b"?? c.rs\n");
+        assert!(!commit(&changed, "tangle").unwrap(), "nothing changed");
+    }
+}
@@ -0,0 +1,214 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use crate::section::Section;
+use crate::Document;
+
+// The structure of a document: its section tree, code blocks and the files they tangle to
+struct Graph {
+    nodes: Vec<Node>,
+    // from, to, and an optional label
+    edges: Vec<(String, String, Option<String>)>,
+}
+
+struct Node {
+    id: String,
+    label: String,
+    shape: Shape,
+}
+
+enum Shape {
+    Section,
+    Block,
+    File,
+}
+
+impl Graph {
+    fn new(doc: &Document) -> Self {
+        let mut graph = Graph {
+            nodes: Vec::new(),
+            edges: Vec::new(),
+        };
+        let mut sections = 0;
+        graph.add_section(doc, &doc.root, &mut sections);
+        let mut files: HashMap<Cow<[u8]>, String> = HashMap::new();
+        for (idx, block) in doc.code_blocks.iter().enumerate() {
+            for filename in doc.filenames(idx) {
+                let file = match files.get(&filename) {
+                    Some(file) => file.clone(),
+                    None => {
+                        let id = format!("f{}", files.len());
+                        graph.nodes.push(Node {
+                            id: id.clone(),
+                            label: String::from_utf8_lossy(&filename).into(),
+                            shape: Shape::File,
+                        });
+                        files.insert(filename, id.clone());
+                        id
+                    }
+                };
+                let mode = block.properties.mode.as_ref().map(|m| m.to_string());
+                graph.edges.push((format!("b{}", idx), file, mode));
+            }
+        }
+        graph
+    }
+
+    fn add_section(&mut self, doc: &Document, section: &Section, count: &mut usize) -> String {
+        let id = format!("s{}", count);
+        *count += 1;
+        self.nodes.push(Node {
+            id: id.clone(),
+            label: section.part.title().unwrap_or_else(|| "document".into()),
+            shape: Shape::Section,
+        });
+        for &idx in section.code_block_indexes.iter() {
+            let block = &doc.code_blocks[idx];
+            let mut label = match block.part.id {
+                Some(id) => String::from_utf8_lossy(id).into(),
+                None => format!("block {}", idx),
+            };
+            if let Some(lang) = block.part.lang {
+                label += &format!(" ({})", String::from_utf8_lossy(lang));
+            }
+            self.nodes.push(Node {
+                id: format!("b{}", idx),
+                label,
+                shape: Shape::Block,
+            });
+            self.edges.push((id.clone(), format!("b{}", idx), None));
+        }
+        for child in section.children.iter() {
+            let child = self.add_section(doc, child, count);
+            self.edges.push((id.clone(), child, None));
+        }
+        id
+    }
+}
+
+// A mermaid flowchart of the document
+pub fn mermaid(doc: &Document) -> String {
+    let graph = Graph::new(doc);
+    // mermaid labels are quoted, so that e.g. the brackets of an insert mode are kept, and
+    // can't contain double quotes, it has its own escape for them
+    let escape = |s: &str| s.replace('"', "#quot;");
+    let mut output = String::from("flowchart LR\n");
+    for node in graph.nodes.iter() {
+        let label = escape(&node.label);
+        let (open, close) = match node.shape {
+            Shape::Section => ("[\"", "\"]"),
+            Shape::Block => ("(\"", "\")"),
+            Shape::File => ("[(\"", "\")]"),
+        };
+        output += &format!("    {}{}{}{}\n", node.id, open, label, close);
+    }
+    for (from, to, label) in graph.edges.iter() {
+        match label {
+            Some(label) => output += &format!("    {} -->|\"{}\"| {}\n", from, escape(label), to),
+            None => output += &format!("    {} --> {}\n", from, to),
+        }
+    }
+    output
+}
+
+// A graphviz digraph of the document, in the dot language
+pub fn dot(doc: &Document) -> String {
+    let graph = Graph::new(doc);
+    let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+    let mut output = String::from("digraph betwixt {\n    rankdir=LR;\n");
+    for node in graph.nodes.iter() {
+        let shape = match node.shape {
+            Shape::Section => "box",
+            Shape::Block => "ellipse",
+            Shape::File => "cylinder",
+        };
+        output += &format!(
+            "    {} [label={} shape={}];\n",
+            node.id,
+            quote(&node.label),
+            shape
+        );
+    }
+    for (from, to, label) in graph.edges.iter() {
+        match label {
+            Some(label) => output += &format!("    {} -> {} [label={}];\n", from, to, quote(label)),
+            None => output += &format!("    {} -> {};\n", from, to),
+        }
+    }
+    output += "}\n";
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{betwixt, code, section, MarkdownParsers, Strictness, BETWIXT_TOKEN, CLOSE_TOKEN};
+
+    #[test]
+    fn test_graph() {
+        let markdown = &b"# Graph \"quoted\"
+<?btxt filename='main.rs' mode='overwrite' ?>
+```rust hello
+fn main() {}
+```
+## Child
+<?btxt mode='append' ?>
+```rust
+fn other() {}
+```
+<?btxt mode='insert-after[other(\"x\")]' ?>
+```rust
+fn inserted() {}
+```
+"[..];
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let doc = Document::from_contents(markdown, parsers).unwrap();
+        assert_eq!(
+            mermaid(&doc),
+            "flowchart LR
+    s0[\"document\"]
+    s1[\"Graph #quot;quoted#quot;\"]
+    b0(\"hello (rust)\")
+    s2[\"Child\"]
+    b1(\"block 1 (rust)\")
+    b2(\"block 2 (rust)\")
+    f0[(\"main.rs\")]
+    s1 --> b0
+    s2 --> b1
+    s2 --> b2
+    s1 --> s2
+    s0 --> s1
+    b0 -->|\"overwrite\"| f0
+    b1 -->|\"append\"| f0
+    b2 -->|\"insert-after[other(#quot;x#quot;)]\"| f0
+"
+        );
+        assert_eq!(
+            dot(&doc),
+            "digraph betwixt {
+    rankdir=LR;
+    s0 [label=\"document\" shape=box];
+    s1 [label=\"Graph \\\"quoted\\\"\" shape=box];
+    b0 [label=\"hello (rust)\" shape=ellipse];
+    s2 [label=\"Child\" shape=box];
+    b1 [label=\"block 1 (rust)\" shape=ellipse];
+    b2 [label=\"block 2 (rust)\" shape=ellipse];
+    f0 [label=\"main.rs\" shape=cylinder];
+    s1 -> b0;
+    s2 -> b1;
+    s2 -> b2;
+    s1 -> s2;
+    s0 -> s1;
+    b0 -> f0 [label=\"overwrite\"];
+    b1 -> f0 [label=\"append\"];
+    b2 -> f0 [label=\"insert-after[other(\\\"x\\\")]\"];
+}
+"
+        );
+    }
+}
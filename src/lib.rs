@@ -1,36 +1,112 @@
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
-use std::error::Error;
-use std::fmt::{Debug, Display};
-use std::mem;
+use std::fmt::Debug;
+use std::ops::Range;
 use std::str::{from_utf8, Utf8Error};
+use std::sync::Arc;
 
+use memchr::{memchr, memchr_iter};
 use nom::branch::alt;
-use nom::bytes::complete::take_until;
-use nom::Parser;
 
+mod api;
+#[cfg(feature = "archive")]
+pub mod archive;
+#[cfg(any(feature = "wasm", feature = "python", feature = "ffi"))]
+mod bindings;
+mod builder;
+#[cfg(feature = "fs")]
+pub mod cache;
 mod code;
+pub mod comments;
+pub mod config;
+pub mod detangle;
+pub mod errors;
+pub mod exec;
+#[cfg(feature = "ffi")]
+mod ffi;
+mod flavor;
+#[cfg(feature = "fs")]
+pub mod git;
+pub mod graph;
+pub mod observer;
+#[cfg(feature = "parsers")]
+pub mod parsers;
+#[cfg(not(feature = "parsers"))]
+mod parsers;
 mod properties;
+#[cfg(feature = "python")]
+mod python;
+pub mod render;
+pub mod report;
+pub mod scanner;
 mod section;
+#[cfg(feature = "fs")]
+pub mod snapshot;
+pub mod stats;
+pub mod strictness;
+pub mod tangle;
+pub mod tangler;
+pub mod template;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod tree;
+#[cfg(feature = "wasm")]
+mod wasm;
+pub mod weave;
 
-pub use code::code;
-pub use code::Code;
+pub use api::{parse, parse_with_defaults};
+pub use builder::DocumentBuilder;
 use code::*;
-use nom::error::ParseError;
-pub use properties::{betwixt, TangleMode, properties as extract_props};
-pub use section::section;
+pub use code::{Code, CodePart};
+pub use errors::{DocumentError, InvalidMatchDetails};
+pub use flavor::FlavorBuilder;
+pub use observer::Observer;
+use parsers::*;
+use properties::properties as extract_props;
+// tests use the betwixt parser, which is otherwise only exported with the `parsers` feature
+#[cfg(all(test, not(feature = "parsers")))]
+use properties::*;
+pub use properties::{
+    Ansi, Custom, CustomProperty, Marker, Merge, Occurrence, Position, Properties, PropertiesError,
+    PropertyError, Scope, Tabs, TangleMode,
+};
+use scanner::LineScanner;
+pub use scanner::{Event, Scanner};
 use section::*;
+pub use section::{Section, SectionPart};
+pub use strictness::Strictness;
+pub use tangler::Tangler;
+use tree::Tree;
 
-use crate::properties::Properties;
+use crate::strictness::{Problem, Warning};
 
-pub const BETWIXT_TOKEN: &'static str = "<?btxt";
-pub const BETWIXT_COM_TOKEN: &'static str = "<!--btxt";
-pub const CLOSE_TOKEN: &'static str = "?>";
-pub const CLOSE_COM_TOKEN: &'static str = "-->";
+pub const BETWIXT_TOKEN: &str = "<?btxt";
+pub const BETWIXT_COM_TOKEN: &str = "<!--btxt";
+pub const CLOSE_TOKEN: &str = "?>";
+pub const CLOSE_COM_TOKEN: &str = "-->";
 
 pub struct Document<'a> {
     pub code_blocks: Vec<Code<'a>>,
+    // every betwixt block in the document, in document order
+    pub directives: Vec<Directive<'a>>,
+    // the spans of code blocks that were not tangled because of `ignore=true`
+    pub ignored: Vec<Range<usize>>,
     ids: HashSet<&'a [u8]>,
+    // the length of the document parsed, where blocks are appended by insert_block
+    len: usize,
+    // the spans of code blocks removed by remove_block
+    pub(crate) removed: Vec<Range<usize>>,
     pub root: Section<'a>,
+    // problems found while parsing that were only warned about
+    pub warnings: Vec<Warning>,
+}
+
+// A betwixt block as it appeared in the document
+#[derive(Clone, Debug, PartialEq)]
+pub struct Directive<'a> {
+    pub span: Range<usize>,
+    pub lang: Option<&'a [u8]>,
+    pub properties: Properties<'a>,
 }
 
 impl<'a> Document<'a> {
@@ -38,176 +114,531 @@ impl<'a> Document<'a> {
         contents: &'a [u8],
         parsers: MarkdownParsers<P1, P2, P3>,
     ) -> Result<Self, DocumentError>
+    where
+        P1: LineParser<'a>,
+        P2: LineParser<'a>,
+        P3: LineParser<'a>,
+    {
+        Self::build(contents, parsers, &HashMap::new(), None)
+    }
+
+    // Parse a document like `from_contents`, giving each code block the properties of defaults
+    // for its language that it doesn't have, e.g. those of `[lang.rust]` in betwixt.toml. They
+    // are below everything the document sets, so simple documents need no betwixt blocks at all
+    pub fn from_contents_with_defaults<P1, P2, P3>(
+        contents: &'a [u8],
+        parsers: MarkdownParsers<P1, P2, P3>,
+        defaults: &HashMap<&'a [u8], Properties<'a>>,
+    ) -> Result<Self, DocumentError>
+    where
+        P1: LineParser<'a>,
+        P2: LineParser<'a>,
+        P3: LineParser<'a>,
+    {
+        Self::build(contents, parsers, defaults, None)
+    }
+
+    // Parse a document like `from_contents`, calling observer's hooks as it is built
+    pub fn from_contents_with_observer<P1, P2, P3>(
+        contents: &'a [u8],
+        parsers: MarkdownParsers<P1, P2, P3>,
+        observer: &mut dyn Observer<'a>,
+    ) -> Result<Self, DocumentError>
+    where
+        P1: LineParser<'a>,
+        P2: LineParser<'a>,
+        P3: LineParser<'a>,
+    {
+        Self::build(contents, parsers, &HashMap::new(), Some(observer))
+    }
+
+    pub(crate) fn build<P1, P2, P3>(
+        contents: &'a [u8],
+        parsers: MarkdownParsers<P1, P2, P3>,
+        defaults: &HashMap<&'a [u8], Properties<'a>>,
+        observer: Option<&mut dyn Observer<'a>>,
+    ) -> Result<Self, DocumentError>
     where
         P1: LineParser<'a>,
         P2: LineParser<'a>,
         P3: LineParser<'a>,
     {
         let mut parser = alt((parsers.code, parsers.section, parsers.betwixt));
+        let defaults = Arc::new(defaults.clone());
+        let mut doc = Self::scan(
+            contents,
+            &mut parser,
+            parsers.strict,
+            &defaults,
+            &[],
+            &mut (),
+        )?;
+        // scoped betwixt blocks may apply to sections before them, so the document is parsed
+        // again knowing them
+        let scoped: Vec<Directive<'a>> = doc
+            .directives
+            .iter()
+            .filter(|directive| directive.properties.scope.is_some())
+            .cloned()
+            .collect();
+        // observers only see the document as it is finally built
+        match observer {
+            Some(observer) => {
+                doc = Self::scan(
+                    contents,
+                    &mut parser,
+                    parsers.strict,
+                    &defaults,
+                    &scoped,
+                    observer,
+                )?
+            }
+            None if !scoped.is_empty() => {
+                doc = Self::scan(
+                    contents,
+                    &mut parser,
+                    parsers.strict,
+                    &defaults,
+                    &scoped,
+                    &mut (),
+                )?
+            }
+            None => {}
+        }
+        doc.validate(contents, parsers.strict)?;
+        Ok(doc)
+    }
+
+    // Check the properties of each block go together, so a document that can't be tangled
+    // fails as it is parsed rather than halfway through writing its files: a fenced block
+    // can't take its contents from a code property too, and an inserted block needs a valid
+    // marker and a block before it tangled to the same file to be inserted into
+    fn validate(&mut self, contents: &[u8], strict: Strictness) -> Result<(), DocumentError> {
+        if strict == Strictness::Ignore {
+            return Ok(());
+        }
+        let sections = self.sections();
+        let filenames: Vec<Vec<Cow<[u8]>>> = (0..self.code_blocks.len())
+            .map(|idx| self.filenames_in(idx, sections[idx]))
+            .collect();
+        let mut conflicts = Vec::new();
+        for (idx, block) in self.code_blocks.iter().enumerate() {
+            if let (Some(prop_line), Some(_)) = (block.part.prop_line, block.properties.code) {
+                conflicts.push((
+                    idx,
+                    "a code block can't have a code property, it has contents of its own".into(),
+                    offset(contents, prop_line),
+                ));
+            }
+            let Some(TangleMode::Insert(_, marker)) = &block.properties.mode else {
+                continue;
+            };
+            if let Err(err) = tangle::matching_lines(b"", marker) {
+                conflicts.push((idx, err.to_string(), block.span.clone()));
+                continue;
+            }
+            for filename in filenames[idx].iter() {
+                let earlier =
+                    self.code_blocks[..idx]
+                        .iter()
+                        .zip(&filenames)
+                        .any(|(before, names)| {
+                            before.properties.mode.is_some() && names.contains(filename)
+                        });
+                if !earlier {
+                    conflicts.push((
+                        idx,
+                        format!(
+                            "nothing is tangled to {} before the block inserted into it",
+                            String::from_utf8_lossy(filename)
+                        ),
+                        block.span.clone(),
+                    ));
+                }
+            }
+        }
+        for (idx, message, span) in conflicts {
+            if strict == Strictness::Deny {
+                return Err(DocumentError::Conflict(message, Some(span)));
+            }
+            self.warnings.push(Warning {
+                problem: Problem::InvalidProperties,
+                message,
+                line: Some(line(contents, span.start)),
+                block: Some(idx),
+            });
+        }
+        Ok(())
+    }
+
+    fn scan<P: LineParser<'a>>(
+        contents: &'a [u8],
+        parser: &mut P,
+        strict: Strictness,
+        defaults: &Arc<HashMap<&'a [u8], Properties<'a>>>,
+        scoped: &[Directive<'a>],
+        observer: &mut dyn Observer<'a>,
+    ) -> Result<Self, DocumentError> {
         let mut ids = HashSet::new();
-        let mut scanner = LineScanner::new(contents, parsers.strict);
-        let mut next = scanner.scan(&mut parser);
-        let properties = PropertiesCollection {
-            global: Properties {
-                ..Default::default()
-            },
-            languages: HashMap::new(),
-        };
+        let mut scanner = LineScanner::new(contents, strict);
+        let mut next = scanner.scan(parser);
         let mut blocks = Vec::new();
-        let mut section = Section {
-            part: SectionPart {
-                heading: None,
-                level: 0,
-            },
-            code_block_indexes: Vec::new(),
-            properties,
-            children: Vec::new(),
-        };
-        // a given index in the stack is the current parent of that level.
-        let mut section_frame: [Option<Section>; 10] =
-            [None, None, None, None, None, None, None, None, None, None]; // support 9 + root levels of headings
+        let mut directives = Vec::new();
+        let mut ignored = Vec::new();
+        let mut tree = Tree::new();
+        Arc::make_mut(&mut tree.current().properties).defaults = Arc::clone(defaults);
+        for directive in scoped {
+            if directive.properties.scope == Some(Scope::Document) {
+                let mut props = directive.properties.clone();
+                props.scope = None;
+                Arc::make_mut(&mut tree.current().properties).update(directive.lang, props);
+            }
+        }
         loop {
             match next {
                 Ok(item) => {
                     match item {
                         ScanResult::Section(new) => {
-                            if new.level == section.part.level {
-                                // parent section isn't changing, just the active section is.
-                                let props = section_frame[section.part.level]
-                                    .as_ref()
-                                    .unwrap()
-                                    .properties
-                                    .clone();
-                                section_frame[section.part.level]
-                                    .as_mut()
-                                    .unwrap()
-                                    .children
-                                    .push(section);
-                                section = Section::new(new, props);
-                            } else if new.level < section.part.level {
-                                // we're going back to a higher level heading. This means append the section
-                                // to the current level's parent. Then find the appropriate parent for the new
-                                // level and set the new current parent.
-                                section_frame[section.part.level]
-                                    .as_mut()
-                                    .unwrap()
-                                    .children
-                                    .push(section);
-                                if section_frame[new.level].is_none() {
-                                    // find the next highest index with a parent
-                                    for idx in new.level + 1..10 {
-                                        if section_frame[idx].is_some() {
-                                            section_frame.swap(new.level, idx);
-                                            break;
-                                        }
-                                    }
-                                }
-                                // all children lower (numerically higher) than the new section
-                                // will never get a chance to be reconciled. We need to do so now.
-                                for idx in (new.level + 1..10).rev() {
-                                    if section_frame[idx].is_some() {
-                                        let mut child = None;
-                                        mem::swap(&mut section_frame[idx], &mut child);
-                                        let child = child.unwrap();
-                                        section_frame[child.part.level]
-                                            .as_mut()
-                                            .unwrap()
-                                            .children
-                                            .push(child);
-                                    }
+                            // the section starts on the line after its heading, if there is one
+                            let start = new
+                                .heading
+                                .map(|heading| {
+                                    let end = offset(contents, heading).end;
+                                    memchr(b'\n', &contents[end..])
+                                        .map_or(contents.len(), |idx| end + idx + 1)
+                                })
+                                .unwrap_or_default();
+                            tree.open_with(new, start, |closed| observer.exit(closed));
+                            let section = tree.current();
+                            for directive in scoped {
+                                let Some(Scope::Headings(glob)) = directive.properties.scope else {
+                                    continue;
+                                };
+                                if path_matches(glob, section.path()) {
+                                    let mut props = directive.properties.clone();
+                                    props.scope = None;
+                                    Arc::make_mut(&mut section.properties)
+                                        .update(directive.lang, props);
                                 }
-                                let idx = new.level;
-                                section = Section::new(
-                                    new,
-                                    section_frame[idx].as_ref().unwrap().properties.clone(),
-                                );
-                            } else {
-                                // going to a child section
-                                let props = section.properties.clone();
-                                section_frame[new.level] = Some(section);
-                                section = Section::new(new, props);
                             }
+                            observer.enter(section).map_err(|why| {
+                                DocumentError::Rejected(why, Some(scanner.span()))
+                            })?;
                         }
                         ScanResult::Code(code) => {
+                            let section = tree.current();
                             if let Some(id) = code.id {
                                 if ids.contains(id) {
                                     return Err(DocumentError::DuplicateID(
-                                        from_utf8(id).unwrap().into(),
+                                        String::from_utf8_lossy(id).into(),
+                                        Some(offset(contents, id)),
                                     ));
                                 }
                                 ids.insert(id);
                             }
                             let mut props = section.properties.get_code_props(code.lang);
-							props = if let Some(prop_line) = code.prop_line {
-								match extract_props(prop_line) {
-									Ok((_, mut properties)) => {
-										properties.merge(&props);
-										properties
-									},
-									Err(_) => props
-								}
-							} else {
-								props
-							};
-                            if !props.ignore.unwrap_or(false) {
+                            props = if let Some(prop_line) = code.prop_line {
+                                match extract_props(prop_line) {
+                                    Ok((_, mut properties)) => {
+                                        properties.merge(&props);
+                                        properties
+                                    }
+                                    // words after the id that don't look like properties are
+                                    // taken for a description of the block
+                                    Err(_) if !prop_line.contains(&b'=') => props,
+                                    Err(err) => {
+                                        let err = PropertiesError::of(err, prop_line);
+                                        match strict {
+                                            Strictness::Deny => {
+                                                return Err(DocumentError::InvalidProperties(
+                                                    err.to_string(),
+                                                    Some(offset(contents, err.input)),
+                                                ))
+                                            }
+                                            Strictness::Warn => {
+                                                scanner.warnings.push(Warning {
+                                                    problem: Problem::InvalidProperties,
+                                                    message: format!(
+                                                        "code block properties ignored: {}",
+                                                        err
+                                                    ),
+                                                    line: Some(scanner.line(scanner.span().start)),
+                                                    block: Some(blocks.len()),
+                                                });
+                                                props
+                                            }
+                                            Strictness::Ignore => props,
+                                        }
+                                    }
+                                }
+                            } else {
+                                props
+                            };
+                            if !props.ignore.unwrap_or(false) && !code.notangle {
                                 section.code_block_indexes.push(blocks.len());
                                 blocks.push(Code {
                                     properties: props,
+                                    contents_span: offset(contents, code.contents),
                                     part: code,
+                                    span: scanner.span(),
                                 });
+                                observer
+                                    .accept(blocks.len() - 1, &blocks[blocks.len() - 1])
+                                    .map_err(|why| {
+                                        DocumentError::Rejected(why, Some(scanner.span()))
+                                    })?;
+                            } else {
+                                observer.skip(&code, scanner.span());
+                                tracing::debug!(
+                                    line = scanner.line(scanner.span().start),
+                                    "skipped block, it is ignored"
+                                );
+                                ignored.push(scanner.span());
                             }
                         }
                         ScanResult::Properties(props) => {
+                            observer.update(props.0, &props.1).map_err(|why| {
+                                DocumentError::Rejected(why, Some(scanner.span()))
+                            })?;
+                            let section = tree.current();
+                            directives.push(Directive {
+                                span: scanner.span(),
+                                lang: props.0,
+                                properties: props.1.clone(),
+                            });
+                            // scoped blocks apply where their scope says, not where they are
+                            if props.1.scope.is_some() {
+                                next = scanner.scan(parser);
+                                continue;
+                            }
+                            // variables belong to the betwixt block declaring them, rather
+                            // than the code blocks it applies to
+                            let mut props = props;
+                            (props.1.var, props.1.prompt, props.1.default) = (None, None, None);
                             if let Some(code) = props.1.code {
                                 section.code_block_indexes.push(blocks.len());
                                 let lang = props.0;
-                                section.properties.update(props.0, props.1);
+                                Arc::make_mut(&mut section.properties).update(props.0, props.1);
                                 let props = section.properties.get_code_props(lang);
                                 blocks.push(Code {
                                     part: CodePart {
                                         id: None,
                                         lang,
                                         contents: code,
-										prop_line: None,
+                                        path: None,
+                                        prop_line: None,
+                                        notangle: false,
                                     },
                                     properties: props,
-                                })
+                                    span: scanner.span(),
+                                    contents_span: offset(contents, code),
+                                });
+                                observer
+                                    .accept(blocks.len() - 1, &blocks[blocks.len() - 1])
+                                    .map_err(|why| {
+                                        DocumentError::Rejected(why, Some(scanner.span()))
+                                    })?;
                             } else {
-                                section.properties.update(props.0, props.1);
+                                Arc::make_mut(&mut section.properties).update(props.0, props.1);
                             }
                         }
+                        ScanResult::Skipped => {}
                         ScanResult::End => {
                             break;
                         }
                     }
-                    next = scanner.scan(&mut parser);
+                    next = scanner.scan(parser);
                 }
                 Err(err) => return Err(DocumentError::InvalidMatch(err)),
             }
         }
-        section_frame[section.part.level]
-            .as_mut()
-            .unwrap()
-            .children
-            .push(section);
-        for idx in (0..10).rev() {
-            if section_frame[idx].is_some() {
-                let mut child = None;
-                mem::swap(&mut section_frame[idx], &mut child);
-                let child = child.unwrap();
-                match section_frame[child.part.level].as_mut() {
-                    Some(parent) => parent.children.push(child),
-                    None => {
-                        return Ok(Document {
-                            code_blocks: blocks,
-                            ids,
-                            root: child,
-                        })
-                    }
+        Ok(Document {
+            code_blocks: blocks,
+            directives,
+            ignored,
+            ids,
+            len: contents.len(),
+            removed: Vec::new(),
+            root: tree.finish_with(|closed| observer.exit(closed)),
+            warnings: scanner.warnings,
+        })
+    }
+
+    pub fn has_id(&self, id: &[u8]) -> bool {
+        self.ids.contains(id)
+    }
+
+    // Insert a new code block before the block at idx, or at the end of the document when
+    // idx is the number of blocks. The block takes its properties from the section it is
+    // inserted into, and from part.prop_line, which is written after the block's id just
+    // like when parsing
+    pub fn insert_block(&mut self, idx: usize, part: CodePart<'a>) -> Result<(), DocumentError> {
+        if let Some(id) = part.id {
+            if self.ids.contains(id) {
+                return Err(DocumentError::DuplicateID(
+                    String::from_utf8_lossy(id).into(),
+                    None,
+                ));
+            }
+        }
+        if part.prop_line.is_some() && part.id.is_none() {
+            return Err(DocumentError::PropertiesWithoutID);
+        }
+        let at = match self.code_blocks.get(idx) {
+            Some(block) => block.span.start,
+            None => self.len,
+        };
+        // the new block belongs to the section of the block it is inserted before, or the
+        // section of the last block when appending
+        let neighbour = if idx < self.code_blocks.len() {
+            Some(idx)
+        } else {
+            idx.checked_sub(1)
+        };
+        let section = match neighbour {
+            Some(neighbour) => self.root.find_block(neighbour).ok_or_else(|| {
+                DocumentError::InvalidProperties(
+                    format!(
+                        "no section holds code block {} to take them from",
+                        neighbour
+                    ),
+                    None,
+                )
+            })?,
+            None => &mut self.root,
+        };
+        let mut properties = section.properties.get_code_props(part.lang);
+        if let Some(prop_line) = part.prop_line {
+            let mut own = extract_props(prop_line)
+                .map_err(|err| {
+                    let err = PropertiesError::of(err, prop_line);
+                    DocumentError::InvalidProperties(err.to_string(), None)
+                })?
+                .1;
+            own.merge(&properties);
+            properties = own;
+        }
+        let position = section
+            .code_block_indexes
+            .iter()
+            .position(|&i| i >= idx)
+            .unwrap_or(section.code_block_indexes.len());
+        section.code_block_indexes.insert(position, usize::MAX);
+        self.root.reindex(|indexes| {
+            for i in indexes.iter_mut() {
+                match *i {
+                    usize::MAX => *i = idx,
+                    _ if *i >= idx => *i += 1,
+                    _ => {}
+                }
+            }
+        });
+        if let Some(id) = part.id {
+            self.ids.insert(id);
+        }
+        // inserted blocks have empty spans, they aren't part of the source document
+        self.code_blocks.insert(
+            idx,
+            Code {
+                properties,
+                part,
+                span: at..at,
+                contents_span: at..at,
+            },
+        );
+        Ok(())
+    }
+
+    // Remove the code block at idx. Blocks created by a `code` property are removed along
+    // with their betwixt block
+    pub fn remove_block(&mut self, idx: usize) -> Code<'a> {
+        let block = self.code_blocks.remove(idx);
+        if let Some(id) = block.part.id {
+            self.ids.remove(id);
+        }
+        if block.properties.code.is_some() {
+            self.directives.retain(|d| d.span != block.span);
+        }
+        if !block.span.is_empty() {
+            self.removed.push(block.span.clone());
+        }
+        self.root.reindex(|indexes| {
+            indexes.retain(|&i| i != idx);
+            for i in indexes.iter_mut() {
+                if *i > idx {
+                    *i -= 1;
+                }
+            }
+        });
+        block
+    }
+
+    // The files the code block at idx is tangled to: those of its filename, or when it has
+    // none and infer-filename is set, the path on its fence or the file its id names. Failing
+    // that, when split-by-section is set, the one its filename-template names for its section,
+    // e.g. `src/{slug}.rs` is `src/parsing.rs` for a block under `## Parsing`
+    pub fn filenames(&self, idx: usize) -> Vec<Cow<'a, [u8]>> {
+        self.filenames_in(idx, self.root.holding(idx))
+    }
+
+    // Like filenames, given the section holding the block, e.g. from `sections`
+    pub(crate) fn filenames_in(
+        &self,
+        idx: usize,
+        section: Option<&Section<'a>>,
+    ) -> Vec<Cow<'a, [u8]>> {
+        let block = &self.code_blocks[idx];
+        let properties = &block.properties;
+        let own: Vec<Cow<[u8]>> = properties.filenames().map(Cow::Borrowed).collect();
+        if !own.is_empty() {
+            return own;
+        }
+        if properties.infer_filename == Some(true) {
+            match (block.part.path, block.part.id, properties.filename_template) {
+                (Some(path), _, _) => return vec![Cow::Borrowed(path)],
+                (None, Some(id), Some(template)) => {
+                    let template = String::from_utf8_lossy(template);
+                    let filename = template.replace("{id}", &String::from_utf8_lossy(id));
+                    return vec![Cow::Owned(filename.into_bytes())];
+                }
+                (None, Some(id), None) => return vec![Cow::Borrowed(id)],
+                (None, None, _) => {}
+            }
+        }
+        self.section_filename(idx, section)
+            .map(Cow::Owned)
+            .into_iter()
+            .collect()
+    }
+
+    // The file the code block at idx is tangled to by split-by-section, its filename-template
+    // for the heading of section, the one holding it. Blocks with a filename of their own, or
+    // one inferred, are tangled there instead
+    pub(crate) fn section_filename(
+        &self,
+        idx: usize,
+        section: Option<&Section<'a>>,
+    ) -> Option<Vec<u8>> {
+        let properties = &self.code_blocks[idx].properties;
+        if properties.split_by_section != Some(true) {
+            return None;
+        }
+        let template = String::from_utf8_lossy(properties.filename_template?);
+        let heading = section?.part.heading?;
+        Some(template.replace("{slug}", &slug(heading)).into_bytes())
+    }
+
+    // The section holding each code block, by the block's index, found in one walk of the tree
+    pub fn sections(&self) -> Vec<Option<&Section<'a>>> {
+        let mut sections = vec![None; self.code_blocks.len()];
+        for section in self.root.walk() {
+            for &idx in section.indexes() {
+                if let Some(holding) = sections.get_mut(idx) {
+                    *holding = Some(section);
                 }
             }
         }
-        panic!("unreachable");
+        sections
     }
 
     pub fn describe(&self, section: &Section) -> Result<String, Utf8Error> {
@@ -251,12 +682,18 @@ impl<'a> Document<'a> {
                         TangleMode::Overwrite => "overwrites",
                         TangleMode::Append => "appends to",
                         TangleMode::Prepend => "prepends to",
-                        TangleMode::Insert(_) => "inserts into",
+                        TangleMode::Symlink => "symlinks",
+                        TangleMode::Hardlink => "hardlinks",
+                        TangleMode::Insert(..) => "inserts into",
                     },
                 },
-                match self.code_blocks[idx].properties.filename {
-                    None => "**No Filename**",
-                    Some(fname) => from_utf8(fname)?,
+                match self.filenames(idx).as_slice() {
+                    [] => "**No Filename**".to_owned(),
+                    filenames => filenames
+                        .iter()
+                        .map(|fname| from_utf8(fname))
+                        .collect::<Result<Vec<_>, _>>()?
+                        .join(";"),
                 }
             ))
         }
@@ -268,163 +705,31 @@ impl<'a> Document<'a> {
     }
 }
 
-pub struct MarkdownParsers<P1, P2, P3> {
-    pub betwixt: P1,
-    pub section: P2,
-    pub code: P3,
-    pub strict: bool,
-}
-
-#[derive(Debug, PartialEq)]
-pub enum ScanResult<'a> {
-    Code(CodePart<'a>),
-    Section(SectionPart<'a>),
-    Properties((Option<&'a [u8]>, Properties<'a>)),
-    End,
+// The line, from 1, of the document that the byte at pos is on
+pub fn line(document: &[u8], pos: usize) -> usize {
+    memchr_iter(b'\n', &document[..pos]).count() + 1
 }
 
-#[derive(Debug, PartialEq)]
-pub enum LineParseResult<'a> {
-    Matched(ScanResult<'a>),
-    PartialMatch,
+// The range of the document covered by slice, which must have been taken from it
+fn offset(document: &[u8], slice: &[u8]) -> Range<usize> {
+    let start = slice.as_ptr() as usize - document.as_ptr() as usize;
+    start..start + slice.len()
 }
 
-#[derive(Debug)]
-// The error result of any LineParser
-pub enum LineParseError<'a> {
-    // Not really an error, just indicates the parser didn't match this line (move on)
-    NoMatch,
-    // We matched start/end tokens but the body had invalid contents. Check strict mode
-    InvalidMatch(&'a [u8]),
-}
-
-impl<'a> ParseError<&'a [u8]> for LineParseError<'a> {
-    fn from_error_kind(_input: &'a [u8], _kind: nom::error::ErrorKind) -> Self {
-        LineParseError::NoMatch
-    }
-
-    fn append(_input: &'a [u8], _kind: nom::error::ErrorKind, other: Self) -> Self {
-        other
-    }
-}
-
-#[derive(Debug)]
-pub enum DocumentError {
-    InvalidMatch(InvalidMatchDetails),
-    DuplicateID(String),
-}
-
-impl Error for DocumentError {}
-
-impl Display for DocumentError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match &self {
-                DocumentError::InvalidMatch(im) => format!("{}", im),
-                DocumentError::DuplicateID(id) => format!("re-used code block id: {}", id),
-            }
-        )
-    }
-}
-
-#[derive(Debug)]
-pub struct InvalidMatchDetails {
-    line_start: usize,
-    line_end: usize,
-    line: String,
-}
-
-impl Error for InvalidMatchDetails {}
-
-impl Display for InvalidMatchDetails {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "invalid properties from line {} to line {}: {}",
-            self.line_start, self.line_end, self.line,
-        )
-    }
-}
-
-// TODO the line parser approach is very inefficient with long multi line strings
-// as it has to continually try and parse for each line. We can improve this, just need
-// more sophisticated types
-pub trait LineParser<'a>: Parser<&'a [u8], LineParseResult<'a>, LineParseError<'a>> {}
-impl<'a, F> LineParser<'a> for F where F: Parser<&'a [u8], LineParseResult<'a>, LineParseError<'a>> {}
-
-struct LineScanner<'a> {
-    // lines stores the end index of each line in the byte slice
-    // e.g. data[lines[x]] should always be set to \n
-    lines: Vec<usize>,
-    slice: (usize, usize), // the start and end of the current working slice
-    data: &'a [u8],        // all the bytes in the file
-    strict: bool,
-    block_start: usize,
-}
-
-impl<'a> LineScanner<'a> {
-    fn new(data: &'a [u8], strict: bool) -> Self {
-        LineScanner {
-            lines: Vec::new(),
-            slice: (0, 0),
-            block_start: 1,
-            data,
-            strict,
-        }
-    }
-    fn scan<P>(&mut self, parser: &mut P) -> Result<ScanResult<'a>, InvalidMatchDetails>
-    where
-        P: LineParser<'a>,
-    {
-        while self.slice.1 != self.data.len() {
-            let line = match take_until::<&str, &'a [u8], nom::error::Error<&'a [u8]>>("\n")(
-                &self.data[self.slice.1..],
-            ) {
-                Ok((_, line)) => line,
-                Err(_) => &self.data[self.slice.1..],
-            };
-            self.lines.push(self.slice.1 + line.len());
-            let new_end = std::cmp::min(self.data.len(), self.slice.1 + line.len() + 1);
-            self.slice = (self.slice.0, new_end);
-            match parser.parse(&self.data[self.slice.0..self.slice.1]) {
-                Ok((_, result)) => match result {
-                    LineParseResult::Matched(m) => {
-                        self.slice = (self.slice.1, self.slice.1);
-                        return Ok(m);
-                    }
-                    LineParseResult::PartialMatch => {
-                        self.block_start = self.lines.len();
-                        return self.scan(parser);
-                    }
-                },
-                Err(err) => {
-                    if self.strict {
-                        match err {
-                            nom::Err::Incomplete(_) => panic!("unreachable in complete parsers"),
-                            nom::Err::Error(err) | nom::Err::Failure(err) => match err {
-                                LineParseError::InvalidMatch(bytes) => {
-                                    return Err(InvalidMatchDetails {
-                                        line_start: self.block_start,
-                                        line_end: self.lines.len(),
-                                        line: from_utf8(&bytes).unwrap().to_string(),
-                                    })
-                                }
-                                LineParseError::NoMatch => {
-                                    self.block_start = self.lines.len() + 1;
-                                    self.slice = (self.slice.1, self.slice.1)
-                                }
-                            },
-                        };
-                    } else {
-                        self.slice = (self.slice.1, self.slice.1)
-                    }
-                }
-            };
-        }
-        Ok(ScanResult::End)
-    }
+// The first line, from 1, where before and now differ. When every line both have is the same,
+// one has more lines than the other, and it is the line after the shorter one ends
+#[cfg(feature = "fs")]
+pub(crate) fn first_difference(before: &[u8], now: &[u8]) -> usize {
+    let (before, now) = (
+        String::from_utf8_lossy(before),
+        String::from_utf8_lossy(now),
+    );
+    before
+        .lines()
+        .zip(now.lines())
+        .position(|(before, now)| before != now)
+        .unwrap_or_else(|| before.lines().count().min(now.lines().count()))
+        + 1
 }
 
 #[cfg(test)]
@@ -485,6 +790,221 @@ func main() {"[..]
         let btxt = &b"<?btxt tag=\"test\" filename='moop' mode= append' ?>";
         let res = betwixt(BETWIXT_TOKEN, CLOSE_TOKEN)(&btxt[..]);
         assert!(res.is_err(), "invalid body should not parse");
+        for (btxt, at, message) in [
+            (
+                &b"<?btxt filename='a' review='alice' ?>"[..],
+                &b"review='alice'"[..],
+                "unknown property: review",
+            ),
+            (
+                b"<?btxt tag='a' tag='b' ?>",
+                b"tag='b'",
+                "property given more than once: tag",
+            ),
+            (
+                b"<?btxt ignore='true' ?>",
+                b"ignore='true'",
+                "ignore is true or false, without quotes",
+            ),
+            (
+                b"<?btxt isolate='true' ?>",
+                b"isolate='true'",
+                "isolate is true or false, without quotes",
+            ),
+            (
+                b"<?btxt mode='sideways' tag='b' ?>",
+                b"mode='sideways'",
+                "invalid value for mode: sideways",
+            ),
+            (
+                b"<?btxt filename='a'tag='b' ?>",
+                b"filename='a'tag='b'",
+                "invalid property: filename='a'tag='b'",
+            ),
+        ] {
+            match betwixt(BETWIXT_TOKEN, CLOSE_TOKEN)(btxt) {
+                Err(nom::Err::Failure(LineParseError::InvalidMatch(err))) => {
+                    assert_eq!(err.input, at);
+                    assert_eq!(err.to_string(), message);
+                }
+                _ => panic!("{} should not parse", from_utf8(btxt).unwrap()),
+            }
+        }
+        // booleans are only accepted bare
+        let (_, props) = extract_props(b"isolate=true").unwrap();
+        assert_eq!(props.isolate, Some(true));
+    }
+
+    #[test]
+    fn test_inherited_properties() {
+        let contents = &b"# Parent
+<?btxt+rust filename='lib.rs' ?>
+<?btxt filename='other' mode='append' ?>
+## Child
+<?btxt filename='child' tag='c' ?>
+```rust
+```
+### Grandchild
+<?btxt+rust mode='overwrite' ?>
+```rust
+```
+```python
+```
+## Sibling
+```python
+```
+"[..];
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let doc = Document::from_contents(contents, parsers).unwrap();
+        let props: Vec<_> = doc
+            .code_blocks
+            .iter()
+            .map(|b| {
+                (
+                    b.properties.filename,
+                    b.properties.mode.clone(),
+                    b.properties.tag,
+                )
+            })
+            .collect();
+        // language properties win over global ones, even those set in a child section
+        assert_eq!(
+            props,
+            vec![
+                (
+                    Some(&b"lib.rs"[..]),
+                    Some(TangleMode::Append),
+                    Some(&b"c"[..])
+                ),
+                (
+                    Some(&b"lib.rs"[..]),
+                    Some(TangleMode::Overwrite),
+                    Some(&b"c"[..])
+                ),
+                (
+                    Some(&b"child"[..]),
+                    Some(TangleMode::Append),
+                    Some(&b"c"[..])
+                ),
+                (Some(&b"other"[..]), Some(TangleMode::Append), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_block_tags() {
+        let contents = &b"# Runbook
+<?btxt tag='docs' filename='run.sh' mode='append' ?>
+```sh #setup tag=ci
+make setup
+```
+```sh
+make docs
+```
+```sh build tag=release/v1.2 filename=\"build.sh\"
+make
+```
+"[..];
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let doc = Document::from_contents(contents, parsers).unwrap();
+        let tags: Vec<_> = doc
+            .code_blocks
+            .iter()
+            .map(|b| (b.part.id, b.properties.tag, b.properties.filename))
+            .collect();
+        // a block's own tag wins over its section's
+        assert_eq!(
+            tags,
+            vec![
+                (Some(&b"setup"[..]), Some(&b"ci"[..]), Some(&b"run.sh"[..])),
+                (None, Some(&b"docs"[..]), Some(&b"run.sh"[..])),
+                (
+                    Some(&b"build"[..]),
+                    Some(&b"release/v1.2"[..]),
+                    Some(&b"build.sh"[..])
+                ),
+            ]
+        );
+        // booleans can't be quoted, and bare values are a single word
+        assert!(properties(b"ignore='true'").is_err());
+        assert!(properties(b"tag=a,b").is_err());
+    }
+
+    #[test]
+    fn test_unterminated_code_block() {
+        let mut contents = b"# Unterminated\n```rust\n".to_vec();
+        for _ in 0..100_000 {
+            contents.extend_from_slice(b"let x = 1;\n");
+        }
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let doc = Document::from_contents(&contents, parsers).unwrap();
+        assert!(doc.code_blocks.is_empty());
+    }
+
+    #[test]
+    fn test_custom_properties() {
+        let contents = &b"# Parent
+<?btxt filename='lib.rs' review='alice' label='a' ?>
+## Child
+<?btxt+rust review='bob' label='b' ?>
+```rust
+```
+```python
+```
+"[..];
+        let custom = vec![
+            CustomProperty {
+                name: "review".into(),
+                parse: |value| value.iter().all(u8::is_ascii_lowercase),
+                merge: Merge::Inherit,
+            },
+            CustomProperty {
+                name: "label".into(),
+                parse: |_| true,
+                merge: Merge::Accumulate,
+            },
+        ];
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt_with(BETWIXT_TOKEN, CLOSE_TOKEN, custom.clone()),
+            strict: Strictness::Deny,
+        };
+        let doc = Document::from_contents(contents, parsers).unwrap();
+        let (rust, python) = (
+            &doc.code_blocks[0].properties,
+            &doc.code_blocks[1].properties,
+        );
+        assert_eq!(rust.get("review"), Some(&b"bob"[..]));
+        assert_eq!(rust.get_all("label"), &[&b"a"[..], b"b"]);
+        assert_eq!(rust.filename, Some(&b"lib.rs"[..]));
+        assert_eq!(python.get("review"), Some(&b"alice"[..]));
+        assert_eq!(python.get_all("label"), &[&b"a"[..]]);
+
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        assert!(Document::from_contents(contents, parsers).is_err());
+        let btxt = &b"<?btxt review='Alice' ?>"[..];
+        assert!(betwixt_with(BETWIXT_TOKEN, CLOSE_TOKEN, custom)(btxt).is_err());
     }
 
     #[test]
@@ -498,90 +1018,689 @@ tog='bad' ?>"[..];
             code: code("```", "```"),
             section: section('#'),
             betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
-            strict: true,
+            strict: Strictness::Deny,
         };
 
         let result = Document::from_contents(contents, parsers);
         assert!(result.is_err());
         match result {
-            Err(err) => assert_eq!(
-                err.to_string(),
-                "invalid properties from line 4 to line 5: 
-tog='bad' "
-            ),
+            Err(err) => {
+                assert_eq!(
+                    err.to_string(),
+                    "invalid properties from line 4 to line 5: unknown property: tog"
+                );
+                assert_eq!(&contents[err.span().unwrap()], b"tog='bad'");
+            }
             Ok(_) => panic!("unreachable"),
         }
+        let contents = [&b"# Warn\n"[..], contents].concat();
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Warn,
+        };
+        let doc = Document::from_contents(&contents, parsers).unwrap();
+        assert_eq!(
+            doc.warnings,
+            vec![Warning {
+                problem: Problem::InvalidProperties,
+                message: "betwixt block with invalid properties skipped: unknown property: tog"
+                    .into(),
+                line: Some(5),
+                block: None,
+            }]
+        );
     }
 
     #[test]
-    fn test_header_sections() {
-        let contents = &b"
-Welcome!
-
-## This is some project
-with some random body crap
+    fn test_invalid_property_span() {
+        let contents = &b"# Spans
+<?btxt tag='docs' filname='a.rs' mode='overwrite' ?>
+"[..];
+        let Err(err) = crate::parse(contents, FlavorBuilder::github()) else {
+            panic!("the document should not parse");
+        };
+        assert_eq!(
+            err.to_string(),
+            "invalid properties from line 1 to line 2: unknown property: filname"
+        );
+        assert_eq!(&contents[err.span().unwrap()], b"filname='a.rs'");
+        let contents = &b"# Spans
+```rust #main mode='overwrite' mode='append' tag='docs'
+```
+"[..];
+        let Err(err) = crate::parse(contents, FlavorBuilder::github()) else {
+            panic!("the document should not parse");
+        };
+        assert_eq!(
+            err.to_string(),
+            "invalid code block properties: property given more than once: mode"
+        );
+        assert_eq!(&contents[err.span().unwrap()], b"mode='append'");
+    }
 
-## Help
+    // Documents cut off part way through a block may or may not parse, but must not panic
+    #[test]
+    fn test_fuzzed_inputs() {
+        let inputs: [&[u8]; 6] = [
+            b"<?btxt",
+            b"<?btxt mode='",
+            b"<?btxt ?",
+            b"<?btxt+rust@'Examples",
+            b"```rust mode='overwrite\n```\n",
+            b"# A\n<?btxt+rust mode='overwrite' ?>\n<?btxt+rust tag='a' ?>\n```rust\n```\n",
+        ];
+        for input in inputs {
+            for flavor in [
+                FlavorBuilder::github(),
+                FlavorBuilder::github().strict(Strictness::Warn),
+                FlavorBuilder::nested(),
+            ] {
+                let _ = crate::parse(input, flavor);
+            }
+        }
+        // betwixt blocks used to panic on properties wanting more input
+        let err = PropertiesError::of(nom::Err::Incomplete(nom::Needed::Unknown), b"mode=");
+        assert_eq!(err.to_string(), "invalid property: mode=");
+    }
 
-More content
-";
-        let mut parser = alt((
-            code("```", "```"),
-            section('#'),
-            betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+    #[test]
+    fn test_code_block_properties_warnings() {
+        let contents = &b"# Warnings
+```rust hello filename=main.rs;
+```
+```rust described with some words
+```
+"[..];
+        let parsers = |strict| MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict,
+        };
+        let doc = Document::from_contents(contents, parsers(Strictness::Warn)).unwrap();
+        assert_eq!(
+            doc.warnings,
+            vec![Warning {
+                problem: Problem::InvalidProperties,
+                message: "code block properties ignored: invalid property: filename=main.rs;"
+                    .into(),
+                line: Some(2),
+                block: Some(0),
+            }]
+        );
+        assert_eq!(
+            doc.warnings[0].to_string(),
+            "line 2: block 0: code block properties ignored: invalid property: filename=main.rs; \
+             [invalid-properties]"
+        );
+        assert!(matches!(
+            Document::from_contents(contents, parsers(Strictness::Deny)),
+            Err(DocumentError::InvalidProperties(..))
         ));
-        let mut scanner = LineScanner::new(&contents[..], true);
-        let results = scanner.scan(&mut parser);
-        assert!(results.is_ok());
-        let results = results.unwrap();
-        match results {
-            ScanResult::Section(section) => {
-                assert_eq!(Some(&b"This is some project"[..]), section.heading);
+    }
+
+    #[test]
+    fn test_conflicting_properties() {
+        let contents = &b"# Conflicts
+```rust main code='fn main() {}' filename='main.rs'
+fn main() {}
+```
+```rust mode='insert[// uses]' filename='lib.rs'
+use std::fs;
+```
+```rust mode='overwrite' filename='lib.rs'
+// uses
+```
+```rust mode='insert[// uses]' filename='lib.rs'
+use std::env;
+```
+"[..];
+        let parsers = |strict| MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict,
+        };
+        let doc = Document::from_contents(contents, parsers(Strictness::Warn)).unwrap();
+        // the last block is inserted into what the one before it tangled
+        assert_eq!(
+            doc.warnings,
+            vec![
+                Warning {
+                    problem: Problem::InvalidProperties,
+                    message: "a code block can't have a code property, it has contents of its own"
+                        .into(),
+                    line: Some(2),
+                    block: Some(0),
+                },
+                Warning {
+                    problem: Problem::InvalidProperties,
+                    message: "nothing is tangled to lib.rs before the block inserted into it"
+                        .into(),
+                    line: Some(5),
+                    block: Some(1),
+                },
+            ]
+        );
+        match Document::from_contents(contents, parsers(Strictness::Deny)) {
+            Err(err @ DocumentError::Conflict(..)) => {
+                assert_eq!(err.span(), Some(24..63));
             }
-            _ => panic!("invalid scan result"),
+            other => panic!("expected a conflict, got {:?}", other.map(|_| ())),
         }
-        let results = scanner.scan(&mut parser);
-        assert!(results.is_ok());
-        let results = results.unwrap();
-        match results {
-            ScanResult::Section(section) => assert_eq!(Some(&b"Help"[..]), section.heading),
-            _ => panic!("invalid scan result"),
+        assert!(
+            Document::from_contents(contents, parsers(Strictness::Ignore))
+                .unwrap()
+                .warnings
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_crlf() {
+        let lf = &b"# Windows
+<?btxt filename='main.rs'
+  mode='overwrite' ?>
+```rust hello tag='x'
+fn main() {}
+```
+## Child
+```python
+print()
+```
+"[..];
+        let crlf = String::from_utf8_lossy(lf).replace('\n', "\r\n");
+        let parsers = || MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let expected = Document::from_contents(lf, parsers()).unwrap();
+        let doc = Document::from_contents(crlf.as_bytes(), parsers()).unwrap();
+        let headings = |doc: &Document| -> Vec<Option<Vec<u8>>> {
+            doc.root
+                .walk()
+                .iter()
+                .map(|s| s.part.heading.map(<[u8]>::to_vec))
+                .collect()
+        };
+        assert_eq!(headings(&doc), headings(&expected));
+        assert_eq!(doc.code_blocks.len(), 2);
+        for (block, expected) in doc.code_blocks.iter().zip(expected.code_blocks.iter()) {
+            assert_eq!(block.properties, expected.properties);
+            assert_eq!(block.part.id, expected.part.id);
+            assert_eq!(block.part.lang, expected.part.lang);
+            assert_eq!(
+                String::from_utf8_lossy(block.part.contents),
+                String::from_utf8_lossy(expected.part.contents).replace('\n', "\r\n")
+            );
         }
+        assert_eq!(
+            &crlf.as_bytes()[doc.root.children[0].start..][..5],
+            b"<?btx"
+        );
     }
 
     #[test]
-    fn test_code_blocks() {
-        let mut parser = alt((
-            code("```", "```"),
-            section('#'),
-            betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
-        ));
-        let contents = &b"
-This is a big ol' code block
-''
-With some potential gotchas!
+    fn test_bom_and_unicode() {
+        let contents = "\u{feff}# Über ✓
+<?btxt filename='日本/main.rs' pre='// ünïcode' ?>
 ```rust
-```this doesn't count
-// this is still code
+fn main() {}
 ```
-And this isn't code anymore
+"
+        .as_bytes();
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let doc = Document::from_contents(contents, parsers).unwrap();
+        let section = &doc.root.children[0];
+        assert_eq!(section.part.heading, Some("Über ✓".as_bytes()));
+        let props = &doc.code_blocks[0].properties;
+        assert_eq!(props.filename, Some("日本/main.rs".as_bytes()));
+        assert_eq!(props.prefix, Some("// ünïcode".as_bytes()));
+        assert_eq!(&contents[doc.directives[0].span.clone()][..6], b"<?btxt");
+        assert!(doc.describe(&doc.root).unwrap().contains("日本/main.rs"));
+    }
+
+    #[test]
+    fn test_heading_edge_cases() {
+        for (line, heading, level) in [
+            ("## Title ##\n", Some("Title"), 2),
+            ("# Title #####   \n", Some("Title"), 1),
+            ("### C#\n", Some("C#"), 3),
+            ("## Title#\n", Some("Title#"), 2),
+            ("## ##\n", Some(""), 2),
+            ("##Title\n", None, 0),
+        ] {
+            let parsed = section('#')(line.as_bytes());
+            match parsed {
+                Ok((_, LineParseResult::Matched(ScanResult::Section(part)))) => {
+                    assert_eq!(part.heading, heading.map(str::as_bytes), "{}", line);
+                    assert_eq!(part.level, level, "{}", line);
+                }
+                _ => assert!(heading.is_none(), "{} should parse", line),
+            }
+        }
+        let parsed = section_with('#', false)(b"##Title\n");
+        match parsed {
+            Ok((_, LineParseResult::Matched(ScanResult::Section(part)))) => {
+                assert_eq!(part.heading, Some(&b"Title"[..]));
+                assert_eq!(part.level, 2);
+            }
+            _ => panic!("headings without a space should parse"),
+        }
+    }
+
+    #[test]
+    fn test_eof_without_newline() {
+        let parsers = || MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let contents = &b"# First
+<?btxt filename='main.rs' ?>
+```rust
+fn main() {}
+```"[..];
+        let doc = Document::from_contents(contents, parsers()).unwrap();
+        assert_eq!(doc.code_blocks.len(), 1);
+        assert_eq!(doc.code_blocks[0].part.contents, b"fn main() {}\n");
+        assert_eq!(
+            doc.code_blocks[0].properties.filename,
+            Some(&b"main.rs"[..])
+        );
+
+        let contents = &b"# First\n## Last"[..];
+        let doc = Document::from_contents(contents, parsers()).unwrap();
+        let first = &doc.root.children[0];
+        assert_eq!(first.children[0].part.heading, Some(&b"Last"[..]));
+
+        let contents = &b"# First\n<?btxt filename='main.rs' ?>"[..];
+        let doc = Document::from_contents(contents, parsers()).unwrap();
+        assert_eq!(
+            doc.root.children[0].properties.global.filename,
+            Some(&b"main.rs"[..])
+        );
+    }
+
+    #[test]
+    fn test_deep_headings() {
+        let parsers = || MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let contents = (1..=12)
+            .map(|level| format!("{} Level {}\n", "#".repeat(level), level))
+            .collect::<String>();
+        let doc = Document::from_contents(contents.as_bytes(), parsers()).unwrap();
+        let mut section = &doc.root;
+        for level in 1..=12 {
+            assert_eq!(section.children.len(), 1);
+            section = &section.children[0];
+            assert_eq!(section.part.level, level);
+            assert_eq!(
+                section.part.heading,
+                Some(format!("Level {}", level).as_bytes())
+            );
+        }
+        assert!(section.children.is_empty());
+
+        // missing intermediate levels attach to the closest section above them
+        let contents = &b"# A
+########## Deep
+### B
+#### C
+## D
+# E
+"[..];
+        let doc = Document::from_contents(contents, parsers()).unwrap();
+        let a = &doc.root.children[0];
+        fn headings<'a>(section: &Section<'a>) -> Vec<&'a [u8]> {
+            section
+                .children
+                .iter()
+                .map(|child| child.part.heading.unwrap())
+                .collect()
+        }
+        assert_eq!(headings(&doc.root), vec![&b"A"[..], b"E"]);
+        assert_eq!(headings(a), vec![&b"Deep"[..], b"B", b"D"]);
+        assert_eq!(headings(&a.children[1]), vec![&b"C"[..]]);
+
+        let doc = Document::from_contents(&b"no headings at all\n"[..], parsers()).unwrap();
+        assert!(doc.root.children.is_empty());
+    }
+
+    #[test]
+    fn test_section_paths() {
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let contents = "# Getting Started
+## Install (from source!)
+#### Über_alles
 ";
-        let mut scanner = LineScanner::new(&contents[..], true);
-        let results = scanner.scan(&mut parser);
-        assert!(results.is_ok());
-        match &results.as_ref().unwrap() {
-            ScanResult::Code(code) => {
-                assert!(code.lang.is_some());
-                assert_eq!(code.lang.unwrap(), &b"rust"[..]);
-                assert_eq!(
-                    code.contents,
-                    &b"```this doesn't count
-// this is still code
-"[..]
-                );
+        let doc = Document::from_contents(contents.as_bytes(), parsers).unwrap();
+        assert_eq!(doc.root.path(), &[] as &[&[u8]]);
+        assert_eq!(doc.root.slug(), "");
+        let install = &doc.root.children[0].children[0];
+        assert_eq!(
+            install.path(),
+            &[&b"Getting Started"[..], b"Install (from source!)"]
+        );
+        assert_eq!(install.slug(), "getting-started/install-from-source");
+        assert_eq!(
+            install.children[0].slug(),
+            "getting-started/install-from-source/über_alles"
+        );
+    }
+
+    #[test]
+    fn test_scoped_properties() {
+        let contents = "# Guide
+```rust main
+fn main() {}
+```
+## Examples
+### Hello
+```rust hello
+println!(\"hello\");
+```
+#### Deeper
+```rust deeper mode='append'
+```
+## Reference
+```rust reference
+```
+# Appendix
+<?btxt@\"*/Exam?les/*\" ignore=true ?>
+<?btxt+rust@'Guide/**' filename='guide.rs' mode='overwrite' ?>
+```rust appendix filename='appendix.rs'
+```
+";
+        let doc = crate::parse(contents.as_bytes(), FlavorBuilder::github()).unwrap();
+        let ids: Vec<&[u8]> = doc
+            .code_blocks
+            .iter()
+            .map(|block| block.part.id.unwrap())
+            .collect();
+        // everything under Guide/Examples/ is ignored, however deep, and the rust blocks of
+        // the sections in Guide are written to guide.rs, though both are declared after them
+        assert_eq!(ids, vec![&b"main"[..], b"reference", b"appendix"]);
+        assert_eq!(doc.code_blocks[0].properties.filename, None);
+        assert_eq!(
+            doc.code_blocks[1].properties.filename,
+            Some(&b"guide.rs"[..])
+        );
+        assert_eq!(doc.code_blocks[1].properties.scope, None);
+        // the section containing them isn't in their scope
+        assert_eq!(
+            doc.code_blocks[2].properties.filename,
+            Some(&b"appendix.rs"[..])
+        );
+        assert_eq!(doc.code_blocks[2].properties.mode, None);
+        assert_eq!(
+            doc.directives[1].properties.scope,
+            Some(Scope::Headings(b"Guide/**"))
+        );
+        assert!(path_matches(b"Guide/*", &[b"Guide", b"`Hello`"]));
+
+        let contents = "# Intro
+```python intro
+```
+<?btxt tag='docs' ?>
+## Usage
+```python usage filename='usage.py'
+```
+# Appendix
+<?btxt+python scope='document' filename='all.py' mode='overwrite' ?>
+<?btxt scope=document tag='everywhere' ?>
+";
+        let doc = crate::parse(contents.as_bytes(), FlavorBuilder::github()).unwrap();
+        let intro = &doc.code_blocks[0].properties;
+        assert_eq!(intro.filename, Some(&b"all.py"[..]));
+        assert_eq!(intro.tag, Some(&b"everywhere"[..]));
+        assert_eq!(intro.scope, None);
+        // like any other betwixt block at the start of the document, it is overridden by those
+        // of sections and blocks
+        let usage = &doc.code_blocks[1].properties;
+        assert_eq!(usage.filename, Some(&b"usage.py"[..]));
+        assert_eq!(usage.tag, Some(&b"docs"[..]));
+        assert_eq!(usage.mode, Some(TangleMode::Overwrite));
+        assert!(crate::parse(b"<?btxt scope='section' ?>\n", FlavorBuilder::github()).is_err());
+        assert!(!path_matches(b"Guide/*", &[b"Guide", b"Hello", b"Deeper"]));
+    }
+
+    #[test]
+    fn test_section_blocks() {
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let contents = "```rust first
+```
+# Nested
+```python second
+```
+```python third
+```
+";
+        let mut doc = Document::from_contents(contents.as_bytes(), parsers).unwrap();
+        let ids = |section: &Section, doc: &Document| -> Vec<Option<Vec<u8>>> {
+            section
+                .blocks(doc)
+                .map(|block| block.part.id.map(<[u8]>::to_vec))
+                .collect()
+        };
+        assert_eq!(ids(&doc.root, &doc), vec![Some(b"first".to_vec())]);
+        let nested = &doc.root.children[0];
+        assert_eq!(nested.indexes(), &[1, 2]);
+        assert_eq!(
+            ids(nested, &doc),
+            vec![Some(b"second".to_vec()), Some(b"third".to_vec())]
+        );
+        assert_eq!(
+            nested.block(&doc, 1).and_then(|block| block.part.id),
+            Some(&b"third"[..])
+        );
+        assert!(nested.block(&doc, 2).is_none());
+
+        for block in doc.root.children[0].blocks_mut(&mut doc.code_blocks) {
+            block.properties.tag = Some(b"nested");
+        }
+        assert_eq!(doc.code_blocks[0].properties.tag, None);
+        assert_eq!(doc.code_blocks[2].properties.tag, Some(&b"nested"[..]));
+    }
+
+    #[test]
+    fn test_section_titles() {
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let contents = "# Using **betwixt** with `cargo_test` ##\r
+## [Links](https://example.com) and _more_
+### 1. First
+";
+        let doc = Document::from_contents(contents.as_bytes(), parsers).unwrap();
+        let using = &doc.root.children[0];
+        assert_eq!(
+            using.part.heading,
+            Some(&b"Using **betwixt** with `cargo_test`"[..])
+        );
+        assert_eq!(
+            using.part.title().as_deref(),
+            Some("Using betwixt with cargo_test")
+        );
+        assert_eq!(using.slug(), "using-betwixt-with-cargo_test");
+        let links = &using.children[0];
+        assert_eq!(links.part.title().as_deref(), Some("Links and more"));
+        assert_eq!(links.children[0].part.title().as_deref(), Some("1. First"));
+        assert_eq!(doc.root.part.title(), None);
+    }
+
+    #[test]
+    fn test_language_identifiers() {
+        for (info, lang, id) in [
+            ("objective-c", "objective-c", None),
+            ("c++ main", "c++", Some(&b"main"[..])),
+            ("c#", "c#", None),
+            ("shell_session", "shell_session", None),
+            ("vb.net", "vb.net", None),
+            ("python3", "python3", None),
+        ] {
+            let block = format!("```{}\ncode\n```\n", info);
+            let parsed = code("```", "```")(block.as_bytes());
+            match parsed {
+                Ok((_, LineParseResult::Matched(ScanResult::Code(code)))) => {
+                    assert_eq!(code.lang, Some(lang.as_bytes()));
+                    assert_eq!(code.id, id);
+                }
+                _ => panic!("{} should parse", info),
+            }
+        }
+        match betwixt(BETWIXT_TOKEN, CLOSE_TOKEN)(b"<?btxt+objective-c tag='a' ?>") {
+            Ok((_, LineParseResult::Matched(ScanResult::Properties((lang, _))))) => {
+                assert_eq!(lang, Some(&b"objective-c"[..]))
+            }
+            _ => panic!("language properties should parse"),
+        }
+    }
+
+    #[test]
+    fn test_block_ids() {
+        for (info, lang, id, prop_line) in [
+            (
+                "rust #setup-db tag='x'",
+                Some("rust"),
+                Some("setup-db"),
+                Some(" tag='x'"),
+            ),
+            ("rust setup_db", Some("rust"), Some("setup_db"), Some("")),
+            ("#main", None, Some("main"), Some("")),
+            (
+                "rust filename='a.rs'",
+                Some("rust"),
+                None,
+                Some("filename='a.rs'"),
+            ),
+            ("rust # not an id", Some("rust"), None, Some("# not an id")),
+        ] {
+            let block = format!("```{}\ncode\n```\n", info);
+            let parsed = code("```", "```")(block.as_bytes());
+            match parsed {
+                Ok((_, LineParseResult::Matched(ScanResult::Code(code)))) => {
+                    assert_eq!(code.lang, lang.map(str::as_bytes), "{}", info);
+                    assert_eq!(code.id, id.map(str::as_bytes), "{}", info);
+                    assert_eq!(code.prop_line, prop_line.map(str::as_bytes), "{}", info);
+                }
+                _ => panic!("{} should parse", info),
+            }
+        }
+        let contents = &b"# Ids
+```rust #setup-db filename='db.rs'
+```
+"[..];
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let doc = Document::from_contents(contents, parsers).unwrap();
+        assert!(doc.has_id(b"setup-db"));
+        assert_eq!(doc.code_blocks[0].properties.filename, Some(&b"db.rs"[..]));
+
+        let contents = &b"# Ids
+```rust twice
+```
+```rust twice
+```
+"[..];
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        match Document::from_contents(contents, parsers) {
+            Err(err @ DocumentError::DuplicateID(..)) => assert_eq!(err.span(), Some(32..37)),
+            _ => panic!("re-used ids should fail"),
+        }
+    }
+
+    #[test]
+    fn test_notangle() {
+        for (info, id, prop_line, notangle) in [
+            ("rust !notangle", None, Some(""), true),
+            ("rust _ tag='x'", None, Some(" tag='x'"), true),
+            (
+                "rust #setup !notangle tag='x'",
+                Some("setup"),
+                Some(" tag='x'"),
+                true,
+            ),
+            (
+                "rust setup !notangled",
+                Some("setup"),
+                Some(" !notangled"),
+                false,
+            ),
+        ] {
+            let block = format!("```{}\ncode\n```\n", info);
+            let parsed = code("```", "```")(block.as_bytes());
+            match parsed {
+                Ok((_, LineParseResult::Matched(ScanResult::Code(code)))) => {
+                    assert_eq!(code.id, id.map(str::as_bytes), "{}", info);
+                    assert_eq!(code.prop_line, prop_line.map(str::as_bytes), "{}", info);
+                    assert_eq!(code.notangle, notangle, "{}", info);
+                }
+                _ => panic!("{} should parse", info),
             }
-            _ => panic!("unexpected scan result"),
         }
+        let contents = &b"# Snippets
+<?btxt filename='main.rs' ?>
+```rust _
+illustration();
+```
+```rust _
+another();
+```
+```rust main
+fn main() {}
+```
+```rust !notangle
+aside();
+```
+"[..];
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let doc = Document::from_contents(contents, parsers).unwrap();
+        assert_eq!(doc.code_blocks.len(), 1);
+        assert_eq!(doc.code_blocks[0].part.id, Some(&b"main"[..]));
+        assert_eq!(doc.ignored.len(), 3);
+        assert!(!doc.has_id(b"_"));
     }
 
     #[test]
@@ -622,10 +1741,22 @@ And this isn't code anymore
             parsed.is_ok(),
             "parsing valid string 'insert[<<>> INSERT HERE <<>>] should succeed"
         );
-        assert!(matches!(
+        assert_eq!(
             parsed.unwrap().1,
-            TangleMode::Insert(b"<<>> INSERT HERE <<>>")
-        ));
+            TangleMode::Insert(Position::Before, Marker::Text(b"<<>> INSERT HERE <<>>"))
+        );
+        let parsed = TangleMode::from_bytes(b"insert-after[/^fn [a-z]+/]");
+        assert_eq!(
+            parsed.unwrap().1,
+            TangleMode::Insert(Position::After, Marker::Regex(b"^fn [a-z]+"))
+        );
+        let parsed = TangleMode::from_bytes(b"replace[// TODO]").unwrap().1;
+        assert_eq!(
+            parsed,
+            TangleMode::Insert(Position::Replace, Marker::Text(b"// TODO"))
+        );
+        assert_eq!(parsed.to_string(), "replace[// TODO]");
+        assert!(TangleMode::from_bytes(b"insert-above[x]").is_err());
         let excess = &b"appends";
         let parsed = TangleMode::from_bytes(&excess[..]);
         assert!(
@@ -646,7 +1777,7 @@ And this isn't code anymore
             code: code("```", "```"),
             section: section('#'),
             betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
-            strict: true,
+            strict: Strictness::Deny,
         };
         let markdown = &b"Test document
 <?btxt filename='test.rs' ?> some other stuff
@@ -1,20 +1,44 @@
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::Infallible;
 use std::env;
 use std::fmt::Display;
-use std::fs::{self, File, OpenOptions};
-use std::io::BufReader;
+use std::fs::{self, File};
 use std::io::Read;
 use std::io::Write;
-use std::path::PathBuf;
+use std::io::{self, BufReader, IsTerminal};
+use std::iter;
+use std::ops::Deref;
+use std::path::{Component, Path, PathBuf};
 use std::process;
 use std::str::from_utf8;
+use std::time::Instant;
 
 use anyhow::{anyhow, Context, Result};
-use betwixt_parse::TangleMode;
-use betwixt_parse::{
-    betwixt, code, section, Code, Document, MarkdownParsers, BETWIXT_TOKEN, CLOSE_TOKEN,
-};
-use clap::{Parser, ValueEnum};
+use betwixt_parse::archive;
+use betwixt_parse::cache::{Cache, CACHE_FILE};
+use betwixt_parse::config::Config;
+use betwixt_parse::detangle;
+use betwixt_parse::exec::{self, Env, ExecError, Policy, Redactor, Runner, Stream};
+use betwixt_parse::git::{self, GitError};
+use betwixt_parse::graph;
+use betwixt_parse::render;
+use betwixt_parse::report::{Executed, Report, SkipReason, Skipped, Written};
+use betwixt_parse::snapshot::Snapshot;
+use betwixt_parse::stats::Stats;
+use betwixt_parse::strictness::{Lints, Problem, Strictness, Warning};
+use betwixt_parse::tangle::{self, Link, TangleError, TangleOptions};
+use betwixt_parse::template;
+use betwixt_parse::weave;
+use betwixt_parse::{line, Ansi, Code, Document, DocumentError, FlavorBuilder, Properties};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use indicatif::{ProgressBar, ProgressStyle};
+use memmap2::Mmap;
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use tempfile::TempDir;
+use tracing::{debug, info, info_span, Level};
 
 #[derive(ValueEnum, Clone)]
 enum Mode {
@@ -61,62 +85,910 @@ impl Display for Flavor {
     }
 }
 
+#[derive(ValueEnum, Clone)]
+enum WeaveFormat {
+    // markdown with betwixt blocks removed
+    Markdown,
+    // a standalone html page, with code blocks annotated with their tangle targets
+    Html,
+}
+
+impl Display for WeaveFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match &self {
+                WeaveFormat::Markdown => "markdown",
+                WeaveFormat::Html => "html",
+            }
+        )
+    }
+}
+
+#[derive(ValueEnum, Clone)]
+enum GraphFormat {
+    // a mermaid flowchart, which many markdown renderers display inline
+    Mermaid,
+    // graphviz's dot language
+    Dot,
+}
+
+impl Display for GraphFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match &self {
+                GraphFormat::Mermaid => "mermaid",
+                GraphFormat::Dot => "dot",
+            }
+        )
+    }
+}
+
+const EXIT_CODES: &str = "Exit codes:
+  0  success
+  1  a document couldn't be parsed
+  2  tangling failed, e.g. a file couldn't be written, or the arguments are invalid
+  3  fmt --check found a document that isn't formatted
+  4  a command failed, or wasn't allowed to run";
+
 #[derive(Parser)]
 #[command(name = "betwixt")]
-#[command(author, version, about)]
+#[command(author, version, about, after_help = EXIT_CODES)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    tangle: TangleArgs,
+    #[arg(short = 'v', long = "verbose", action = clap::ArgAction::Count, global = true)]
+    /// Log each file written, and with -vv why each skipped block was skipped
+    verbose: u8,
+    #[arg(short = 'q', long = "quiet", global = true, conflicts_with = "verbose")]
+    /// Only print errors
+    quiet: bool,
+}
+
+impl Cli {
+    fn level(&self) -> Level {
+        match (self.quiet, self.verbose) {
+            (true, _) => Level::ERROR,
+            (false, 0) => Level::WARN,
+            (false, 1) => Level::INFO,
+            (false, _) => Level::DEBUG,
+        }
+    }
+}
+
+// arguments are parsed once, boxing the largest of them gains nothing
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+enum Command {
+    /// Write code blocks out to files. This is the default when no command is given
+    Tangle(TangleArgs),
+    /// Write the document back out, without any betwixt blocks, for publishing
+    Weave(WeaveArgs),
+    /// Copy edits made to files tangled with --markers back into the document
+    Detangle(DetangleArgs),
+    /// Rewrite betwixt blocks in a canonical form, leaving everything else as is
+    Fmt(FmtArgs),
+    /// Count sections, code blocks by language, tags, and the bytes tangled to each file
+    Stats(StatsArgs),
+    /// Compare what a document tangles to with the manifest of an earlier run, listing the
+    /// files that would change, be added, or are no longer tangled, without writing anything
+    Diff(DiffArgs),
+    /// Draw the sections and code blocks of a document, and the files they tangle to
+    Graph(GraphArgs),
+    /// Walk through the blocks of a document with a cmd, showing each one and asking whether
+    /// to run it, as a runbook
+    #[command(alias = "exec")]
+    Run(RunArgs),
+    /// Tangle a template document into a new project, asking for the values of the variables
+    /// it declares and filling them in. Without a template, start a literate project with a
+    /// README.md, a betwixt.toml and a CI check
+    Init(InitArgs),
+    /// Tangle the nested flavor documents in a directory into a scratch directory, run the cmd
+    /// of each of their blocks there, and report which passed and which failed
+    Selftest(SelftestArgs),
+    /// Print a script completing betwixt's arguments for a shell
+    Completions(CompletionsArgs),
+    /// Print the tags used in a document, one per line, for completing -t
+    #[command(name = "__complete-tags", hide = true)]
+    CompleteTags(CompleteArgs),
+    /// Print the ids of blocks in a document, one per line, for completing -e
+    #[command(name = "__complete-ids", hide = true)]
+    CompleteIds(CompleteArgs),
+}
+
+// The contents of an input document. Files are memory mapped rather than read into memory,
+// so even very large documents are only held in memory once, by the OS
+enum Input {
+    Mapped(Mmap),
+    Read(Vec<u8>),
+}
+
+impl Deref for Input {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Input::Mapped(map) => map,
+            Input::Read(bytes) => bytes,
+        }
+    }
+}
+
+fn read_input(path: &Path) -> Result<Input> {
+    let file = File::open(path).context(format!("unable to open {}", path.to_string_lossy()))?;
+    // SAFETY: the mapping is only sound while nothing truncates or modifies the file.
+    // Tangled files, even a document tangled over itself with --allow-self-write, are
+    // written to a staged file that is renamed into place, which leaves the mapped file
+    // as it was. Other writes to a document, e.g. by fmt, happen after dropping the
+    // mapping. So this relies on nothing else editing the document while betwixt runs
+    if let Ok(map) = unsafe { Mmap::map(&file) } {
+        return Ok(Input::Mapped(map));
+    }
+    // not everything can be mapped, e.g. pipes, so fall back to reading those
+    let mut reader = BufReader::new(file);
+    let mut bytes = Vec::new();
+    reader
+        .read_to_end(&mut bytes)
+        .context("failed reading contents of file")?;
+    Ok(Input::Read(bytes))
+}
+
+#[derive(Args)]
+struct DocumentArgs {
     /// The markdown file to parse as input
-    file: PathBuf,
-    #[arg(short = 'o', long = "outpath")]
-    /// The root directory to write all files to
-    output_dir: Option<PathBuf>,
+    #[arg(required = true)]
+    file: Option<PathBuf>,
     #[arg(long = "no-strict")]
     /// Ignore certain errors that are probably a bad thing
     no_strict: bool,
-    #[arg(short = 't')]
-    /// Only Tangle blocks with this tag
-    tag: Option<String>,
+    #[arg(long = "warn", value_parser = problem)]
+    /// Warn about a class of problem instead of failing or ignoring it. One of
+    /// invalid-properties, missing-filename, missing-mode, unknown-language or ambiguous-marker
+    warn: Vec<Problem>,
+    #[arg(long = "deny", value_parser = problem)]
+    /// Fail on a class of problem, even with --no-strict. Takes precedence over --warn
+    deny: Vec<Problem>,
     #[arg(long = "flavor", default_value_t = Flavor::Github)]
     /// The markdown flavor to use for parsing (usually ignore this)
     flavor: Flavor,
+    #[arg(long = "code-fence", value_parser = leak, allow_hyphen_values = true)]
+    /// The token opening and closing code blocks, instead of the flavor's, e.g. ~~~
+    code_fence: Option<&'static str>,
+    #[arg(long = "section-mark")]
+    /// The character marking headings, instead of the flavor's, e.g. =
+    section_mark: Option<char>,
+    #[arg(
+        long = "btxt-open",
+        value_parser = leak,
+        requires = "btxt_close",
+        allow_hyphen_values = true
+    )]
+    /// The token opening betwixt blocks, e.g. <!--btxt
+    btxt_open: Option<&'static str>,
+    #[arg(
+        long = "btxt-close",
+        value_parser = leak,
+        requires = "btxt_open",
+        allow_hyphen_values = true
+    )]
+    /// The token closing betwixt blocks, e.g. -->
+    btxt_close: Option<&'static str>,
+    #[arg(long = "read-comments")]
+    /// Parse code blocks and headings inside html comments, instead of skipping them
+    read_comments: bool,
+}
+
+fn problem(arg: &str) -> Result<Problem, String> {
+    Problem::from_name(arg).ok_or_else(|| {
+        let names: Vec<String> = Problem::ALL.iter().map(|p| p.to_string()).collect();
+        format!("expected one of {}", names.join(", "))
+    })
+}
+
+// Parsers only take static tokens. Arguments are parsed once and live as long as
+// betwixt runs anyway, so leaking them costs nothing
+fn leak(arg: &str) -> Result<&'static str, Infallible> {
+    Ok(arg.to_string().leak())
+}
+
+impl DocumentArgs {
+    fn file(&self) -> Result<&Path> {
+        self.file
+            .as_deref()
+            .ok_or_else(|| anyhow!("a markdown file to parse is required"))
+    }
+
+    fn read(&self) -> Result<Input> {
+        read_input(self.file()?)
+    }
+
+    fn lints(&self) -> Lints {
+        let mut lints = if self.no_strict {
+            Lints::default()
+        } else {
+            Lints::strict()
+        };
+        for &problem in self.warn.iter() {
+            lints.set(problem, Strictness::Warn);
+        }
+        for &problem in self.deny.iter() {
+            lints.set(problem, Strictness::Deny);
+        }
+        lints
+    }
+
+    // Parse a document, reporting the problems only warned about
+    fn parse<'a>(&self, bytes: &'a [u8], path: &Path) -> Result<Document<'a>> {
+        self.parse_with_defaults(bytes, path, &HashMap::new())
+    }
+
+    // Parse a document like `parse`, with the defaults of betwixt.toml for its code blocks
+    fn parse_with_defaults<'a>(
+        &self,
+        bytes: &'a [u8],
+        path: &Path,
+        defaults: &HashMap<&'a [u8], Properties<'a>>,
+    ) -> Result<Document<'a>> {
+        let _span = info_span!("parse", file = %path.display()).entered();
+        let context = format!("strict mode: failed to parse {}", path.to_string_lossy());
+        let markdown = match betwixt_parse::parse_with_defaults(bytes, self.flavor(), defaults) {
+            Ok(markdown) => markdown,
+            Err(err) if cfg!(feature = "miette") && err.span().is_some() => {
+                snippet(err, bytes, path);
+                return Err(Failure::Parse(context).into());
+            }
+            Err(err) => return Err(anyhow::Error::new(err).context(context)),
+        };
+        warn(path, &markdown.warnings);
+        info!("parsed {} code blocks", markdown.code_blocks.len());
+        Ok(markdown)
+    }
+
+    fn flavor(&self) -> FlavorBuilder {
+        let mut flavor = match self.flavor {
+            Flavor::Github => FlavorBuilder::github(),
+            Flavor::Nested => FlavorBuilder::nested(),
+        };
+        if let Some(fence) = self.code_fence {
+            flavor = flavor.fence(fence, fence);
+        }
+        if let Some(mark) = self.section_mark {
+            flavor = flavor.section_mark(mark);
+        }
+        if let (Some(open), Some(close)) = (self.btxt_open, self.btxt_close) {
+            flavor = flavor.tokens(open, close);
+        }
+        if self.read_comments {
+            flavor = flavor.no_comments();
+        }
+        flavor.strict(self.lints().invalid_properties)
+    }
+}
+
+#[derive(Args)]
+struct TangleArgs {
+    #[command(flatten)]
+    document: DocumentArgs,
+    /// More markdown files to tangle along with the first. No two documents may tangle to
+    /// the same file
+    more: Vec<PathBuf>,
+    #[arg(short = 'j', long = "jobs")]
+    /// How many documents to parse at once. Defaults to the number of CPUs
+    jobs: Option<usize>,
+    #[arg(short = 'o', long = "outpath")]
+    /// The root directory to write all files to
+    output_dir: Option<PathBuf>,
+    #[arg(short = 't')]
+    /// Only Tangle blocks with this tag
+    tag: Option<String>,
     #[arg(short = 'e')]
     /// A list of block IDs that should be executed in addition to being tangled
     execute: Option<Vec<String>>,
+    #[arg(long = "exec-tag", value_name = "TAG")]
+    /// Execute every block with this tag and a cmd, in document order. Can be repeated
+    exec_tags: Vec<String>,
+    #[arg(short = 'y', long = "yes")]
+    /// Execute commands without asking for confirmation first
+    yes: bool,
+    #[arg(long = "config")]
+    /// The betwixt.toml to use. Defaults to one next to the input file, if present
+    config: Option<PathBuf>,
+    #[arg(long = "markers")]
+    /// Surround each block with comments recording where it came from, for detangle
+    markers: bool,
+    #[arg(long = "gitignore")]
+    /// List each file written in the nearest .gitignore, for projects where only documents
+    /// are committed
+    gitignore: bool,
+    #[arg(long = "respect-git")]
+    /// Refuse to overwrite files that git has uncommitted changes to. Appending is allowed
+    respect_git: bool,
+    #[arg(long = "commit", value_name = "MESSAGE", conflicts_with = "gitignore")]
+    /// Commit the files written, and nothing else, with this message once tangling is done
+    commit: Option<String>,
+    #[arg(long = "changed-since", value_name = "REVISION")]
+    /// Only write files with blocks that changed since a git revision, e.g. HEAD~1
+    changed_since: Option<String>,
+    #[arg(
+        long = "archive",
+        value_name = "PATH",
+        conflicts_with_all = ["output_dir", "execute", "exec_tags", "gitignore", "respect_git", "commit", "cache"]
+    )]
+    /// Write the files to a .tar, .tar.gz or .zip archive instead, without formatting them or
+    /// running hooks
+    archive: Option<PathBuf>,
+    #[arg(long = "map", value_name = "FROM=TO", value_parser = mapping)]
+    /// Write files whose names start with FROM under TO instead, e.g. src/=generated/src/.
+    /// Can be repeated, the first matching map is used
+    map: Vec<(String, String)>,
+    #[arg(long = "cache")]
+    /// Remember what was written to each file in .betwixt/cache, and leave files whose
+    /// contents haven't changed untouched, without formatting them or running after hooks
+    cache: bool,
+    #[arg(long = "allow-self-write")]
+    /// Allow blocks to tangle to one of the documents being tangled, overwriting it with
+    /// what it generates
+    allow_self_write: bool,
+    #[arg(long = "fail-on-empty")]
+    /// Fail when no blocks are selected, or none of them tangle to a file, e.g. because of a
+    /// mistyped tag
+    fail_on_empty: bool,
+    #[arg(long = "porcelain")]
+    /// Report what was done with a stable, tab separated line per file, skipped block and
+    /// command, for scripts
+    porcelain: bool,
+    #[arg(long = "json", conflicts_with = "porcelain")]
+    /// Report what was done as json, including the exit status, duration and output size of
+    /// each command run, e.g. to keep as a CI artifact
+    json: bool,
     /// The mode of operation of betwixt
     #[arg(short = 'm', default_value_t = Mode::Tangle)]
     mode: Mode,
 }
 
-fn execute(block: &Code, exec_ids: &HashSet<String>) -> Result<Option<String>> {
-    if let Some(id) = &block.part.id {
-        let id = from_utf8(&id).unwrap();
-        if exec_ids.contains(id) {
-            let cmd = block
-                .properties
-                .cmd
-                .context(format!("specified exec id {} has no cmd specified", id))?;
-            let cmd = from_utf8(cmd).unwrap();
-            let cmds = cmd.split("&&").into_iter();
-            let mut output: Vec<u8> = Vec::new();
-            for cmd in cmds {
-                let cmd: Vec<&str> = cmd.split_whitespace().collect();
-                let mut command = std::process::Command::new(cmd[0]);
-                output = command
-                    .args(&cmd[1..cmd.len()])
-                    .output()
-                    .context(format!("failed executing command for id {}", id))?
-                    .stdout;
-            }
-            Ok(Some(from_utf8(&output).unwrap().to_owned()))
-        } else {
-            Ok(None)
+#[derive(Args)]
+struct WeaveArgs {
+    #[command(flatten)]
+    document: DocumentArgs,
+    #[arg(short = 'o', long = "output")]
+    /// The file to write the woven markdown to. Defaults to stdout
+    output: Option<PathBuf>,
+    #[arg(long = "strip-ignored")]
+    /// Also remove code blocks that are ignored for tangling
+    strip_ignored: bool,
+    #[arg(long = "format", default_value_t = WeaveFormat::Markdown)]
+    /// The format to weave the document into
+    format: WeaveFormat,
+    #[arg(long = "link-prefix", default_value = "")]
+    /// Prepended to tangle targets when linking to them in html output
+    link_prefix: String,
+}
+
+#[derive(Args)]
+struct DetangleArgs {
+    #[command(flatten)]
+    document: DocumentArgs,
+    #[arg(required = true)]
+    /// The tangled files to read edited blocks from
+    files: Vec<PathBuf>,
+}
+
+#[derive(Args)]
+struct FmtArgs {
+    #[command(flatten)]
+    document: DocumentArgs,
+    #[arg(long = "check")]
+    /// Don't write anything, fail if the document isn't already formatted
+    check: bool,
+}
+
+#[derive(Args)]
+struct StatsArgs {
+    #[command(flatten)]
+    document: DocumentArgs,
+    #[arg(long = "json")]
+    /// Print the stats as json instead of a table
+    json: bool,
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    #[command(flatten)]
+    document: DocumentArgs,
+    #[arg(short = 'o', long = "outpath")]
+    /// The directory the earlier run wrote to, with its manifest in .betwixt/cache. Defaults
+    /// to the current directory
+    output_dir: Option<PathBuf>,
+    #[arg(long = "manifest", value_name = "PATH")]
+    /// The manifest to compare with, instead of the one in the output directory
+    manifest: Option<PathBuf>,
+    #[arg(short = 't')]
+    /// Only compare blocks with this tag
+    tag: Option<String>,
+    #[arg(long = "config")]
+    /// The betwixt.toml to use. Defaults to one next to the input file, if present
+    config: Option<PathBuf>,
+    #[arg(long = "map", value_name = "FROM=TO", value_parser = mapping)]
+    /// Compare files whose names start with FROM under TO instead, as tangling with --map does
+    map: Vec<(String, String)>,
+}
+
+#[derive(Args)]
+struct GraphArgs {
+    #[command(flatten)]
+    document: DocumentArgs,
+    #[arg(long = "format", default_value_t = GraphFormat::Mermaid)]
+    /// The diagram language to write
+    format: GraphFormat,
+    #[arg(short = 'o', long = "output")]
+    /// The file to write the graph to. Defaults to stdout
+    output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct RunArgs {
+    #[command(flatten)]
+    document: DocumentArgs,
+    #[arg(long = "config")]
+    /// The betwixt.toml to use. Defaults to one next to the input file, if present
+    config: Option<PathBuf>,
+    #[arg(short = 'y', long = "yes")]
+    /// Run every block without asking
+    yes: bool,
+    #[arg(long = "json")]
+    /// Report the blocks run as json, with the exit status, duration and output size of each
+    json: bool,
+    #[arg(long = "record", value_name = "DIR")]
+    /// Save what each block run writes to stdout and stderr, and its exit code, in DIR under
+    /// the block's id. Blocks that fail are recorded too, and don't stop the run
+    record: Option<PathBuf>,
+    #[arg(long = "verify", value_name = "DIR", conflicts_with = "record")]
+    /// Check what each block run writes, and its exit code, against those recorded in DIR,
+    /// failing if any differ
+    verify: Option<PathBuf>,
+}
+
+#[derive(Args)]
+#[command(mut_arg("file", |arg| arg.required(false)))]
+struct InitArgs {
+    #[command(flatten)]
+    document: DocumentArgs,
+    #[arg(short = 'o', long = "outpath")]
+    /// The directory to create the project in, made if it doesn't exist. Defaults to the
+    /// current directory
+    output_dir: Option<PathBuf>,
+    #[arg(long = "set", value_name = "NAME=VALUE", value_parser = assignment)]
+    /// Give a variable its value instead of asking for it. Can be repeated
+    set: Vec<(String, String)>,
+    #[arg(long = "defaults")]
+    /// Take the default of every variable not set, instead of asking
+    defaults: bool,
+}
+
+#[derive(Args)]
+struct SelftestArgs {
+    /// The directory of documents to test, searched recursively for .md files, or a document
+    dir: PathBuf,
+    #[arg(long = "config")]
+    /// The betwixt.toml to use. Defaults to one in the directory, if present
+    config: Option<PathBuf>,
+    #[arg(long = "keep")]
+    /// Leave the scratch directory of each document in place, to look into what failed
+    keep: bool,
+}
+
+#[derive(Args)]
+struct CompletionsArgs {
+    /// The shell to complete arguments for
+    shell: Shell,
+}
+
+#[derive(Args)]
+struct CompleteArgs {
+    #[command(flatten)]
+    document: DocumentArgs,
+}
+
+// Progress through the documents, files or blocks of a run, shown as a bar on a terminal.
+// Elsewhere, and when logging with -v, the log lines for each step report progress instead
+struct Progress {
+    bar: Option<ProgressBar>,
+}
+
+impl Progress {
+    fn new(action: &'static str, len: usize, show: bool) -> Self {
+        // a single step finishes too quickly for a bar to be of any use
+        let bar = (show && len > 1).then(|| {
+            let style =
+                ProgressStyle::with_template("{prefix:>10} [{bar:30}] {pos}/{len} {wide_msg}")
+                    .expect("valid template")
+                    .progress_chars("=> ");
+            ProgressBar::new(len as u64)
+                .with_style(style)
+                .with_prefix(action)
+        });
+        Progress { bar }
+    }
+
+    // Start on the step named item
+    fn start(&self, item: &str) {
+        if let Some(bar) = &self.bar {
+            bar.set_message(item.to_owned());
         }
-    } else {
-        Ok(None)
     }
+
+    fn step(&self) {
+        if let Some(bar) = &self.bar {
+            bar.inc(1);
+        }
+    }
+
+    // Hide the bar while f prints or prompts
+    fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        match &self.bar {
+            Some(bar) => bar.suspend(f),
+            None => f(),
+        }
+    }
+
+    fn finish(&self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+// Errors with an exit code of their own, that can't be told apart by the errors they wrap
+#[derive(Debug)]
+enum Failure {
+    // the document at the path failed to parse, and the error was already shown
+    Parse(String),
+    // the document at the path isn't formatted
+    Unformatted(PathBuf),
+    // --fail-on-empty was given, and there was nothing to tangle
+    Empty(String),
+    // selftest ran commands, and this many of them failed
+    Failed(usize),
+    // run --verify ran commands, and this many of them didn't do what was recorded
+    Differs(usize),
+    // a command needed confirmation, but stdin isn't a terminal to ask on
+    Unconfirmed(String),
+}
+
+impl std::error::Error for Failure {}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Parse(context) => write!(f, "{}", context),
+            Failure::Unformatted(path) => write!(f, "{} is not formatted", path.display()),
+            Failure::Empty(reason) => write!(f, "nothing to tangle: {}", reason),
+            Failure::Failed(failed) => write!(f, "{} commands failed", failed),
+            Failure::Differs(differs) => {
+                write!(f, "{} commands differ from their recordings", differs)
+            }
+            Failure::Unconfirmed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+// The exit code for an error, see EXIT_CODES
+fn exit_code(err: &anyhow::Error) -> i32 {
+    for cause in err.chain() {
+        if cause.is::<DocumentError>() || matches!(cause.downcast_ref(), Some(Failure::Parse(_))) {
+            return 1;
+        }
+        if matches!(cause.downcast_ref(), Some(Failure::Unformatted(_))) {
+            return 3;
+        }
+        if cause.is::<ExecError>()
+            || matches!(
+                cause.downcast_ref(),
+                Some(Failure::Failed(_) | Failure::Differs(_) | Failure::Unconfirmed(_))
+            )
+        {
+            return 4;
+        }
+    }
+    2
+}
+
+// Show a command and ask whether to run it for target (a block id or file).
+// Always true when the user passed --yes
+fn confirm(target: &str, cmd: &str, yes: bool) -> Result<bool> {
+    if yes {
+        return Ok(true);
+    }
+    if !io::stdin().is_terminal() {
+        return Err(Failure::Unconfirmed(format!(
+            "refusing to execute '{}' for {} without confirmation, pass --yes to allow it",
+            cmd, target
+        ))
+        .into());
+    }
+    eprint!("execute '{}' for {}? [y/N] ", cmd, target);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .context("failed reading confirmation")?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn run_hooks(stage: &str, hooks: &[String], policy: &Policy) -> Result<()> {
+    if hooks.is_empty() {
+        return Ok(());
+    }
+    let redactor = redactor(None, policy)?;
+    for hook in hooks {
+        let output = Runner::Local
+            .run(hook, &[], policy)
+            .map_err(|err| redactor.error(err))
+            .context(format!("{} hook '{}' failed", stage, hook))?;
+        print!("{}", String::from_utf8_lossy(&redactor.redact(&output)));
+    }
+    Ok(())
+}
+
+// Run the cmd of a block selected for execution, given by its id or, when selected by tag
+// without one, where it is. What the command writes is shown as it is written, each line
+// prefixed with the label, on stderr when stdout is kept for a report. Commands that are run
+// or declined are recorded in executed, even when they fail
+fn execute(
+    block: &Code,
+    label: &str,
+    policy: &Policy,
+    yes: bool,
+    progress: &Progress,
+    keep_stdout: bool,
+    executed: &mut Vec<Executed>,
+) -> Result<()> {
+    let name = match block.part.id {
+        Some(_) => format!("id {}", label),
+        None => format!("block {}", label),
+    };
+    let _span = info_span!("execute", block = %label).entered();
+    let cmd = block
+        .properties
+        .cmd
+        .context(format!("specified exec {} has no cmd specified", name))?;
+    let cmd = from_utf8(cmd).context(format!("cmd for {} is not valid utf8", name))?;
+    let env = Env::of(block);
+    let cmd = &env
+        .expand(cmd)
+        .context(format!("not executing command for {}", name))?;
+    // check the policy up front so denied commands fail without prompting
+    policy
+        .check(cmd)
+        .context(format!("not executing command for {}", name))?;
+    let redactor = redactor(block.properties.redact, policy)?;
+    progress.start(label);
+    if !progress.suspend(|| confirm(&name, cmd, yes))? {
+        eprintln!("skipped execution of {}", name);
+        executed.push(declined(label, &redactor.redact_str(cmd)));
+        return Ok(());
+    }
+    let files: Vec<&Path> = block
+        .properties
+        .filenames()
+        .map(tangle::path)
+        .collect::<Result<_, _>>()?;
+    let started = Instant::now();
+    let mut bytes = 0;
+    let runner = block.properties.runner.clone().unwrap_or_default();
+    let terminal = (io::stdout().is_terminal(), io::stderr().is_terminal());
+    let show = |stream: Stream, line: &str| {
+        progress.suspend(|| match stream {
+            Stream::Stdout if !keep_stdout => println!("[{}] {}", label, line),
+            _ => eprintln!("[{}] {}", label, line),
+        })
+    };
+    let (result, tries) = attempts(block, label, || {
+        let timeout = block.properties.timeout;
+        let (mut stdout, mut stderr) = (limit(block), limit(block));
+        let result = runner.stream(cmd, &files, &env, policy, timeout, &mut |stream, line| {
+            bytes += line.len() as u64;
+            let line = redactor.redact(line);
+            let line = match stream {
+                Stream::Stdout if !keep_stdout => ansi(block, &line, terminal.0),
+                _ => ansi(block, &line, terminal.1),
+            };
+            let line = match stream {
+                Stream::Stdout => stdout.keep(&line),
+                Stream::Stderr => stderr.keep(&line),
+            };
+            if !line.is_empty() {
+                let line = String::from_utf8_lossy(&line);
+                show(stream, line.strip_suffix('\n').unwrap_or(&line));
+            }
+        });
+        for (stream, limit) in [(Stream::Stdout, stdout), (Stream::Stderr, stderr)] {
+            if let Some(marker) = limit.marker() {
+                show(stream, marker.trim_end());
+            }
+        }
+        result
+    });
+    let result = result.map_err(|err| failure(block, &redactor, err));
+    let cmd = &redactor.redact_str(cmd);
+    executed.push(ran(label, cmd, started, bytes, &result, tries));
+    result.context(format!("failed executing command for {}", name))?;
+    info!("executed '{}'", cmd);
+    Ok(())
+}
+
+// The secrets to keep out of what a command writes, from the redact property of its block and
+// the environment variables the policy's patterns match
+fn redactor(redact: Option<&[u8]>, policy: &Policy) -> Result<Redactor> {
+    let vars: Vec<(String, String)> = env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .collect();
+    Ok(Redactor::new(
+        redact,
+        &policy.redact,
+        vars.iter()
+            .map(|(name, value)| (name.as_str(), value.as_str())),
+    )?)
 }
 
-fn tangle(cli: Cli) -> Result<()> {
+// How much of each stream of what a block's command writes is shown and recorded
+fn limit(block: &Code) -> exec::Limit {
+    exec::Limit::new(
+        block.properties.max_output_lines,
+        block.properties.max_output_bytes,
+    )
+}
+
+// What a block's command wrote, without escape sequences unless its ansi property keeps them,
+// or it sets neither and what it wrote is shown on a terminal
+fn ansi<'o>(block: &Code, output: &'o [u8], terminal: bool) -> Cow<'o, [u8]> {
+    match block.properties.ansi {
+        Some(Ansi::Keep) => Cow::Borrowed(output),
+        None if terminal => Cow::Borrowed(output),
+        _ => exec::strip_ansi(output),
+    }
+}
+
+// err, with what its command wrote to stderr as errors are shown: redacted, and without
+// escape sequences unless the block keeps them
+fn failure(block: &Code, redactor: &Redactor, err: ExecError) -> ExecError {
+    let err = match err {
+        ExecError::Failed(program, code, stderr) => {
+            let stderr = ansi(block, stderr.as_bytes(), false);
+            ExecError::Failed(program, code, String::from_utf8_lossy(&stderr).into_owned())
+        }
+        err => err,
+    };
+    redactor.error(err)
+}
+
+// The record of a command the user declined to run
+fn declined(label: &str, cmd: &str) -> Executed {
+    Executed {
+        id: label.to_owned(),
+        command: cmd.to_owned(),
+        ran: false,
+        status: None,
+        millis: 0,
+        bytes: 0,
+        attempts: 0,
+    }
+}
+
+// The record of a command run since started, that wrote bytes over all its attempts, whether
+// it succeeded or not
+fn ran(
+    label: &str,
+    cmd: &str,
+    started: Instant,
+    bytes: u64,
+    result: &Result<Vec<u8>, ExecError>,
+    attempts: u32,
+) -> Executed {
+    Executed {
+        id: label.to_owned(),
+        command: cmd.to_owned(),
+        ran: true,
+        status: match result {
+            Ok(_) => Some(0),
+            Err(ExecError::Failed(_, code, _)) => *code,
+            Err(_) => None,
+        },
+        millis: started.elapsed().as_millis() as u64,
+        bytes,
+        attempts,
+    }
+}
+
+// Run a block's command with attempt as many times as its retries allow, saying on stderr when
+// it is tried again
+fn attempts(
+    block: &Code,
+    label: &str,
+    mut attempt: impl FnMut() -> Result<Vec<u8>, ExecError>,
+) -> (Result<Vec<u8>, ExecError>, u32) {
+    let retries = block.properties.retries.unwrap_or(0);
+    let delay = block.properties.retry_delay.unwrap_or_default();
+    exec::retry(retries, delay, |n| {
+        if n > 1 {
+            eprintln!(
+                "[{}] failed, trying again ({} of {})",
+                label,
+                n,
+                retries + 1
+            );
+        }
+        attempt()
+    })
+}
+
+// A directory of its own for commands to run in, made in the temp directory under a name no one
+// else has, so nothing left there, or put there by anyone else, is used. Commands are run from
+// it once it is entered, until it is dropped, when it is removed unless it was made to be kept
+struct Scratch {
+    dir: TempDir,
+    previous: PathBuf,
+}
+
+impl Scratch {
+    fn new(prefix: &str, keep: bool) -> Result<Self> {
+        let dir = tempfile::Builder::new()
+            .prefix(prefix)
+            .disable_cleanup(keep)
+            .tempdir()
+            .context("failed creating a scratch directory")?;
+        let previous = env::current_dir().context("failed finding the current directory")?;
+        Ok(Scratch { dir, previous })
+    }
+
+    fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    fn enter(&self) -> Result<()> {
+        env::set_current_dir(self.path()).context("unable to change to scratch directory")
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = env::set_current_dir(&self.previous);
+    }
+}
+
+// The scratch directory an isolated block's command is run from, holding copies of files, which
+// are relative to the current directory. Files outside the current directory aren't copied,
+// the command finds them where they are
+fn isolate(files: &[PathBuf]) -> Result<Scratch> {
+    let scratch = Scratch::new("betwixt-isolate-", false)?;
+    let inside = |file: &&PathBuf| {
+        file.components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    };
+    for file in files.iter().filter(inside) {
+        let copy = scratch.path().join(file);
+        if let Some(parent) = copy.parent() {
+            fs::create_dir_all(parent).context(format!("failed creating {}", parent.display()))?;
+        }
+        fs::copy(file, &copy).context(format!(
+            "failed copying {} to {}",
+            file.display(),
+            scratch.path().display()
+        ))?;
+    }
+    scratch.enter()?;
+    Ok(scratch)
+}
+
+// Tangle documents, showing progress bars when show_progress is set, recording what was done
+// in report, unless only describing the documents
+fn tangle(cli: TangleArgs, show_progress: bool, report: &mut Report) -> Result<()> {
     let exec_ids = match cli.execute {
         Some(ids) => ids.into_iter().collect(),
         None => HashSet::new(),
@@ -131,127 +1003,1325 @@ fn tangle(cli: Cli) -> Result<()> {
             out_dir.to_string_lossy()
         ));
     };
-    let file = File::open(cli.file).context("unable to open input file")?;
+    // outputs are found under it once betwixt is in it, so a relative one mustn't be resolved
+    // against itself
+    let out_dir = fs::canonicalize(&out_dir).context("failed resolving the output directory")?;
+    let file = cli.document.file()?;
+    let config = match &cli.config {
+        Some(path) => Config::from_file(path),
+        None => Config::discover(file.parent().unwrap_or(Path::new("."))),
+    }
+    .context("failed loading betwixt.toml")?;
+    let paths: Vec<&Path> = iter::once(file)
+        .chain(cli.more.iter().map(PathBuf::as_path))
+        .collect();
+    let inputs = paths
+        .iter()
+        .map(|path| read_input(path))
+        .collect::<Result<Vec<Input>>>()?;
+    // where each document really is, to find files tangled over them once in the output directory
+    let sources = paths
+        .iter()
+        .map(|path| fs::canonicalize(path).context(format!("failed finding {}", path.display())))
+        .collect::<Result<Vec<PathBuf>>>()?;
+    let archive = cli
+        .archive
+        .as_deref()
+        .map(std::path::absolute)
+        .transpose()
+        .context("failed resolving the archive path")?;
     std::env::set_current_dir(&out_dir).context("unable to change to output directory")?;
 
-    let mut reader = BufReader::new(file);
-    let mut bytes = Vec::new();
-    reader
-        .read_to_end(&mut bytes)
-        .context("failed reading contents of file")?;
-
-    let parsers = match cli.flavor {
-        Flavor::Github => MarkdownParsers {
-            code: code("```", "```"),
-            section: section('#'),
-            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
-            strict: !cli.no_strict,
-        },
-        Flavor::Nested => MarkdownParsers {
-            code: code("'''", "'''"),
-            section: section('#'),
-            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
-            strict: !cli.no_strict,
-        },
-    };
-    let markdown =
-        Document::from_contents(&bytes[..], parsers).context("strict mode: failed to parse")?;
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(cli.jobs.unwrap_or_default())
+        .build()
+        .context("failed starting threads")?;
+    let defaults = config.defaults()?;
+    let progress = Progress::new("parsing", paths.len(), show_progress);
+    let documents = pool.install(|| {
+        inputs
+            .par_iter()
+            .zip(paths.par_iter())
+            .map(|(input, path)| {
+                progress.start(&path.to_string_lossy());
+                let markdown = cli
+                    .document
+                    .parse_with_defaults(&input[..], path, &defaults);
+                progress.step();
+                markdown
+            })
+            .collect::<Result<Vec<Document>>>()
+    })?;
+    progress.finish();
     match cli.mode {
         Mode::Describe => {
-            let output = markdown
-                .describe(&markdown.root)
-                .context("failed building describe output")?;
-            println!("{}", output);
+            for markdown in documents.iter() {
+                let output = markdown
+                    .describe(&markdown.root)
+                    .context("failed building describe output")?;
+                println!("{}", output);
+            }
+            Ok(())
         }
         Mode::Tangle => {
-            for block in markdown.code_blocks.iter() {
-                if let Some(filter) = cli.tag.as_ref() {
-                    match block.properties.tag {
-                        Some(tag) => {
-                            if from_utf8(tag).context("failed to parse tag as utf8")? != filter {
-                                continue;
-                            }
+            let selected = documents
+                .iter()
+                .map(|markdown| select(markdown, cli.tag.as_deref()))
+                .collect::<Result<Vec<Vec<usize>>>>()?;
+            if cli.fail_on_empty && selected.iter().all(Vec::is_empty) {
+                return Err(Failure::Empty(match &cli.tag {
+                    Some(tag) => format!("no code blocks are tagged {}", tag),
+                    None => "there are no code blocks".into(),
+                })
+                .into());
+            }
+            for ((markdown, blocks), (input, path)) in documents
+                .iter()
+                .zip(&selected)
+                .zip(inputs.iter().zip(&paths))
+            {
+                report
+                    .skipped
+                    .extend(skipped(markdown, blocks, input, path));
+            }
+            let options = TangleOptions {
+                lints: cli.document.lints(),
+                markers: cli.markers,
+                comments: config.comments.clone(),
+            };
+            let plans = pool.install(|| {
+                documents
+                    .par_iter()
+                    .zip(selected.par_iter())
+                    .zip(paths.par_iter())
+                    .map(|((markdown, blocks), path)| {
+                        let mut warnings = Vec::new();
+                        let plan =
+                            tangle::plan_with_warnings(markdown, blocks, &options, &mut warnings);
+                        warn(path, &warnings);
+                        plan
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })?;
+            // the document each file is tangled from, for pointing at its blocks
+            let origins: Vec<usize> = plans
+                .iter()
+                .enumerate()
+                .flat_map(|(doc, plan)| iter::repeat_n(doc, plan.len()))
+                .collect();
+            let outputs = tangle::merge(plans).map_err(|err| match err {
+                TangleError::Conflict(name, first, second) => anyhow!(
+                    "{} and {} both tangle to {}",
+                    paths[first].to_string_lossy(),
+                    paths[second].to_string_lossy(),
+                    String::from_utf8_lossy(&name)
+                ),
+                err => err.into(),
+            })?;
+            if cli.fail_on_empty && outputs.is_empty() {
+                return Err(Failure::Empty("no code blocks tangle to a file".into()).into());
+            }
+            let (mut outputs, mut origins) = (outputs, origins);
+            if let Some(rev) = &cli.changed_since {
+                let mut changed = HashSet::new();
+                for ((markdown, input), path) in documents.iter().zip(&inputs).zip(&paths) {
+                    changed.extend(changed_files(markdown, input, path, rev)?);
+                }
+                (outputs, origins) = outputs
+                    .into_iter()
+                    .zip(origins)
+                    .filter(|(output, _)| {
+                        let keep = changed.contains(&output.filename);
+                        if !keep {
+                            debug!(
+                                file = %String::from_utf8_lossy(&output.filename),
+                                "not writing file, none of its blocks changed since {}",
+                                rev
+                            );
                         }
-                        None => continue,
+                        keep
+                    })
+                    .unzip();
+            }
+            tangle::remap(&mut outputs, &cli.map)?;
+            if let Some(archive) = &archive {
+                for output in outputs.iter() {
+                    if output.format.is_some() || output.assemble.is_some() {
+                        eprintln!(
+                            "warning: {} is archived as tangled, without formatting or assembling it",
+                            String::from_utf8_lossy(&output.filename)
+                        );
                     }
                 }
-                // FIXME don't repeatedly open and write files. Do it once. This is easier for now
-                // FIXME don't just use utf8 blindly on filenames
-                if let Some(mode) = &block.properties.mode {
-                    if let Some(filename) = block.properties.filename {
-                        let mut file = match mode {
-                            TangleMode::Overwrite => {
-                                let mut path = out_dir.clone();
-                                path.push(from_utf8(filename).unwrap());
-                                OpenOptions::new()
-                                    .create(true)
-                                    .write(true)
-                                    .truncate(true)
-                                    .open(path)
-                                    .unwrap()
-                            }
-                            TangleMode::Append => {
-                                let mut path = out_dir.clone();
-                                path.push(from_utf8(filename).unwrap());
-                                OpenOptions::new()
-                                    .write(true)
-                                    .append(true)
-                                    .open(path)
-                                    .unwrap()
-                            }
-                            TangleMode::Prepend => {
-                                panic!("prepend mode is unimplemented");
-                            }
-                            TangleMode::Insert(_) => {
-                                panic!("insert mode is unimplemented");
-                            }
-                        };
-                        if let Some(prefix) = block.properties.prefix {
-                            file.write_all(prefix)
-                                .context("failed to write prefix for code block to file")?;
-                        }
-                        file.write_all(block.part.contents)
-                            .context("failed to write code block to file")?;
-                        if let Some(postfix) = block.properties.postfix {
-                            file.write_all(postfix)
-                                .context("failed to write postfix for code block to file")?;
-                        }
-                        // If execute was set, and the IDs provided match this block's ID, then execute this block's cmd
-                        match execute(block, &exec_ids)? {
-                            Some(output) => print!("{}", output),
-                            None => (),
-                        }
+                archive::write(archive, &outputs)
+                    .with_context(|| format!("failed writing {}", archive.display()))?;
+                info!("wrote {} files to {}", outputs.len(), archive.display());
+                report.files.extend(outputs.iter().map(|output| Written {
+                    path: String::from_utf8_lossy(&output.filename).into_owned(),
+                    bytes: output.contents.len() as u64,
+                    delta: output.contents.len() as i64,
+                }));
+                return Ok(());
+            }
+            // files appended or prepended to must already exist, unless they are to be created.
+            // Every file is checked before any is written, so a run doesn't stop halfway
+            let mut missing = Vec::new();
+            for (output, &doc) in outputs.iter_mut().zip(&origins) {
+                let path = output.path(&out_dir)?;
+                if !(output.append || output.prepend) || path.exists() {
+                    continue;
+                }
+                if output.create {
+                    output.create_file();
+                    continue;
+                }
+                let start = documents[doc].code_blocks[output.blocks[0]].span.start;
+                missing.push(format!(
+                    "{}:{}: {} doesn't exist to be {} to, set create=true to create it",
+                    paths[doc].to_string_lossy(),
+                    line(&inputs[doc], start),
+                    String::from_utf8_lossy(&output.filename),
+                    if output.append {
+                        "appended"
                     } else {
-                        if !cli.no_strict {
-                            return Err(anyhow!(
-                                "code block without filename found, strict mode enforced"
-                            ));
+                        "prepended"
+                    }
+                ));
+            }
+            if !missing.is_empty() {
+                return Err(anyhow!(missing.join("\n")));
+            }
+            // a document tangled over itself, or another being tangled with it, would read what
+            // it generated the next time it is tangled
+            if !cli.allow_self_write {
+                let mut cycles = Vec::new();
+                for (output, &doc) in outputs.iter().zip(&origins) {
+                    let Ok(path) = fs::canonicalize(output.path(&out_dir)?) else {
+                        continue;
+                    };
+                    let Some(input) = sources.iter().position(|source| *source == path) else {
+                        continue;
+                    };
+                    let start = documents[doc].code_blocks[output.blocks[0]].span.start;
+                    cycles.push(format!(
+                        "{}:{}: {} is {}, pass --allow-self-write to tangle over it",
+                        paths[doc].to_string_lossy(),
+                        line(&inputs[doc], start),
+                        String::from_utf8_lossy(&output.filename),
+                        if input == doc {
+                            "the document it is tangled from".into()
+                        } else {
+                            format!("the input document {}", paths[input].to_string_lossy())
                         }
+                    ));
+                }
+                if !cycles.is_empty() {
+                    return Err(anyhow!(cycles.join("\n")));
+                }
+            }
+            if cli.respect_git {
+                let overwritten = outputs
+                    .iter()
+                    .filter(|output| !output.append && !output.prepend)
+                    .map(|output| output.path(&out_dir))
+                    .collect::<Result<Vec<PathBuf>, _>>()?;
+                let dirty = git::uncommitted(&overwritten)?;
+                if !dirty.is_empty() {
+                    return Err(GitError::Uncommitted(dirty).into());
+                }
+            }
+            run_hooks("before", &config.hooks.before, &config.exec)?;
+            let progress = Progress::new("writing", outputs.len(), show_progress);
+            let mut written = Vec::new();
+            let mut cache = if cli.cache {
+                Some(Cache::load(&out_dir).context("failed reading the cache")?)
+            } else {
+                None
+            };
+            for output in outputs.iter() {
+                let filename = String::from_utf8_lossy(&output.filename);
+                let _span = info_span!("write", file = %filename).entered();
+                progress.start(&filename);
+                if let Some(cache) = cache
+                    .as_mut()
+                    .filter(|_| !output.append && !output.prepend && output.link.is_none())
+                {
+                    let path = output.path(&out_dir)?;
+                    if path.exists() && cache.unchanged(&filename, &output.contents) {
+                        debug!("left {} alone, it hasn't changed", path.display());
+                        written.push(fs::canonicalize(&path).unwrap_or(path));
+                        progress.step();
                         continue;
                     }
-                } else {
-                    if !cli.no_strict {
-                        return Err(anyhow!(
-                            "code block without mode found, strict mode enforced"
-                        ));
+                    cache.record(&filename, &output.contents);
+                }
+                let before = fs::metadata(output.path(&out_dir)?).map_or(0, |meta| meta.len());
+                let path = output.write_with(&out_dir, |staged| -> Result<()> {
+                    let Some(assemble) = output.assemble else {
+                        return Ok(());
+                    };
+                    let assemble =
+                        from_utf8(assemble).context("failed to parse assemble as utf8")?;
+                    config
+                        .exec
+                        .check(assemble)
+                        .context(format!("not assembling {}", filename))?;
+                    if progress.suspend(|| confirm(&filename, assemble, cli.yes))? {
+                        exec::assemble(assemble, staged, &config.exec)
+                            .context(format!("failed assembling {}", filename))?;
                     }
-                    continue;
+                    Ok(())
+                })?;
+                info!(
+                    bytes = output.contents.len(),
+                    mode = match (output.link, output.append, output.prepend) {
+                        (Some(Link::Symbolic), ..) => "symlink",
+                        (Some(Link::Hard), ..) => "hardlink",
+                        (None, true, _) => "append",
+                        (None, false, true) => "prepend",
+                        (None, false, false) => "overwrite",
+                    },
+                    "wrote {}",
+                    path.display()
+                );
+                if let Some(format) = output.format {
+                    let format = from_utf8(format).context("failed to parse format as utf8")?;
+                    let target = path.to_string_lossy();
+                    config
+                        .exec
+                        .check(format)
+                        .context(format!("not formatting {}", target))?;
+                    if progress.suspend(|| confirm(&target, format, cli.yes))? {
+                        exec::format(format, &path, &config.exec)
+                            .context(format!("failed formatting {}", target))?;
+                    }
+                }
+                let bytes = fs::metadata(&path).map_or(0, |meta| meta.len());
+                written.push(fs::canonicalize(&path).unwrap_or(path));
+                report.files.push(Written {
+                    path: filename.into_owned(),
+                    bytes,
+                    delta: bytes as i64 - before as i64,
+                });
+                progress.step();
+            }
+            progress.finish();
+            if let Some(cache) = &cache {
+                cache.save().context("failed writing the cache")?;
+            }
+            if cli.gitignore {
+                for ignore in git::ignore(&written).context("failed updating .gitignore")? {
+                    info!("listed tangled files in {}", ignore.display());
+                }
+            }
+            // Commands run once every file is written, so they see the complete output
+            // blocks are run in document order, each given by its id, or where it is when it was
+            // selected by tag and has none
+            let executed: Vec<(&Code, String)> = documents
+                .iter()
+                .zip(selected)
+                .zip(inputs.iter().zip(&paths))
+                .flat_map(|((markdown, blocks), (input, path))| {
+                    blocks
+                        .into_iter()
+                        .map(move |idx| (&markdown.code_blocks[idx], input, path))
+                })
+                .filter_map(|(block, input, path)| {
+                    let id = block.part.id.map(|id| String::from_utf8_lossy(id));
+                    let by_id = id.as_ref().is_some_and(|id| exec_ids.contains(id.as_ref()));
+                    let by_tag = block.properties.cmd.is_some()
+                        && block.properties.tag.is_some_and(|tag| {
+                            cli.exec_tags
+                                .iter()
+                                .any(|exec_tag| exec_tag.as_bytes() == tag)
+                        });
+                    let label = match id {
+                        Some(id) => id.into_owned(),
+                        None => format!(
+                            "{}:{}",
+                            path.to_string_lossy(),
+                            line(input, block.span.start)
+                        ),
+                    };
+                    (by_id || by_tag).then_some((block, label))
+                })
+                .collect();
+            // isolated commands are given copies of every file tangled
+            let root = fs::canonicalize(".").context("failed finding the output directory")?;
+            let tangled: Vec<PathBuf> = written
+                .iter()
+                .filter_map(|path| Some(path.strip_prefix(&root).ok()?.to_path_buf()))
+                .collect();
+            let progress = Progress::new("executing", executed.len(), show_progress);
+            for (block, label) in executed {
+                let _isolation = match block.properties.isolate {
+                    Some(true) => Some(isolate(&tangled)?),
+                    _ => None,
                 };
+                execute(
+                    block,
+                    &label,
+                    &config.exec,
+                    cli.yes,
+                    &progress,
+                    cli.json || cli.porcelain,
+                    &mut report.executed,
+                )?;
+                progress.step();
+            }
+            progress.finish();
+            // nothing to do after when every file was left alone
+            if cache.is_none() || !report.files.is_empty() {
+                run_hooks("after", &config.hooks.after, &config.exec)?;
+            }
+            if let Some(message) = &cli.commit {
+                if git::commit(&written, message)? {
+                    info!("committed {} files", written.len());
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+// The files tangled from blocks of a document that changed since the git revision rev.
+// Changing a betwixt block can change the file any block after it tangles to, so counts as
+// changing every block
+fn changed_files<'a>(
+    markdown: &Document<'a>,
+    input: &[u8],
+    path: &Path,
+    rev: &str,
+) -> Result<HashSet<Cow<'a, [u8]>>> {
+    let changed = git::changed_lines(path, rev).context(format!(
+        "failed diffing {} against {}",
+        path.to_string_lossy(),
+        rev
+    ))?;
+    let touched = |span: &std::ops::Range<usize>| {
+        let lines = line(input, span.start)..line(input, span.end.saturating_sub(1)) + 1;
+        changed
+            .iter()
+            .any(|range| range.start < lines.end && lines.start < range.end)
+    };
+    let everything = markdown
+        .directives
+        .iter()
+        .any(|directive| touched(&directive.span));
+    Ok((0..markdown.code_blocks.len())
+        .filter(|&idx| everything || touched(&markdown.code_blocks[idx].span))
+        .flat_map(|idx| markdown.filenames(idx))
+        .collect())
+}
+
+// The blocks of a document that won't be tangled and why, given those selected. Blocks
+// missing a mode or filename are skipped unless that is denied, which fails the run
+fn skipped(markdown: &Document, selected: &[usize], input: &[u8], path: &Path) -> Vec<Skipped> {
+    let location = |pos: usize| format!("{}:{}", path.to_string_lossy(), line(input, pos));
+    let mut skipped: Vec<(usize, SkipReason)> = markdown
+        .ignored
+        .iter()
+        .map(|span| (span.start, SkipReason::Ignored))
+        .collect();
+    for (idx, block) in markdown.code_blocks.iter().enumerate() {
+        let reason = if !selected.contains(&idx) {
+            SkipReason::Untagged
+        } else if block.properties.mode.is_none() {
+            SkipReason::NoMode
+        } else if markdown.filenames(idx).is_empty() {
+            SkipReason::NoFilename
+        } else {
+            continue;
+        };
+        skipped.push((block.span.start, reason));
+    }
+    skipped.sort_by_key(|&(pos, _)| pos);
+    skipped
+        .into_iter()
+        .map(|(pos, reason)| Skipped {
+            block: location(pos),
+            reason,
+        })
+        .collect()
+}
+
+// How the report of a run is printed
+#[derive(Clone, Copy)]
+enum ReportFormat {
+    Human,
+    Porcelain,
+    Json,
+}
+
+// Tangle, then print the report of what was done. When a command fails, the report of the
+// commands run up to and including it is still printed, so failed runs can be audited
+fn tangle_and_report(cli: TangleArgs, show_progress: bool, quiet: bool) -> Result<()> {
+    let describe = matches!(cli.mode, Mode::Describe);
+    let format = match (cli.porcelain, cli.json) {
+        (true, _) => ReportFormat::Porcelain,
+        (_, true) => ReportFormat::Json,
+        _ => ReportFormat::Human,
+    };
+    let mut report = Report::default();
+    match tangle(cli, show_progress, &mut report) {
+        Ok(()) if describe => {
+            if !quiet {
+                println!("Done");
+            }
+            Ok(())
+        }
+        Ok(()) => summarize(&report, format, quiet, true),
+        Err(err) => {
+            if !report.executed.is_empty() {
+                summarize(&report, format, quiet, false)?;
+            }
+            Err(err)
+        }
+    }
+}
+
+// Print the report of a tangle run, ending with Done when it succeeded
+fn summarize(report: &Report, format: ReportFormat, quiet: bool, succeeded: bool) -> Result<()> {
+    match format {
+        _ if quiet => {}
+        ReportFormat::Porcelain => print!("{}", report.porcelain()),
+        ReportFormat::Json => println!("{}", serde_json::to_string_pretty(report)?),
+        ReportFormat::Human => {
+            print!("{}", report.render(io::stdout().is_terminal()));
+            if succeeded {
+                println!("Done");
+            }
+        }
+    }
+    Ok(())
+}
+
+// The indexes of the blocks of a document to tangle, those with the given tag if any
+fn select(markdown: &Document, tag: Option<&str>) -> Result<Vec<usize>> {
+    let mut blocks = Vec::new();
+    for (idx, block) in markdown.code_blocks.iter().enumerate() {
+        if let Some(filter) = tag {
+            match block.properties.tag {
+                Some(tag) => {
+                    let tag = from_utf8(tag).context("failed to parse tag as utf8")?;
+                    if tag != filter {
+                        debug!(block = idx, tag, "skipped block, its tag isn't selected");
+                        continue;
+                    }
+                }
+                None => {
+                    debug!(block = idx, "skipped block, it has no tag");
+                    continue;
+                }
+            }
+        }
+        blocks.push(idx);
+    }
+    Ok(blocks)
+}
+
+// Show an error in a document as an annotated snippet of the document
+#[cfg(feature = "miette")]
+fn snippet(err: DocumentError, bytes: &[u8], path: &Path) {
+    let source = miette::NamedSource::new(
+        path.to_string_lossy(),
+        String::from_utf8_lossy(bytes).into_owned(),
+    );
+    eprintln!("{:?}", miette::Report::new(err).with_source_code(source));
+}
+
+#[cfg(not(feature = "miette"))]
+fn snippet(_: DocumentError, _: &[u8], _: &Path) {}
+
+fn warn(path: &Path, warnings: &[Warning]) {
+    for warning in warnings {
+        eprintln!("warning: {}: {}", path.to_string_lossy(), warning);
+    }
+}
+
+fn weave(cli: WeaveArgs) -> Result<()> {
+    let bytes = cli.document.read()?;
+    let markdown = cli.document.parse(&bytes[..], cli.document.file()?)?;
+    let output = match cli.format {
+        WeaveFormat::Markdown => weave::weave(&bytes, &markdown, cli.strip_ignored),
+        WeaveFormat::Html => {
+            let file = cli.document.file()?;
+            let title = file
+                .file_stem()
+                .map(|stem| stem.to_string_lossy())
+                .unwrap_or_default();
+            let options = weave::HtmlOptions {
+                title: &title,
+                strip_ignored: cli.strip_ignored,
+                link_prefix: &cli.link_prefix,
+            };
+            weave::weave_html(&bytes, &markdown, &options).into_bytes()
+        }
+    };
+    match &cli.output {
+        Some(path) => fs::write(path, output).context("failed writing woven markdown")?,
+        None => io::stdout()
+            .write_all(&output)
+            .context("failed writing woven markdown")?,
+    }
+    Ok(())
+}
+
+fn detangle(cli: DetangleArgs) -> Result<()> {
+    let file = cli.document.file()?;
+    let bytes = cli.document.read()?;
+    let markdown = cli.document.parse(&bytes[..], cli.document.file()?)?;
+    let mut tangled = Vec::new();
+    for path in cli.files.iter() {
+        tangled.push(fs::read(path).context(format!("unable to read {}", path.to_string_lossy()))?);
+    }
+    let tangled: Vec<&[u8]> = tangled.iter().map(|t| &t[..]).collect();
+    let (updated, changed) = detangle::detangle(&bytes, &markdown, &tangled)?;
+    if changed.is_empty() {
+        println!("No changes");
+        return Ok(());
+    }
+    let changed: Vec<String> = changed
+        .into_iter()
+        .map(|idx| match markdown.code_blocks[idx].part.id {
+            Some(id) => format!("{} ({})", idx, String::from_utf8_lossy(id)),
+            None => idx.to_string(),
+        })
+        .collect();
+    // the document may be mapped, it must be released before writing over it
+    drop(markdown);
+    drop(bytes);
+    fs::write(file, updated).context("failed writing updated document")?;
+    for block in changed {
+        println!("Updated block {}", block);
+    }
+    Ok(())
+}
+
+fn fmt(cli: FmtArgs) -> Result<()> {
+    let file = cli.document.file()?;
+    let bytes = cli.document.read()?;
+    let markdown = cli.document.parse(&bytes[..], cli.document.file()?)?;
+    let formatted = render::format(&bytes, &markdown);
+    if formatted[..] == bytes[..] {
+        return Ok(());
+    }
+    if cli.check {
+        return Err(Failure::Unformatted(file.to_owned()).into());
+    }
+    // the document may be mapped, it must be released before writing over it
+    drop(markdown);
+    drop(bytes);
+    fs::write(file, formatted).context("failed writing formatted document")?;
+    println!("Formatted {}", file.to_string_lossy());
+    Ok(())
+}
+
+fn stats(cli: StatsArgs) -> Result<()> {
+    let bytes = cli.document.read()?;
+    let markdown = cli.document.parse(&bytes[..], cli.document.file()?)?;
+    let stats = Stats::from_document(&markdown)?;
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+    } else {
+        print!("{}", stats);
+    }
+    Ok(())
+}
+
+fn diff(cli: DiffArgs) -> Result<()> {
+    let file = cli.document.file()?;
+    let manifest = match cli.manifest {
+        Some(path) => path,
+        None => match cli.output_dir {
+            Some(dir) => dir,
+            None => {
+                env::current_dir().context("betwixt must be in a directory or pass --outpath")?
             }
         }
+        .join(CACHE_FILE),
+    };
+    let manifest = Cache::open(&manifest).context(format!(
+        "failed reading the manifest {}, tangle with --cache to record one",
+        manifest.display()
+    ))?;
+    let config = match &cli.config {
+        Some(path) => Config::from_file(path),
+        None => Config::discover(file.parent().unwrap_or(Path::new("."))),
+    }
+    .context("failed loading betwixt.toml")?;
+    let bytes = cli.document.read()?;
+    let defaults = config.defaults()?;
+    let markdown = cli
+        .document
+        .parse_with_defaults(&bytes[..], file, &defaults)?;
+    let selected = select(&markdown, cli.tag.as_deref())?;
+    let options = TangleOptions {
+        lints: cli.document.lints(),
+        comments: config.comments.clone(),
+        ..Default::default()
     };
+    let mut warnings = Vec::new();
+    let mut outputs = tangle::plan_with_warnings(&markdown, &selected, &options, &mut warnings)?;
+    warn(file, &warnings);
+    tangle::remap(&mut outputs, &cli.map)?;
+    for (change, file) in manifest.diff(&outputs) {
+        println!("{:<9} {}", change, file);
+    }
+    Ok(())
+}
 
+fn graph(cli: GraphArgs) -> Result<()> {
+    let bytes = cli.document.read()?;
+    let markdown = cli.document.parse(&bytes[..], cli.document.file()?)?;
+    let output = match cli.format {
+        GraphFormat::Mermaid => graph::mermaid(&markdown),
+        GraphFormat::Dot => graph::dot(&markdown),
+    };
+    match &cli.output {
+        Some(path) => fs::write(path, output).context("failed writing graph")?,
+        None => print!("{}", output),
+    }
+    Ok(())
+}
+
+// What to do with the next block of a runbook
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    Run,
+    Skip,
+    Abort,
+}
+
+// Ask what to do with the block shown, until given an answer. The end of input aborts
+fn step() -> Result<Step> {
+    loop {
+        eprint!("[r]un, [s]kip or [a]bort? ");
+        io::stderr().flush()?;
+        let mut answer = String::new();
+        if io::stdin()
+            .read_line(&mut answer)
+            .context("failed reading answer")?
+            == 0
+        {
+            eprintln!();
+            return Ok(Step::Abort);
+        }
+        match answer.trim() {
+            "r" | "run" => return Ok(Step::Run),
+            "s" | "skip" => return Ok(Step::Skip),
+            "a" | "abort" => return Ok(Step::Abort),
+            _ => {}
+        }
+    }
+}
+
+// A prefix of filenames and what it is replaced with, given as from=to
+fn mapping(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((from, to)) if !from.is_empty() => Ok((from.into(), to.into())),
+        _ => Err(format!("expected FROM=TO, got '{}'", arg)),
+    }
+}
+
+// A variable's value, given as name=value
+fn assignment(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.into(), value.into())),
+        _ => Err(format!("expected NAME=VALUE, got '{}'", arg)),
+    }
+}
+
+fn init(cli: InitArgs) -> Result<()> {
+    if cli.document.file.is_none() {
+        let out_dir = match cli.output_dir {
+            Some(dir) => dir,
+            None => {
+                env::current_dir().context("betwixt must be in a directory or pass --outpath")?
+            }
+        };
+        return scaffold(&out_dir);
+    }
+    let file = cli.document.file()?;
+    let bytes = cli.document.read()?;
+    let markdown = cli.document.parse(&bytes[..], file)?;
+    let variables = template::variables(&markdown);
+    let mut values: BTreeMap<String, String> = BTreeMap::new();
+    for (name, value) in cli.set {
+        if !variables.iter().any(|var| var.name == name.as_bytes()) {
+            return Err(anyhow!(
+                "{} isn't a variable of {}",
+                name,
+                file.to_string_lossy()
+            ));
+        }
+        values.insert(name, value);
+    }
+    for var in variables.iter() {
+        let name = from_utf8(var.name).context("failed to parse var as utf8")?;
+        if values.contains_key(name) {
+            continue;
+        }
+        let default = var
+            .default
+            .map(|default| String::from_utf8_lossy(default).into_owned());
+        let value = if cli.defaults {
+            default.ok_or_else(|| anyhow!("{} has no default, pass --set {}=...", name, name))?
+        } else {
+            ask(name, var.prompt, default)?
+        };
+        values.insert(name.into(), value);
+    }
+    let out_dir = match cli.output_dir {
+        Some(dir) => dir,
+        None => env::current_dir().context("betwixt must be in a directory or pass --outpath")?,
+    };
+    let selected = select(&markdown, None)?;
+    let options = TangleOptions {
+        lints: cli.document.lints(),
+        ..Default::default()
+    };
+    let mut warnings = Vec::new();
+    let mut outputs = tangle::plan_with_warnings(&markdown, &selected, &options, &mut warnings)?;
+    warn(file, &warnings);
+    template::instantiate(&mut outputs, &values);
+    for output in outputs.iter_mut() {
+        let path = output.path(&out_dir)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context(format!("failed creating {}", dir.display()))?;
+        }
+        // a new project has nothing to append or prepend to yet
+        if !path.exists() {
+            (output.append, output.prepend) = (false, false);
+        }
+        let path = output.write(&out_dir)?;
+        println!("created {}", path.display());
+    }
+    Ok(())
+}
+
+// The files of a new literate project: a document to tangle, the config beside it, and a
+// workflow checking what is tangled from it is committed
+const SCAFFOLD: [(&str, &str); 3] = [
+    ("README.md", include_str!("scaffold/README.md")),
+    ("betwixt.toml", include_str!("scaffold/betwixt.toml")),
+    (
+        ".github/workflows/check.yml",
+        include_str!("scaffold/check.yml"),
+    ),
+];
+
+// Start a literate project in dir. Nothing is written if any of its files are already there
+fn scaffold(dir: &Path) -> Result<()> {
+    let existing: Vec<String> = SCAFFOLD
+        .iter()
+        .map(|(name, _)| dir.join(name))
+        .filter(|path| path.exists())
+        .map(|path| path.display().to_string())
+        .collect();
+    if !existing.is_empty() {
+        return Err(anyhow!(
+            "not starting a project over {}",
+            existing.join(", ")
+        ));
+    }
+    for (name, contents) in SCAFFOLD {
+        let path = dir.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context(format!("failed creating {}", parent.display()))?;
+        }
+        fs::write(&path, contents).context(format!("failed writing {}", path.display()))?;
+        println!("created {}", path.display());
+    }
+    Ok(())
+}
+
+// Ask for the value of a template variable, until one is given or there is a default
+fn ask(name: &str, prompt: Option<&[u8]>, default: Option<String>) -> Result<String> {
+    if !io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "refusing to guess {}, pass --set {}=... or --defaults",
+            name,
+            name
+        ));
+    }
+    let prompt = prompt.map_or(name.into(), String::from_utf8_lossy);
+    loop {
+        match &default {
+            Some(default) => eprint!("{} [{}] ", prompt, default),
+            None => eprint!("{} ", prompt),
+        }
+        io::stderr().flush()?;
+        let mut answer = String::new();
+        if io::stdin()
+            .read_line(&mut answer)
+            .context(format!("failed reading {}", name))?
+            == 0
+        {
+            return Err(anyhow!("no value given for {}", name));
+        }
+        match (answer.trim(), &default) {
+            ("", Some(default)) => return Ok(default.clone()),
+            ("", None) => continue,
+            (answer, _) => return Ok(answer.into()),
+        }
+    }
+}
+
+// Run the blocks of a document with a cmd in order, each after showing its section, contents
+// and command and asking whether to. Commands are run from the current directory, unless
+// isolated, and their output is shown as it is written. Ends with a report of the blocks run,
+// which is all that is written to stdout with --json
+fn run(cli: RunArgs) -> Result<()> {
+    let file = cli.document.file()?;
+    let config = match &cli.config {
+        Some(path) => Config::from_file(path),
+        None => Config::discover(file.parent().unwrap_or(Path::new("."))),
+    }
+    .context("failed loading betwixt.toml")?;
+    let bytes = cli.document.read()?;
+    let defaults = config.defaults()?;
+    let markdown = cli
+        .document
+        .parse_with_defaults(&bytes[..], file, &defaults)?;
+    let mut titles = vec![None; markdown.code_blocks.len()];
+    for section in markdown.root.walk() {
+        for &idx in section.indexes() {
+            titles[idx] = section.part.title();
+        }
+    }
+    let steps: Vec<usize> = (0..markdown.code_blocks.len())
+        .filter(|&idx| markdown.code_blocks[idx].properties.cmd.is_some())
+        .collect();
+    if steps.is_empty() {
+        println!("No blocks to run");
+        return Ok(());
+    }
+    if !cli.yes && !io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "refusing to run {} without asking, pass --yes to run every block",
+            file.to_string_lossy()
+        ));
+    }
+    let mut out: Box<dyn Write> = if cli.json {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    };
+    let mut report = Report::default();
+    let snapshots = cli.record.as_deref().or(cli.verify.as_deref());
+    // whether the output of commands, and what they write to stderr, is shown on a terminal
+    let terminal = (
+        if cli.json {
+            io::stderr().is_terminal()
+        } else {
+            io::stdout().is_terminal()
+        },
+        io::stderr().is_terminal(),
+    );
+    let mut differs = 0;
+    // whether every block was run or skipped, rather than the run aborted
+    let mut walk = || -> Result<bool> {
+        for (n, &idx) in steps.iter().enumerate() {
+            let block = &markdown.code_blocks[idx];
+            let label = match block.part.id {
+                Some(id) => String::from_utf8_lossy(id).into_owned(),
+                None => format!(
+                    "{}:{}",
+                    file.to_string_lossy(),
+                    line(&bytes, block.span.start)
+                ),
+            };
+            // recordings are kept under the block's id, so only blocks with one can be checked
+            if snapshots.is_some() && block.part.id.is_none() {
+                return Err(anyhow!("{} has no id to record its output under", label));
+            }
+            let cmd = block.properties.cmd.unwrap_or_default();
+            let cmd = from_utf8(cmd).context(format!("cmd for {} is not valid utf8", label))?;
+            let env = Env::of(block);
+            let cmd = &env.expand(cmd).context(format!("not running {}", label))?;
+            let title = titles[idx].as_deref().unwrap_or("(no heading)");
+            writeln!(out, "== {} ({}/{}, {})", title, n + 1, steps.len(), label)?;
+            let contents = String::from_utf8_lossy(block.part.contents);
+            write!(out, "{}", contents)?;
+            if !contents.is_empty() && !contents.ends_with('\n') {
+                writeln!(out)?;
+            }
+            writeln!(out, "$ {}", cmd)?;
+            out.flush()?;
+            config
+                .exec
+                .check(cmd)
+                .context(format!("not running {}", label))?;
+            let redactor = redactor(block.properties.redact, &config.exec)?;
+            match if cli.yes { Step::Run } else { step()? } {
+                Step::Run => {}
+                Step::Skip => {
+                    report
+                        .executed
+                        .push(declined(&label, &redactor.redact_str(cmd)));
+                    continue;
+                }
+                Step::Abort => return Ok(false),
+            }
+            // nothing is tangled first, so only files already tangled can be copied for runners
+            let files: Vec<&Path> = block
+                .properties
+                .filenames()
+                .map(tangle::path)
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .filter(|path| path.exists())
+                .collect();
+            // isolated commands are given copies of the files of the blocks up to theirs, those
+            // already tangled
+            let isolation = match block.properties.isolate {
+                Some(true) => {
+                    let tangled: Vec<PathBuf> = markdown.code_blocks[..=idx]
+                        .iter()
+                        .flat_map(|block| block.properties.filenames())
+                        .map(|name| tangle::path(name).map(Path::to_path_buf))
+                        .collect::<Result<Vec<_>, _>>()?
+                        .into_iter()
+                        .filter(|path| path.is_file())
+                        .collect();
+                    Some(isolate(&tangled)?)
+                }
+                _ => None,
+            };
+            let started = Instant::now();
+            let mut bytes = 0;
+            let mut snapshot = Snapshot::default();
+            let runner = block.properties.runner.clone().unwrap_or_default();
+            let (result, tries) = attempts(block, &label, || {
+                // only the last attempt is recorded
+                snapshot = Snapshot::default();
+                let timeout = block.properties.timeout;
+                let (mut stdout, mut stderr) = (limit(block), limit(block));
+                let result = runner.stream(
+                    cmd,
+                    &files,
+                    &env,
+                    &config.exec,
+                    timeout,
+                    &mut |stream, line| {
+                        bytes += line.len() as u64;
+                        let line = &redactor.redact(line);
+                        // recordings are never shown on a terminal
+                        let _ = match stream {
+                            Stream::Stdout => {
+                                snapshot.stdout.extend_from_slice(&ansi(block, line, false));
+                                let line = ansi(block, line, terminal.0);
+                                out.write_all(&stdout.keep(&line)).and_then(|_| out.flush())
+                            }
+                            Stream::Stderr => {
+                                snapshot.stderr.extend_from_slice(&ansi(block, line, false));
+                                let line = ansi(block, line, terminal.1);
+                                io::stderr().write_all(&stderr.keep(&line))
+                            }
+                        };
+                    },
+                );
+                if let Some(marker) = stdout.marker() {
+                    let _ = out.write_all(marker.as_bytes());
+                }
+                if let Some(marker) = stderr.marker() {
+                    eprint!("{}", marker);
+                }
+                result
+            });
+            // recordings are kept relative to where betwixt was run from
+            drop(isolation);
+            let result = result.map_err(|err| failure(block, &redactor, err));
+            let executed = ran(
+                &label,
+                &redactor.redact_str(cmd),
+                started,
+                bytes,
+                &result,
+                tries,
+            );
+            snapshot.status = executed.status;
+            // what is recorded is kept within the block's limits, like what is shown, and so
+            // are the runs checked against it
+            let (lines, limit) = (
+                block.properties.max_output_lines,
+                block.properties.max_output_bytes,
+            );
+            for output in [&mut snapshot.stdout, &mut snapshot.stderr] {
+                if let Cow::Owned(truncated) = exec::truncate(output, lines, limit) {
+                    *output = truncated;
+                }
+            }
+            report.executed.push(executed);
+            match (&result, snapshots) {
+                // a command exiting with an error is recorded like any other
+                (Ok(_) | Err(ExecError::Failed(..)), Some(dir)) => {
+                    if let Some(dir) = &cli.record {
+                        snapshot.save(dir, &label).context(format!(
+                            "failed recording {} in {}",
+                            label,
+                            dir.display()
+                        ))?;
+                        continue;
+                    }
+                    let recorded = Snapshot::load(dir, &label)
+                        .context(format!("failed reading the recording of {}", label))?;
+                    let difference = match recorded {
+                        Some(recorded) => snapshot.compare(&recorded),
+                        None => Some(format!("no recording in {}\n", dir.display())),
+                    };
+                    if let Some(difference) = difference {
+                        differs += 1;
+                        write!(out, "{} differs from its recording\n{}", label, difference)?;
+                    }
+                }
+                _ => {
+                    result.context(format!("failed running {}", label))?;
+                }
+            }
+        }
+        Ok(true)
+    };
+    let finished = walk().and_then(|finished| match differs {
+        0 => Ok(finished),
+        differs => Err(Failure::Differs(differs).into()),
+    });
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", report.render_executed(io::stdout().is_terminal()));
+        match finished {
+            Ok(true) => println!("Done"),
+            Ok(false) => println!("Aborted"),
+            Err(_) => {}
+        }
+    }
+    finished.map(|_| ())
+}
+
+// The markdown documents under path, in a stable order, or path itself when it is a file
+fn documents(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_owned()]);
+    }
+    let mut found = Vec::new();
+    for entry in fs::read_dir(path).context(format!("failed reading {}", path.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            found.extend(documents(&path)?);
+        } else if path.extension().is_some_and(|ext| ext == "md") {
+            found.push(path);
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+// Tangle each nested flavor document into a scratch directory of its own and run the cmd of
+// every block in it from there, in document order. A failing command doesn't stop the rest,
+// its output is shown and it is counted, failing the run once every document is tested
+fn selftest(cli: SelftestArgs) -> Result<()> {
+    let root =
+        fs::canonicalize(&cli.dir).context(format!("failed finding {}", cli.dir.display()))?;
+    let config = match &cli.config {
+        Some(path) => Config::from_file(path),
+        None => Config::discover(if root.is_dir() {
+            &root
+        } else {
+            root.parent().unwrap_or(Path::new("."))
+        }),
+    }
+    .context("failed loading betwixt.toml")?;
+    let defaults = config.defaults()?;
+    let (mut passed, mut failed) = (0, 0);
+    for file in documents(&root)?.iter() {
+        let name = file.strip_prefix(&root).unwrap_or(file);
+        let name = match name.as_os_str().is_empty() {
+            true => file.file_name().map_or(file.as_path(), Path::new),
+            false => name,
+        };
+        let bytes = read_input(file)?;
+        let markdown =
+            betwixt_parse::parse_with_defaults(&bytes[..], FlavorBuilder::nested(), &defaults)
+                .context(format!("failed to parse {}", name.display()))?;
+        warn(name, &markdown.warnings);
+        let steps: Vec<&Code> = markdown
+            .code_blocks
+            .iter()
+            .filter(|block| block.properties.cmd.is_some())
+            .collect();
+        if steps.is_empty() {
+            continue;
+        }
+        let scratch = Scratch::new("betwixt-selftest-", cli.keep)?;
+        let selected = select(&markdown, None)?;
+        let mut warnings = Vec::new();
+        let outputs = tangle::plan_with_warnings(
+            &markdown,
+            &selected,
+            &TangleOptions::default(),
+            &mut warnings,
+        )?;
+        warn(name, &warnings);
+        for mut output in outputs {
+            let path = output.path(scratch.path())?;
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).context(format!("failed creating {}", dir.display()))?;
+            }
+            // every test starts from nothing, so there is nothing to append or prepend to
+            if !path.exists() {
+                (output.append, output.prepend) = (false, false);
+            }
+            output.write(scratch.path())?;
+        }
+        // commands run locally from the directory they were tangled to
+        scratch.enter()?;
+        for block in steps {
+            let label = match block.part.id {
+                Some(id) => format!("{} {}", name.display(), String::from_utf8_lossy(id)),
+                None => format!("{}:{}", name.display(), line(&bytes, block.span.start)),
+            };
+            let cmd = from_utf8(block.properties.cmd.unwrap_or_default())
+                .context(format!("cmd for {} is not valid utf8", label))?;
+            let env = Env::of(block);
+            let redactor = redactor(block.properties.redact, &config.exec)?;
+            let mut output = Vec::new();
+            let result = env.expand(cmd).and_then(|cmd| {
+                let files: Vec<&Path> = block
+                    .properties
+                    .filenames()
+                    .filter_map(|name| tangle::path(name).ok())
+                    .collect();
+                let runner = block.properties.runner.clone().unwrap_or_default();
+                attempts(block, &label, || {
+                    output.clear();
+                    // what a failing command wrote to stderr is already in its error
+                    let (policy, timeout) = (&config.exec, block.properties.timeout);
+                    runner.stream(&cmd, &files, &env, policy, timeout, &mut |stream, line| {
+                        if let Stream::Stdout = stream {
+                            output.extend_from_slice(&ansi(
+                                block,
+                                &redactor.redact(line),
+                                io::stdout().is_terminal(),
+                            ))
+                        }
+                    })
+                })
+                .0
+                .map_err(|err| failure(block, &redactor, err))
+            });
+            match result {
+                Ok(_) => {
+                    passed += 1;
+                    println!("ok   {}", label);
+                }
+                Err(err) => {
+                    failed += 1;
+                    println!("FAIL {}: {}", label, err);
+                    print!("{}", String::from_utf8_lossy(&output));
+                }
+            }
+        }
+        if cli.keep {
+            println!("kept {}", scratch.path().display());
+        }
+    }
+    println!("{} passed, {} failed", passed, failed);
+    match failed {
+        0 => Ok(()),
+        failed => Err(Failure::Failed(failed).into()),
+    }
+}
+
+// Completes -t and -e from the document being tangled, by asking betwixt for its tags and
+// ids, and everything else with the completions clap generates
+const BASH_DYNAMIC: &str = r#"
+_betwixt_dynamic() {
+    local cur="${COMP_WORDS[COMP_CWORD]}" prev="${COMP_WORDS[COMP_CWORD-1]}" word file helper
+    for word in "${COMP_WORDS[@]:1}"; do
+        if [[ "$word" == *.md ]]; then
+            file="$word"
+            break
+        fi
+    done
+    case "$prev" in
+        -t|--exec-tag) helper=__complete-tags ;;
+        -e) helper=__complete-ids ;;
+    esac
+    if [[ -n "$file" && -n "$helper" ]]; then
+        COMPREPLY=($(compgen -W "$(betwixt "$helper" "$file" 2>/dev/null)" -- "$cur"))
+        return 0
+    fi
+    _betwixt "$@"
+}
+complete -F _betwixt_dynamic -o nosort -o bashdefault -o default betwixt
+"#;
+
+fn completions(cli: CompletionsArgs) -> Result<()> {
+    // generated into memory first, as clap_complete panics on failed writes
+    let mut script = Vec::new();
+    clap_complete::generate(cli.shell, &mut Cli::command(), "betwixt", &mut script);
+    if cli.shell == Shell::Bash {
+        script.extend_from_slice(BASH_DYNAMIC.as_bytes());
+    }
+    io::stdout()
+        .write_all(&script)
+        .context("failed writing completions")
+}
+
+// Print the distinct values found by value in the code blocks of a document, sorted
+fn complete(cli: CompleteArgs, value: for<'a> fn(&Code<'a>) -> Option<&'a [u8]>) -> Result<()> {
+    let bytes = cli.document.read()?;
+    let markdown = cli.document.parse(&bytes[..], cli.document.file()?)?;
+    let mut values: Vec<String> = markdown
+        .code_blocks
+        .iter()
+        .filter_map(|block| value(block).map(|v| String::from_utf8_lossy(v).into_owned()))
+        .collect();
+    values.sort();
+    values.dedup();
+    for value in values {
+        println!("{}", value);
+    }
     Ok(())
 }
 
 fn main() {
     let cli = Cli::parse();
+    tracing_subscriber::fmt()
+        .with_max_level(cli.level())
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .without_time()
+        .with_target(false)
+        .init();
 
-    match tangle(cli) {
-        Ok(()) => println!("Done"),
+    // bars would be interleaved with the log lines of -v, which report progress themselves
+    let progress = cli.level() == Level::WARN && io::stderr().is_terminal();
+    let quiet = cli.quiet;
+    let result = match cli.command {
+        None => tangle_and_report(cli.tangle, progress, quiet),
+        Some(Command::Tangle(args)) => tangle_and_report(args, progress, quiet),
+        // weave output may be going to stdout, so it must not be followed by anything else
+        Some(Command::Weave(args)) => weave(args),
+        Some(Command::Detangle(args)) => detangle(args),
+        Some(Command::Fmt(args)) => fmt(args),
+        Some(Command::Stats(args)) => stats(args),
+        Some(Command::Diff(args)) => diff(args),
+        Some(Command::Graph(args)) => graph(args),
+        Some(Command::Run(args)) => run(args),
+        Some(Command::Init(args)) => init(args),
+        Some(Command::Selftest(args)) => selftest(args),
+        Some(Command::Completions(args)) => completions(args),
+        Some(Command::CompleteTags(args)) => complete(args, |block| block.properties.tag),
+        Some(Command::CompleteIds(args)) => complete(args, |block| block.part.id),
+    };
+    match result {
+        Ok(()) => (),
         Err(err) => {
             println!("Error: {:#}", err);
-            process::exit(1);
+            process::exit(exit_code(&err));
         }
     }
 }
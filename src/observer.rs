@@ -0,0 +1,149 @@
+use std::ops::Range;
+
+use crate::code::{Code, CodePart};
+use crate::properties::Properties;
+use crate::section::Section;
+
+// Hooks called as a document is built, in the order what they are about appears in it, for
+// policies of a project's own, e.g. that no block is tangled to an absolute path:
+//
+//   struct Relative;
+//   impl<'a> Observer<'a> for Relative {
+//       fn accept(&mut self, _: usize, block: &Code<'a>) -> Result<(), String> {
+//           match block.properties.filename {
+//               Some(name) if name.starts_with(b"/") => Err("absolute filename".into()),
+//               _ => Ok(()),
+//           }
+//       }
+//   }
+//
+// Every hook does nothing by default. Those that return an error stop the document being
+// built, which fails with `DocumentError::Rejected` and the reason given
+pub trait Observer<'a> {
+    // a section starting at its heading, with the properties it inherits
+    fn enter(&mut self, _section: &Section<'a>) -> Result<(), String> {
+        Ok(())
+    }
+
+    // a section ending, at the next heading of its level or higher, or the end of the
+    // document. The root section is neither entered nor exited
+    fn exit(&mut self, _section: &Section<'a>) {}
+
+    // a betwixt block, with the language it is for and its properties as written, before
+    // they update those of the section it is in, or those its scope names
+    fn update(
+        &mut self,
+        _lang: Option<&'a [u8]>,
+        _properties: &Properties<'a>,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+
+    // a code block taken into the document at idx, with every property it has
+    fn accept(&mut self, _idx: usize, _block: &Code<'a>) -> Result<(), String> {
+        Ok(())
+    }
+
+    // a code block left out of the document, as it is ignored or never to be tangled, and
+    // the bytes of the document it spans
+    fn skip(&mut self, _part: &CodePart<'a>, _span: Range<usize>) {}
+}
+
+// Observes nothing, for building documents without hooks
+impl<'a> Observer<'a> for () {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Document, DocumentError, MarkdownParsers};
+
+    // Every hook called, as a line naming it and what it was called with
+    #[derive(Default)]
+    struct Recorder(Vec<String>);
+
+    impl<'a> Observer<'a> for Recorder {
+        fn enter(&mut self, section: &Section<'a>) -> Result<(), String> {
+            let mode = section.properties.get_code_props(None).mode;
+            self.0
+                .push(format!("enter {:?} {:?}", section.part.title(), mode));
+            Ok(())
+        }
+
+        fn exit(&mut self, section: &Section<'a>) {
+            self.0.push(format!("exit {:?}", section.part.title()));
+        }
+
+        fn update(&mut self, _: Option<&'a [u8]>, props: &Properties<'a>) -> Result<(), String> {
+            self.0.push(format!("update {:?}", props.mode));
+            Ok(())
+        }
+
+        fn accept(&mut self, idx: usize, block: &Code<'a>) -> Result<(), String> {
+            let filename = block.properties.filename.map(String::from_utf8_lossy);
+            if filename.as_ref().is_some_and(|name| name.starts_with('/')) {
+                return Err(format!("{} is absolute", filename.unwrap()));
+            }
+            self.0.push(format!("accept {} {:?}", idx, filename));
+            Ok(())
+        }
+
+        fn skip(&mut self, part: &CodePart<'a>, _: Range<usize>) {
+            self.0
+                .push(format!("skip {:?}", part.lang.map(String::from_utf8_lossy)));
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        let markdown = &b"# Observed
+<?btxt mode='overwrite' ?>
+## First
+```rust filename='main.rs'
+```
+```text ignore=true
+```
+### Nested
+## Second
+"[..];
+        let mut recorder = Recorder::default();
+        let doc = Document::from_contents_with_observer(
+            markdown,
+            MarkdownParsers::github(),
+            &mut recorder,
+        )
+        .unwrap();
+        assert_eq!(doc.code_blocks.len(), 1);
+        assert_eq!(
+            recorder.0,
+            vec![
+                "enter Some(\"Observed\") None",
+                "update Some(Overwrite)",
+                "enter Some(\"First\") Some(Overwrite)",
+                "accept 0 Some(\"main.rs\")",
+                "skip Some(\"text\")",
+                "enter Some(\"Nested\") Some(Overwrite)",
+                "exit Some(\"Nested\")",
+                "exit Some(\"First\")",
+                "enter Some(\"Second\") Some(Overwrite)",
+                "exit Some(\"Second\")",
+                "exit Some(\"Observed\")",
+            ]
+        );
+
+        let markdown = &b"# Absolute
+```rust filename='/etc/main.rs' mode='overwrite'
+```
+"[..];
+        match Document::from_contents_with_observer(
+            markdown,
+            MarkdownParsers::github(),
+            &mut Recorder::default(),
+        ) {
+            Err(err @ DocumentError::Rejected(..)) => {
+                assert_eq!(err.to_string(), "rejected: /etc/main.rs is absolute");
+                assert_eq!(err.span(), Some(11..63));
+            }
+            other => panic!("expected a rejection, got {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+// The line parsers a document is read with, and what they produce, for reading markdown
+// dialects a FlavorBuilder can't describe. They are built with nom, so are only public with
+// the `parsers` feature; `parse` is the stable way to read documents
+use nom::error::ParseError;
+use nom::Parser;
+
+use crate::code::CodePart;
+use crate::properties::{Properties, PropertiesError};
+use crate::section::SectionPart;
+use crate::Strictness;
+
+#[cfg(feature = "parsers")]
+pub use crate::code::{code, comment};
+#[cfg(feature = "parsers")]
+pub use crate::properties::{betwixt, betwixt_with, properties};
+#[cfg(feature = "parsers")]
+pub use crate::section::{section, section_with};
+
+pub struct MarkdownParsers<P1, P2, P3> {
+    pub betwixt: P1,
+    pub section: P2,
+    pub code: P3,
+    // how betwixt blocks with invalid properties are handled
+    pub strict: Strictness,
+}
+
+// like line results, scan results are consumed as soon as they are made
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, PartialEq)]
+pub enum ScanResult<'a> {
+    Code(CodePart<'a>),
+    Section(SectionPart<'a>),
+    Properties((Option<&'a [u8]>, Properties<'a>)),
+    // a region of the document that isn't looked into, like a comment
+    Skipped,
+    End,
+}
+
+// results are consumed as soon as a line is parsed, boxing them gains nothing
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, PartialEq)]
+pub enum LineParseResult<'a> {
+    Matched(ScanResult<'a>),
+    PartialMatch,
+}
+
+// TODO the line parser approach is very inefficient with long multi line strings
+// as it has to continually try and parse for each line. We can improve this, just need
+// more sophisticated types
+pub trait LineParser<'a>: Parser<&'a [u8], LineParseResult<'a>, LineParseError<'a>> {}
+impl<'a, F> LineParser<'a> for F where F: Parser<&'a [u8], LineParseResult<'a>, LineParseError<'a>> {}
+
+#[derive(Debug)]
+// The error result of any LineParser
+pub enum LineParseError<'a> {
+    // Not really an error, just indicates the parser didn't match this line (move on)
+    NoMatch,
+    // We matched start/end tokens but the body had invalid contents. Check strict mode
+    InvalidMatch(PropertiesError<'a>),
+}
+
+impl<'a> ParseError<&'a [u8]> for LineParseError<'a> {
+    fn from_error_kind(_input: &'a [u8], _kind: nom::error::ErrorKind) -> Self {
+        LineParseError::NoMatch
+    }
+
+    fn append(_input: &'a [u8], _kind: nom::error::ErrorKind, other: Self) -> Self {
+        other
+    }
+}
@@ -1,69 +1,394 @@
-use std::fmt::Debug;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::str::from_utf8;
+use std::time::Duration;
 
 use nom::branch::alt;
-use nom::bytes::complete::{tag, take_until, take_until1, take_while};
-use nom::character::complete::space0;
+use nom::bytes::complete::{tag, take_until, take_while, take_while1};
 use nom::character::{is_alphanumeric, is_newline, is_space};
-use nom::combinator::{all_consuming, map, opt};
-use nom::error::ParseError;
-use nom::sequence::{delimited, pair, preceded, terminated, tuple};
-use nom::{IResult, Parser};
+use nom::combinator::{all_consuming, map, map_opt, opt, rest};
+use nom::error::{ErrorKind, ParseError};
+use nom::sequence::{pair, preceded, terminated};
+use nom::IResult;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
 
+use crate::code::is_lang_char;
+use crate::exec::Runner;
 use crate::LineParseError;
 
 use super::{LineParseResult, ScanResult};
 
-const FILENAME_PROP: &'static str = "filename";
-const TAG_PROP: &'static str = "tag";
-const CODE_PROP: &'static str = "code";
-const TANGLE_MODE_PROP: &'static str = "mode";
-const IGNORE_PROP: &'static str = "ignore";
-const PREFIX_PROP: &'static str = "pre";
-const POSTFIX_PROP: &'static str = "post";
-const CMD_PROP: &'static str = "cmd";
+const FILENAME_PROP: &str = "filename";
+const TAG_PROP: &str = "tag";
+const CODE_PROP: &str = "code";
+const TANGLE_MODE_PROP: &str = "mode";
+const IGNORE_PROP: &str = "ignore";
+const PREFIX_PROP: &str = "pre";
+const POSTFIX_PROP: &str = "post";
+const FILE_PREFIX_PROP: &str = "file-pre";
+const FILE_POSTFIX_PROP: &str = "file-post";
+const SEPARATOR_PROP: &str = "sep";
+const CMD_PROP: &str = "cmd";
+const RUNNER_PROP: &str = "runner";
+const RETRIES_PROP: &str = "retries";
+const RETRY_DELAY_PROP: &str = "retry-delay";
+const TIMEOUT_PROP: &str = "timeout";
+const REDACT_PROP: &str = "redact";
+const MAX_OUTPUT_LINES_PROP: &str = "max-output-lines";
+const MAX_OUTPUT_BYTES_PROP: &str = "max-output-bytes";
+const ANSI_PROP: &str = "ansi";
+const ISOLATE_PROP: &str = "isolate";
+const FORMAT_PROP: &str = "format";
+const ASSEMBLE_PROP: &str = "assemble";
+const OCCURRENCE_PROP: &str = "occurrence";
+const SPLIT_BY_SECTION_PROP: &str = "split-by-section";
+const FILENAME_TEMPLATE_PROP: &str = "filename-template";
+const INFER_FILENAME_PROP: &str = "infer-filename";
+const TRIM_TRAILING_PROP: &str = "trim-trailing";
+const CREATE_PROP: &str = "create";
+const TABS_PROP: &str = "tabs";
+const SHEBANG_PROP: &str = "shebang";
+const VAR_PROP: &str = "var";
+const PROMPT_PROP: &str = "prompt";
+const DEFAULT_PROP: &str = "default";
+const SCOPE_PROP: &str = "scope";
 
 #[derive(Default, Clone, Debug, PartialEq)]
 pub struct Properties<'a> {
     pub filename: Option<&'a [u8]>,
+    // blocks without a filename are tangled to a file named after their section's heading,
+    // by filename_template with `{slug}` in it replaced by the heading's slug
+    pub split_by_section: Option<bool>,
+    pub filename_template: Option<&'a [u8]>,
+    // blocks without a filename are tangled to the path written in place of their id, e.g.
+    // ```rust src/lib.rs, or to their id, put in filename_template for `{id}` if there is one
+    pub infer_filename: Option<bool>,
     pub tag: Option<&'a [u8]>,
     pub mode: Option<TangleMode<'a>>,
+    // a file appended or prepended to that doesn't exist yet is created, rather than it being
+    // an error
+    pub create: Option<bool>,
+    // which of the lines matching the marker of an insert mode the block goes by
+    pub occurrence: Option<Occurrence>,
     pub ignore: Option<bool>,
     pub prefix: Option<&'a [u8]>,
     pub postfix: Option<&'a [u8]>,
+    // like prefix and postfix, but written once at the start and end of the file, however many
+    // blocks are tangled to it
+    pub file_prefix: Option<&'a [u8]>,
+    pub file_postfix: Option<&'a [u8]>,
+    // the first line of a file that is overwritten, e.g. `#!/usr/bin/env bash`
+    pub shebang: Option<&'a [u8]>,
+    // written between a block and the one before it in the same file
+    pub separator: Option<&'a [u8]>,
+    // whitespace is tidied in what is written for a block: trailing whitespace removed from
+    // each line, and tabs expanded to spaces or indentation turned into tabs
+    pub trim_trailing: Option<bool>,
+    pub tabs: Option<Tabs>,
     pub cmd: Option<&'a [u8]>,
+    pub runner: Option<Runner<'a>>,
+    // how many more times cmd is run when it fails, and how long to wait before each, e.g.
+    // `retries=3 retry-delay='2s'` for commands that fetch things over a flaky network
+    pub retries: Option<u32>,
+    pub retry_delay: Option<Duration>,
+    // how long cmd may run before it is killed, each time it is run
+    pub timeout: Option<Duration>,
+    // secrets kept out of what cmd writes, see Redactor
+    pub redact: Option<&'a [u8]>,
+    // how much of what cmd writes is kept, see exec::truncate
+    pub max_output_lines: Option<usize>,
+    pub max_output_bytes: Option<usize>,
+    // whether escape sequences, e.g. colors, are kept in what cmd writes
+    pub ansi: Option<Ansi>,
+    // cmd is run in a directory of its own, holding copies of the files tangled so far and
+    // nothing else, so it can't depend on what else is around
+    pub isolate: Option<bool>,
+    pub format: Option<&'a [u8]>,
+    // commands run over a file once its blocks are combined, before it is put in place
+    pub assemble: Option<&'a [u8]>,
+    // a variable of a template, asked for when it is instantiated with `betwixt init`: its
+    // name, the question asked and the answer when none is given, e.g.
+    // `var='project_name' prompt='Project name?'`. Only betwixt blocks declare variables, so
+    // these aren't inherited
+    pub var: Option<&'a [u8]>,
+    pub prompt: Option<&'a [u8]>,
+    pub default: Option<&'a [u8]>,
+    // where a betwixt block applies, when that isn't where it is. It belongs to the betwixt
+    // block, so isn't inherited either
+    pub scope: Option<Scope<'a>>,
     // TODO there is an alternative where parsing properties with code
     // simply returns a code block with the applied properties. At the moment,
     // though, this is the solution that seems less hacky
     pub code: Option<&'a [u8]>,
+    // properties registered by library users, see CustomProperty
+    pub custom: Vec<Custom<'a>>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+// A property defined outside of betwixt, e.g. `review='alice'`. Its values are carried
+// through to the code blocks it applies to, like any other property
+#[derive(Clone, Debug)]
+pub struct CustomProperty {
+    pub name: String,
+    // whether a value is valid for the property
+    pub parse: fn(&[u8]) -> bool,
+    pub merge: Merge,
+}
+
+// How values of a custom property from enclosing sections combine with a block's own
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Merge {
+    // the closest value wins, like the built in properties
+    #[default]
+    Inherit,
+    // every value is kept, the closest last
+    Accumulate,
+}
+
+// The values a custom property was set to
+#[derive(Clone, Debug, PartialEq)]
+pub struct Custom<'a> {
+    pub name: &'a str,
+    pub values: Vec<&'a [u8]>,
+    pub merge: Merge,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
 pub enum TangleMode<'a> {
     Overwrite,
+    #[default]
     Append,
     Prepend,
-    Insert(&'a [u8]),
+    // make the file a link to the path the block holds, e.g. `v2/` for `latest -> v2/`
+    Symlink,
+    Hardlink,
+    // put the block by the lines of what is already tangled to the file that match the marker
+    Insert(Position, Marker<'a>),
+}
+
+// The sections a betwixt block applies to wherever it is in the document: those whose
+// heading path matches a glob, written after the open token, e.g.
+// `<?btxt@"Examples/*" ignore=true ?>`, or the whole document, with `scope='document'`. It
+// applies as if it were at the start of each of them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope<'a> {
+    Headings(&'a [u8]),
+    Document,
+}
+
+// Where an inserted block goes, relative to the line matching its marker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Position {
+    Before,
+    After,
+    // in place of the line
+    Replace,
+}
+
+// What marks the line a block is inserted by, e.g. `insert-after[// routes]`. A marker
+// written between slashes is a regular expression matched against each line, so `^` and `$`
+// are the start and end of the line, e.g. `insert-after[/^fn main/]`. Anything else is
+// matched by any line containing it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Marker<'a> {
+    Text(&'a [u8]),
+    Regex(&'a [u8]),
+}
+
+// What is done with tabs in a block: kept as they are, expanded to spaces with a tab stop
+// every n columns (`spaces:4`), or indentation of n spaces turned into a tab (`tabs`, which is
+// `tabs:4`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tabs {
+    Keep,
+    Spaces(usize),
+    Tabs(usize),
+}
+
+impl Tabs {
+    fn from_bytes(b: &[u8]) -> Option<Self> {
+        let width =
+            |n: &[u8]| -> Option<usize> { from_utf8(n).ok()?.parse().ok().filter(|&n| n > 0) };
+        match b {
+            b"keep" => Some(Tabs::Keep),
+            b"tabs" => Some(Tabs::Tabs(4)),
+            _ => {
+                if let Some(n) = b.strip_prefix(b"spaces:") {
+                    width(n).map(Tabs::Spaces)
+                } else {
+                    width(b.strip_prefix(b"tabs:")?).map(Tabs::Tabs)
+                }
+            }
+        }
+    }
+}
+
+// What becomes of the ansi escape sequences commands write, e.g. to color their output. When
+// neither is set they are kept only in output shown on a terminal, and stripped from output
+// that is kept or sent elsewhere
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ansi {
+    Strip,
+    Keep,
+}
+
+impl Ansi {
+    fn from_bytes(b: &[u8]) -> Option<Self> {
+        match b {
+            b"strip" => Some(Ansi::Strip),
+            b"keep" => Some(Ansi::Keep),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Ansi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Ansi::Strip => write!(f, "strip"),
+            Ansi::Keep => write!(f, "keep"),
+        }
+    }
+}
+
+// A whole number, e.g. of lines
+fn count(b: &[u8]) -> Option<usize> {
+    from_utf8(b).ok()?.parse().ok()
+}
+
+// A length of time, in milliseconds, seconds or minutes, e.g. `500ms`, `2s` or `1m`
+fn duration(b: &[u8]) -> Option<Duration> {
+    let b = from_utf8(b).ok()?;
+    let (n, unit) = b.split_at(b.find(|c: char| !c.is_ascii_digit())?);
+    let n: u64 = n.parse().ok()?;
+    match unit {
+        "ms" => Some(Duration::from_millis(n)),
+        "s" => Some(Duration::from_secs(n)),
+        "m" => Some(Duration::from_secs(n.checked_mul(60)?)),
+        _ => None,
+    }
+}
+
+// A duration as `duration` reads it, in the largest unit that holds it exactly
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    match millis {
+        0 => "0s".into(),
+        _ if millis.is_multiple_of(60_000) => format!("{}m", millis / 60_000),
+        _ if millis.is_multiple_of(1000) => format!("{}s", millis / 1000),
+        _ => format!("{}ms", millis),
+    }
+}
+
+impl Display for Tabs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Tabs::Keep => write!(f, "keep"),
+            Tabs::Spaces(n) => write!(f, "spaces:{}", n),
+            Tabs::Tabs(n) => write!(f, "tabs:{}", n),
+        }
+    }
+}
+
+// Which of the lines matching a marker a block is inserted by: the first, the last, every one,
+// or the nth, counting from 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Occurrence {
+    First,
+    Last,
+    All,
+    Nth(usize),
+}
+
+impl Occurrence {
+    fn from_bytes(b: &[u8]) -> Option<Self> {
+        match b {
+            b"first" => Some(Occurrence::First),
+            b"last" => Some(Occurrence::Last),
+            b"all" => Some(Occurrence::All),
+            _ => from_utf8(b)
+                .ok()?
+                .parse()
+                .ok()
+                .filter(|&n| n > 0)
+                .map(Occurrence::Nth),
+        }
+    }
+}
+
+impl Display for Occurrence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Occurrence::First => write!(f, "first"),
+            Occurrence::Last => write!(f, "last"),
+            Occurrence::All => write!(f, "all"),
+            Occurrence::Nth(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+impl<'a> Marker<'a> {
+    fn from_bytes(b: &'a [u8]) -> Self {
+        match b.strip_prefix(b"/").and_then(|b| b.strip_suffix(b"/")) {
+            Some(regex) if !regex.is_empty() => Marker::Regex(regex),
+            _ => Marker::Text(b),
+        }
+    }
+}
+
+impl<'a> Display for Marker<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Marker::Text(text) => write!(f, "{}", String::from_utf8_lossy(text)),
+            Marker::Regex(regex) => write!(f, "/{}/", String::from_utf8_lossy(regex)),
+        }
+    }
 }
 
 impl<'a> TangleMode<'a> {
-    pub fn from_bytes(b: &[u8]) -> IResult<&[u8], TangleMode> {
+    pub(crate) fn from_bytes(b: &[u8]) -> IResult<&[u8], TangleMode<'_>> {
         let overwrite = map(tag("overwrite"), |_| TangleMode::Overwrite);
         let append = map(tag("append"), |_| TangleMode::Append);
         let prepend = map(tag("prepend"), |_| TangleMode::Prepend);
-        let insert = map(
-            pair(
-                tag("insert"),
-                delimited(tag("["), take_until1("]"), tag("]")),
-            ),
-            |(_, s)| TangleMode::Insert(s),
+        let symlink = map(tag("symlink"), |_| TangleMode::Symlink);
+        let hardlink = map(tag("hardlink"), |_| TangleMode::Hardlink);
+        // `insert` is short for `insert-before`. The marker runs to the last `]`, so it may
+        // hold others, as regular expressions often do
+        let position = alt((
+            map(tag("insert-before"), |_| Position::Before),
+            map(tag("insert-after"), |_| Position::After),
+            map(tag("insert"), |_| Position::Before),
+            map(tag("replace"), |_| Position::Replace),
+        ));
+        let insert = map_opt(
+            pair(position, preceded(tag("["), rest)),
+            |(position, rest): (Position, &[u8])| {
+                let marker = rest.strip_suffix(b"]").filter(|m| !m.is_empty())?;
+                Some(TangleMode::Insert(position, Marker::from_bytes(marker)))
+            },
         );
-        all_consuming(alt((overwrite, append, prepend, insert)))(b)
+        all_consuming(alt((overwrite, append, prepend, symlink, hardlink, insert)))(b)
     }
 }
 
-impl<'a> Default for TangleMode<'a> {
-    fn default() -> Self {
-        Self::Append
+impl<'a> Display for TangleMode<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TangleMode::Overwrite => write!(f, "overwrite"),
+            TangleMode::Append => write!(f, "append"),
+            TangleMode::Prepend => write!(f, "prepend"),
+            TangleMode::Symlink => write!(f, "symlink"),
+            TangleMode::Hardlink => write!(f, "hardlink"),
+            TangleMode::Insert(position, marker) => {
+                let position = match position {
+                    Position::Before => "insert-before",
+                    Position::After => "insert-after",
+                    Position::Replace => "replace",
+                };
+                write!(f, "{}[{}]", position, marker)
+            }
+        }
     }
 }
 
@@ -72,12 +397,27 @@ impl<'a> Properties<'a> {
         if self.filename.is_none() {
             self.filename = parent.filename;
         }
+        if self.split_by_section.is_none() {
+            self.split_by_section = parent.split_by_section;
+        }
+        if self.filename_template.is_none() {
+            self.filename_template = parent.filename_template;
+        }
+        if self.infer_filename.is_none() {
+            self.infer_filename = parent.infer_filename;
+        }
         if self.tag.is_none() {
             self.tag = parent.tag;
         }
         if self.mode.is_none() {
             self.mode = parent.mode.clone();
         }
+        if self.create.is_none() {
+            self.create = parent.create;
+        }
+        if self.occurrence.is_none() {
+            self.occurrence = parent.occurrence;
+        }
         if self.ignore.is_none() {
             self.ignore = parent.ignore;
         }
@@ -87,24 +427,309 @@ impl<'a> Properties<'a> {
         if self.postfix.is_none() {
             self.postfix = parent.postfix;
         }
+        if self.file_prefix.is_none() {
+            self.file_prefix = parent.file_prefix;
+        }
+        if self.file_postfix.is_none() {
+            self.file_postfix = parent.file_postfix;
+        }
+        if self.shebang.is_none() {
+            self.shebang = parent.shebang;
+        }
+        if self.separator.is_none() {
+            self.separator = parent.separator;
+        }
+        if self.trim_trailing.is_none() {
+            self.trim_trailing = parent.trim_trailing;
+        }
+        if self.tabs.is_none() {
+            self.tabs = parent.tabs;
+        }
         if self.cmd.is_none() {
             self.cmd = parent.cmd;
         }
+        if self.runner.is_none() {
+            self.runner = parent.runner.clone();
+        }
+        if self.retries.is_none() {
+            self.retries = parent.retries;
+        }
+        if self.retry_delay.is_none() {
+            self.retry_delay = parent.retry_delay;
+        }
+        if self.timeout.is_none() {
+            self.timeout = parent.timeout;
+        }
+        if self.redact.is_none() {
+            self.redact = parent.redact;
+        }
+        if self.max_output_lines.is_none() {
+            self.max_output_lines = parent.max_output_lines;
+        }
+        if self.max_output_bytes.is_none() {
+            self.max_output_bytes = parent.max_output_bytes;
+        }
+        if self.ansi.is_none() {
+            self.ansi = parent.ansi;
+        }
+        if self.isolate.is_none() {
+            self.isolate = parent.isolate;
+        }
+        if self.format.is_none() {
+            self.format = parent.format;
+        }
+        if self.assemble.is_none() {
+            self.assemble = parent.assemble;
+        }
+        for inherited in parent.custom.iter() {
+            match self.custom.iter_mut().find(|c| c.name == inherited.name) {
+                Some(own) if own.merge == Merge::Accumulate => {
+                    own.values.splice(0..0, inherited.values.iter().copied());
+                }
+                Some(_) => {}
+                None => self.custom.push(inherited.clone()),
+            }
+        }
+    }
+
+    // The files a block is tangled to. Several can be given, separated by `;`, e.g.
+    // `filename='src/a.rs;tests/a.rs'`
+    pub fn filenames(&self) -> impl Iterator<Item = &'a [u8]> {
+        self.filename
+            .unwrap_or_default()
+            .split(|&b| b == b';')
+            .map(<[u8]>::trim_ascii)
+            .filter(|filename| !filename.is_empty())
+    }
+
+    // The value of a custom property, the closest one when it accumulates
+    pub fn get(&self, name: &str) -> Option<&'a [u8]> {
+        self.get_all(name).last().copied()
+    }
+
+    // Every value of a custom property, the closest last
+    pub fn get_all(&self, name: &str) -> &[&'a [u8]] {
+        match self.custom.iter().find(|c| c.name == name) {
+            Some(custom) => &custom.values,
+            None => &[],
+        }
+    }
+}
+
+impl<'a> Properties<'a> {
+    // Set a property by the name it has in a betwixt block, e.g. `pre` or `mode`
+    pub fn set(&mut self, name: &str, value: &'a [u8]) -> Result<(), PropertyError> {
+        let invalid = || PropertyError::InvalidValue(name.into(), value.to_vec());
+        match name {
+            FILENAME_PROP => self.filename = Some(value),
+            TAG_PROP => self.tag = Some(value),
+            CODE_PROP => self.code = Some(value),
+            TANGLE_MODE_PROP => {
+                self.mode = Some(TangleMode::from_bytes(value).map_err(|_| invalid())?.1)
+            }
+            IGNORE_PROP => self.ignore = Some(boolean(value).ok_or_else(invalid)?),
+            SPLIT_BY_SECTION_PROP => {
+                self.split_by_section = Some(boolean(value).ok_or_else(invalid)?)
+            }
+            FILENAME_TEMPLATE_PROP => self.filename_template = Some(value),
+            INFER_FILENAME_PROP => self.infer_filename = Some(boolean(value).ok_or_else(invalid)?),
+            TRIM_TRAILING_PROP => self.trim_trailing = Some(boolean(value).ok_or_else(invalid)?),
+            CREATE_PROP => self.create = Some(boolean(value).ok_or_else(invalid)?),
+            TABS_PROP => self.tabs = Some(Tabs::from_bytes(value).ok_or_else(invalid)?),
+            PREFIX_PROP => self.prefix = Some(value),
+            POSTFIX_PROP => self.postfix = Some(value),
+            FILE_PREFIX_PROP => self.file_prefix = Some(value),
+            FILE_POSTFIX_PROP => self.file_postfix = Some(value),
+            SHEBANG_PROP => self.shebang = Some(value),
+            SEPARATOR_PROP => self.separator = Some(value),
+            CMD_PROP => self.cmd = Some(value),
+            RUNNER_PROP => self.runner = Some(Runner::from_bytes(value).map_err(|_| invalid())?.1),
+            RETRIES_PROP => {
+                self.retries = Some(
+                    from_utf8(value)
+                        .ok()
+                        .and_then(|n| n.parse().ok())
+                        .ok_or_else(invalid)?,
+                )
+            }
+            RETRY_DELAY_PROP => self.retry_delay = Some(duration(value).ok_or_else(invalid)?),
+            // a bare number is seconds
+            TIMEOUT_PROP => {
+                self.timeout = Some(
+                    duration(value)
+                        .or_else(|| count(value).map(|secs| Duration::from_secs(secs as u64)))
+                        .ok_or_else(invalid)?,
+                )
+            }
+            REDACT_PROP => self.redact = Some(value),
+            ANSI_PROP => self.ansi = Some(Ansi::from_bytes(value).ok_or_else(invalid)?),
+            ISOLATE_PROP => self.isolate = Some(boolean(value).ok_or_else(invalid)?),
+            MAX_OUTPUT_LINES_PROP => {
+                self.max_output_lines = Some(count(value).ok_or_else(invalid)?)
+            }
+            MAX_OUTPUT_BYTES_PROP => {
+                self.max_output_bytes = Some(count(value).ok_or_else(invalid)?)
+            }
+            FORMAT_PROP => self.format = Some(value),
+            ASSEMBLE_PROP => self.assemble = Some(value),
+            VAR_PROP => {
+                let valid = !value.is_empty()
+                    && value
+                        .iter()
+                        .all(|&b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-');
+                if !valid {
+                    return Err(invalid());
+                }
+                self.var = Some(value)
+            }
+            PROMPT_PROP => self.prompt = Some(value),
+            DEFAULT_PROP => self.default = Some(value),
+            SCOPE_PROP if value == b"document" => self.scope = Some(Scope::Document),
+            SCOPE_PROP => return Err(invalid()),
+            OCCURRENCE_PROP => {
+                self.occurrence = Some(Occurrence::from_bytes(value).ok_or_else(invalid)?)
+            }
+            _ => return Err(PropertyError::Unknown(name.into())),
+        }
+        Ok(())
+    }
+}
+
+fn boolean(value: &[u8]) -> Option<bool> {
+    match value {
+        b"true" => Some(true),
+        b"false" => Some(false),
+        _ => None,
+    }
+}
+
+// Properties from a map of their names to values, e.g. `{"filename": "main.rs", "ignore": false}`
+// in json, checked like those of a betwixt block. Values are borrowed from what is being
+// deserialized, so it must be able to lend them, as json strings without escapes can
+impl<'de: 'a, 'a> Deserialize<'de> for Properties<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw<'a> {
+            Str(&'a str),
+            Bool(bool),
+        }
+        let values: BTreeMap<&'de str, Raw<'de>> = BTreeMap::deserialize(deserializer)?;
+        let mut props = Properties::default();
+        for (name, value) in values {
+            let value: &[u8] = match value {
+                Raw::Str(value) => value.as_bytes(),
+                Raw::Bool(true) => b"true",
+                Raw::Bool(false) => b"false",
+            };
+            props.set(name, value).map_err(D::Error::custom)?;
+        }
+        Ok(props)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertyError {
+    Unknown(String),
+    InvalidValue(String, Vec<u8>),
+    // a property given more than once in the same place
+    Repeated(String),
+    // a boolean property with a quoted value, e.g. `ignore='true'`
+    Quoted(String),
+}
+
+impl Error for PropertyError {}
+
+impl Display for PropertyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PropertyError::Unknown(name) => write!(f, "unknown property: {}", name),
+            PropertyError::InvalidValue(name, value) => write!(
+                f,
+                "invalid value for {}: {}",
+                name,
+                String::from_utf8_lossy(value)
+            ),
+            PropertyError::Repeated(name) => write!(f, "property given more than once: {}", name),
+            PropertyError::Quoted(name) => {
+                write!(f, "{} is true or false, without quotes", name)
+            }
+        }
+    }
+}
+
+// Why properties couldn't be parsed: the `name=value` pair at fault, or the word parsing
+// stopped at when it isn't one, and what is wrong with the pair when it is more than its syntax
+#[derive(Debug, PartialEq)]
+pub struct PropertiesError<'a> {
+    pub input: &'a [u8],
+    pub error: Option<PropertyError>,
+}
+
+impl<'a> ParseError<&'a [u8]> for PropertiesError<'a> {
+    fn from_error_kind(input: &'a [u8], _kind: ErrorKind) -> Self {
+        PropertiesError { input, error: None }
+    }
+
+    fn append(_input: &'a [u8], _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+impl<'a> PropertiesError<'a> {
+    // The error parsing the properties in input failed with. They are parsed by complete
+    // parsers, which never ask for more input, but if one did all of it would be at fault
+    pub(crate) fn of(err: nom::Err<Self>, input: &'a [u8]) -> Self {
+        match err {
+            nom::Err::Error(err) | nom::Err::Failure(err) => err,
+            nom::Err::Incomplete(_) => PropertiesError { input, error: None },
+        }
+    }
+}
+
+impl<'a> Display for PropertiesError<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error {
+            Some(err) => write!(f, "{}", err),
+            None => write!(
+                f,
+                "invalid property: {}",
+                String::from_utf8_lossy(self.input)
+            ),
+        }
     }
 }
 
 pub fn betwixt<'a>(
     start: &'static str,
     end: &'static str,
+) -> impl Fn(&'a [u8]) -> IResult<&'a [u8], LineParseResult<'a>, LineParseError<'a>> {
+    betwixt_with(start, end, Vec::new())
+}
+
+// A betwixt parser that also accepts the given custom properties
+pub fn betwixt_with<'a>(
+    start: &'static str,
+    end: &'static str,
+    custom: Vec<CustomProperty>,
 ) -> impl Fn(&'a [u8]) -> IResult<&'a [u8], LineParseResult<'a>, LineParseError<'a>> {
     move |i: &[u8]| {
         let (input, _) = tag(start)(i)?;
-        let (input, (lang, body)) = match terminated(
+        let (input, ((lang, glob), body)) = match terminated(
             pair(
-                opt(preceded(
-                    tag::<&str, &'a [u8], nom::error::Error<&'a [u8]>>("+"),
-                    take_while(is_alphanumeric),
-                )),
+                pair(
+                    opt(preceded(
+                        tag::<&str, &'a [u8], nom::error::Error<&'a [u8]>>("+"),
+                        take_while(is_lang_char),
+                    )),
+                    opt(preceded(
+                        tag("@"),
+                        alt((
+                            preceded(tag("\""), terminated(take_until("\""), tag("\""))),
+                            preceded(tag("'"), terminated(take_until("'"), tag("'"))),
+                        )),
+                    )),
+                ),
                 take_until(end),
             ),
             tag(end),
@@ -113,169 +738,138 @@ pub fn betwixt<'a>(
             Ok(result) => result,
             Err(_) => return Ok((input, LineParseResult::PartialMatch)),
         };
-        let properties = properties(body).map_err(|err| match err {
-            nom::Err::Failure(err) | nom::Err::Error(err) => {
-                nom::Err::Failure(LineParseError::InvalidMatch(err.input))
-            }
-            _ => panic!("unreachable when dealing with complete bytes"),
-        })?;
+        let mut properties = properties_with(body, &custom)
+            .map_err(|err| {
+                nom::Err::Failure(LineParseError::InvalidMatch(PropertiesError::of(err, body)))
+            })?
+            .1;
+        if let Some(glob) = glob {
+            properties.scope = Some(Scope::Headings(glob));
+        }
         Ok((
             input,
-            LineParseResult::Matched(ScanResult::Properties((lang, properties.1))),
+            LineParseResult::Matched(ScanResult::Properties((lang, properties))),
         ))
     }
 }
 
-fn property<'a>(t: &'static str) -> impl Fn(&[u8]) -> IResult<&[u8], &[u8]> {
-    move |i: &[u8]| {
-        let (input, _) = take_while(|c| is_space(c) || is_newline(c))(i)?;
-        let (input, quote) = preceded(
-            tuple((tag(t), tag("="))),
-            alt((tag("'"), tag("\""), tag("|||"))),
-        )(input)?;
-        let (input, bytes) = terminated(take_until(quote), pair(tag(quote), space0))(input)?;
-        Ok((input, bytes))
-    }
+// A property value as written in a betwixt block. Values are quoted with ', " or |||, or
+// left bare when they are a single word, e.g. `tag=ci` or `filename=src/main.rs`. Booleans
+// are always bare, e.g. `ignore=true`
+enum Value<'a> {
+    Quoted(&'a [u8]),
+    Bare(&'a [u8]),
 }
 
-fn bool_property<'a>(t: &'static str) -> impl Fn(&[u8]) -> IResult<&[u8], bool> {
-    move |i: &[u8]| {
-        let (input, _) = take_while(|c| is_space(c) || is_newline(c))(i)?;
-        let (input, bytes) = delimited(
-            pair(tag(t), tag("=")),
-            alt((tag("true"), tag("false"))),
-            opt(space0),
-        )(input)?;
-        Ok((
-            input,
-            match bytes {
-                b"true" => true,
-                _ => false,
-            },
-        ))
+// Spaces, tabs and line endings, \r\n included
+fn is_whitespace(c: u8) -> bool {
+    is_space(c) || is_newline(c) || c == b'\r'
+}
+
+// A single `name=value` pair
+fn property(i: &[u8]) -> IResult<&[u8], (&[u8], Value<'_>)> {
+    let (input, name) = terminated(
+        take_while1(|c| is_alphanumeric(c) || c == b'_' || c == b'-'),
+        tag("="),
+    )(i)?;
+    if let Ok((input, quote)) = alt((
+        tag::<_, _, nom::error::Error<&[u8]>>("'"),
+        tag("\""),
+        tag("|||"),
+    ))(input)
+    {
+        let (input, value) = terminated(take_until(quote), tag(quote))(input)?;
+        return Ok((input, (name, Value::Quoted(value))));
     }
+    let (input, value) = take_while1(is_bare_char)(input)?;
+    Ok((input, (name, Value::Bare(value))))
 }
 
-// Checks all permutations of input parsers repeatedly against the input until
-// all have matched or all remaining fail. Returns None for any unmatches parsers
-// TODO make this a macro cause this is silly.
-fn opt_permutation<P, PBOOL, I, O, OBOOL, E>(
-    mut parsers: (P, P, P, P, P, P, P, PBOOL),
-) -> impl FnMut(
-    I,
-) -> IResult<
-    I,
-    (
-        Option<O>,
-        Option<O>,
-        Option<O>,
-        Option<O>,
-        Option<O>,
-        Option<O>,
-        Option<O>,
-        Option<OBOOL>,
-    ),
-    E,
->
-where
-    P: Parser<I, O, E>,
-    PBOOL: Parser<I, OBOOL, E>,
-    E: ParseError<I>,
-    I: Clone + Debug,
-{
-    move |i: I| {
-        let mut success = true;
-        let mut results = (None, None, None, None, None, None, None, None);
-        let mut input = i;
-        while success {
-            success = false;
-            if results.0.is_none() {
-                if let Ok((i, output)) = parsers.0.parse(input.clone()) {
-                    results.0 = Some(output);
-                    success = true;
-                    input = i;
-                }
-            }
-            if results.1.is_none() {
-                if let Ok((i, output)) = parsers.1.parse(input.clone()) {
-                    results.1 = Some(output);
-                    success = true;
-                    input = i;
-                }
-            }
-            if results.2.is_none() {
-                if let Ok((i, output)) = parsers.2.parse(input.clone()) {
-                    results.2 = Some(output);
-                    success = true;
-                    input = i;
-                }
-            }
-            if results.3.is_none() {
-                if let Ok((i, output)) = parsers.3.parse(input.clone()) {
-                    results.3 = Some(output);
-                    success = true;
-                    input = i;
-                }
-            }
-            if results.4.is_none() {
-                if let Ok((i, output)) = parsers.4.parse(input.clone()) {
-                    results.4 = Some(output);
-                    success = true;
-                    input = i;
-                }
-            }
-            if results.5.is_none() {
-                if let Ok((i, output)) = parsers.5.parse(input.clone()) {
-                    results.5 = Some(output);
-                    success = true;
-                    input = i;
-                }
-            }
-            if results.6.is_none() {
-                if let Ok((i, output)) = parsers.6.parse(input.clone()) {
-                    results.6 = Some(output);
-                    success = true;
-                    input = i;
-                }
-            }
-            if results.7.is_none() {
-                if let Ok((i, output)) = parsers.7.parse(input.clone()) {
-                    results.7 = Some(output);
-                    success = true;
-                    input = i;
+// Whether c can be part of an unquoted value
+fn is_bare_char(c: u8) -> bool {
+    is_alphanumeric(c) || b"-_./".contains(&c)
+}
+
+// Parse whitespace separated properties in any order. Errors point at the offending
+// property: an unknown or repeated name, or a value that is invalid for the property
+pub fn properties(i: &[u8]) -> IResult<&[u8], Properties<'_>, PropertiesError<'_>> {
+    properties_with(i, &[])
+}
+
+// Parse properties, accepting the given custom properties along with the built in ones
+pub fn properties_with<'a>(
+    i: &'a [u8],
+    custom: &[CustomProperty],
+) -> IResult<&'a [u8], Properties<'a>, PropertiesError<'a>> {
+    let mut props = Properties::default();
+    let mut seen: Vec<&[u8]> = Vec::new();
+    let mut input = i;
+    loop {
+        let (start, _) = take_while(is_whitespace)(input)?;
+        if start.is_empty() {
+            return Ok((start, props));
+        }
+        // the text from start up to the whitespace after end
+        let word = |end: &'a [u8]| {
+            let glued = end
+                .iter()
+                .position(|&c| is_whitespace(c))
+                .unwrap_or(end.len());
+            &start[..start.len() - end.len() + glued]
+        };
+        let invalid = |input, error| nom::Err::Error(PropertiesError { input, error });
+        let (rest, (name, value)) = property(start).map_err(|_| invalid(word(start), None))?;
+        // properties must be separated by whitespace
+        if rest.first().is_some_and(|&c| !is_whitespace(c)) {
+            return Err(invalid(word(rest), None));
+        }
+        let pair = &start[..start.len() - rest.len()];
+        // names are checked to be alphanumeric by the parser
+        let name = from_utf8(name).unwrap();
+        if seen.contains(&name.as_bytes()) {
+            return Err(invalid(pair, Some(PropertyError::Repeated(name.into()))));
+        }
+        seen.push(name.as_bytes());
+        let value = match (name, value) {
+            (
+                IGNORE_PROP
+                | SPLIT_BY_SECTION_PROP
+                | INFER_FILENAME_PROP
+                | TRIM_TRAILING_PROP
+                | CREATE_PROP
+                | ISOLATE_PROP,
+                Value::Bare(value),
+            ) => value,
+            (
+                IGNORE_PROP
+                | SPLIT_BY_SECTION_PROP
+                | INFER_FILENAME_PROP
+                | TRIM_TRAILING_PROP
+                | CREATE_PROP
+                | ISOLATE_PROP,
+                Value::Quoted(_),
+            ) => return Err(invalid(pair, Some(PropertyError::Quoted(name.into())))),
+            (_, Value::Quoted(value) | Value::Bare(value)) => value,
+        };
+        match props.set(name, value) {
+            Ok(()) => {}
+            Err(PropertyError::Unknown(_)) => {
+                let registered = custom
+                    .iter()
+                    .find(|c| c.name == name)
+                    .ok_or_else(|| invalid(pair, Some(PropertyError::Unknown(name.into()))))?;
+                if !(registered.parse)(value) {
+                    let err = PropertyError::InvalidValue(name.into(), value.to_vec());
+                    return Err(invalid(pair, Some(err)));
                 }
+                props.custom.push(Custom {
+                    name,
+                    values: vec![value],
+                    merge: registered.merge,
+                });
             }
+            Err(err) => return Err(invalid(pair, Some(err))),
         }
-        Ok((input, results))
+        input = rest;
     }
 }
-
-pub fn properties<'a>(i: &'a [u8]) -> IResult<&'a [u8], Properties> {
-    let fname = property(FILENAME_PROP);
-    let tag = property(TAG_PROP);
-    let mode = property(TANGLE_MODE_PROP);
-    let code = property(CODE_PROP);
-    let ignore = bool_property(IGNORE_PROP);
-    let prefix = property(PREFIX_PROP);
-    let postfix = property(POSTFIX_PROP);
-    let cmd = property(CMD_PROP);
-    let (input, (filename, cmd, prefix, postfix, tag, mode, code, ignore)) = all_consuming(
-        opt_permutation((fname, cmd, prefix, postfix, tag, mode, code, ignore)),
-    )(i)?;
-    Ok((
-        input,
-        Properties {
-            filename,
-            tag,
-            prefix,
-            postfix,
-            mode: match mode {
-                Some(mode) => Some(TangleMode::from_bytes(mode)?.1),
-                None => None,
-            },
-            code,
-            cmd,
-            ignore,
-        },
-    ))
-}
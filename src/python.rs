@@ -0,0 +1,50 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use crate::bindings::{blocks, document, files};
+
+// Python bindings, so documentation tooling such as mkdocs or sphinx plugins can read
+// documents without running the cli. Built with maturin, see pyproject.toml
+
+fn value_error(err: impl std::error::Error) -> PyErr {
+    PyValueError::new_err(err.to_string())
+}
+
+// Parse a document, returning a list of its code blocks as dicts
+#[pyfunction]
+fn parse<'py>(py: Python<'py>, markdown: &str) -> PyResult<Vec<Bound<'py, PyDict>>> {
+    let doc = document(markdown).map_err(value_error)?;
+    blocks(&doc)
+        .into_iter()
+        .map(|block| {
+            let dict = PyDict::new(py);
+            dict.set_item("id", block.id)?;
+            dict.set_item("lang", block.lang)?;
+            dict.set_item("contents", block.contents)?;
+            dict.set_item("filename", block.filename)?;
+            dict.set_item("mode", block.mode)?;
+            dict.set_item("tag", block.tag)?;
+            Ok(dict)
+        })
+        .collect()
+}
+
+// Tangle a document, returning a dict of each filename to the contents tangled to it, in
+// the order the files are first written to
+#[pyfunction]
+fn tangle<'py>(py: Python<'py>, markdown: &str) -> PyResult<Bound<'py, PyDict>> {
+    let doc = document(markdown).map_err(value_error)?;
+    let dict = PyDict::new(py);
+    for (filename, contents) in files(&doc).map_err(value_error)? {
+        dict.set_item(filename, contents)?;
+    }
+    Ok(dict)
+}
+
+#[pymodule]
+fn betwixt(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(parse, m)?)?;
+    m.add_function(wrap_pyfunction!(tangle, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,501 @@
+use std::ops::Range;
+
+use crate::properties::{betwixt, format_duration, Properties, Scope};
+use crate::{
+    CodePart, Directive, Document, LineParseResult, ScanResult, BETWIXT_COM_TOKEN, BETWIXT_TOKEN,
+    CLOSE_COM_TOKEN, CLOSE_TOKEN, NOTANGLE,
+};
+
+// Write a document back out as markdown. Everything betwixt doesn't understand is copied
+// from source verbatim, so only the parts of the document changed since it was parsed
+// from source are rewritten: the contents of fenced code blocks, the properties of
+// betwixt blocks, and blocks and properties added or removed through the document's
+// mutation methods. Code blocks created by a `code` property are changed through the
+// properties of their betwixt block.
+pub fn render(source: &[u8], doc: &Document) -> Vec<u8> {
+    // edits are applied in order of where they start, then by rank, so that properties
+    // added to a section come before blocks inserted at its start, which come before
+    // whatever was already there
+    let mut edits: Vec<(Range<usize>, u8, Vec<u8>)> = Vec::new();
+    for section in doc.root.walk() {
+        for directive in section.added.iter() {
+            let mut bytes = render_directive(directive, BETWIXT_TOKEN, CLOSE_TOKEN);
+            bytes.push(b'\n');
+            edits.push((directive.span.clone(), 0, bytes));
+        }
+    }
+    let fence = fence(source, doc);
+    for block in doc.code_blocks.iter() {
+        if block.span.is_empty() {
+            edits.push((block.span.clone(), 1, render_block(&block.part, fence)));
+            continue;
+        }
+        if block.properties.code.is_some()
+            || source.get(block.contents_span.clone()) == Some(block.part.contents)
+        {
+            continue;
+        }
+        edits.push((
+            block.contents_span.clone(),
+            2,
+            terminated(block.part.contents),
+        ));
+    }
+    for directive in doc.directives.iter() {
+        let original = &source[directive.span.clone()];
+        let (open, close) = tokens(original);
+        let unchanged = match betwixt(open, close)(original) {
+            Ok((_, LineParseResult::Matched(ScanResult::Properties((lang, properties))))) => {
+                lang == directive.lang && properties == directive.properties
+            }
+            _ => false,
+        };
+        if !unchanged {
+            edits.push((
+                directive.span.clone(),
+                2,
+                render_directive(directive, open, close),
+            ));
+        }
+    }
+    for span in doc.removed.iter() {
+        // take the rest of the line with the block, so no blank line is left behind
+        let end = match source.get(span.end) {
+            Some(b'\n') => span.end + 1,
+            _ => span.end,
+        };
+        edits.push((span.start..end, 2, Vec::new()));
+    }
+    splice(source, edits)
+}
+
+// Apply edits, each a span of source to replace, its rank and the replacement, to source
+fn splice(source: &[u8], mut edits: Vec<(Range<usize>, u8, Vec<u8>)>) -> Vec<u8> {
+    edits.sort_by_key(|(span, rank, _)| (span.start, *rank));
+    let mut output = Vec::with_capacity(source.len());
+    let mut pos = 0;
+    for (span, _, bytes) in edits {
+        if span.start >= pos {
+            output.extend_from_slice(&source[pos..span.start]);
+        }
+        if span.is_empty() && !output.is_empty() && !output.ends_with(b"\n") {
+            // inserted text always starts on a line of its own
+            output.push(b'\n');
+        }
+        output.extend_from_slice(&bytes);
+        pos = pos.max(span.end);
+    }
+    output.extend_from_slice(&source[pos..]);
+    output
+}
+
+// Contents followed by a newline, so a closing fence after them stays on its own line
+fn terminated(contents: &[u8]) -> Vec<u8> {
+    let mut contents = contents.to_vec();
+    if !contents.is_empty() && !contents.ends_with(b"\n") {
+        contents.push(b'\n');
+    }
+    contents
+}
+
+// The fence code blocks in the document are written with, e.g. ``` or '''
+fn fence<'s>(source: &'s [u8], doc: &Document) -> &'s [u8] {
+    doc.code_blocks
+        .iter()
+        .filter(|block| !block.span.is_empty() && block.properties.code.is_none())
+        .map(|block| {
+            let fenced = &source[block.span.clone()];
+            let len = fenced
+                .iter()
+                .take_while(|c| !c.is_ascii_alphanumeric() && !c.is_ascii_whitespace())
+                .count();
+            &fenced[..len]
+        })
+        .find(|fence| !fence.is_empty())
+        .unwrap_or(b"```")
+}
+
+// Write a fenced code block out in full, including the newline after the closing fence
+fn render_block(part: &CodePart, fence: &[u8]) -> Vec<u8> {
+    let mut output = fence.to_vec();
+    // the language must follow the fence directly, or it would be read as the id
+    if let Some(lang) = part.lang {
+        output.extend_from_slice(lang);
+    }
+    let notangle = Some(NOTANGLE).filter(|_| part.notangle);
+    for word in [part.id, part.path, notangle, part.prop_line]
+        .into_iter()
+        .flatten()
+    {
+        output.push(b' ');
+        output.extend_from_slice(word);
+    }
+    output.push(b'\n');
+    output.extend_from_slice(&terminated(part.contents));
+    output.extend_from_slice(fence);
+    output.push(b'\n');
+    output
+}
+
+// The open and close tokens a betwixt block was written with
+fn tokens(directive: &[u8]) -> (&'static str, &'static str) {
+    if directive.starts_with(BETWIXT_COM_TOKEN.as_bytes()) {
+        (BETWIXT_COM_TOKEN, CLOSE_COM_TOKEN)
+    } else {
+        (BETWIXT_TOKEN, CLOSE_TOKEN)
+    }
+}
+
+// Betwixt blocks longer than this are written with one property per line
+pub const LINE_WIDTH: usize = 80;
+
+// Write a betwixt block out in full, in canonical form, e.g.
+// `<?btxt+rust filename='main.rs' ?>`. Blocks that are too long for one line, or have
+// values spanning lines, get a line per property
+pub fn render_directive(directive: &Directive, open: &str, close: &str) -> Vec<u8> {
+    let properties: Vec<Vec<u8>> = properties(&directive.properties)
+        .into_iter()
+        .map(|(name, value)| [name.as_bytes(), b"=", &value].concat())
+        .collect();
+    let mut output = open.as_bytes().to_vec();
+    if let Some(lang) = directive.lang {
+        output.push(b'+');
+        output.extend_from_slice(lang);
+    }
+    if let Some(Scope::Headings(glob)) = directive.properties.scope {
+        let quote = if glob.contains(&b'"') { b'\'' } else { b'"' };
+        output.push(b'@');
+        output.push(quote);
+        output.extend_from_slice(glob);
+        output.push(quote);
+    }
+    let width =
+        output.len() + close.len() + properties.iter().map(|p| p.len() + 1).sum::<usize>() + 1;
+    let separator: &[u8] = if width > LINE_WIDTH || properties.iter().any(|p| p.contains(&b'\n')) {
+        b"\n  "
+    } else {
+        b" "
+    };
+    for property in properties {
+        output.extend_from_slice(separator);
+        output.extend_from_slice(&property);
+    }
+    // the close token can't go on a line of its own, betwixt blocks don't allow
+    // trailing newlines
+    output.push(b' ');
+    output.extend_from_slice(close.as_bytes());
+    output
+}
+
+// Rewrite every betwixt block of a document in canonical form, leaving everything else
+// untouched
+pub fn format(source: &[u8], doc: &Document) -> Vec<u8> {
+    let edits = doc
+        .directives
+        .iter()
+        .map(|directive| {
+            let (open, close) = tokens(&source[directive.span.clone()]);
+            (
+                directive.span.clone(),
+                2,
+                render_directive(directive, open, close),
+            )
+        })
+        .collect();
+    splice(source, edits)
+}
+
+// Every set property as a name and a value ready to be written, in a stable order
+pub fn properties<'a>(properties: &Properties<'a>) -> Vec<(&'a str, Vec<u8>)> {
+    let mode = properties.mode.as_ref().map(ToString::to_string);
+    let runner = properties.runner.as_ref().map(ToString::to_string);
+    let occurrence = properties.occurrence.as_ref().map(ToString::to_string);
+    let tabs = properties.tabs.as_ref().map(ToString::to_string);
+    let retries = properties.retries.as_ref().map(ToString::to_string);
+    let retry_delay = properties.retry_delay.map(format_duration);
+    let timeout = properties.timeout.map(format_duration);
+    let max_output_lines = properties.max_output_lines.map(|lines| lines.to_string());
+    let max_output_bytes = properties.max_output_bytes.map(|bytes| bytes.to_string());
+    let ansi = properties.ansi.as_ref().map(ToString::to_string);
+    let mut output = Vec::new();
+    let mut push = |name, value: Option<&[u8]>| {
+        if let Some(value) = value {
+            output.push((name, quote(value)));
+        }
+    };
+    push("filename", properties.filename);
+    push("filename-template", properties.filename_template);
+    push("mode", mode.as_deref().map(str::as_bytes));
+    push("occurrence", occurrence.as_deref().map(str::as_bytes));
+    push("tag", properties.tag);
+    push("pre", properties.prefix);
+    push("post", properties.postfix);
+    push("file-pre", properties.file_prefix);
+    push("file-post", properties.file_postfix);
+    push("shebang", properties.shebang);
+    push("sep", properties.separator);
+    push("tabs", tabs.as_deref().map(str::as_bytes));
+    push("cmd", properties.cmd);
+    push("runner", runner.as_deref().map(str::as_bytes));
+    push("retries", retries.as_deref().map(str::as_bytes));
+    push("retry-delay", retry_delay.as_deref().map(str::as_bytes));
+    push("timeout", timeout.as_deref().map(str::as_bytes));
+    push("redact", properties.redact);
+    push(
+        "max-output-lines",
+        max_output_lines.as_deref().map(str::as_bytes),
+    );
+    push(
+        "max-output-bytes",
+        max_output_bytes.as_deref().map(str::as_bytes),
+    );
+    push("ansi", ansi.as_deref().map(str::as_bytes));
+    push("format", properties.format);
+    push("assemble", properties.assemble);
+    push("var", properties.var);
+    push("prompt", properties.prompt);
+    push("default", properties.default);
+    if properties.scope == Some(Scope::Document) {
+        push("scope", Some(b"document"));
+    }
+    push("code", properties.code);
+    if let Some(ignore) = properties.ignore {
+        output.push(("ignore", ignore.to_string().into_bytes()));
+    }
+    if let Some(trim) = properties.trim_trailing {
+        output.push(("trim-trailing", trim.to_string().into_bytes()));
+    }
+    if let Some(split) = properties.split_by_section {
+        output.push(("split-by-section", split.to_string().into_bytes()));
+    }
+    if let Some(infer) = properties.infer_filename {
+        output.push(("infer-filename", infer.to_string().into_bytes()));
+    }
+    if let Some(create) = properties.create {
+        output.push(("create", create.to_string().into_bytes()));
+    }
+    if let Some(isolate) = properties.isolate {
+        output.push(("isolate", isolate.to_string().into_bytes()));
+    }
+    // a betwixt block sets each custom property once, inherited values aren't its own
+    for custom in properties.custom.iter() {
+        if let Some(value) = custom.values.last() {
+            output.push((custom.name, quote(value)));
+        }
+    }
+    output
+}
+
+// Surround a value with the first quote that doesn't appear in it. Values spanning lines
+// are easiest to read between ||| (and most likely to contain other quotes)
+fn quote(value: &[u8]) -> Vec<u8> {
+    let multiline = value.contains(&b'\n') && !value.windows(3).any(|w| w == b"|||");
+    let quote: &[u8] = if multiline {
+        b"|||"
+    } else if !value.contains(&b'\'') {
+        b"'"
+    } else if !value.contains(&b'"') {
+        b"\""
+    } else {
+        b"|||"
+    };
+    [quote, value, quote].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::properties::TangleMode;
+    use crate::{code, section, DocumentError, MarkdownParsers, Strictness};
+
+    #[test]
+    fn test_render() {
+        let markdown = &b"# Render
+Some text that is kept   exactly as is.
+<?btxt+rust filename=\"main.rs\"  mode='overwrite' ?>
+```rust hello
+fn main() {}
+```
+<?btxt tag='docs' code='echo \"hi\"' ?>
+## Unchanged
+```python
+print('hi')
+```
+"[..];
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let mut doc = Document::from_contents(markdown, parsers).unwrap();
+        assert_eq!(render(markdown, &doc), markdown.to_vec());
+
+        doc.code_blocks[0].part.contents = b"fn main() {\n    println!(\"hello\");\n}";
+        doc.directives[0].properties.mode = Some(TangleMode::Append);
+        doc.directives[1].properties.code = Some(b"echo 'hi' \"there\"");
+        assert_eq!(
+            String::from_utf8(render(markdown, &doc)).unwrap(),
+            "# Render
+Some text that is kept   exactly as is.
+<?btxt+rust filename='main.rs' mode='append' ?>
+```rust hello
+fn main() {
+    println!(\"hello\");
+}
+```
+<?btxt tag='docs' code=|||echo 'hi' \"there\"||| ?>
+## Unchanged
+```python
+print('hi')
+```
+"
+        );
+    }
+
+    #[test]
+    fn test_render_mutations() {
+        let markdown = &b"# Mutate
+```rust first
+fn first() {}
+```
+## Child
+<?btxt filename='b.py' ?>
+```python
+print('removed')
+```
+```python kept
+print('kept')
+```
+"[..];
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let mut doc = Document::from_contents(markdown, parsers).unwrap();
+        doc.root.children[0]
+            .set_property(Some(b"rust"), "filename", b"a.rs")
+            .unwrap();
+        assert!(doc.root.children[0]
+            .set_property(None, "mode", b"sideways")
+            .is_err());
+        let removed = doc.remove_block(1);
+        assert_eq!(removed.part.contents, b"print('removed')\n");
+        doc.insert_block(
+            1,
+            CodePart {
+                contents: b"print('inserted')",
+                lang: Some(b"python"),
+                id: Some(b"new"),
+                path: None,
+                prop_line: Some(b"mode='overwrite'"),
+                notangle: false,
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            doc.insert_block(
+                0,
+                CodePart {
+                    contents: b"",
+                    lang: None,
+                    id: Some(b"kept"),
+                    path: None,
+                    prop_line: None,
+                    notangle: false,
+                }
+            ),
+            Err(DocumentError::DuplicateID(..))
+        ));
+        doc.insert_block(
+            3,
+            CodePart {
+                contents: b"last\n",
+                lang: None,
+                id: None,
+                path: None,
+                prop_line: None,
+                notangle: false,
+            },
+        )
+        .unwrap();
+        assert_eq!(doc.code_blocks[1].properties.filename, Some(&b"b.py"[..]));
+        assert_eq!(
+            doc.code_blocks[1].properties.mode,
+            Some(TangleMode::Overwrite)
+        );
+        assert_eq!(doc.root.children[0].children[0].indexes(), &[1, 2, 3]);
+        assert_eq!(
+            String::from_utf8(render(markdown, &doc)).unwrap(),
+            "# Mutate
+<?btxt+rust filename='a.rs' ?>
+```rust first
+fn first() {}
+```
+## Child
+<?btxt filename='b.py' ?>
+```python new mode='overwrite'
+print('inserted')
+```
+```python kept
+print('kept')
+```
+```
+last
+```
+"
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        let markdown = &b"# Format
+<?btxt+rust  mode=\"overwrite\"   filename=\"src/main.rs\" ?>
+```rust
+fn main() {}
+```
+<?btxt ignore=true tag=|||build||| cmd='cargo build --release' pre='fn main() {' post='}' ?>
+<?btxt+rust@'Examples/*'   ignore=true ?>
+<?btxt retry-delay=120s cmd='curl example.com' timeout=90 retries=3 ?>
+"[..];
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let doc = Document::from_contents(markdown, parsers).unwrap();
+        let formatted = format(markdown, &doc);
+        assert_eq!(
+            String::from_utf8(formatted.clone()).unwrap(),
+            "# Format
+<?btxt+rust filename='src/main.rs' mode='overwrite' ?>
+```rust
+fn main() {}
+```
+<?btxt
+  tag='build'
+  pre='fn main() {'
+  post='}'
+  cmd='cargo build --release'
+  ignore=true ?>
+<?btxt+rust@\"Examples/*\" ignore=true ?>
+<?btxt cmd='curl example.com' retries='3' retry-delay='2m' timeout='90s' ?>
+"
+        );
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let reformatted = Document::from_contents(&formatted, parsers).unwrap();
+        assert_eq!(reformatted.directives.len(), 4);
+        for (before, after) in doc.directives.iter().zip(reformatted.directives.iter()) {
+            assert_eq!(before.properties, after.properties);
+        }
+        assert_eq!(format(&formatted, &reformatted), formatted);
+    }
+}
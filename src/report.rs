@@ -0,0 +1,316 @@
+use std::fmt::{Display, Write};
+
+use serde::Serialize;
+
+// What a tangle run did, reported once it is over: the files written, the blocks that
+// weren't tangled and why, and the commands run for blocks
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Report {
+    pub files: Vec<Written>,
+    pub skipped: Vec<Skipped>,
+    pub executed: Vec<Executed>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Written {
+    pub path: String,
+    pub bytes: u64,
+    // the change in the file's size, which is all of it for new files
+    pub delta: i64,
+}
+
+// A block, by where it is in its document, e.g. `README.md:12`
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Skipped {
+    pub block: String,
+    pub reason: SkipReason,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SkipReason {
+    Ignored,
+    // a tag was selected, and the block doesn't have it
+    Untagged,
+    NoFilename,
+    NoMode,
+}
+
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                SkipReason::Ignored => "ignored",
+                SkipReason::Untagged => "untagged",
+                SkipReason::NoFilename => "no-filename",
+                SkipReason::NoMode => "no-mode",
+            }
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Executed {
+    // the block's id, or where it is when it has none
+    pub id: String,
+    pub command: String,
+    // false when the user declined to run the command
+    pub ran: bool,
+    // the command's exit code, when it exited with one. Commands killed by a signal, or that
+    // couldn't be started, have none
+    pub status: Option<i32>,
+    // how long the command took to run, in milliseconds
+    pub millis: u64,
+    // the bytes the command wrote, to stdout and stderr
+    pub bytes: u64,
+    // how many times the command was run, more than once when it failed and was retried
+    pub attempts: u32,
+}
+
+impl Executed {
+    // What became of the command: ok, declined, exit and its code, or failed
+    pub fn outcome(&self) -> String {
+        match (self.ran, self.status) {
+            (false, _) => "declined".into(),
+            (true, Some(0)) => "ok".into(),
+            (true, Some(code)) => format!("exit {}", code),
+            (true, None) => "failed".into(),
+        }
+    }
+}
+
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+impl Report {
+    // The report for people, colored with ansi escapes when color is set
+    pub fn render(&self, color: bool) -> String {
+        let paint = |code: &str, text: String| paint(color, code, text);
+        let mut out = String::new();
+        let width = self.width();
+        let _ = writeln!(
+            out,
+            "{}",
+            paint(GREEN, format!("wrote {}", plural(self.files.len(), "file")))
+        );
+        for file in self.files.iter() {
+            let _ = writeln!(
+                out,
+                "  {:<width$}{} bytes ({:+})",
+                file.path,
+                file.bytes,
+                file.delta,
+                width = width
+            );
+        }
+        if !self.skipped.is_empty() {
+            let _ = writeln!(
+                out,
+                "{}",
+                paint(
+                    YELLOW,
+                    format!("skipped {}", plural(self.skipped.len(), "block"))
+                )
+            );
+            for skip in self.skipped.iter() {
+                let _ = writeln!(
+                    out,
+                    "  {:<width$}{}",
+                    skip.block,
+                    skip.reason,
+                    width = width
+                );
+            }
+        }
+        out.push_str(&self.render_executed(color));
+        out
+    }
+
+    // Just the commands run, for when nothing was tangled
+    pub fn render_executed(&self, color: bool) -> String {
+        let paint = |code: &str, text: String| paint(color, code, text);
+        let mut out = String::new();
+        if self.executed.is_empty() {
+            return out;
+        }
+        let width = self.width();
+        let _ = writeln!(
+            out,
+            "{}",
+            paint(
+                GREEN,
+                format!("executed {}", plural(self.executed.len(), "command"))
+            )
+        );
+        for exec in self.executed.iter() {
+            let outcome = format!("{:<10}", exec.outcome());
+            let outcome = match exec.status {
+                Some(0) => paint(GREEN, outcome),
+                _ => paint(YELLOW, outcome),
+            };
+            if exec.ran {
+                let _ = write!(
+                    out,
+                    "  {:<width$}{}{:>7.2}s {:>9}  {}",
+                    exec.id,
+                    outcome,
+                    exec.millis as f64 / 1000.0,
+                    plural(exec.bytes as usize, "byte"),
+                    exec.command,
+                    width = width
+                );
+                let _ = match exec.attempts {
+                    0 | 1 => writeln!(out),
+                    attempts => writeln!(out, " ({})", plural(attempts as usize, "attempt")),
+                };
+            } else {
+                let _ = writeln!(
+                    out,
+                    "  {:<width$}{}{:>20}{}",
+                    exec.id,
+                    outcome,
+                    "",
+                    exec.command,
+                    width = width
+                );
+            }
+        }
+        out
+    }
+
+    // the width of the first column, wide enough for every file, block and command
+    fn width(&self) -> usize {
+        self.files
+            .iter()
+            .map(|file| file.path.len())
+            .chain(self.skipped.iter().map(|skip| skip.block.len()))
+            .chain(self.executed.iter().map(|exec| exec.id.len()))
+            .max()
+            .unwrap_or(0)
+            + 2
+    }
+
+    // The report for scripts: a tab separated line for each file, block and command, that
+    // won't change between versions. e.g.
+    //   wrote	src/main.rs	120	+12
+    //   skipped	README.md:40	no-filename
+    //   executed	build	ok
+    // Commands are ok, declined, or failed
+    pub fn porcelain(&self) -> String {
+        let mut out = String::new();
+        for file in self.files.iter() {
+            let _ = writeln!(
+                out,
+                "wrote\t{}\t{}\t{:+}",
+                file.path, file.bytes, file.delta
+            );
+        }
+        for skip in self.skipped.iter() {
+            let _ = writeln!(out, "skipped\t{}\t{}", skip.block, skip.reason);
+        }
+        for exec in self.executed.iter() {
+            let status = match (exec.ran, exec.status) {
+                (false, _) => "declined",
+                (true, Some(0)) => "ok",
+                (true, _) => "failed",
+            };
+            let _ = writeln!(out, "executed\t{}\t{}", exec.id, status);
+        }
+        out
+    }
+}
+
+fn paint(color: bool, code: &str, text: String) -> String {
+    if color {
+        format!("{}{}{}", code, text, RESET)
+    } else {
+        text
+    }
+}
+
+fn plural(count: usize, noun: &str) -> String {
+    if count == 1 {
+        format!("{} {}", count, noun)
+    } else {
+        format!("{} {}s", count, noun)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let report = Report {
+            files: vec![Written {
+                path: "main.rs".into(),
+                bytes: 38,
+                delta: -2,
+            }],
+            skipped: vec![Skipped {
+                block: "README.md:12".into(),
+                reason: SkipReason::NoFilename,
+            }],
+            executed: vec![
+                Executed {
+                    id: "build".into(),
+                    command: "cargo build".into(),
+                    ran: true,
+                    status: Some(0),
+                    millis: 1250,
+                    bytes: 340,
+                    attempts: 1,
+                },
+                Executed {
+                    id: "README.md:40".into(),
+                    command: "make deploy".into(),
+                    ran: false,
+                    status: None,
+                    millis: 0,
+                    bytes: 0,
+                    attempts: 0,
+                },
+                Executed {
+                    id: "test".into(),
+                    command: "cargo test".into(),
+                    ran: true,
+                    status: Some(101),
+                    millis: 30,
+                    bytes: 1,
+                    attempts: 3,
+                },
+            ],
+        };
+        assert_eq!(
+            report.render(false),
+            "wrote 1 file
+  main.rs       38 bytes (-2)
+skipped 1 block
+  README.md:12  no-filename
+executed 3 commands
+  build         ok           1.25s 340 bytes  cargo build
+  README.md:40  declined                      make deploy
+  test          exit 101     0.03s    1 byte  cargo test (3 attempts)
+"
+        );
+        assert_eq!(
+            report.porcelain(),
+            "wrote\tmain.rs\t38\t-2
+skipped\tREADME.md:12\tno-filename
+executed\tbuild\tok
+executed\tREADME.md:40\tdeclined
+executed\ttest\tfailed
+"
+        );
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["skipped"][0]["reason"], "no-filename");
+        assert_eq!(json["executed"][2]["status"], 101);
+        assert_eq!(json["executed"][0]["millis"], 1250);
+        assert_eq!(json["executed"][2]["attempts"], 3);
+        assert!(report.render(true).contains("\x1b[32mwrote 1 file\x1b[0m"));
+    }
+}
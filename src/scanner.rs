@@ -0,0 +1,528 @@
+use std::io::{self, BufRead};
+use std::ops::Range;
+
+use memchr::{memchr, memchr_iter};
+use nom::bytes::complete::take_until;
+
+use nom::branch::alt;
+use nom::{IResult, Parser};
+
+use crate::code::CodePart;
+use crate::errors::InvalidMatchDetails;
+use crate::offset;
+use crate::parsers::{LineParseError, LineParseResult, LineParser, ScanResult};
+use crate::properties::Properties;
+use crate::section::SectionPart;
+use crate::strictness::{Problem, Strictness, Warning};
+use crate::{DocumentError, FlavorBuilder};
+
+// Reading documents a line at a time. Documents borrow what they are parsed from, so they
+// are parsed from a single buffer, but it needn't be one the caller already has: `read`
+// fills one from any source of lines, such as a file being read, or the chunks of a rope
+// an editor holds a document in, e.g.
+//
+//   let contents = scanner::read(Chunks::new(rope.chunks()))?;
+//   let doc = betwixt_parse::parse(&contents, FlavorBuilder::github())?;
+//
+// Tools that only need what a document is made of, like syntax highlighters, can take the
+// headings, betwixt blocks and code blocks found as it is scanned, without the section tree
+// and inherited properties `parse` works out, e.g.
+//
+//   for event in Scanner::new(&contents, FlavorBuilder::github()).events() {
+//       let (event, span) = event?;
+//   }
+
+// the UTF-8 byte order mark, which some editors start documents with
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+// Where the lines of a document come from
+pub trait LineSource {
+    // Append the next line, with its newline when it has one, to buf, returning its length,
+    // or 0 when there are no lines left
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> io::Result<usize>;
+}
+
+// Byte slices, and readers, which are read a buffer at a time
+impl<R: BufRead> LineSource for R {
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        self.read_until(b'\n', buf)
+    }
+}
+
+// A document held in pieces, taken in order, e.g. the chunks of a rope. Lines may run across
+// pieces, and pieces may hold several lines
+pub struct Chunks<I: Iterator> {
+    chunks: I,
+    // the piece being read, and how much of it has been
+    current: Option<I::Item>,
+    at: usize,
+}
+
+impl<I: Iterator> Chunks<I> {
+    pub fn new(chunks: I) -> Self {
+        Chunks {
+            chunks,
+            current: None,
+            at: 0,
+        }
+    }
+}
+
+impl<I> LineSource for Chunks<I>
+where
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let mut read = 0;
+        loop {
+            if self.current.is_none() {
+                self.current = self.chunks.next();
+                self.at = 0;
+            }
+            let Some(chunk) = &self.current else {
+                return Ok(read);
+            };
+            let rest = &chunk.as_ref()[self.at..];
+            match memchr(b'\n', rest) {
+                Some(idx) => {
+                    buf.extend_from_slice(&rest[..=idx]);
+                    self.at += idx + 1;
+                    return Ok(read + idx + 1);
+                }
+                None => {
+                    buf.extend_from_slice(rest);
+                    read += rest.len();
+                    self.current = None;
+                }
+            }
+        }
+    }
+}
+
+// Every line of source, in one buffer to parse a document from
+pub fn read(mut source: impl LineSource) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    while source.read_line(&mut contents)? > 0 {}
+    Ok(contents)
+}
+
+// What is found scanning a document, in the order it is found. Each comes with the bytes of
+// the document it was found in. Events are handled as they come, boxing them gains nothing
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event<'a> {
+    // a heading, starting a section
+    Section(SectionPart<'a>),
+    // a betwixt block, with the language it is for. Its properties are as written, before
+    // anything is inherited
+    Properties(Option<&'a [u8]>, Properties<'a>),
+    // a fenced code block, ignored or not
+    Code(CodePart<'a>),
+}
+
+type BoxedParser<'a> =
+    Box<dyn FnMut(&'a [u8]) -> IResult<&'a [u8], LineParseResult<'a>, LineParseError<'a>> + 'a>;
+
+// Scans a document in the markdown dialect of a flavor, see `events`
+pub struct Scanner<'a> {
+    lines: LineScanner<'a>,
+    parser: BoxedParser<'a>,
+}
+
+impl<'a> Scanner<'a> {
+    pub fn new(contents: &'a [u8], flavor: FlavorBuilder) -> Self {
+        let parsers = flavor.build();
+        let mut parser = alt((parsers.code, parsers.section, parsers.betwixt));
+        Scanner {
+            lines: LineScanner::new(contents, parsers.strict),
+            parser: Box::new(move |i| parser.parse(i)),
+        }
+    }
+
+    // The events of the document, ending at its end, or at the first betwixt block with
+    // invalid properties when the flavor is strict about them
+    pub fn events(self) -> Events<'a> {
+        Events {
+            scanner: self,
+            done: false,
+        }
+    }
+}
+
+// The events of a document, as `Scanner::events` returns them
+pub struct Events<'a> {
+    scanner: Scanner<'a>,
+    done: bool,
+}
+
+impl<'a> Events<'a> {
+    // betwixt blocks with invalid properties skipped so far, when the flavor warns of them
+    pub fn warnings(&self) -> &[Warning] {
+        &self.scanner.lines.warnings
+    }
+}
+
+impl<'a> Iterator for Events<'a> {
+    type Item = Result<(Event<'a>, Range<usize>), DocumentError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let lines = &mut self.scanner.lines;
+            let event = match lines.scan(&mut self.scanner.parser) {
+                Ok(ScanResult::Section(section)) => Event::Section(section),
+                Ok(ScanResult::Properties((lang, properties))) => {
+                    Event::Properties(lang, properties)
+                }
+                Ok(ScanResult::Code(code)) => Event::Code(code),
+                Ok(ScanResult::Skipped) => continue,
+                Ok(ScanResult::End) => break,
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(DocumentError::InvalidMatch(err)));
+                }
+            };
+            let mut span = lines.span();
+            // the parser takes a heading's marks, but the heading runs to the end of its line
+            if let Event::Section(_) = event {
+                let rest = &lines.data[span.start..];
+                span.end = span.start + memchr(b'\n', rest).unwrap_or(rest.len());
+            }
+            return Some(Ok((event, span)));
+        }
+        self.done = true;
+        None
+    }
+}
+
+pub(crate) struct LineScanner<'a> {
+    // lines stores the end index of each line in the byte slice
+    // e.g. data[lines[x]] should always be set to \n
+    lines: Vec<usize>,
+    slice: (usize, usize), // the start and end of the current working slice
+    span: (usize, usize),  // the bytes consumed by the most recent match
+    data: &'a [u8],        // all the bytes in the file
+    strict: Strictness,
+    block_start: usize,
+    pub(crate) warnings: Vec<Warning>,
+}
+
+impl<'a> LineScanner<'a> {
+    pub(crate) fn new(data: &'a [u8], strict: Strictness) -> Self {
+        // a byte order mark isn't part of the first line. It is skipped rather than
+        // stripped, so spans still index into data
+        let start = if data.starts_with(BOM) { BOM.len() } else { 0 };
+        LineScanner {
+            lines: Vec::new(),
+            slice: (start, start),
+            span: (0, 0),
+            block_start: 1,
+            data,
+            strict,
+            warnings: Vec::new(),
+        }
+    }
+    // The byte range of the document matched by the last successful scan
+    pub(crate) fn span(&self) -> Range<usize> {
+        self.span.0..self.span.1
+    }
+
+    // The line of the document pos is on, counting from 1. Only lines already scanned
+    // are known
+    pub(crate) fn line(&self, pos: usize) -> usize {
+        self.lines.partition_point(|&end| end < pos) + 1
+    }
+
+    // Extend the working slice to end, recording the lines it passes over
+    fn advance(&mut self, end: usize) {
+        let start = self.slice.1;
+        self.lines
+            .extend(memchr_iter(b'\n', &self.data[start..end]).map(|idx| start + idx));
+        if end == self.data.len() && !self.data.ends_with(b"\n") && end > start {
+            self.lines.push(end);
+        }
+        self.slice = (self.slice.0, end);
+    }
+
+    pub(crate) fn scan<P>(&mut self, parser: &mut P) -> Result<ScanResult<'a>, InvalidMatchDetails>
+    where
+        P: LineParser<'a>,
+    {
+        while self.slice.1 != self.data.len() {
+            let line = match take_until::<&str, &'a [u8], nom::error::Error<&'a [u8]>>("\n")(
+                &self.data[self.slice.1..],
+            ) {
+                Ok((_, line)) => line,
+                Err(_) => &self.data[self.slice.1..],
+            };
+            self.lines.push(self.slice.1 + line.len());
+            let new_end = std::cmp::min(self.data.len(), self.slice.1 + line.len() + 1);
+            self.slice = (self.slice.0, new_end);
+            match parser.parse(&self.data[self.slice.0..self.slice.1]) {
+                Ok((rest, result)) => match result {
+                    LineParseResult::Matched(m) => {
+                        self.span = (self.slice.0, self.slice.1 - rest.len());
+                        self.slice = (self.slice.1, self.slice.1);
+                        return Ok(m);
+                    }
+                    LineParseResult::PartialMatch => {
+                        self.block_start = self.lines.len();
+                        // Rather than adding a line at a time until the parser can decide,
+                        // which is quadratic in the length of the block, give it the rest
+                        // of the document at once. Only errors are left to be found line by
+                        // line, so they are reported on the right line
+                        match parser.parse(&self.data[self.slice.0..]) {
+                            Ok((rest, LineParseResult::Matched(m))) => {
+                                let end = self.data.len() - rest.len();
+                                self.span = (self.slice.0, end);
+                                let line_end = if end > self.slice.0 && self.data[end - 1] == b'\n'
+                                {
+                                    end
+                                } else {
+                                    memchr(b'\n', &self.data[end..])
+                                        .map_or(self.data.len(), |idx| end + idx + 1)
+                                };
+                                self.advance(line_end);
+                                self.slice = (self.slice.1, self.slice.1);
+                                return Ok(m);
+                            }
+                            Ok((_, LineParseResult::PartialMatch)) => {
+                                // never terminated, there's nothing left to match
+                                self.advance(self.data.len());
+                                return Ok(ScanResult::End);
+                            }
+                            Err(_) => {}
+                        }
+                    }
+                },
+                Err(err) => match err {
+                    // only streaming parsers want more input, which there isn't. The built in
+                    // parsers are complete, but those built with `parsers` needn't be
+                    nom::Err::Incomplete(_) => {
+                        self.block_start = self.lines.len() + 1;
+                        self.slice = (self.slice.1, self.slice.1)
+                    }
+                    nom::Err::Error(err) | nom::Err::Failure(err) => match err {
+                        LineParseError::InvalidMatch(err) => {
+                            let details = InvalidMatchDetails {
+                                line_start: self.block_start,
+                                line_end: self.lines.len(),
+                                reason: err.to_string(),
+                                span: offset(self.data, err.input.trim_ascii_end()),
+                            };
+                            match self.strict {
+                                Strictness::Deny => return Err(details),
+                                Strictness::Warn => self.warnings.push(Warning {
+                                    problem: Problem::InvalidProperties,
+                                    message: format!(
+                                        "betwixt block with invalid properties skipped: {}",
+                                        details.reason
+                                    ),
+                                    line: Some(details.line_start),
+                                    block: None,
+                                }),
+                                Strictness::Ignore => {}
+                            }
+                            self.block_start = self.lines.len() + 1;
+                            self.slice = (self.slice.1, self.slice.1)
+                        }
+                        LineParseError::NoMatch => {
+                            self.block_start = self.lines.len() + 1;
+                            self.slice = (self.slice.1, self.slice.1)
+                        }
+                    },
+                },
+            };
+        }
+        Ok(ScanResult::End)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::code;
+    use crate::properties::betwixt;
+    use crate::section::section;
+    use crate::{BETWIXT_TOKEN, CLOSE_TOKEN};
+    use nom::branch::alt;
+
+    #[test]
+    fn test_header_sections() {
+        let contents = &b"
+Welcome!
+
+## This is some project
+with some random body crap
+
+## Help
+
+More content
+";
+        let mut parser = alt((
+            code("```", "```"),
+            section('#'),
+            betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+        ));
+        let mut scanner = LineScanner::new(&contents[..], Strictness::Deny);
+        let results = scanner.scan(&mut parser);
+        assert!(results.is_ok());
+        let results = results.unwrap();
+        match results {
+            ScanResult::Section(section) => {
+                assert_eq!(Some(&b"This is some project"[..]), section.heading);
+            }
+            _ => panic!("invalid scan result"),
+        }
+        let results = scanner.scan(&mut parser);
+        assert!(results.is_ok());
+        let results = results.unwrap();
+        match results {
+            ScanResult::Section(section) => assert_eq!(Some(&b"Help"[..]), section.heading),
+            _ => panic!("invalid scan result"),
+        }
+    }
+
+    #[test]
+    fn test_code_blocks() {
+        let mut parser = alt((
+            code("```", "```"),
+            section('#'),
+            betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+        ));
+        let contents = &b"
+This is a big ol' code block
+''
+With some potential gotchas!
+```rust
+```this doesn't count
+// this is still code, even with ```
+```
+And this isn't code anymore
+";
+        let mut scanner = LineScanner::new(&contents[..], Strictness::Deny);
+        let results = scanner.scan(&mut parser);
+        assert!(results.is_ok());
+        match &results.as_ref().unwrap() {
+            ScanResult::Code(code) => {
+                assert!(code.lang.is_some());
+                assert_eq!(code.lang.unwrap(), &b"rust"[..]);
+                assert_eq!(
+                    code.contents,
+                    &b"```this doesn't count
+// this is still code, even with ```
+"[..]
+                );
+            }
+            _ => panic!("unexpected scan result"),
+        }
+    }
+
+    // Parsers built outside the crate may be streaming, and want more input than there is.
+    // Scanning used to panic on them
+    #[test]
+    fn test_incomplete_parser() {
+        fn incomplete(_: &[u8]) -> IResult<&[u8], LineParseResult<'_>, LineParseError<'_>> {
+            Err(nom::Err::Incomplete(nom::Needed::Unknown))
+        }
+        let mut scanner = LineScanner::new(&b"# Streaming\nmore\n"[..], Strictness::Deny);
+        assert_eq!(scanner.scan(&mut incomplete).unwrap(), ScanResult::End);
+    }
+
+    #[test]
+    fn test_events() {
+        let contents = &b"# Events
+<?btxt+rust mode='overwrite' ?>
+<!-- a comment, skipped -->
+## Code
+```rust main filename='main.rs'
+fn main() {}
+```
+<?btxt filename= ?>
+"[..];
+        let events: Vec<(Event, Range<usize>)> = Scanner::new(contents, FlavorBuilder::github())
+            .events()
+            .take(3)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            events,
+            vec![
+                (
+                    Event::Section(SectionPart {
+                        heading: Some(b"Events"),
+                        level: 1,
+                    }),
+                    0..8,
+                ),
+                (
+                    Event::Properties(
+                        Some(b"rust"),
+                        Properties {
+                            mode: Some(crate::TangleMode::Overwrite),
+                            ..Default::default()
+                        },
+                    ),
+                    9..40,
+                ),
+                (
+                    Event::Section(SectionPart {
+                        heading: Some(b"Code"),
+                        level: 2,
+                    }),
+                    69..76,
+                ),
+            ]
+        );
+        let mut events = Scanner::new(contents, FlavorBuilder::github()).events();
+        match events.nth(3) {
+            Some(Ok((Event::Code(code), span))) => {
+                assert_eq!(code.id, Some(&b"main"[..]));
+                assert_eq!(
+                    &contents[span],
+                    b"```rust main filename='main.rs'\nfn main() {}\n```"
+                );
+            }
+            other => panic!("expected a code block, got {:?}", other),
+        }
+        assert!(matches!(
+            events.next(),
+            Some(Err(DocumentError::InvalidMatch(_)))
+        ));
+        assert!(events.next().is_none());
+
+        let flavor = FlavorBuilder::github().strict(Strictness::Warn);
+        let mut events = Scanner::new(contents, flavor).events();
+        assert_eq!(events.by_ref().count(), 4);
+        assert_eq!(events.warnings().len(), 1);
+    }
+
+    #[test]
+    fn test_line_sources() {
+        let markdown = "# Sources\n```rust filename='main.rs'\nfn main() {}\n```\nno newline";
+        // pieces split lines anywhere, and may be empty
+        let pieces = [
+            "# Sou",
+            "rces\n```rust filename=",
+            "",
+            "'main.rs'\nfn main() {}\n```\nno ",
+            "newline",
+        ];
+        let mut chunks = Chunks::new(pieces.iter());
+        let mut line = Vec::new();
+        assert_eq!(chunks.read_line(&mut line).unwrap(), 10);
+        assert_eq!(line, b"# Sources\n");
+        assert_eq!(
+            read(Chunks::new(pieces.iter())).unwrap(),
+            markdown.as_bytes()
+        );
+        assert_eq!(
+            read(io::BufReader::with_capacity(4, markdown.as_bytes())).unwrap(),
+            markdown.as_bytes()
+        );
+        assert_eq!(read(markdown.as_bytes()).unwrap(), markdown.as_bytes());
+        assert!(read(Chunks::new(std::iter::empty::<&str>()))
+            .unwrap()
+            .is_empty());
+    }
+}
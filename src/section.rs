@@ -1,49 +1,99 @@
-use nom::bytes::complete::{take_until1, take_while1};
+use memchr::memchr;
+use nom::bytes::complete::{take_while, take_while1};
 use nom::character::is_space;
-use nom::combinator::peek;
 use nom::sequence::tuple;
 use nom::{IResult, InputLength};
+use pulldown_cmark::{Event, Parser};
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::sync::Arc;
 
-use crate::LineParseError;
+use crate::code::Code;
+use crate::{Document, LineParseError};
 
-use super::properties::Properties;
+use super::properties::{Properties, PropertyError};
+use super::Directive;
 use super::{LineParseResult, ScanResult};
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct SectionPart<'a> {
+    // the heading as written, without its marks or line ending, e.g. `Using **betwixt**`
     pub heading: Option<&'a [u8]>,
     pub level: usize,
 }
 
-#[derive(Clone, Debug, PartialEq)]
-// TODO can we get rid of this Clone?
+impl<'a> SectionPart<'a> {
+    // The heading as it reads once rendered, e.g. `Using betwixt`
+    pub fn title(&self) -> Option<String> {
+        self.heading.map(title)
+    }
+}
+
+// The properties set in a section, layered over those of the sections it is nested in.
+// Parents are shared with their children rather than copied into each of them.
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct PropertiesCollection<'a> {
     pub global: Properties<'a>,
     pub languages: HashMap<&'a [u8], Properties<'a>>,
+    pub parent: Option<Arc<PropertiesCollection<'a>>>,
+    // properties by language that are below everything the document sets, e.g. those of
+    // `[lang.rust]` in betwixt.toml. Every section has the same
+    pub defaults: Arc<HashMap<&'a [u8], Properties<'a>>>,
 }
 
 impl<'a> PropertiesCollection<'a> {
+    // An empty collection, inheriting everything from parent
+    pub fn inherit(parent: &Arc<PropertiesCollection<'a>>) -> Self {
+        PropertiesCollection {
+            parent: Some(Arc::clone(parent)),
+            defaults: Arc::clone(&parent.defaults),
+            ..Default::default()
+        }
+    }
+
     pub fn get_code_props(&self, lang: Option<&'a [u8]>) -> Properties<'a> {
-        match lang {
-            None => self.global.clone(),
-            Some(lang) => match self.languages.get(lang) {
-                None => self.global.clone(),
-                Some(lang_props) => {
-                    let mut lang_props = lang_props.clone();
-                    lang_props.merge(&self.global);
-                    lang_props
-                }
-            },
+        // language properties take precedence over global ones, no matter which
+        // section either was set in
+        let global = self.global_props();
+        let mut props = match lang.and_then(|lang| self.lang_props(lang)) {
+            None => global,
+            Some(mut lang_props) => {
+                lang_props.merge(&global);
+                lang_props
+            }
+        };
+        if let Some(defaults) = lang.and_then(|lang| self.defaults.get(lang)) {
+            props.merge(defaults);
+        }
+        props
+    }
+
+    fn global_props(&self) -> Properties<'a> {
+        let mut props = self.global.clone();
+        if let Some(parent) = &self.parent {
+            props.merge(&parent.global_props());
+        }
+        props
+    }
+
+    fn lang_props(&self, lang: &'a [u8]) -> Option<Properties<'a>> {
+        let inherited = self.parent.as_ref().and_then(|p| p.lang_props(lang));
+        match (self.languages.get(lang), inherited) {
+            (Some(props), Some(inherited)) => {
+                let mut props = props.clone();
+                props.merge(&inherited);
+                Some(props)
+            }
+            (Some(props), None) => Some(props.clone()),
+            (None, inherited) => inherited,
         }
     }
 
     pub fn update(&mut self, lang: Option<&'a [u8]>, mut props: Properties<'a>) {
         match lang {
             Some(lang) => {
-                if self.languages.contains_key(lang) {
-                    props.merge(self.languages.get(lang).unwrap());
+                if let Some(own) = self.languages.get(lang) {
+                    props.merge(own);
                 }
                 self.languages.insert(lang, props);
             }
@@ -58,38 +108,404 @@ impl<'a> PropertiesCollection<'a> {
 #[derive(Debug, PartialEq)]
 pub struct Section<'a> {
     pub part: SectionPart<'a>,
-    pub properties: PropertiesCollection<'a>,
-    pub code_block_indexes: Vec<usize>,
+    // where the section's contents start in the document, just after its heading
+    pub start: usize,
+    // shared with the section's children once it has any
+    pub properties: Arc<PropertiesCollection<'a>>,
+    // indexes into Document::code_blocks, see blocks for the blocks themselves
+    pub(crate) code_block_indexes: Vec<usize>,
     pub children: Vec<Section<'a>>,
+    // betwixt blocks added by set_property, written at the start of the section by `render`
+    pub(crate) added: Vec<Directive<'a>>,
+    // the headings from the root down to and including this section's
+    pub(crate) path: Vec<&'a [u8]>,
+    pub(crate) slug: String,
 }
 
 impl<'a> Section<'a> {
-    pub fn new(part: SectionPart<'a>, properties: PropertiesCollection<'a>) -> Self {
+    pub fn new(part: SectionPart<'a>, start: usize, properties: PropertiesCollection<'a>) -> Self {
+        let path: Vec<&[u8]> = part.heading.into_iter().collect();
+        let slug = path
+            .first()
+            .map(|heading| slug(heading))
+            .unwrap_or_default();
         Section {
             part,
-            properties,
+            start,
+            properties: Arc::new(properties),
             children: Vec::new(),
             code_block_indexes: Vec::new(),
+            added: Vec::new(),
+            path,
+            slug,
+        }
+    }
+
+    // Make the section a child of parent, extending the parent's path with its heading
+    pub(crate) fn within(&mut self, parent: &Section<'a>) {
+        self.path = parent
+            .path
+            .iter()
+            .copied()
+            .chain(self.part.heading)
+            .collect();
+        self.slug = self
+            .path
+            .iter()
+            .map(|h| slug(h))
+            .collect::<Vec<_>>()
+            .join("/");
+    }
+
+    // The headings of the sections from the root down to this one. The root has no heading,
+    // so its path is empty
+    pub fn path(&self) -> &[&'a [u8]] {
+        &self.path
+    }
+
+    // The path as a single identifier, each heading slugified and joined with '/', e.g.
+    // `getting-started/install`. It names the section wherever it is referred to
+    pub fn slug(&self) -> &str {
+        &self.slug
+    }
+
+    // The indexes in Document::code_blocks of the section's own code blocks, not those of its
+    // children, in document order
+    pub fn indexes(&self) -> &[usize] {
+        &self.code_block_indexes
+    }
+
+    // The section's own code blocks, in document order. doc must be the document the section
+    // is in
+    pub fn blocks<'d>(&'d self, doc: &'d Document<'a>) -> impl Iterator<Item = &'d Code<'a>> {
+        self.code_block_indexes
+            .iter()
+            .map(|&idx| &doc.code_blocks[idx])
+    }
+
+    // The nth of the section's own code blocks
+    pub fn block<'d>(&self, doc: &'d Document<'a>, n: usize) -> Option<&'d Code<'a>> {
+        self.code_block_indexes
+            .get(n)
+            .map(|&idx| &doc.code_blocks[idx])
+    }
+
+    // The section's own code blocks, to change them. Takes the document's code blocks rather
+    // than the document, which the section is borrowed from, e.g.
+    // `doc.root.children[0].blocks_mut(&mut doc.code_blocks)`
+    pub fn blocks_mut<'d>(
+        &'d self,
+        code_blocks: &'d mut [Code<'a>],
+    ) -> impl Iterator<Item = &'d mut Code<'a>> {
+        code_blocks
+            .iter_mut()
+            .enumerate()
+            .filter(|(idx, _)| self.code_block_indexes.contains(idx))
+            .map(|(_, block)| block)
+    }
+
+    // Set a property for the whole section (and its children), or only for the code blocks
+    // of lang in it. Code blocks already parsed keep the properties they were resolved
+    // with; the property applies to them once the rendered document is parsed again
+    pub fn set_property(
+        &mut self,
+        lang: Option<&'a [u8]>,
+        name: &str,
+        value: &'a [u8],
+    ) -> Result<(), PropertyError> {
+        let mut props = Properties::default();
+        props.set(name, value)?;
+        let idx = match self.added.iter().position(|d| d.lang == lang) {
+            Some(idx) => idx,
+            None => {
+                self.added.push(Directive {
+                    span: self.start..self.start,
+                    lang,
+                    properties: Properties::default(),
+                });
+                self.added.len() - 1
+            }
+        };
+        self.added[idx].properties.set(name, value)?;
+        // a set property overrides whatever the section had before
+        Arc::make_mut(&mut self.properties).update(lang, props);
+        Ok(())
+    }
+
+    // Every section in the tree, parents before their children
+    pub fn walk(&self) -> Vec<&Section<'a>> {
+        let mut sections = vec![self];
+        for child in self.children.iter() {
+            sections.extend(child.walk());
+        }
+        sections
+    }
+
+    fn walk_mut(&mut self, f: &mut impl FnMut(&mut Section<'a>)) {
+        f(self);
+        for child in self.children.iter_mut() {
+            child.walk_mut(f);
+        }
+    }
+
+    // The section in the tree holding the code block at idx
+    pub fn holding(&self, idx: usize) -> Option<&Section<'a>> {
+        if self.code_block_indexes.contains(&idx) {
+            return Some(self);
         }
+        self.children.iter().find_map(|child| child.holding(idx))
     }
+
+    // The section in the tree holding the code block at idx, to change it
+    pub(crate) fn find_block(&mut self, idx: usize) -> Option<&mut Section<'a>> {
+        if self.code_block_indexes.contains(&idx) {
+            return Some(self);
+        }
+        self.children
+            .iter_mut()
+            .find_map(|child| child.find_block(idx))
+    }
+
+    // Apply f to the code block indexes of every section in the tree
+    pub(crate) fn reindex(&mut self, mut f: impl FnMut(&mut Vec<usize>)) {
+        self.walk_mut(&mut |section| f(&mut section.code_block_indexes));
+    }
+}
+
+// The text of a heading without its inline markup: emphasis, links, images and html are
+// reduced to the text they show, and code spans to their code
+pub fn title(heading: &[u8]) -> String {
+    // parsed as a heading, so text that would start a list or a quote elsewhere is kept
+    let markdown = format!("# {}", String::from_utf8_lossy(heading));
+    Parser::new(&markdown)
+        .filter_map(|event| match event {
+            Event::Text(text) | Event::Code(text) => Some(text),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .concat()
+}
+
+// A heading as it is written in a link to it: its title, lowercase, with spaces turned into
+// dashes and anything other than letters, digits, dashes and underscores dropped
+pub fn slug(heading: &[u8]) -> String {
+    title(heading)
+        .trim()
+        .chars()
+        .flat_map(char::to_lowercase)
+        .filter_map(|c| match c {
+            ' ' => Some('-'),
+            c if c.is_alphanumeric() || c == '-' || c == '_' => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+// Whether a section's path matches glob, matched against the titles of its headings joined
+// with `/`, e.g. `Examples/*`. `*` matches anything but `/`, `**` anything at all, and `?`
+// any one character other than `/`
+pub(crate) fn path_matches(glob: &[u8], path: &[&[u8]]) -> bool {
+    fn matches(glob: &[char], text: &[char]) -> bool {
+        match glob {
+            [] => text.is_empty(),
+            ['*', '*', rest @ ..] => (0..=text.len()).any(|i| matches(rest, &text[i..])),
+            ['*', rest @ ..] => {
+                let segment = text.iter().position(|&c| c == '/').unwrap_or(text.len());
+                (0..=segment).any(|i| matches(rest, &text[i..]))
+            }
+            ['?', rest @ ..] => {
+                text.first().is_some_and(|&c| c != '/') && matches(rest, &text[1..])
+            }
+            [c, rest @ ..] => text.first() == Some(c) && matches(rest, &text[1..]),
+        }
+    }
+    let glob: Vec<char> = String::from_utf8_lossy(glob).chars().collect();
+    let text: Vec<char> = path
+        .iter()
+        .map(|heading| title(heading).trim().to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+        .chars()
+        .collect();
+    matches(&glob, &text)
 }
 
 // Parse out a section between header levels
+#[cfg_attr(not(feature = "parsers"), allow(dead_code))]
 pub fn section<'a>(
     mark: char,
+) -> impl Fn(&'a [u8]) -> IResult<&'a [u8], LineParseResult<'a>, LineParseError> {
+    section_with(mark, true)
+}
+
+// Parse out a section, where headings need not have a space after their marks unless
+// require_space is set, e.g. `##Title`
+pub fn section_with<'a>(
+    mark: char,
+    require_space: bool,
 ) -> impl Fn(&'a [u8]) -> IResult<&'a [u8], LineParseResult<'a>, LineParseError> {
     move |i: &'a [u8]| {
-        let (input, (header, _, heading)) = tuple((
-            take_while1(|c| c == mark as u8),
-            take_while1(is_space),
-            peek(take_until1("\n")),
-        ))(i)?;
+        let (input, (header, space)) =
+            tuple((take_while1(|c| c == mark as u8), take_while(is_space)))(i)?;
+        // the heading runs to the end of the line, or of the document
+        let heading = &input[..memchr(b'\n', input).unwrap_or(input.len())];
+        if heading.is_empty() || require_space && space.is_empty() {
+            return Err(nom::Err::Error(LineParseError::NoMatch));
+        }
+        // documents saved on windows end lines with \r\n
+        let heading = heading.strip_suffix(b"\r").unwrap_or(heading);
         Ok((
             input,
             LineParseResult::Matched(ScanResult::Section(SectionPart {
-                heading: Some(heading),
+                heading: Some(closed(heading, mark as u8)),
                 level: header.input_len(),
             })),
         ))
     }
 }
+
+// A heading without its trailing whitespace and closing marks, e.g. `Title` for
+// `## Title ##`. Marks only close a heading when separated from it by a space, so
+// `C#` keeps its mark
+fn closed(heading: &[u8], mark: u8) -> &[u8] {
+    let heading = heading.trim_ascii_end();
+    let end = heading
+        .iter()
+        .rposition(|&c| c != mark)
+        .map_or(0, |idx| idx + 1);
+    let text = &heading[..end];
+    match text.last() {
+        None => text,
+        Some(&c) if is_space(c) => text.trim_ascii_end(),
+        Some(_) => heading,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::DocumentBuilder;
+    use proptest::prelude::*;
+
+    // A line of a generated document
+    #[derive(Clone, Debug)]
+    enum Item {
+        Heading(usize),
+        // a betwixt block for a language, or all of them, setting a tag, a filename or both
+        Set(
+            Option<&'static str>,
+            Option<&'static str>,
+            Option<&'static str>,
+        ),
+        Block(&'static str),
+    }
+
+    fn item() -> impl Strategy<Value = Item> {
+        let lang = || prop::sample::select(vec!["rust", "python"]);
+        let value = || prop::option::of(prop::sample::select(vec!["a", "b", "c"]));
+        prop_oneof![
+            (1usize..4).prop_map(Item::Heading),
+            (prop::option::of(lang()), value(), value())
+                .prop_filter("sets nothing", |(_, tag, filename)| tag.is_some()
+                    || filename.is_some())
+                .prop_map(|(lang, tag, filename)| Item::Set(lang, tag, filename)),
+            lang().prop_map(Item::Block),
+        ]
+    }
+
+    // What a section has set so far: its tag and filename, for every language and for each
+    type Set = [Option<&'static str>; 2];
+
+    #[derive(Default)]
+    struct Frame {
+        level: usize,
+        global: Set,
+        languages: HashMap<&'static str, Set>,
+    }
+
+    fn set(current: &mut Set, tag: Option<&'static str>, filename: Option<&'static str>) {
+        for (value, new) in current.iter_mut().zip([tag, filename]) {
+            if new.is_some() {
+                *value = new;
+            }
+        }
+    }
+
+    // The tag and filename each block should have, by walking the document as a reader would.
+    // Each property is resolved on its own: the closest section setting it for the block's
+    // language wins, however far away, then the closest setting it for every language. So a
+    // block never loses what an enclosing section set unless something closer sets it again,
+    // and language properties always beat global ones
+    fn expected(items: &[Item]) -> Vec<Set> {
+        let mut frames = vec![Frame::default()];
+        let mut blocks = Vec::new();
+        for item in items {
+            match *item {
+                Item::Heading(level) => {
+                    while frames.len() > 1 && frames.last().unwrap().level >= level {
+                        frames.pop();
+                    }
+                    frames.push(Frame {
+                        level,
+                        ..Default::default()
+                    });
+                }
+                Item::Set(lang, tag, filename) => {
+                    let frame = frames.last_mut().unwrap();
+                    match lang {
+                        Some(lang) => set(frame.languages.entry(lang).or_default(), tag, filename),
+                        None => set(&mut frame.global, tag, filename),
+                    }
+                }
+                Item::Block(lang) => blocks.push([0, 1].map(|prop| {
+                    frames
+                        .iter()
+                        .rev()
+                        .find_map(|frame| frame.languages.get(lang).and_then(|set| set[prop]))
+                        .or_else(|| frames.iter().rev().find_map(|frame| frame.global[prop]))
+                })),
+            }
+        }
+        blocks
+    }
+
+    fn document(items: &[Item]) -> DocumentBuilder {
+        items
+            .iter()
+            .enumerate()
+            .fold(DocumentBuilder::new(), |builder, (idx, item)| match *item {
+                Item::Heading(level) => builder.section(&format!("Section {}", idx), level),
+                Item::Set(lang, tag, filename) => {
+                    let mut props = Properties::default();
+                    if let Some(tag) = tag {
+                        props.set("tag", tag.as_bytes()).unwrap();
+                    }
+                    if let Some(filename) = filename {
+                        props.set("filename", filename.as_bytes()).unwrap();
+                    }
+                    builder.properties(lang, &props)
+                }
+                Item::Block(lang) => builder.block(lang, "code\n", &Properties::default()),
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn test_inheritance(items in prop::collection::vec(item(), 0..48)) {
+            let builder = document(&items);
+            let doc = builder.build().unwrap();
+            let found: Vec<[Option<&[u8]>; 2]> = doc
+                .code_blocks
+                .iter()
+                .map(|block| [block.properties.tag, block.properties.filename])
+                .collect();
+            let expected: Vec<[Option<&[u8]>; 2]> = expected(&items)
+                .into_iter()
+                .map(|set| set.map(|value| value.map(str::as_bytes)))
+                .collect();
+            prop_assert_eq!(found, expected);
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::first_difference;
+
+// What a block's command wrote and how it exited, recorded so later runs can be checked
+// against it, as regression tests for runbooks. A snapshot is kept in a directory as three
+// files named by the block's id: `<id>.stdout`, `<id>.stderr` and `<id>.status`, which holds
+// the exit code, or `signal` when the command was killed without one
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Snapshot {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub status: Option<i32>,
+}
+
+impl Snapshot {
+    pub fn save(&self, dir: &Path, id: &str) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join(format!("{}.stdout", id)), &self.stdout)?;
+        fs::write(dir.join(format!("{}.stderr", id)), &self.stderr)?;
+        let status = match self.status {
+            Some(code) => code.to_string(),
+            None => "signal".into(),
+        };
+        fs::write(dir.join(format!("{}.status", id)), status + "\n")
+    }
+
+    // The snapshot recorded in dir for id, or None when there isn't one
+    pub fn load(dir: &Path, id: &str) -> io::Result<Option<Self>> {
+        let status = match fs::read_to_string(dir.join(format!("{}.status", id))) {
+            Ok(status) => status,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let status = match status.trim() {
+            "signal" => None,
+            code => Some(code.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}.status holds '{}', not an exit code", id, code),
+                )
+            })?),
+        };
+        Ok(Some(Snapshot {
+            stdout: fs::read(dir.join(format!("{}.stdout", id)))?,
+            stderr: fs::read(dir.join(format!("{}.stderr", id)))?,
+            status,
+        }))
+    }
+
+    // What differs between this snapshot and the one recorded, a line for each stream and
+    // the status, or None when they are the same
+    pub fn compare(&self, recorded: &Snapshot) -> Option<String> {
+        let mut out = String::new();
+        for (name, now, before) in [
+            ("stdout", &self.stdout, &recorded.stdout),
+            ("stderr", &self.stderr, &recorded.stderr),
+        ] {
+            if now == before {
+                continue;
+            }
+            let line = first_difference(before, now);
+            let _ = writeln!(out, "{}: differs from line {}", name, line);
+        }
+        if self.status != recorded.status {
+            let status = |status: Option<i32>| status.map_or("signal".into(), |s| s.to_string());
+            let _ = writeln!(
+                out,
+                "status: was {}, now {}",
+                status(recorded.status),
+                status(self.status)
+            );
+        }
+        if out.is_empty() {
+            None
+        } else {
+            Some(out)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let snapshot = Snapshot {
+            stdout: b"one\ntwo\n".to_vec(),
+            stderr: Vec::new(),
+            status: Some(0),
+        };
+        assert_eq!(Snapshot::load(dir, "greet").unwrap(), None);
+        snapshot.save(dir, "greet").unwrap();
+        let recorded = Snapshot::load(dir, "greet").unwrap().unwrap();
+        assert_eq!(recorded, snapshot);
+        assert_eq!(snapshot.compare(&recorded), None);
+
+        let changed = Snapshot {
+            stdout: b"one\nthree\n".to_vec(),
+            stderr: b"oops\n".to_vec(),
+            status: None,
+        };
+        assert_eq!(
+            changed.compare(&recorded).unwrap(),
+            "stdout: differs from line 2\nstderr: differs from line 1\nstatus: was 0, now signal\n"
+        );
+        changed.save(dir, "greet").unwrap();
+        assert_eq!(Snapshot::load(dir, "greet").unwrap(), Some(changed));
+    }
+}
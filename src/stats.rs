@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use serde::Serialize;
+
+use crate::tangle::{self, TangleError, TangleOptions};
+use crate::Document;
+
+// Counts describing a document, for auditing large literate projects
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Stats {
+    pub sections: usize,
+    pub blocks: usize,
+    // code blocks tangled to no file, either missing a filename or any other property
+    pub without_filename: usize,
+    // code blocks by language. Blocks without one are counted as "unspecified"
+    pub languages: BTreeMap<String, usize>,
+    // the bytes tangling the document would write to each file
+    pub files: BTreeMap<String, usize>,
+    pub tags: BTreeMap<String, usize>,
+}
+
+impl Stats {
+    pub fn from_document(doc: &Document) -> Result<Self, TangleError> {
+        let mut stats = Stats {
+            // the root isn't a section of the document, just the space before the first heading
+            sections: doc.root.walk().len() - 1,
+            blocks: doc.code_blocks.len(),
+            ..Default::default()
+        };
+        for (idx, block) in doc.code_blocks.iter().enumerate() {
+            let lang = block
+                .part
+                .lang
+                .map(String::from_utf8_lossy)
+                .unwrap_or("unspecified".into());
+            *stats.languages.entry(lang.into()).or_default() += 1;
+            if let Some(tag) = block.properties.tag {
+                *stats
+                    .tags
+                    .entry(String::from_utf8_lossy(tag).into())
+                    .or_default() += 1;
+            }
+            if doc.filenames(idx).is_empty() {
+                stats.without_filename += 1;
+            }
+        }
+        let selected: Vec<usize> = (0..doc.code_blocks.len()).collect();
+        for output in tangle::plan(doc, &selected, &TangleOptions::default())? {
+            stats.files.insert(
+                String::from_utf8_lossy(&output.filename).into(),
+                output.contents.len(),
+            );
+        }
+        Ok(stats)
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:<24}{}", "sections", self.sections)?;
+        writeln!(f, "{:<24}{}", "code blocks", self.blocks)?;
+        writeln!(
+            f,
+            "{:<24}{}",
+            "blocks without filename", self.without_filename
+        )?;
+        for (heading, column, counts) in [
+            ("language", "blocks", &self.languages),
+            ("file", "bytes", &self.files),
+            ("tag", "blocks", &self.tags),
+        ] {
+            if counts.is_empty() {
+                continue;
+            }
+            let width = counts.keys().map(|k| k.len()).max().unwrap_or(0).max(22) + 2;
+            writeln!(f, "\n{:<width$}{}", heading, column, width = width)?;
+            for (name, count) in counts {
+                writeln!(f, "{:<width$}{}", name, count, width = width)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{betwixt, code, section, MarkdownParsers, Strictness, BETWIXT_TOKEN, CLOSE_TOKEN};
+
+    #[test]
+    fn test_stats() {
+        let markdown = &b"# Stats
+<?btxt+rust filename='main.rs' mode='append' tag='build' ?>
+```rust
+fn main() {}
+```
+## Nested
+```rust
+fn other() {}
+```
+```
+no language, no filename
+```
+<?btxt filename='run.sh' mode='overwrite' tag='build' ?>
+```sh
+cargo run
+```
+"[..];
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let doc = Document::from_contents(markdown, parsers).unwrap();
+        let stats = Stats::from_document(&doc).unwrap();
+        assert_eq!(
+            stats,
+            Stats {
+                sections: 2,
+                blocks: 4,
+                without_filename: 1,
+                languages: BTreeMap::from([
+                    ("rust".into(), 2),
+                    ("sh".into(), 1),
+                    ("unspecified".into(), 1)
+                ]),
+                files: BTreeMap::from([("main.rs".into(), 27), ("run.sh".into(), 10)]),
+                tags: BTreeMap::from([("build".into(), 3)]),
+            }
+        );
+    }
+}
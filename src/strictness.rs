@@ -0,0 +1,133 @@
+use std::fmt::Display;
+
+// How a class of problem in a document is handled
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Strictness {
+    // carry on as if nothing was wrong
+    #[default]
+    Ignore,
+    // carry on, but report a Warning
+    Warn,
+    // fail
+    Deny,
+}
+
+// The classes of problem a document can have, each with its own strictness
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Problem {
+    // a betwixt block whose properties can't be parsed. It is skipped unless denied
+    InvalidProperties,
+    // a code block tangled without a filename. It is skipped unless denied
+    MissingFilename,
+    // a code block tangled without a mode. It is skipped unless denied
+    MissingMode,
+    // a code block tangled without a language, or one betwixt has no comment syntax for
+    UnknownLanguage,
+    // an inserted block without an occurrence, whose marker matches several lines. It goes by
+    // the first unless denied
+    AmbiguousMarker,
+}
+
+impl Problem {
+    pub const ALL: [Problem; 5] = [
+        Problem::InvalidProperties,
+        Problem::MissingFilename,
+        Problem::MissingMode,
+        Problem::UnknownLanguage,
+        Problem::AmbiguousMarker,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Problem> {
+        Problem::ALL.into_iter().find(|p| p.to_string() == name)
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Problem::InvalidProperties => "invalid-properties",
+                Problem::MissingFilename => "missing-filename",
+                Problem::MissingMode => "missing-mode",
+                Problem::UnknownLanguage => "unknown-language",
+                Problem::AmbiguousMarker => "ambiguous-marker",
+            }
+        )
+    }
+}
+
+// The strictness of each class of problem. By default every problem is ignored
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Lints {
+    pub invalid_properties: Strictness,
+    pub missing_filename: Strictness,
+    pub missing_mode: Strictness,
+    pub unknown_language: Strictness,
+    pub ambiguous_marker: Strictness,
+}
+
+impl Lints {
+    // Deny every problem betwixt has always been strict about. Blocks in languages it
+    // doesn't know are common and harmless, so they are still ignored
+    pub fn strict() -> Self {
+        Lints {
+            unknown_language: Strictness::Ignore,
+            ..Lints::all(Strictness::Deny)
+        }
+    }
+
+    pub fn all(strictness: Strictness) -> Self {
+        Lints {
+            invalid_properties: strictness,
+            missing_filename: strictness,
+            missing_mode: strictness,
+            unknown_language: strictness,
+            ambiguous_marker: strictness,
+        }
+    }
+
+    pub fn level(&self, problem: Problem) -> Strictness {
+        match problem {
+            Problem::InvalidProperties => self.invalid_properties,
+            Problem::MissingFilename => self.missing_filename,
+            Problem::MissingMode => self.missing_mode,
+            Problem::UnknownLanguage => self.unknown_language,
+            Problem::AmbiguousMarker => self.ambiguous_marker,
+        }
+    }
+
+    pub fn set(&mut self, problem: Problem, strictness: Strictness) {
+        match problem {
+            Problem::InvalidProperties => self.invalid_properties = strictness,
+            Problem::MissingFilename => self.missing_filename = strictness,
+            Problem::MissingMode => self.missing_mode = strictness,
+            Problem::UnknownLanguage => self.unknown_language = strictness,
+            Problem::AmbiguousMarker => self.ambiguous_marker = strictness,
+        }
+    }
+}
+
+// A problem that was let through because it is only warned about
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    pub problem: Problem,
+    pub message: String,
+    // the line of the document the problem starts on, when known
+    pub line: Option<usize>,
+    // the index of the code block with the problem, if any
+    pub block: Option<usize>,
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        if let Some(block) = self.block {
+            write!(f, "block {}: ", block)?;
+        }
+        write!(f, "{} [{}]", self.message, self.problem)
+    }
+}
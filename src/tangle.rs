@@ -0,0 +1,1450 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::Display;
+#[cfg(feature = "fs")]
+use std::fs::{self, File, OpenOptions};
+use std::io;
+#[cfg(feature = "fs")]
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use tracing::debug;
+
+use crate::code::Code;
+use crate::comments::Comments;
+use crate::properties::{Marker, Occurrence, Position, Tabs, TangleMode};
+use crate::section::Section;
+use crate::strictness::{Lints, Problem, Strictness, Warning};
+use crate::Document;
+
+// Everything a tangle run will write to a single file. Blocks are grouped per file
+// so that each file is opened and written exactly once, after all blocks are known.
+#[derive(Debug, PartialEq)]
+pub struct FileOutput<'a> {
+    // borrowed from the document, unless it was made from a filename-template
+    pub filename: Cow<'a, [u8]>,
+    // when set, contents are appended to whatever the file held before tangling
+    pub append: bool,
+    // when set, contents are written before whatever the file held before tangling
+    pub prepend: bool,
+    // a file appended or prepended to is created when it doesn't exist, rather than it being
+    // an error
+    pub create: bool,
+    pub contents: Vec<u8>,
+    // the formatter to run over the file once it has been written
+    pub format: Option<&'a [u8]>,
+    // the commands to run over the file once its blocks are combined, before it replaces
+    // the file, see `write_with`
+    pub assemble: Option<&'a [u8]>,
+    // indexes of the code blocks that make up contents
+    pub blocks: Vec<usize>,
+    // when set, the file is made a link to the path contents holds
+    pub link: Option<Link>,
+    // the shebang of a file appended or prepended to, which it starts with should it be
+    // created, see `create_file`
+    pub shebang: Option<&'a [u8]>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Link {
+    Symbolic,
+    Hard,
+}
+
+impl<'a> FileOutput<'a> {
+    // Write the file from its start rather than appending or prepending to it, as it doesn't
+    // exist yet, starting it with its shebang
+    pub fn create_file(&mut self) {
+        (self.append, self.prepend) = (false, false);
+        if let Some(shebang) = self.shebang.take() {
+            self.contents.splice(0..0, shebang_line(shebang));
+        }
+    }
+
+    pub fn path(&self, dir: &Path) -> Result<PathBuf, TangleError> {
+        Ok(dir.join(path(&self.filename)?))
+    }
+
+    // Write the file out under dir, returning the full path written
+    #[cfg(feature = "fs")]
+    pub fn write(&self, dir: &Path) -> Result<PathBuf, TangleError> {
+        self.write_with(dir, |_| Ok(()))
+    }
+
+    // Write the file out under dir like `write`, giving assemble the path of the file as it
+    // is before it takes the place of the one there. The file is written whole beside it,
+    // appended to a copy of what it held when appending, or followed by it when prepending,
+    // and renamed into place, so it is
+    // never left half written, nor written at all when assemble fails
+    #[cfg(feature = "fs")]
+    pub fn write_with<E: From<TangleError>>(
+        &self,
+        dir: &Path,
+        assemble: impl FnOnce(&Path) -> Result<(), E>,
+    ) -> Result<PathBuf, E> {
+        let path = self.path(dir)?;
+        let io = |err| TangleError::Io(path.clone(), err);
+        if let Some(link) = self.link {
+            link.make(self::path(&self.contents)?, &path).map_err(io)?;
+            return Ok(path);
+        }
+        let staged = staging(&path);
+        let written = (|| {
+            let mut file = if self.append {
+                fs::copy(&path, &staged)?;
+                OpenOptions::new().append(true).open(&staged)?
+            } else {
+                File::create(&staged)?
+            };
+            file.write_all(&self.contents)?;
+            if self.prepend {
+                file.write_all(&fs::read(&path)?)?;
+            }
+            // an overwritten file keeps its permissions, so scripts stay executable
+            if let Ok(meta) = fs::metadata(&path) {
+                fs::set_permissions(&staged, meta.permissions())?;
+            }
+            Ok(())
+        })();
+        let assembled = written
+            .map_err(io)
+            .map_err(E::from)
+            .and_then(|_| assemble(&staged));
+        if let Err(err) = assembled {
+            let _ = fs::remove_file(&staged);
+            return Err(err);
+        }
+        fs::rename(&staged, &path).map_err(io)?;
+        Ok(path)
+    }
+}
+
+// Where a file is written before it is renamed into place: beside it, so renaming it
+// doesn't cross filesystems, under a name that keeps its extension for tools that go by it,
+// e.g. `src/.btxt-main.rs`
+#[cfg(feature = "fs")]
+fn staging(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".btxt-");
+    name.push(path.file_name().unwrap_or_default());
+    path.with_file_name(name)
+}
+
+#[cfg(feature = "fs")]
+impl Link {
+    // Make a link at path to target, in place of any file or link there. A relative target
+    // is relative to the link's directory, as it is for symbolic links
+    fn make(self, target: &Path, path: &Path) -> io::Result<()> {
+        if is_link(path) {
+            remove_link(path)?;
+        } else if path.is_file() {
+            fs::remove_file(path)?;
+        }
+        let resolved = path.parent().unwrap_or(Path::new("")).join(target);
+        match self {
+            Link::Hard => fs::hard_link(resolved, path),
+            Link::Symbolic => symlink(target, &resolved, path),
+        }
+    }
+}
+
+#[cfg(feature = "fs")]
+fn is_link(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_symlink())
+}
+
+// Links to directories are removed as directories on windows
+#[cfg(feature = "fs")]
+fn remove_link(path: &Path) -> io::Result<()> {
+    fs::remove_file(path).or_else(|err| fs::remove_dir(path).map_err(|_| err))
+}
+
+#[cfg(all(feature = "fs", unix))]
+fn symlink(target: &Path, _resolved: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+// Making symbolic links on windows takes developer mode or administrator rights. Without
+// them, links to files fall back to hard links, or copies where those can't be made either
+// (across drives). Links to directories have no fallback
+#[cfg(all(feature = "fs", windows))]
+fn symlink(target: &Path, resolved: &Path, path: &Path) -> io::Result<()> {
+    use std::os::windows::fs::{symlink_dir, symlink_file};
+    // ERROR_PRIVILEGE_NOT_HELD
+    const NOT_PERMITTED: i32 = 1314;
+    if resolved.is_dir() {
+        return symlink_dir(target, path);
+    }
+    match symlink_file(target, path) {
+        Err(err) if err.raw_os_error() == Some(NOT_PERMITTED) => {
+            fs::hard_link(resolved, path).or_else(|_| fs::copy(resolved, path).map(|_| ()))
+        }
+        linked => linked,
+    }
+}
+
+#[cfg(all(feature = "fs", not(any(unix, windows))))]
+fn symlink(_target: &Path, _resolved: &Path, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "symbolic links aren't supported on this platform",
+    ))
+}
+
+#[derive(Debug, Default)]
+pub struct TangleOptions {
+    // how blocks missing a filename or a mode, or in an unknown language, are handled
+    pub lints: Lints,
+    // surround each block with comments recording where it came from, so edits to
+    // the tangled file can be synced back to the document (see `detangle`)
+    pub markers: bool,
+    // the comment syntax of each language, for markers
+    pub comments: Comments,
+}
+
+// A filename from a document as a path. Paths are bytes on unix, so any filename will
+// do. Elsewhere they must be valid unicode
+#[cfg(unix)]
+pub fn path(filename: &[u8]) -> Result<&Path, TangleError> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    Ok(Path::new(OsStr::from_bytes(filename)))
+}
+
+#[cfg(not(unix))]
+pub fn path(filename: &[u8]) -> Result<&Path, TangleError> {
+    std::str::from_utf8(filename)
+        .map(Path::new)
+        .map_err(|_| TangleError::InvalidFilename(filename.to_vec()))
+}
+
+// The marker comments written around a block, given the block's index in its document.
+// Returns None when the block's language has no known comment syntax
+pub fn markers(block: &Code, idx: usize, comments: &Comments) -> Option<(String, String)> {
+    let syntax = comments.syntax(block.part.lang?)?;
+    let id = match block.part.id {
+        Some(id) => format!(" {}", String::from_utf8_lossy(id)),
+        None => String::new(),
+    };
+    Some((
+        format!(
+            "{}\n",
+            syntax.comment(&format!("{} {}{}", MARKER_BEGIN, idx, id))?
+        ),
+        format!("{}\n", syntax.comment(&format!("{} {}", MARKER_END, idx))?),
+    ))
+}
+
+pub const MARKER_BEGIN: &str = "btxt:begin";
+pub const MARKER_END: &str = "btxt:end";
+
+// Group the selected code blocks (by index) of a document by their target file, in the
+// order the files are first written to. Blocks are taken in document order whatever order they
+// are selected in, so the plan is the same for the same document every time
+pub fn plan<'a>(
+    doc: &Document<'a>,
+    selected: &[usize],
+    options: &TangleOptions,
+) -> Result<Vec<FileOutput<'a>>, TangleError> {
+    plan_with_warnings(doc, selected, options, &mut Vec::new())
+}
+
+// Like plan, recording the problems that were only warned about in warnings
+pub fn plan_with_warnings<'a>(
+    doc: &Document<'a>,
+    selected: &[usize],
+    options: &TangleOptions,
+    warnings: &mut Vec<Warning>,
+) -> Result<Vec<FileOutput<'a>>, TangleError> {
+    let mut selected = selected.to_vec();
+    selected.sort_unstable();
+    let mut outputs: Vec<FileOutput> = Vec::new();
+    let mut indexes: HashMap<Cow<[u8]>, usize> = HashMap::new();
+    let sections = doc.sections();
+    // the section each file named by split-by-section is named after
+    let mut named: HashMap<Vec<u8>, &Section> = HashMap::new();
+    // the shebang, file-pre and file-post of each output, from the first of its blocks that
+    // sets them
+    let mut bookends = Vec::new();
+    // denied problems are an error, the others let the block through to be skipped or not
+    let mut check = |problem, block_idx, err: TangleError| match options.lints.level(problem) {
+        Strictness::Deny => Err(err),
+        Strictness::Warn => {
+            warnings.push(Warning {
+                problem,
+                message: err.to_string(),
+                line: None,
+                block: Some(block_idx),
+            });
+            Ok(())
+        }
+        Strictness::Ignore => Ok(()),
+    };
+    for block_idx in selected {
+        let block = &doc.code_blocks[block_idx];
+        let mode = match &block.properties.mode {
+            Some(mode) => mode,
+            None => {
+                check(Problem::MissingMode, block_idx, TangleError::MissingMode)?;
+                debug!(block = block_idx, "skipped block, it has no mode");
+                continue;
+            }
+        };
+        let section = sections[block_idx];
+        let filenames = doc.filenames_in(block_idx, section);
+        // sections with the same heading under different parents would share a file, and the
+        // blocks of one be lost when it is overwritten
+        if let (Some(section), Some(filename)) = (section, doc.section_filename(block_idx, section))
+        {
+            if filenames == [&filename[..]] {
+                if let Some(&other) = named.get(&filename) {
+                    if !std::ptr::eq(other, section) {
+                        return Err(TangleError::SectionsCollide(
+                            filename,
+                            other.slug().to_owned(),
+                            section.slug().to_owned(),
+                        ));
+                    }
+                }
+                named.insert(filename, section);
+            }
+        }
+        if filenames.is_empty() {
+            check(
+                Problem::MissingFilename,
+                block_idx,
+                TangleError::MissingFilename,
+            )?;
+            debug!(block = block_idx, "skipped block, it has no filename");
+            continue;
+        }
+        if block
+            .part
+            .lang
+            .and_then(|lang| options.comments.syntax(lang))
+            .is_none()
+        {
+            let lang = block
+                .part
+                .lang
+                .map(|lang| String::from_utf8_lossy(lang).into());
+            check(
+                Problem::UnknownLanguage,
+                block_idx,
+                TangleError::UnknownLanguage(lang),
+            )?;
+        }
+        // a block with several files is tangled to each as if it were a block of its own
+        for filename in filenames {
+            let idx = *indexes.entry(filename.clone()).or_insert_with(|| {
+                outputs.push(FileOutput {
+                    filename: filename.clone(),
+                    append: matches!(mode, TangleMode::Append),
+                    prepend: matches!(mode, TangleMode::Prepend),
+                    create: false,
+                    contents: Vec::new(),
+                    format: None,
+                    assemble: None,
+                    blocks: Vec::new(),
+                    link: None,
+                    shebang: None,
+                });
+                bookends.push((None::<&[u8]>, None::<&[u8]>, None::<&[u8]>));
+                outputs.len() - 1
+            });
+            let output = &mut outputs[idx];
+            match mode {
+                TangleMode::Overwrite => {
+                    output.append = false;
+                    output.prepend = false;
+                    output.contents.clear();
+                    output.blocks.clear();
+                    output.link = None;
+                    bookends[idx] = (None, None, None);
+                }
+                // a link is made of its block alone, whose contents are the path linked to
+                TangleMode::Symlink | TangleMode::Hardlink => {
+                    output.append = false;
+                    output.prepend = false;
+                    output.contents = block.part.contents.trim_ascii().to_vec();
+                    output.blocks = vec![block_idx];
+                    output.format = None;
+                    output.assemble = None;
+                    output.link = Some(match mode {
+                        TangleMode::Symlink => Link::Symbolic,
+                        _ => Link::Hard,
+                    });
+                    bookends[idx] = (None, None, None);
+                    continue;
+                }
+                // prepended blocks are in document order, like appended ones, and go before
+                // what the file held as a whole
+                TangleMode::Append | TangleMode::Prepend | TangleMode::Insert(..) => {}
+            }
+            output.create |= block.properties.create == Some(true);
+            if output.link.is_some() {
+                return Err(TangleError::Linked(filename.to_vec()));
+            }
+            let insert = match mode {
+                TangleMode::Insert(position, marker) => Some((*position, marker)),
+                _ => None,
+            };
+            if let Some(separator) = block
+                .properties
+                .separator
+                .filter(|_| !output.blocks.is_empty() && insert.is_none())
+            {
+                output.contents.extend_from_slice(separator);
+            }
+            output.blocks.push(block_idx);
+            let (shebang, file_prefix, file_postfix) = &mut bookends[idx];
+            if shebang.is_none() {
+                *shebang = block.properties.shebang;
+            }
+            if file_prefix.is_none() {
+                *file_prefix = block.properties.file_prefix;
+            }
+            if file_postfix.is_none() {
+                *file_postfix = block.properties.file_postfix;
+            }
+            let mut text = Vec::new();
+            if let Some(prefix) = block.properties.prefix {
+                text.extend_from_slice(prefix);
+            }
+            // blocks from a `code` property aren't fenced, so there is nothing to sync them back to
+            let markers = if options.markers && block.properties.code.is_none() {
+                markers(block, block_idx, &options.comments)
+            } else {
+                None
+            };
+            match markers {
+                Some((begin, end)) => {
+                    // markers must be on their own lines to be found again. Inserted blocks
+                    // always start on one
+                    let last = match insert {
+                        None => text.last().or(output.contents.last()),
+                        Some(_) => text.last(),
+                    };
+                    if last.is_some_and(|&last| last != b'\n') {
+                        text.push(b'\n');
+                    }
+                    text.extend_from_slice(begin.as_bytes());
+                    text.extend_from_slice(block.part.contents);
+                    text.extend_from_slice(end.as_bytes());
+                }
+                None => text.extend_from_slice(block.part.contents),
+            }
+            if let Some(postfix) = block.properties.postfix {
+                text.extend_from_slice(postfix);
+            }
+            let text = tidy(
+                text,
+                block.properties.trim_trailing == Some(true),
+                block.properties.tabs.unwrap_or(Tabs::Keep),
+            );
+            match insert {
+                Some((position, marker)) => {
+                    let matching = matching_lines(&output.contents, marker)?;
+                    let file = || String::from_utf8_lossy(&filename).into_owned();
+                    if matching.is_empty() {
+                        return Err(TangleError::MarkerNotFound(marker.to_string(), file()));
+                    }
+                    let occurrence = match block.properties.occurrence {
+                        Some(occurrence) => occurrence,
+                        None => {
+                            if matching.len() > 1 {
+                                check(
+                                    Problem::AmbiguousMarker,
+                                    block_idx,
+                                    TangleError::AmbiguousMarker(
+                                        marker.to_string(),
+                                        file(),
+                                        matching.len(),
+                                    ),
+                                )?;
+                            }
+                            Occurrence::First
+                        }
+                    };
+                    let lines = match occurrence {
+                        Occurrence::First => &matching[..1],
+                        Occurrence::Last => &matching[matching.len() - 1..],
+                        Occurrence::All => &matching[..],
+                        Occurrence::Nth(n) => matching.get(n - 1..n).ok_or_else(|| {
+                            TangleError::OccurrenceNotFound(marker.to_string(), file(), n)
+                        })?,
+                    };
+                    insert_lines(&mut output.contents, &text, position, lines);
+                }
+                None => output.contents.extend_from_slice(&text),
+            }
+            if block.properties.format.is_some() {
+                output.format = block.properties.format;
+            }
+            if block.properties.assemble.is_some() {
+                output.assemble = block.properties.assemble;
+            }
+        }
+    }
+    for (output, (shebang, file_prefix, file_postfix)) in outputs.iter_mut().zip(bookends) {
+        if let Some(file_prefix) = file_prefix {
+            output.contents.splice(0..0, file_prefix.iter().copied());
+        }
+        // only a file written from its start can start with a shebang
+        match shebang {
+            Some(shebang) if !output.append && !output.prepend => {
+                output.contents.splice(0..0, shebang_line(shebang));
+            }
+            _ => output.shebang = shebang,
+        }
+        if let Some(file_postfix) = file_postfix {
+            output.contents.extend_from_slice(file_postfix);
+        }
+    }
+    Ok(outputs)
+}
+
+// The first line of a file for a shebang property, which may or may not end the line itself
+fn shebang_line(shebang: &[u8]) -> Vec<u8> {
+    match shebang.last() {
+        Some(b'\n') => shebang.to_vec(),
+        _ => [shebang, b"\n"].concat(),
+    }
+}
+
+// Tidy the whitespace of what is written for a block, line by line: with trim set, trailing
+// spaces and tabs are removed, and tabs are expanded to spaces or indentation turned into tabs
+fn tidy(text: Vec<u8>, trim: bool, tabs: Tabs) -> Vec<u8> {
+    if !trim && tabs == Tabs::Keep {
+        return text;
+    }
+    let mut out = Vec::with_capacity(text.len());
+    for line in text.split_inclusive(|&b| b == b'\n') {
+        let (mut line, ending) = match line.strip_suffix(b"\n") {
+            Some(line) => match line.strip_suffix(b"\r") {
+                Some(line) => (line, &b"\r\n"[..]),
+                None => (line, &b"\n"[..]),
+            },
+            None => (line, &b""[..]),
+        };
+        if trim {
+            while let Some((b' ' | b'\t', rest)) = line.split_last() {
+                line = rest;
+            }
+        }
+        match tabs {
+            Tabs::Keep => out.extend_from_slice(line),
+            Tabs::Spaces(width) => {
+                let mut column = 0;
+                for &b in line {
+                    if b == b'\t' {
+                        let stop = (column / width + 1) * width;
+                        out.resize(out.len() + stop - column, b' ');
+                        column = stop;
+                    } else {
+                        out.push(b);
+                        column += 1;
+                    }
+                }
+            }
+            Tabs::Tabs(width) => {
+                let indent = line
+                    .iter()
+                    .position(|&b| b != b' ' && b != b'\t')
+                    .unwrap_or(line.len());
+                // the indentation's width in columns, with its tabs at their tab stops
+                let columns = line[..indent].iter().fold(0, |column, &b| match b {
+                    b'\t' => (column / width + 1) * width,
+                    _ => column + 1,
+                });
+                out.resize(out.len() + columns / width, b'\t');
+                out.resize(out.len() + columns % width, b' ');
+                out.extend_from_slice(&line[indent..]);
+            }
+        }
+        out.extend_from_slice(ending);
+    }
+    out
+}
+
+// The spans of the lines of contents matching marker
+pub(crate) fn matching_lines(
+    contents: &[u8],
+    marker: &Marker,
+) -> Result<Vec<Range<usize>>, TangleError> {
+    let matcher = Matcher::new(marker)?;
+    Ok(lines(contents)
+        .filter(|line| matcher.matches(&contents[line.clone()]))
+        .collect())
+}
+
+// Put text, made whole lines, by each of lines (spans of contents, in order)
+fn insert_lines(contents: &mut Vec<u8>, text: &[u8], position: Position, lines: &[Range<usize>]) {
+    let mut text = text.to_vec();
+    if !text.ends_with(b"\n") {
+        text.push(b'\n');
+    }
+    // from the last, so the spans of those before are still where they were
+    for line in lines.iter().rev() {
+        let mut text = text.clone();
+        let at = match position {
+            Position::Before => line.start..line.start,
+            // the last line may not end in a newline
+            Position::After if !contents[line.clone()].ends_with(b"\n") => {
+                text.insert(0, b'\n');
+                line.end..line.end
+            }
+            Position::After => line.end..line.end,
+            Position::Replace => line.clone(),
+        };
+        contents.splice(at, text);
+    }
+}
+
+// A marker, ready to be matched against lines
+enum Matcher<'a> {
+    Text(&'a [u8]),
+    Regex(regex::bytes::Regex),
+}
+
+impl<'a> Matcher<'a> {
+    fn new(marker: &Marker<'a>) -> Result<Self, TangleError> {
+        match *marker {
+            Marker::Text(text) => Ok(Matcher::Text(text)),
+            Marker::Regex(regex) => String::from_utf8(regex.to_vec())
+                .ok()
+                .and_then(|regex| regex::bytes::Regex::new(&regex).ok())
+                .map(Matcher::Regex)
+                .ok_or_else(|| TangleError::InvalidMarker(marker.to_string())),
+        }
+    }
+
+    // whether the line, which may end in a newline, matches
+    fn matches(&self, line: &[u8]) -> bool {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        match self {
+            Matcher::Text(text) => memchr::memmem::find(line, text).is_some(),
+            Matcher::Regex(regex) => regex.is_match(line),
+        }
+    }
+}
+
+// The spans of each line of contents, newlines included
+fn lines(contents: &[u8]) -> impl Iterator<Item = Range<usize>> + '_ {
+    let mut start = 0;
+    std::iter::from_fn(move || {
+        if start == contents.len() {
+            return None;
+        }
+        let end =
+            memchr::memchr(b'\n', &contents[start..]).map_or(contents.len(), |end| start + end + 1);
+        let line = start..end;
+        start = end;
+        Some(line)
+    })
+}
+
+// Combine the plans of several documents, in the order they are given, whatever order they
+// were planned in. Documents are tangled independently of each other, so two of them writing
+// to the same file is a conflict
+pub fn merge<'a>(plans: Vec<Vec<FileOutput<'a>>>) -> Result<Vec<FileOutput<'a>>, TangleError> {
+    let mut owners: HashMap<Cow<[u8]>, usize> = HashMap::new();
+    let mut merged = Vec::new();
+    for (doc, plan) in plans.into_iter().enumerate() {
+        for output in plan {
+            if let Some(&other) = owners.get(&output.filename) {
+                return Err(TangleError::Conflict(output.filename.to_vec(), other, doc));
+            }
+            owners.insert(output.filename.clone(), doc);
+            merged.push(output);
+        }
+    }
+    Ok(merged)
+}
+
+// Rewrite the start of each file's name by the first of maps, from and to, whose from it starts
+// with, e.g. `src/` to `generated/src/` to tangle into a staging tree. Two files given the same
+// name are a conflict
+pub fn remap(outputs: &mut [FileOutput], maps: &[(String, String)]) -> Result<(), TangleError> {
+    let mut names = HashSet::new();
+    for output in outputs.iter_mut() {
+        let map = maps
+            .iter()
+            .find(|(from, _)| output.filename.starts_with(from.as_bytes()));
+        if let Some((from, to)) = map {
+            let rest = &output.filename[from.len()..];
+            output.filename = Cow::Owned([to.as_bytes(), rest].concat());
+        }
+        if !names.insert(output.filename.clone()) {
+            return Err(TangleError::Remapped(output.filename.to_vec()));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum TangleError {
+    MissingMode,
+    MissingFilename,
+    InvalidFilename(Vec<u8>),
+    Io(PathBuf, io::Error),
+    // a file tangled by two documents, given by their index
+    Conflict(Vec<u8>, usize, usize),
+    UnknownLanguage(Option<String>),
+    // an inserted block's marker, and the file no line of matched it in
+    MarkerNotFound(String, String),
+    // a marker that isn't a valid regular expression
+    InvalidMarker(String),
+    // a marker, the file, and how many of its lines the marker matches
+    AmbiguousMarker(String, String, usize),
+    // a marker, the file, and the occurrence of the marker wanted, which it doesn't have
+    OccurrenceNotFound(String, String, usize),
+    // a file made a link by one block, that another adds to
+    Linked(Vec<u8>),
+    // a name two files are mapped to
+    Remapped(Vec<u8>),
+    // a file named by split-by-section after two sections, given by their slugs
+    SectionsCollide(Vec<u8>, String, String),
+}
+
+impl Error for TangleError {}
+
+impl Display for TangleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TangleError::MissingMode => {
+                write!(f, "code block without mode")
+            }
+            TangleError::MissingFilename => {
+                write!(f, "code block without filename")
+            }
+            TangleError::InvalidFilename(name) => write!(
+                f,
+                "filename is not valid utf8: {}",
+                String::from_utf8_lossy(name)
+            ),
+            TangleError::Io(path, err) => {
+                write!(f, "failed writing {}: {}", path.to_string_lossy(), err)
+            }
+            TangleError::UnknownLanguage(Some(lang)) => {
+                write!(f, "code block in unknown language {}", lang)
+            }
+            TangleError::UnknownLanguage(None) => write!(f, "code block without language"),
+            TangleError::MarkerNotFound(marker, file) => {
+                write!(f, "no line of {} matches the marker {}", file, marker)
+            }
+            TangleError::AmbiguousMarker(marker, file, count) => write!(
+                f,
+                "the marker {} matches {} lines of {}, set occurrence to choose",
+                marker, count, file
+            ),
+            TangleError::OccurrenceNotFound(marker, file, n) => {
+                write!(
+                    f,
+                    "the marker {} doesn't match {} lines of {}",
+                    marker, n, file
+                )
+            }
+            TangleError::Linked(name) => write!(
+                f,
+                "{} is a link, only a block overwriting it can follow the block linking it",
+                String::from_utf8_lossy(name)
+            ),
+            TangleError::Remapped(name) => write!(
+                f,
+                "more than one file is mapped to {}",
+                String::from_utf8_lossy(name)
+            ),
+            TangleError::InvalidMarker(marker) => {
+                write!(f, "marker {} is not a valid regular expression", marker)
+            }
+            TangleError::Conflict(name, first, second) => write!(
+                f,
+                "documents {} and {} both tangle to {}",
+                first,
+                second,
+                String::from_utf8_lossy(name)
+            ),
+            TangleError::SectionsCollide(name, first, second) => write!(
+                f,
+                "sections {} and {} are both split to {}, give their blocks a filename or \
+                 change the filename-template",
+                first,
+                second,
+                String::from_utf8_lossy(name)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{betwixt, code, section, Document, MarkdownParsers, BETWIXT_TOKEN, CLOSE_TOKEN};
+
+    #[test]
+    fn test_plan_groups_by_file() {
+        let markdown = &b"# Tangle
+<?btxt mode='overwrite' filename='a.rs' ?>
+```rust
+one
+```
+<?btxt+rust mode='append' format='rustfmt' ?>
+```rust
+two
+```
+<?btxt filename='b.py' mode='append' ?>
+```python
+three
+```
+<?btxt+rust filename='a.rs' mode='overwrite' ?>
+```rust
+four
+```
+"[..];
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let doc = Document::from_contents(markdown, parsers).unwrap();
+        let selected: Vec<usize> = (0..doc.code_blocks.len()).collect();
+        let options = TangleOptions {
+            lints: Lints::strict(),
+            ..Default::default()
+        };
+        let outputs = plan(&doc, &selected, &options).unwrap();
+        assert_eq!(
+            outputs,
+            vec![
+                FileOutput {
+                    filename: Cow::Borrowed(b"a.rs"),
+                    append: false,
+                    prepend: false,
+                    create: false,
+                    contents: b"four\n".to_vec(),
+                    format: Some(b"rustfmt"),
+                    assemble: None,
+                    blocks: vec![3],
+                    link: None,
+                    shebang: None,
+                },
+                FileOutput {
+                    filename: Cow::Borrowed(b"b.py"),
+                    append: true,
+                    prepend: false,
+                    create: false,
+                    contents: b"three\n".to_vec(),
+                    format: None,
+                    assemble: None,
+                    blocks: vec![2],
+                    link: None,
+                    shebang: None,
+                },
+            ]
+        );
+        let options = TangleOptions {
+            lints: Lints::strict(),
+            markers: true,
+            ..Default::default()
+        };
+        let outputs = plan(&doc, &selected, &options).unwrap();
+        assert_eq!(
+            outputs[0].contents,
+            b"// btxt:begin 3\nfour\n// btxt:end 3\n".to_vec()
+        );
+        assert_eq!(
+            outputs[1].contents,
+            b"# btxt:begin 2\nthree\n# btxt:end 2\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_plan_inserts() {
+        let markdown = &b"# Insert
+<?btxt+rust filename='main.rs' ?>
+```rust mode='overwrite'
+use std::env;
+
+fn main() {
+    // args
+    println!(\"done\");
+}
+```
+```rust mode='insert-after[/^fn [a-z]+/]'
+    let verbose = false;
+```
+```rust mode='insert[// args]'
+    let args: Vec<String> = env::args().collect();
+```
+```rust mode='replace[// args]'
+    println!(\"{:?}\", args);
+```
+```rust mode='insert-before[use std::env]'
+use std::fs;
+```
+"[..];
+        let doc = crate::parse(markdown, crate::FlavorBuilder::github()).unwrap();
+        let outputs = plan(&doc, &[0, 1, 2, 3, 4], &TangleOptions::default()).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&outputs[0].contents),
+            "use std::fs;
+use std::env;
+
+fn main() {
+    let verbose = false;
+    let args: Vec<String> = env::args().collect();
+    println!(\"{:?}\", args);
+    println!(\"done\");
+}
+"
+        );
+        assert!(matches!(
+            plan(&doc, &[0, 2, 3, 3], &TangleOptions::default()),
+            Err(TangleError::MarkerNotFound(marker, file)) if marker == "// args" && file == "main.rs"
+        ));
+        let markdown = b"```rust filename='a.rs' mode='insert-after[/(/]'\n```\n";
+        // found as the document is parsed, unless problems are ignored
+        assert!(matches!(
+            crate::parse(markdown, crate::FlavorBuilder::github()),
+            Err(crate::DocumentError::Conflict(..))
+        ));
+        let flavor = crate::FlavorBuilder::github().strict(Strictness::Ignore);
+        let doc = crate::parse(markdown, flavor).unwrap();
+        assert!(matches!(
+            plan(&doc, &[0], &TangleOptions::default()),
+            Err(TangleError::InvalidMarker(_))
+        ));
+    }
+
+    #[test]
+    fn test_plan_insert_occurrences() {
+        let markdown = &b"# Occurrences
+<?btxt+text filename='list.txt' ?>
+```text mode='overwrite'
+- a
+- b
+- c
+```
+```text mode='insert-after[- ]'
+first
+```
+```text mode='insert-before[- ]' occurrence='last'
+last
+```
+```text mode='insert-after[/^- [ab]$/]' occurrence='all'
+after a or b
+```
+```text mode='replace[- ]' occurrence='2'
+second
+```
+```text mode='insert[- ]' occurrence='9'
+```
+"[..];
+        let doc = crate::parse(markdown, crate::FlavorBuilder::github()).unwrap();
+        let outputs = plan(&doc, &[0, 1, 2, 3, 4], &TangleOptions::default()).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&outputs[0].contents),
+            "- a\nafter a or b\nfirst\nsecond\nafter a or b\nlast\n- c\n"
+        );
+        assert!(matches!(
+            plan(&doc, &[0, 5], &TangleOptions::default()),
+            Err(TangleError::OccurrenceNotFound(_, _, 9))
+        ));
+        // without an occurrence, a marker matching several lines is ambiguous
+        let strict = TangleOptions {
+            lints: Lints::strict(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            plan(&doc, &[0, 1], &strict),
+            Err(TangleError::AmbiguousMarker(_, _, 3))
+        ));
+        assert!(plan(&doc, &[0, 2], &strict).is_ok());
+        assert_eq!(
+            doc.code_blocks[4].properties.occurrence,
+            Some(Occurrence::Nth(2))
+        );
+        assert!(crate::Properties::default()
+            .set("occurrence", b"0")
+            .is_err());
+    }
+
+    #[test]
+    fn test_remap() {
+        let markdown = &b"# Remap
+<?btxt mode='overwrite' ?>
+```rust filename='src/main.rs'
+```
+```rust filename='src/bin/tool.rs'
+```
+```toml filename='Cargo.toml'
+```
+"[..];
+        let doc = crate::parse(markdown, crate::FlavorBuilder::github()).unwrap();
+        let mut outputs = plan(&doc, &[0, 1, 2], &TangleOptions::default()).unwrap();
+        let maps = [
+            ("src/bin/".to_string(), "tools/".to_string()),
+            ("src/".to_string(), "generated/src/".to_string()),
+        ];
+        remap(&mut outputs, &maps).unwrap();
+        let names: Vec<&[u8]> = outputs.iter().map(|o| &o.filename[..]).collect();
+        assert_eq!(
+            names,
+            vec![
+                &b"generated/src/main.rs"[..],
+                b"tools/tool.rs",
+                b"Cargo.toml"
+            ]
+        );
+        let mut outputs = plan(&doc, &[0, 2], &TangleOptions::default()).unwrap();
+        let maps = [("src/main.rs".to_string(), "Cargo.toml".to_string())];
+        assert!(matches!(
+            remap(&mut outputs, &maps),
+            Err(TangleError::Remapped(name)) if name == b"Cargo.toml"
+        ));
+    }
+
+    #[test]
+    fn test_plan_several_files() {
+        let markdown = &b"# Shared
+```rust mode='append' filename='src/a.rs; tests/a_copy.rs;'
+const SHARED: u8 = 1;
+```
+```rust mode='append' filename='tests/a_copy.rs'
+fn test() {}
+```
+"[..];
+        let doc = crate::parse(markdown, crate::FlavorBuilder::github()).unwrap();
+        let filenames: Vec<&[u8]> = doc.code_blocks[0].properties.filenames().collect();
+        assert_eq!(filenames, vec![&b"src/a.rs"[..], &b"tests/a_copy.rs"[..]]);
+        let outputs = plan(&doc, &[0, 1], &TangleOptions::default()).unwrap();
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(&*outputs[0].filename, b"src/a.rs");
+        assert_eq!(outputs[0].contents, b"const SHARED: u8 = 1;\n");
+        assert_eq!(&*outputs[1].filename, b"tests/a_copy.rs");
+        assert_eq!(
+            outputs[1].contents,
+            b"const SHARED: u8 = 1;\nfn test() {}\n"
+        );
+    }
+
+    #[test]
+    fn test_plan_split_by_section() {
+        let markdown = &b"# Crate
+<?btxt split-by-section=true filename-template='src/{slug}.rs' mode='append' ?>
+## Parsing Input
+```rust
+fn parse() {}
+```
+```rust
+fn lex() {}
+```
+## Output
+```rust filename='src/lib.rs'
+mod output;
+```
+```rust
+fn write() {}
+```
+"[..];
+        let doc = crate::parse(markdown, crate::FlavorBuilder::github()).unwrap();
+        let outputs = plan(&doc, &[0, 1, 2, 3], &TangleOptions::default()).unwrap();
+        let files: Vec<(String, String)> = outputs
+            .iter()
+            .map(|o| {
+                (
+                    String::from_utf8_lossy(&o.filename).into(),
+                    String::from_utf8_lossy(&o.contents).into(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                (
+                    "src/parsing-input.rs".into(),
+                    "fn parse() {}\nfn lex() {}\n".into()
+                ),
+                // a filename of its own wins over the section's
+                ("src/lib.rs".into(), "mod output;\n".into()),
+                ("src/output.rs".into(), "fn write() {}\n".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_split_by_section_collision() {
+        let markdown = &b"# Crate
+<?btxt split-by-section=true filename-template='src/{slug}.rs' mode='overwrite' ?>
+## One
+### Setup
+```rust
+fn one() {}
+```
+## Two
+### Setup
+```rust
+fn two() {}
+```
+"[..];
+        let doc = crate::parse(markdown, crate::FlavorBuilder::github()).unwrap();
+        let err = plan(&doc, &[0, 1], &TangleOptions::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "sections crate/one/setup and crate/two/setup are both split to src/setup.rs, give \
+             their blocks a filename or change the filename-template"
+        );
+    }
+
+    #[test]
+    fn test_plan_infer_filename() {
+        let markdown = &b"# Crate
+<?btxt infer-filename=true mode='append' ?>
+```rust src/main.rs
+fn main() {}
+```
+```rust parser
+fn parse() {}
+```
+```rust
+fn untangled() {}
+```
+## Templated
+<?btxt filename-template='src/{id}.rs' ?>
+```rust #lexer
+fn lex() {}
+```
+```rust src/lib.rs filename='src/mod.rs'
+mod lexer;
+```
+"[..];
+        let doc = crate::parse(markdown, crate::FlavorBuilder::github()).unwrap();
+        let filenames: Vec<Vec<Cow<[u8]>>> = (0..doc.code_blocks.len())
+            .map(|idx| doc.filenames(idx))
+            .collect();
+        assert_eq!(
+            filenames,
+            vec![
+                vec![Cow::Borrowed(&b"src/main.rs"[..])],
+                vec![Cow::Borrowed(&b"parser"[..])],
+                vec![],
+                vec![Cow::Borrowed(&b"src/lexer.rs"[..])],
+                // a filename of its own wins over the one on its fence
+                vec![Cow::Borrowed(&b"src/mod.rs"[..])],
+            ]
+        );
+        assert!(crate::parse(
+            &b"<?btxt infer-filename=maybe ?>\n"[..],
+            crate::FlavorBuilder::github()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_plan_whitespace() {
+        let markdown = b"# Whitespace
+<?btxt filename='main.py' mode='append' trim-trailing=true tabs='spaces:4' ?>
+```python
+def main():\t
+\tif True:  \r
+\t\tprint(\"a\tb\")
+```
+```python tabs='tabs'
+def other():
+      return 1
+```
+";
+        let doc = crate::parse(markdown, crate::FlavorBuilder::github()).unwrap();
+        assert_eq!(doc.code_blocks[1].properties.tabs, Some(Tabs::Tabs(4)));
+        let outputs = plan(&doc, &[0, 1], &TangleOptions::default()).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&outputs[0].contents),
+            "def main():\n    if True:\r\n        print(\"a    b\")\ndef other():\n\t  return 1\n"
+        );
+        assert_eq!(tidy(b"a \t".to_vec(), true, Tabs::Keep), b"a");
+        assert!(crate::Properties::default()
+            .set("tabs", b"spaces:0")
+            .is_err());
+    }
+
+    #[test]
+    fn test_plan_file_bookends_and_separators() {
+        let markdown = &b"# Go
+<?btxt+go filename='main.go' mode='append' file-pre=|||package main
+func main() {
+||| file-post='}' pre='\t' sep=|||
+||| ?>
+```go
+one()
+```
+```go
+two()
+```
+"[..];
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let doc = Document::from_contents(markdown, parsers).unwrap();
+        let outputs = plan(&doc, &[0, 1], &TangleOptions::default()).unwrap();
+        assert_eq!(
+            outputs[0].contents,
+            b"package main\nfunc main() {\n\tone()\n\n\ttwo()\n}".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_plan_shebang() {
+        let markdown = &b"# Scripts
+<?btxt+bash shebang='#!/usr/bin/env bash' file-pre=|||set -e
+||| ?>
+```bash filename='build.sh' mode='overwrite'
+cargo build
+```
+```bash filename='build.sh' mode='append'
+cargo test
+```
+```bash filename='log.sh' mode='append'
+echo done
+```
+"[..];
+        let doc = crate::parse(markdown, crate::FlavorBuilder::github()).unwrap();
+        let outputs = plan(&doc, &[0, 1, 2], &TangleOptions::default()).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&outputs[0].contents),
+            "#!/usr/bin/env bash\nset -e\ncargo build\ncargo test\n"
+        );
+        // appending to a file leaves its first line alone, unless it is created
+        let mut output = outputs.into_iter().nth(1).unwrap();
+        assert_eq!(output.contents, b"set -e\necho done\n");
+        output.create_file();
+        assert!(!output.append);
+        assert_eq!(output.contents, b"#!/usr/bin/env bash\nset -e\necho done\n");
+    }
+
+    #[test]
+    fn test_plan_links() {
+        let markdown = &b"# Links
+<?btxt mode='overwrite' ?>
+```text filename='v2/notes.txt'
+notes
+```
+```text filename='latest' mode='symlink'
+v2/
+```
+```text filename='notes.txt' mode='hardlink'
+v2/notes.txt
+```
+```text filename='notes.txt' mode='append'
+more
+```
+"[..];
+        let doc = crate::parse(markdown, crate::FlavorBuilder::github()).unwrap();
+        let outputs = plan(&doc, &[0, 1, 2], &TangleOptions::default()).unwrap();
+        assert_eq!(outputs[1].link, Some(Link::Symbolic));
+        assert_eq!(outputs[1].contents, b"v2/");
+        assert_eq!(outputs[2].link, Some(Link::Hard));
+        assert!(matches!(
+            plan(&doc, &[2, 3], &TangleOptions::default()),
+            Err(TangleError::Linked(_))
+        ));
+
+        #[cfg(all(unix, feature = "fs"))]
+        {
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = tmp.path();
+            fs::create_dir_all(dir.join("v2")).unwrap();
+            for output in outputs.iter() {
+                output.write(dir).unwrap();
+            }
+            // made again, the links replace those already there
+            for output in outputs.iter() {
+                output.write(dir).unwrap();
+            }
+            assert_eq!(fs::read_link(dir.join("latest")).unwrap(), Path::new("v2/"));
+            assert_eq!(fs::read(dir.join("latest/notes.txt")).unwrap(), b"notes\n");
+            assert_eq!(fs::read(dir.join("notes.txt")).unwrap(), b"notes\n");
+        }
+    }
+
+    #[test]
+    fn test_plan_prepend() {
+        let markdown = &b"# Prepend
+<?btxt filename='notes.txt' shebang='#!/bin/sh' ?>
+```text mode='prepend'
+first
+```
+```text mode='append' create=true
+second
+```
+"[..];
+        let doc = crate::parse(markdown, crate::FlavorBuilder::github()).unwrap();
+        let outputs = plan(&doc, &[0, 1], &TangleOptions::default()).unwrap();
+        // the file isn't written from its start, so there is no shebang
+        assert_eq!(outputs[0].contents, b"first\nsecond\n");
+        assert!(outputs[0].prepend && !outputs[0].append);
+        assert!(outputs[0].create);
+        assert!(!plan(&doc, &[0], &TangleOptions::default()).unwrap()[0].create);
+
+        #[cfg(feature = "fs")]
+        {
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = tmp.path();
+            fs::write(dir.join("notes.txt"), "last\n").unwrap();
+            outputs[0].write(dir).unwrap();
+            assert_eq!(
+                fs::read(dir.join("notes.txt")).unwrap(),
+                b"first\nsecond\nlast\n"
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(all(unix, feature = "fs"))]
+    fn test_write_assembled() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let output = FileOutput {
+            filename: Cow::Borrowed(b"list.txt"),
+            append: false,
+            prepend: false,
+            create: false,
+            contents: b"b\na\n".to_vec(),
+            format: None,
+            assemble: Some(b"sort -o {file} {file}"),
+            blocks: Vec::new(),
+            link: None,
+            shebang: None,
+        };
+        let policy = crate::exec::Policy::default();
+        let assemble = |staged: &Path| {
+            assert_eq!(staged, dir.join(".btxt-list.txt"));
+            crate::exec::assemble("sort -o {file} {file}", staged, &policy)
+                .map_err(|err| TangleError::Io(staged.into(), io::Error::other(err)))
+        };
+        let path = output.write_with(dir, assemble).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"a\nb\n");
+        // when assembling fails, the file is left as it was
+        let failed = output.write_with(dir, |staged| {
+            crate::exec::assemble("false", staged, &policy)
+                .map_err(|err| TangleError::Io(staged.into(), io::Error::other(err)))
+        });
+        assert!(failed.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"a\nb\n");
+        assert!(!dir.join(".btxt-list.txt").exists());
+    }
+
+    #[test]
+    fn test_plan_lints() {
+        let markdown = &b"# Lints
+<?btxt mode='overwrite' ?>
+```rust
+no filename
+```
+<?btxt filename='notes.txt' ?>
+```text
+unknown language
+```
+"[..];
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let doc = Document::from_contents(markdown, parsers).unwrap();
+        let mut options = TangleOptions {
+            lints: Lints::strict(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            plan(&doc, &[0, 1], &options),
+            Err(TangleError::MissingFilename)
+        ));
+        options
+            .lints
+            .set(Problem::MissingFilename, Strictness::Warn);
+        options
+            .lints
+            .set(Problem::UnknownLanguage, Strictness::Warn);
+        let mut warnings = Vec::new();
+        let outputs = plan_with_warnings(&doc, &[0, 1], &options, &mut warnings).unwrap();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(
+            warnings,
+            vec![
+                Warning {
+                    problem: Problem::MissingFilename,
+                    message: "code block without filename".into(),
+                    line: None,
+                    block: Some(0),
+                },
+                Warning {
+                    problem: Problem::UnknownLanguage,
+                    message: "code block in unknown language text".into(),
+                    line: None,
+                    block: Some(1),
+                },
+            ]
+        );
+        options
+            .lints
+            .set(Problem::UnknownLanguage, Strictness::Deny);
+        assert!(matches!(
+            plan(&doc, &[1], &options),
+            Err(TangleError::UnknownLanguage(Some(lang))) if lang == "text"
+        ));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_non_utf8_path() {
+        use std::os::unix::ffi::OsStrExt;
+        let output = FileOutput {
+            filename: Cow::Borrowed(b"caf\xe9.rs"),
+            append: false,
+            prepend: false,
+            create: false,
+            contents: Vec::new(),
+            format: None,
+            assemble: None,
+            blocks: Vec::new(),
+            link: None,
+            shebang: None,
+        };
+        let path = output.path(Path::new("out")).unwrap();
+        assert_eq!(path.as_os_str().as_bytes(), b"out/caf\xe9.rs");
+    }
+
+    #[test]
+    fn test_merge() {
+        let output = |filename: &'static [u8]| FileOutput {
+            filename: Cow::Borrowed(filename),
+            append: false,
+            prepend: false,
+            create: false,
+            contents: Vec::new(),
+            format: None,
+            assemble: None,
+            blocks: Vec::new(),
+            link: None,
+            shebang: None,
+        };
+        let merged = merge(vec![
+            vec![output(b"a.rs"), output(b"b.rs")],
+            vec![output(b"c.rs")],
+        ])
+        .unwrap();
+        let filenames: Vec<&[u8]> = merged.iter().map(|o| &*o.filename).collect();
+        assert_eq!(filenames, vec![&b"a.rs"[..], b"b.rs", b"c.rs"]);
+        assert!(matches!(
+            merge(vec![vec![output(b"a.rs")], vec![], vec![output(b"a.rs")]]),
+            Err(TangleError::Conflict(name, 0, 2)) if name == b"a.rs"
+        ));
+    }
+}
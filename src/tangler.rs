@@ -0,0 +1,281 @@
+use std::error::Error;
+use std::fmt::Display;
+#[cfg(feature = "fs")]
+use std::path::PathBuf;
+use std::str::from_utf8;
+use std::sync::Arc;
+
+use crate::config::{Config, ConfigError};
+use crate::strictness::Warning;
+use crate::tangle::{self, FileOutput, TangleError, TangleOptions};
+use crate::{Code, Document, DocumentError, FlavorBuilder, Observer};
+
+// A tangling engine, configured once and then used for any number of documents, from any
+// number of threads at once, e.g. by a build server tangling every document of a monorepo:
+//
+//   let tangler = Tangler::new(FlavorBuilder::github())
+//       .config(Config::discover(&root)?)?
+//       .tag("release")
+//       .sink(Directory(out));
+//   documents.par_iter().try_for_each(|doc| tangler.tangle(doc).map(|_| ()))?;
+//
+// Files are planned for each document on its own, as `tangle::plan` does, then given to each
+// sink in turn
+pub struct Tangler {
+    flavor: FlavorBuilder,
+    options: TangleOptions,
+    config: Config,
+    tag: Option<String>,
+    filter: Option<Filter>,
+    sinks: Vec<Box<dyn Sink>>,
+    observer: Option<ObserverFactory>,
+}
+
+// Which code blocks are tangled, besides those of the tag
+type Filter = Arc<dyn Fn(&Code) -> bool + Send + Sync>;
+
+// Observers hold state of the document they observe, so each document gets its own
+type ObserverFactory = Arc<dyn Fn() -> Box<dyn for<'a> Observer<'a>> + Send + Sync>;
+
+// Where a Tangler puts the files it tangles. Any function of a file is one
+pub trait Sink: Send + Sync {
+    fn write(&self, output: &FileOutput) -> Result<(), TangleError>;
+}
+
+impl<F> Sink for F
+where
+    F: Fn(&FileOutput) -> Result<(), TangleError> + Send + Sync,
+{
+    fn write(&self, output: &FileOutput) -> Result<(), TangleError> {
+        self(output)
+    }
+}
+
+// Files written under a directory, as the cli writes them
+#[cfg(feature = "fs")]
+pub struct Directory(pub PathBuf);
+
+#[cfg(feature = "fs")]
+impl Sink for Directory {
+    fn write(&self, output: &FileOutput) -> Result<(), TangleError> {
+        output.write(&self.0).map(|_| ())
+    }
+}
+
+// What tangling a document came to: its files, and the problems it has that were warned of
+#[derive(Debug)]
+pub struct Plan<'a> {
+    pub outputs: Vec<FileOutput<'a>>,
+    pub warnings: Vec<Warning>,
+}
+
+impl Tangler {
+    pub fn new(flavor: FlavorBuilder) -> Self {
+        Tangler {
+            flavor,
+            options: TangleOptions::default(),
+            config: Config::default(),
+            tag: None,
+            filter: None,
+            sinks: Vec::new(),
+            observer: None,
+        }
+    }
+
+    pub fn options(mut self, options: TangleOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    // Use the comment syntax and language defaults of a project's config
+    pub fn config(mut self, config: Config) -> Result<Self, ConfigError> {
+        config.defaults()?;
+        self.options.comments = config.comments.clone();
+        self.config = config;
+        Ok(self)
+    }
+
+    // Only tangle blocks tagged tag
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        self.tag = Some(tag.into());
+        self
+    }
+
+    // Only tangle blocks filter is true of
+    pub fn filter(mut self, filter: impl Fn(&Code) -> bool + Send + Sync + 'static) -> Self {
+        self.filter = Some(Arc::new(filter));
+        self
+    }
+
+    // Give the files of each document tangled to sink, after those added before it
+    pub fn sink(mut self, sink: impl Sink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    // Observe each document as it is built with an observer made by observer
+    pub fn observer<O>(mut self, observer: impl Fn() -> O + Send + Sync + 'static) -> Self
+    where
+        O: for<'a> Observer<'a> + 'static,
+    {
+        self.observer = Some(Arc::new(move || Box::new(observer())));
+        self
+    }
+
+    // The files a document tangles to, without giving them to the sinks
+    pub fn plan<'a>(&'a self, contents: &'a [u8]) -> Result<Plan<'a>, TanglerError> {
+        let parsers = self.flavor.clone().build();
+        let defaults = self.config.defaults()?;
+        let mut doc = match &self.observer {
+            Some(observer) => Document::build(contents, parsers, &defaults, Some(&mut *observer())),
+            None => Document::from_contents_with_defaults(contents, parsers, &defaults),
+        }?;
+        let selected: Vec<usize> = (0..doc.code_blocks.len())
+            .filter(|&idx| self.selects(&doc.code_blocks[idx]))
+            .collect();
+        let mut warnings = std::mem::take(&mut doc.warnings);
+        let outputs = tangle::plan_with_warnings(&doc, &selected, &self.options, &mut warnings)?;
+        Ok(Plan { outputs, warnings })
+    }
+
+    // Tangle a document, giving its files to every sink
+    pub fn tangle<'a>(&'a self, contents: &'a [u8]) -> Result<Plan<'a>, TanglerError> {
+        let plan = self.plan(contents)?;
+        for sink in self.sinks.iter() {
+            for output in plan.outputs.iter() {
+                sink.write(output)?;
+            }
+        }
+        Ok(plan)
+    }
+
+    fn selects(&self, block: &Code) -> bool {
+        let tagged = match &self.tag {
+            Some(tag) => block
+                .properties
+                .tag
+                .is_some_and(|own| from_utf8(own) == Ok(tag.as_str())),
+            None => true,
+        };
+        tagged && self.filter.as_ref().is_none_or(|filter| filter(block))
+    }
+}
+
+#[derive(Debug)]
+pub enum TanglerError {
+    Document(DocumentError),
+    Config(ConfigError),
+    Tangle(TangleError),
+}
+
+impl From<DocumentError> for TanglerError {
+    fn from(err: DocumentError) -> Self {
+        TanglerError::Document(err)
+    }
+}
+
+impl From<ConfigError> for TanglerError {
+    fn from(err: ConfigError) -> Self {
+        TanglerError::Config(err)
+    }
+}
+
+impl From<TangleError> for TanglerError {
+    fn from(err: TangleError) -> Self {
+        TanglerError::Tangle(err)
+    }
+}
+
+impl Error for TanglerError {}
+
+impl Display for TanglerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TanglerError::Document(err) => write!(f, "{}", err),
+            TanglerError::Config(err) => write!(f, "{}", err),
+            TanglerError::Tangle(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    // Counts code blocks skipped by the documents it observes
+    struct Skips(Arc<Mutex<usize>>);
+
+    impl<'a> Observer<'a> for Skips {
+        fn skip(&mut self, _: &crate::CodePart<'a>, _: std::ops::Range<usize>) {
+            *self.0.lock().unwrap() += 1;
+        }
+    }
+
+    #[test]
+    fn test_tangler() {
+        assert_send_sync::<Tangler>();
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let skipped = Arc::new(Mutex::new(0));
+        let config: Config = "[lang.rust]
+mode = 'overwrite'
+"
+        .parse()
+        .unwrap();
+        let sink = {
+            let written = written.clone();
+            move |output: &FileOutput| {
+                let name = String::from_utf8_lossy(&output.filename).into_owned();
+                written.lock().unwrap().push(name);
+                Ok(())
+            }
+        };
+        let observed = skipped.clone();
+        let tangler = Tangler::new(FlavorBuilder::github())
+            .config(config)
+            .unwrap()
+            .tag("release")
+            .filter(|block| block.part.id != Some(b"draft"))
+            .observer(move || Skips(observed.clone()))
+            .sink(sink);
+        let documents: Vec<String> = (0..8)
+            .map(|n| {
+                format!(
+                    "# Doc {n}
+```rust filename='{n}.rs' tag='release'
+fn main() {{}}
+```
+```rust draft filename='draft{n}.rs' tag='release'
+```
+```rust filename='debug{n}.rs' tag='debug'
+```
+```text ignore=true
+```
+"
+                )
+            })
+            .collect();
+        std::thread::scope(|scope| {
+            for doc in documents.iter() {
+                let tangler = &tangler;
+                scope.spawn(move || {
+                    let plan = tangler.tangle(doc.as_bytes()).unwrap();
+                    assert_eq!(plan.outputs.len(), 1);
+                    // the mode is the config's
+                    assert!(!plan.outputs[0].append);
+                });
+            }
+        });
+        let mut written = written.lock().unwrap().clone();
+        written.sort();
+        let expected: Vec<String> = (0..8).map(|n| format!("{}.rs", n)).collect();
+        assert_eq!(written, expected);
+        assert_eq!(*skipped.lock().unwrap(), 8);
+        assert!(matches!(
+            tangler.plan(b"<?btxt filename= ?>\n"),
+            Err(TanglerError::Document(_))
+        ));
+    }
+}
@@ -0,0 +1,133 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use crate::tangle::FileOutput;
+use crate::Document;
+
+// Literate documents as project templates. A template declares its variables in betwixt
+// blocks, and `{{name}}` in what is tangled, and in the names of the files tangled to, is
+// replaced by the value given for each when it is instantiated, e.g.
+//
+//   <?btxt var='project_name' prompt='Project name?' default='demo' ?>
+//   ```toml filename='{{project_name}}/Cargo.toml' mode='overwrite'
+//   [package]
+//   name = "{{project_name}}"
+//   ```
+//
+// Placeholders naming no variable are left as they are
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Variable<'a> {
+    pub name: &'a [u8],
+    // the question asked for the variable's value. The name is asked for when there is none
+    pub prompt: Option<&'a [u8]>,
+    pub default: Option<&'a [u8]>,
+}
+
+// The variables declared by a document, in the order they are declared. Only the first
+// declaration of a name counts
+pub fn variables<'a>(doc: &Document<'a>) -> Vec<Variable<'a>> {
+    let mut variables: Vec<Variable> = Vec::new();
+    for directive in doc.directives.iter() {
+        let props = &directive.properties;
+        let Some(name) = props.var else {
+            continue;
+        };
+        if variables.iter().all(|var| var.name != name) {
+            variables.push(Variable {
+                name,
+                prompt: props.prompt,
+                default: props.default,
+            });
+        }
+    }
+    variables
+}
+
+// text with each `{{name}}` placeholder of a variable in values replaced by its value.
+// Whitespace inside the braces is ignored, so `{{ name }}` is the same placeholder
+pub fn substitute(text: &[u8], values: &BTreeMap<String, String>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.windows(2).position(|w| w == b"{{") {
+        out.extend_from_slice(&rest[..start]);
+        let after = &rest[start + 2..];
+        let value = after.windows(2).position(|w| w == b"}}").and_then(|end| {
+            let name = std::str::from_utf8(after[..end].trim_ascii()).ok()?;
+            Some((values.get(name)?, end))
+        });
+        match value {
+            Some((value, end)) => {
+                out.extend_from_slice(value.as_bytes());
+                rest = &after[end + 2..];
+            }
+            None => {
+                out.extend_from_slice(b"{{");
+                rest = after;
+            }
+        }
+    }
+    out.extend_from_slice(rest);
+    out
+}
+
+// Fill in the placeholders of the files tangled from a template, in their names and contents
+pub fn instantiate(outputs: &mut [FileOutput], values: &BTreeMap<String, String>) {
+    for output in outputs.iter_mut() {
+        output.filename = Cow::Owned(substitute(&output.filename, values));
+        output.contents = substitute(&output.contents, values);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tangle::{plan, TangleOptions};
+    use crate::FlavorBuilder;
+
+    #[test]
+    fn test_template() {
+        let markdown = b"# Template
+<?btxt var='project_name' prompt='Project name?' default='demo' ?>
+<?btxt var='author' ?>
+<?btxt var='author' prompt='Again?' mode='overwrite' ?>
+```toml filename='{{project_name}}/Cargo.toml'
+[package]
+name = \"{{ project_name }}\"
+authors = [\"{{author}}\"]
+edition = \"{{edition}}\"
+```
+";
+        let doc = crate::parse(markdown, FlavorBuilder::github()).unwrap();
+        assert_eq!(
+            variables(&doc),
+            vec![
+                Variable {
+                    name: b"project_name",
+                    prompt: Some(b"Project name?"),
+                    default: Some(b"demo"),
+                },
+                Variable {
+                    name: b"author",
+                    prompt: None,
+                    default: None,
+                },
+            ]
+        );
+        // variables are declared, not inherited
+        assert_eq!(doc.code_blocks[0].properties.var, None);
+        let mut outputs = plan(&doc, &[0], &TangleOptions::default()).unwrap();
+        let values = BTreeMap::from([
+            ("project_name".to_string(), "hello".to_string()),
+            ("author".to_string(), "Ada".to_string()),
+        ]);
+        instantiate(&mut outputs, &values);
+        assert_eq!(outputs[0].filename, &b"hello/Cargo.toml"[..]);
+        assert_eq!(
+            String::from_utf8_lossy(&outputs[0].contents),
+            "[package]\nname = \"hello\"\nauthors = [\"Ada\"]\nedition = \"{{edition}}\"\n"
+        );
+        assert_eq!(substitute(b"{{author}} {{", &values), b"Ada {{".to_vec());
+        assert!(crate::parse(b"<?btxt var='a b' ?>\n", FlavorBuilder::github()).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::tangle::{self, TangleOptions};
+use crate::{first_difference, FlavorBuilder};
+
+// Helpers for tests of literate projects, checking that documents still tangle to the files
+// they should. The expected files are kept in a fixture directory, e.g.
+//
+//   #[test]
+//   fn readme_tangles() {
+//       betwixt_parse::testing::assert_tangles_to("README.md", "tests/fixtures/readme");
+//   }
+//
+// Running tests with BETWIXT_BLESS=1 writes what was tangled to the fixture directories
+// instead, for when the changes are intended
+
+pub const BLESS_VAR: &str = "BETWIXT_BLESS";
+
+// Tangle a github markdown document in memory, returning each file's name and contents.
+// Only blocks with a filename are tangled, and files appended to hold only what was appended
+pub fn tangle(markdown: &[u8]) -> Result<BTreeMap<String, Vec<u8>>, String> {
+    let doc = crate::parse(markdown, FlavorBuilder::github()).map_err(|err| err.to_string())?;
+    let selected: Vec<usize> = (0..doc.code_blocks.len())
+        .filter(|&idx| !doc.filenames(idx).is_empty())
+        .collect();
+    let outputs =
+        tangle::plan(&doc, &selected, &TangleOptions::default()).map_err(|err| err.to_string())?;
+    Ok(outputs
+        .into_iter()
+        .map(|output| {
+            (
+                String::from_utf8_lossy(&output.filename).into_owned(),
+                output.contents,
+            )
+        })
+        .collect())
+}
+
+// Every file under dir, by its path relative to dir with / separators
+pub fn fixture(dir: &Path) -> io::Result<BTreeMap<String, Vec<u8>>> {
+    fn read(dir: &Path, prefix: &str, files: &mut BTreeMap<String, Vec<u8>>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+            if entry.file_type()?.is_dir() {
+                read(&entry.path(), &format!("{}/", name), files)?;
+            } else {
+                files.insert(name, fs::read(entry.path())?);
+            }
+        }
+        Ok(())
+    }
+    let mut files = BTreeMap::new();
+    read(dir, "", &mut files)?;
+    Ok(files)
+}
+
+// What differs between the files expected and those tangled, a line for each file, or None
+// when they are the same
+pub fn compare(
+    expected: &BTreeMap<String, Vec<u8>>,
+    tangled: &BTreeMap<String, Vec<u8>>,
+) -> Option<String> {
+    let mut out = String::new();
+    for (name, contents) in expected {
+        match tangled.get(name) {
+            None => {
+                let _ = writeln!(out, "{}: expected, but not tangled", name);
+            }
+            Some(tangled) if tangled != contents => {
+                let line = first_difference(contents, tangled);
+                let _ = writeln!(out, "{}: differs from line {}", name, line);
+            }
+            Some(_) => {}
+        }
+    }
+    for name in tangled.keys().filter(|name| !expected.contains_key(*name)) {
+        let _ = writeln!(out, "{}: tangled, but not expected", name);
+    }
+    if out.is_empty() {
+        None
+    } else {
+        Some(out)
+    }
+}
+
+// Assert that document tangles to exactly the files in the fixture directory, or with
+// BETWIXT_BLESS set, write them there
+pub fn assert_tangles_to(document: impl AsRef<Path>, fixtures: impl AsRef<Path>) {
+    let (document, fixtures) = (document.as_ref(), fixtures.as_ref());
+    let markdown = fs::read(document)
+        .unwrap_or_else(|err| panic!("failed reading {}: {}", document.display(), err));
+    let tangled = tangle(&markdown)
+        .unwrap_or_else(|err| panic!("failed tangling {}: {}", document.display(), err));
+    if env::var_os(BLESS_VAR).is_some() {
+        bless(fixtures, &tangled)
+            .unwrap_or_else(|err| panic!("failed writing {}: {}", fixtures.display(), err));
+        return;
+    }
+    let expected = fixture(fixtures)
+        .unwrap_or_else(|err| panic!("failed reading {}: {}", fixtures.display(), err));
+    if let Some(differences) = compare(&expected, &tangled) {
+        panic!(
+            "{} doesn't tangle to {}\n{}(run with {}=1 to update the fixtures)",
+            document.display(),
+            fixtures.display(),
+            differences,
+            BLESS_VAR
+        );
+    }
+}
+
+// Write the files tangled to the fixture directory. Nothing in it is removed: when it holds
+// files that weren't tangled, fixtures no longer tangled or files that were never fixtures,
+// nothing is written, and they are left to be removed by hand
+fn bless(fixtures: &Path, tangled: &BTreeMap<String, Vec<u8>>) -> io::Result<()> {
+    let existing = match fixture(fixtures) {
+        Ok(existing) => existing,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(err) => return Err(err),
+    };
+    let stray: Vec<&str> = existing
+        .keys()
+        .filter(|name| !tangled.contains_key(*name))
+        .map(String::as_str)
+        .collect();
+    if !stray.is_empty() {
+        return Err(io::Error::other(format!(
+            "it holds files that weren't tangled, remove them if they should go: {}",
+            stray.join(", ")
+        )));
+    }
+    for (name, contents) in tangled {
+        let path = fixtures.join(name);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, contents)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixtures() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let fixtures = root.join("fixtures");
+        let document = root.join("README.md");
+        fs::write(
+            &document,
+            "# Fixtures
+<?btxt mode='overwrite' ?>
+```rust filename='src/main.rs'
+fn main() {}
+```
+```toml filename='Cargo.toml'
+[package]
+```
+",
+        )
+        .unwrap();
+        let tangled = tangle(&fs::read(&document).unwrap()).unwrap();
+        bless(&fixtures, &tangled).unwrap();
+        assert_eq!(fixture(&fixtures).unwrap(), tangled);
+        assert_tangles_to(&document, &fixtures);
+
+        let mut expected = tangled.clone();
+        expected.insert("src/main.rs".into(), b"fn main() {\n}\n".to_vec());
+        expected.insert("build.rs".into(), Vec::new());
+        expected.remove("Cargo.toml");
+        assert_eq!(
+            compare(&expected, &tangled).unwrap(),
+            "build.rs: expected, but not tangled
+src/main.rs: differs from line 1
+Cargo.toml: tangled, but not expected
+"
+        );
+        // blessing writes over fixtures, but never removes anything that wasn't tangled
+        fs::write(fixtures.join("src/main.rs"), "stale").unwrap();
+        bless(&fixtures, &tangled).unwrap();
+        assert_eq!(fixture(&fixtures).unwrap(), tangled);
+        fs::write(fixtures.join("notes.txt"), "mine").unwrap();
+        let err = bless(&fixtures, &tangled).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "it holds files that weren't tangled, remove them if they should go: notes.txt"
+        );
+        assert!(fixtures.join("notes.txt").exists());
+    }
+}
@@ -0,0 +1,151 @@
+use std::mem;
+
+use crate::section::{PropertiesCollection, Section, SectionPart};
+
+// Builds the tree of sections from headings in the order they appear in a document.
+//
+// A section is a child of the closest section before it with a lower level, and inherits its
+// properties. When levels are skipped, e.g. `#` followed by `####`, the `####` section is a
+// child of the `#` section, and a later `##` heading is its sibling rather than its parent.
+// Headings can nest as deeply as the document likes
+pub(crate) struct Tree<'a> {
+    // the ancestors of the current section, the root first
+    ancestors: Vec<Section<'a>>,
+    current: Section<'a>,
+}
+
+impl<'a> Tree<'a> {
+    pub fn new() -> Self {
+        Tree {
+            ancestors: Vec::new(),
+            current: Section::new(
+                SectionPart {
+                    heading: None,
+                    level: 0,
+                },
+                0,
+                PropertiesCollection::default(),
+            ),
+        }
+    }
+
+    // The section that code blocks and betwixt blocks currently belong to
+    pub fn current(&mut self) -> &mut Section<'a> {
+        &mut self.current
+    }
+
+    // Start a new section at the heading part, with its contents starting at start
+    #[cfg(test)]
+    pub fn open(&mut self, part: SectionPart<'a>, start: usize) {
+        self.open_with(part, start, |_| {})
+    }
+
+    // Start a new section like `open`, calling closed with each section it closes
+    pub fn open_with(
+        &mut self,
+        part: SectionPart<'a>,
+        start: usize,
+        mut closed: impl FnMut(&Section<'a>),
+    ) {
+        // close every section at the new heading's level or deeper. The root is never closed
+        while self.current.part.level >= part.level {
+            match self.ancestors.pop() {
+                Some(parent) => {
+                    let child = mem::replace(&mut self.current, parent);
+                    closed(&child);
+                    self.current.children.push(child);
+                }
+                None => break,
+            }
+        }
+        let properties = PropertiesCollection::inherit(&self.current.properties);
+        let mut section = Section::new(part, start, properties);
+        section.within(&self.current);
+        let parent = mem::replace(&mut self.current, section);
+        self.ancestors.push(parent);
+    }
+
+    // Close every open section and return the root
+    #[cfg(test)]
+    pub fn finish(self) -> Section<'a> {
+        self.finish_with(|_| {})
+    }
+
+    // Close every open section like `finish`, calling closed with each
+    pub fn finish_with(mut self, mut closed: impl FnMut(&Section<'a>)) -> Section<'a> {
+        while let Some(parent) = self.ancestors.pop() {
+            let child = mem::replace(&mut self.current, parent);
+            closed(&child);
+            self.current.children.push(child);
+        }
+        self.current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // Build a tree from heading levels, using the index of each heading as its start
+    fn build(levels: &[usize]) -> Section<'static> {
+        let mut tree = Tree::new();
+        for (idx, &level) in levels.iter().enumerate() {
+            tree.open(
+                SectionPart {
+                    heading: None,
+                    level,
+                },
+                idx + 1,
+            );
+        }
+        tree.finish()
+    }
+
+    // Every section as (its start, its parent's start), in document order
+    fn parents(section: &Section, out: &mut Vec<(usize, usize)>) {
+        for child in section.children.iter() {
+            out.push((child.start, section.start));
+            parents(child, out);
+        }
+    }
+
+    #[test]
+    fn test_skipped_levels() {
+        let root = build(&[1, 4, 2, 3, 1]);
+        let mut found = Vec::new();
+        parents(&root, &mut found);
+        assert_eq!(found, vec![(1, 0), (2, 1), (3, 1), (4, 3), (5, 0)]);
+
+        let root = build(&[3, 2, 1]);
+        let mut found = Vec::new();
+        parents(&root, &mut found);
+        assert_eq!(found, vec![(1, 0), (2, 0), (3, 0)]);
+
+        let root = build(&[]);
+        assert!(root.children.is_empty());
+    }
+
+    proptest! {
+        #[test]
+        fn test_nearest_ancestor(levels in prop::collection::vec(1usize..16, 0..64)) {
+            let root = build(&levels);
+            let mut found = Vec::new();
+            parents(&root, &mut found);
+            // every heading is in the tree once, in document order, under the closest
+            // heading before it with a lower level
+            let expected: Vec<(usize, usize)> = levels
+                .iter()
+                .enumerate()
+                .map(|(idx, &level)| {
+                    let parent = levels[..idx]
+                        .iter()
+                        .rposition(|&l| l < level)
+                        .map_or(0, |p| p + 1);
+                    (idx + 1, parent)
+                })
+                .collect();
+            prop_assert_eq!(found, expected);
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use js_sys::{Map, JSON};
+use wasm_bindgen::prelude::*;
+
+use crate::bindings::{blocks, document, files};
+
+// Javascript bindings, for tangling documents in the browser, e.g. a "tangle this page"
+// button in documentation
+
+// Parse a document, returning an array of its code blocks
+#[wasm_bindgen]
+pub fn parse(markdown: &str) -> Result<JsValue, JsError> {
+    let doc = document(markdown)?;
+    let json = serde_json::to_string(&blocks(&doc))?;
+    JSON::parse(&json).map_err(|_| JsError::new("unable to convert code blocks"))
+}
+
+// Tangle a document, returning a Map of each filename to the contents tangled to it
+#[wasm_bindgen(js_name = tangleToMap)]
+pub fn tangle_to_map(markdown: &str) -> Result<Map, JsError> {
+    let map = Map::new();
+    for (filename, contents) in files(&document(markdown)?)? {
+        map.set(&filename.into(), &contents.into());
+    }
+    Ok(map)
+}
@@ -0,0 +1,517 @@
+use std::borrow::Cow;
+use std::iter::Peekable;
+use std::ops::Range;
+use std::str::from_utf8;
+
+use memchr::memrchr;
+use pulldown_cmark::{html, Options, Parser};
+
+use crate::section::Section;
+use crate::{Document, TangleMode};
+
+// What to do with a span of the document when weaving it
+enum Region {
+    // drop it from the output entirely
+    Remove,
+    // a tangled code block, by index into the document's code blocks
+    Block(usize),
+}
+
+// The spans of the document weaving treats specially, in document order. Everything
+// between them is copied through untouched
+fn regions(doc: &Document, strip_ignored: bool) -> Vec<(Range<usize>, Region)> {
+    let mut regions: Vec<(Range<usize>, Region)> = doc
+        .directives
+        .iter()
+        .map(|d| (d.span.clone(), Region::Remove))
+        .collect();
+    if strip_ignored {
+        regions.extend(
+            doc.ignored
+                .iter()
+                .map(|span| (span.clone(), Region::Remove)),
+        );
+    }
+    // blocks created by a `code` property share their directive's span, and are removed with it
+    regions.extend(
+        doc.code_blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, block)| block.properties.code.is_none())
+            .map(|(idx, block)| (block.span.clone(), Region::Block(idx))),
+    );
+    regions.sort_by_key(|(span, _)| span.start);
+    regions
+}
+
+// Produce publishable markdown from a document: every betwixt block is removed, and
+// optionally every code block that was ignored for tangling. Everything else is copied
+// through untouched.
+pub fn weave(contents: &[u8], doc: &Document, strip_ignored: bool) -> Vec<u8> {
+    let mut output = Vec::with_capacity(contents.len());
+    let mut pos = 0;
+    for (span, region) in regions(doc, strip_ignored) {
+        // directives can't overlap, but be defensive about spans we've already passed
+        if span.start < pos {
+            continue;
+        }
+        if let Region::Remove = region {
+            output.extend_from_slice(&contents[pos..span.start]);
+            pos = whole_line_end(contents, span);
+        }
+    }
+    output.extend_from_slice(&contents[pos..]);
+    output
+}
+
+pub struct HtmlOptions<'o> {
+    pub title: &'o str,
+    // also remove code blocks that are ignored for tangling
+    pub strip_ignored: bool,
+    // prepended to tangle targets when linking to them, e.g. the URL generated files are served from
+    pub link_prefix: &'o str,
+}
+
+// Render a document as a standalone HTML page. Prose is rendered as markdown, and each
+// tangled code block is annotated with the file it is tangled into. Code is marked with
+// its language for highlighting, which highlight.js performs when the page loads.
+pub fn weave_html(contents: &[u8], doc: &Document, options: &HtmlOptions) -> String {
+    let regions = regions(doc, options.strip_ignored);
+    let index = Index::new(doc, &regions);
+    let mut anchors = anchors(contents, doc).into_iter().peekable();
+    let mut body = String::new();
+    let mut pos = 0;
+    for (span, region) in regions {
+        if span.start < pos {
+            continue;
+        }
+        push_prose(&mut body, contents, pos..span.start, &mut anchors);
+        pos = match region {
+            Region::Remove => whole_line_end(contents, span),
+            Region::Block(idx) => {
+                push_block(&mut body, doc, &index, idx, options);
+                whole_line_end(contents, span)
+            }
+        };
+    }
+    push_prose(&mut body, contents, pos..contents.len(), &mut anchors);
+    push_appendix(&mut body, doc, &index, options);
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{}</title>
+<link rel="stylesheet" href="https://cdnjs.cloudflare.com/ajax/libs/highlight.js/11.9.0/styles/default.min.css">
+<script src="https://cdnjs.cloudflare.com/ajax/libs/highlight.js/11.9.0/highlight.min.js"></script>
+<script>hljs.highlightAll();</script>
+<style>
+figure.btxt-block {{ margin: 1em 0; }}
+figure.btxt-block figcaption, .btxt-index {{ font-family: monospace; font-size: 0.85em; color: #555; }}
+</style>
+</head>
+<body>
+{}</body>
+</html>
+"#,
+        escape(options.title),
+        body
+    )
+}
+
+// Where the line of each heading starts, and the slug of its section, in document order
+fn anchors<'d>(contents: &[u8], doc: &'d Document) -> Vec<(usize, &'d str)> {
+    doc.root
+        .walk()
+        .into_iter()
+        .filter(|section| !section.slug().is_empty())
+        .filter_map(|section| {
+            let heading = section.part.heading?;
+            let at = heading.as_ptr() as usize - contents.as_ptr() as usize;
+            Some((
+                memrchr(b'\n', &contents[..at]).map_or(0, |idx| idx + 1),
+                section.slug(),
+            ))
+        })
+        .collect()
+}
+
+// Render the prose in range as markdown, with an anchor before each heading in it so the
+// section can be linked to by its slug
+fn push_prose<'d>(
+    html_out: &mut String,
+    contents: &[u8],
+    range: Range<usize>,
+    anchors: &mut Peekable<impl Iterator<Item = (usize, &'d str)>>,
+) {
+    let mut pos = range.start;
+    while let Some(&(start, slug)) = anchors.peek() {
+        if start >= range.end {
+            break;
+        }
+        anchors.next();
+        if start < pos {
+            continue;
+        }
+        push_markdown(html_out, &contents[pos..start]);
+        html_out.push_str(&format!("<a id=\"{}\"></a>\n", escape(slug)));
+        pos = start;
+    }
+    push_markdown(html_out, &contents[pos..range.end]);
+}
+
+fn push_markdown(html_out: &mut String, markdown: &[u8]) {
+    let markdown = String::from_utf8_lossy(markdown);
+    // the github flavored extensions
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES;
+    html::push_html(html_out, Parser::new_ext(&markdown, options));
+}
+
+// Cross reference numbering for woven html. Sections are numbered by their position in
+// the heading tree (§2.3 is the third child of the second top level heading), and
+// rendered code blocks are numbered in document order.
+struct Index {
+    // per code block, its number if it is rendered
+    numbers: Vec<Option<usize>>,
+    // per code block, the number, heading and slug of the section it is defined in
+    sections: Vec<Option<(String, String, String)>>,
+}
+
+impl Index {
+    fn new(doc: &Document, regions: &[(Range<usize>, Region)]) -> Self {
+        let mut numbers = vec![None; doc.code_blocks.len()];
+        let mut count = 0;
+        for (_, region) in regions {
+            if let Region::Block(idx) = region {
+                count += 1;
+                numbers[*idx] = Some(count);
+            }
+        }
+        let mut sections = vec![None; doc.code_blocks.len()];
+        Self::number_sections(&doc.root, "", &mut sections);
+        Index { numbers, sections }
+    }
+
+    fn number_sections(
+        section: &Section,
+        number: &str,
+        sections: &mut [Option<(String, String, String)>],
+    ) {
+        if let Some(title) = section.part.title() {
+            for &idx in section.code_block_indexes.iter() {
+                sections[idx] = Some((
+                    number.to_owned(),
+                    title.trim().to_owned(),
+                    section.slug().to_owned(),
+                ));
+            }
+        }
+        for (child_idx, child) in section.children.iter().enumerate() {
+            let number = if number.is_empty() {
+                format!("{}", child_idx + 1)
+            } else {
+                format!("{}.{}", number, child_idx + 1)
+            };
+            Self::number_sections(child, &number, sections);
+        }
+    }
+
+    // the html id of a rendered block. Blocks with an id are linked to by that id
+    fn anchor(&self, doc: &Document, idx: usize) -> Option<String> {
+        match doc.code_blocks[idx].part.id {
+            Some(id) => Some(String::from_utf8_lossy(id).into_owned()),
+            None => self.numbers[idx].map(|number| format!("block-{}", number)),
+        }
+    }
+
+    // a link to the section a block is defined in
+    fn section(&self, idx: usize) -> Option<String> {
+        self.sections[idx].as_ref().map(|(number, heading, slug)| {
+            format!(
+                "<a href=\"#{}\">§{} {}</a>",
+                escape(slug),
+                number,
+                escape(heading)
+            )
+        })
+    }
+}
+
+fn push_block(
+    html_out: &mut String,
+    doc: &Document,
+    index: &Index,
+    idx: usize,
+    options: &HtmlOptions,
+) {
+    let block = &doc.code_blocks[idx];
+    html_out.push_str("<figure class=\"btxt-block\"");
+    if let Some(anchor) = index.anchor(doc, idx) {
+        html_out.push_str(&format!(" id=\"{}\"", escape(&anchor)));
+    }
+    html_out.push_str(">\n<figcaption>");
+    if let Some(number) = index.numbers[idx] {
+        html_out.push_str(&format!("Block {}", number));
+    }
+    if let Some(id) = block.part.id {
+        html_out.push_str(&format!(
+            " &lt;{}&gt;",
+            escape(&String::from_utf8_lossy(id))
+        ));
+    }
+    if let Some(section) = index.section(idx) {
+        html_out.push_str(&format!(", defined in {}", section));
+    }
+    let links: Vec<String> = doc
+        .filenames(idx)
+        .into_iter()
+        .map(|filename| {
+            let filename = escape(&String::from_utf8_lossy(&filename));
+            format!(
+                "<a href=\"{}{}\">{}</a>",
+                escape(options.link_prefix),
+                filename,
+                filename
+            )
+        })
+        .collect();
+    if !links.is_empty() {
+        let mode = match &block.properties.mode {
+            Some(TangleMode::Overwrite) | None => "",
+            Some(TangleMode::Append) => " (appended)",
+            Some(TangleMode::Prepend) => " (prepended)",
+            Some(TangleMode::Symlink | TangleMode::Hardlink) => " (linked)",
+            Some(TangleMode::Insert(..)) => " (inserted)",
+        };
+        html_out.push_str(&format!(" &rarr; {}{}", links.join(", "), mode));
+    }
+    html_out.push_str("</figcaption>\n<pre><code");
+    if let Some(lang) = block.part.lang.and_then(|lang| from_utf8(lang).ok()) {
+        html_out.push_str(&format!(" class=\"language-{}\"", escape(lang)));
+    }
+    html_out.push('>');
+    html_out.push_str(&escape(&String::from_utf8_lossy(block.part.contents)));
+    html_out.push_str("</code></pre>\n</figure>\n");
+}
+
+// An appendix listing every file the document tangles to, and the blocks composing each
+fn push_appendix(html_out: &mut String, doc: &Document, index: &Index, options: &HtmlOptions) {
+    let mut files: Vec<(Cow<[u8]>, Vec<usize>)> = Vec::new();
+    for idx in 0..doc.code_blocks.len() {
+        if index.numbers[idx].is_none() {
+            continue;
+        }
+        for filename in doc.filenames(idx) {
+            match files.iter_mut().find(|(f, _)| *f == filename) {
+                Some((_, blocks)) => blocks.push(idx),
+                None => files.push((filename, vec![idx])),
+            }
+        }
+    }
+    if files.is_empty() {
+        return;
+    }
+    html_out.push_str("<section class=\"btxt-index\">\n<h2>Tangled Files</h2>\n<dl>\n");
+    for (filename, blocks) in files {
+        let filename = escape(&String::from_utf8_lossy(&filename));
+        html_out.push_str(&format!(
+            "<dt><a href=\"{}{}\">{}</a></dt>\n<dd>",
+            escape(options.link_prefix),
+            filename,
+            filename
+        ));
+        let blocks: Vec<String> = blocks
+            .into_iter()
+            .map(|idx| {
+                let mut entry = format!(
+                    "<a href=\"#{}\">Block {}</a>",
+                    escape(&index.anchor(doc, idx).unwrap_or_default()),
+                    index.numbers[idx].unwrap_or_default()
+                );
+                if let Some((number, _, slug)) = &index.sections[idx] {
+                    entry.push_str(&format!(" (<a href=\"#{}\">§{}</a>)", escape(slug), number));
+                }
+                entry
+            })
+            .collect();
+        html_out.push_str(&blocks.join(", "));
+        html_out.push_str("</dd>\n");
+    }
+    html_out.push_str("</dl>\n</section>\n");
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// When a span is the only thing on its line(s), removing it should also remove the
+// line, rather than leaving an empty one behind. Returns where copying should resume.
+fn whole_line_end(contents: &[u8], span: Range<usize>) -> usize {
+    if span.start != 0 && contents[span.start - 1] != b'\n' {
+        return span.end;
+    }
+    let mut end = span.end;
+    while end < contents.len() && (contents[end] == b' ' || contents[end] == b'\t') {
+        end += 1;
+    }
+    if end == contents.len() {
+        end
+    } else if contents[end] == b'\n' {
+        end + 1
+    } else {
+        span.end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{betwixt, code, section, MarkdownParsers, Strictness, BETWIXT_TOKEN, CLOSE_TOKEN};
+
+    #[test]
+    fn test_weave() {
+        let markdown = &b"<?btxt mode='overwrite' ?>
+# Weaving
+<?btxt+python filename='foo.py' code=|||
+print('hidden')
+||| ?>
+Some prose here.
+<?btxt tag='woven' ?>
+```python
+print('shown')
+```
+<?btxt+rust ignore=true ?>
+```rust
+fn ignored() {}
+```
+The end"[..];
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let doc = Document::from_contents(markdown, parsers).unwrap();
+        assert_eq!(
+            String::from_utf8(weave(markdown, &doc, false)).unwrap(),
+            "# Weaving
+Some prose here.
+```python
+print('shown')
+```
+```rust
+fn ignored() {}
+```
+The end"
+        );
+        assert_eq!(
+            String::from_utf8(weave(markdown, &doc, true)).unwrap(),
+            "# Weaving
+Some prose here.
+```python
+print('shown')
+```
+The end"
+        );
+    }
+
+    #[test]
+    fn test_weave_html() {
+        let markdown = &b"# Weaving
+<?btxt filename='src/lib.rs' mode='overwrite' ?>
+Some *prose*.
+```rust setup
+fn a() -> bool { 1 < 2 }
+```
+"[..];
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let doc = Document::from_contents(markdown, parsers).unwrap();
+        let html = weave_html(
+            markdown,
+            &doc,
+            &HtmlOptions {
+                title: "Weaving",
+                strip_ignored: false,
+                link_prefix: "../",
+            },
+        );
+        assert!(html.contains("<a id=\"weaving\"></a>\n<h1>Weaving</h1>"));
+        assert!(html.contains("<p>Some <em>prose</em>.</p>"));
+        assert!(!html.contains("btxt filename"));
+        assert!(html.contains(
+            "<figure class=\"btxt-block\" id=\"setup\">
+<figcaption>Block 1 &lt;setup&gt;, defined in <a href=\"#weaving\">§1 Weaving</a> &rarr; <a href=\"../src/lib.rs\">src/lib.rs</a></figcaption>
+<pre><code class=\"language-rust\">fn a() -&gt; bool { 1 &lt; 2 }
+</code></pre>
+</figure>"
+        ));
+    }
+
+    #[test]
+    fn test_weave_html_index() {
+        let markdown = &b"# One
+<?btxt filename='a.rs' mode='append' ?>
+## Sub
+```rust
+one
+```
+# Two
+<?btxt filename='b.rs' mode='append' ?>
+```rust
+two
+```
+## Sub
+```rust
+three
+```
+<?btxt filename='a.rs' ?>
+```rust
+four
+```
+"[..];
+        let parsers = MarkdownParsers {
+            code: code("```", "```"),
+            section: section('#'),
+            betwixt: betwixt(BETWIXT_TOKEN, CLOSE_TOKEN),
+            strict: Strictness::Deny,
+        };
+        let doc = Document::from_contents(markdown, parsers).unwrap();
+        let html = weave_html(
+            markdown,
+            &doc,
+            &HtmlOptions {
+                title: "Index",
+                strip_ignored: false,
+                link_prefix: "",
+            },
+        );
+        assert!(html.contains("Block 3, defined in <a href=\"#two/sub\">§2.1 Sub</a> &rarr;"));
+        assert!(html.contains("<a id=\"one/sub\"></a>\n<h2>Sub</h2>"));
+        assert!(html.contains("<a id=\"two/sub\"></a>\n<h2>Sub</h2>"));
+        assert!(html.contains(
+            "<dt><a href=\"a.rs\">a.rs</a></dt>
+<dd><a href=\"#block-1\">Block 1</a> (<a href=\"#one/sub\">§1.1</a>), <a href=\"#block-4\">Block 4</a> (<a href=\"#two/sub\">§2.1</a>)</dd>
+<dt><a href=\"b.rs\">b.rs</a></dt>
+<dd><a href=\"#block-2\">Block 2</a> (<a href=\"#two\">§2</a>), <a href=\"#block-3\">Block 3</a> (<a href=\"#two/sub\">§2.1</a>)</dd>"
+        ));
+    }
+}
@@ -0,0 +1,33 @@
+// What the integration tests share: a directory of their own for each test, removed however
+// the test ends, and running betwixt in one. Not every test uses all of it
+#![allow(dead_code)]
+
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::{Command, Output};
+
+use tempfile::TempDir;
+
+// An empty directory no other test uses
+pub fn dir() -> TempDir {
+    tempfile::Builder::new()
+        .prefix("betwixt-test-")
+        .tempdir()
+        .unwrap()
+}
+
+// betwixt, to be run from dir
+pub fn command(dir: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_betwixt"));
+    command.current_dir(dir);
+    command
+}
+
+// Run betwixt with args from dir, and wait for it to finish
+pub fn betwixt<I, S>(args: I, dir: &Path) -> Output
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    command(dir).args(args).output().unwrap()
+}
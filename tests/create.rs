@@ -0,0 +1,41 @@
+#[cfg(all(feature = "cli", unix))]
+mod common;
+
+// A file appended to with create=true that doesn't exist yet is written like a new file,
+// starting with its shebang
+#[cfg(all(feature = "cli", unix))]
+#[test]
+fn created_files_start_with_their_shebang() {
+    let dir = common::dir();
+    let root = dir.path();
+    std::fs::write(
+        root.join("doc.md"),
+        "# Create
+```sh filename='run.sh' mode='append' create=true shebang='#!/bin/sh'
+echo one
+```
+",
+    )
+    .unwrap();
+    let tangle = |args: &[&str]| {
+        let output = common::betwixt(args, root);
+        assert!(output.status.success(), "{:?}", output);
+    };
+    for (args, dir) in [
+        (&["doc.md"][..], root.to_path_buf()),
+        (&["doc.md", "-o", "out"], root.join("out")),
+    ] {
+        std::fs::create_dir_all(&dir).unwrap();
+        tangle(args);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("run.sh")).unwrap(),
+            "#!/bin/sh\necho one\n"
+        );
+        // once it exists, it is appended to, even when the output directory is relative
+        tangle(args);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("run.sh")).unwrap(),
+            "#!/bin/sh\necho one\necho one\n"
+        );
+    }
+}
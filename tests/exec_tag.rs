@@ -0,0 +1,31 @@
+#[cfg(all(feature = "cli", unix))]
+mod common;
+
+// --exec-tag runs the commands of the blocks with that tag, and no others
+#[cfg(all(feature = "cli", unix))]
+#[test]
+fn exec_tag_only_runs_tagged_blocks() {
+    let root = common::dir();
+    std::fs::write(
+        root.path().join("doc.md"),
+        "# Exec tag
+<?btxt mode='overwrite' ?>
+```sh #first filename='first.sh' cmd='echo first' tag='check'
+```
+
+```sh #second filename='second.sh' cmd='echo second' tag='build'
+```
+
+```sh #third filename='third.sh' cmd='echo third' tag='check'
+```
+",
+    )
+    .unwrap();
+    let output = common::betwixt(["doc.md", "--exec-tag", "check", "--yes"], root.path());
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("[first] first"), "{}", stdout);
+    assert!(stdout.contains("[third] third"), "{}", stdout);
+    assert!(!stdout.contains("[second]"), "{}", stdout);
+    assert!(root.path().join("second.sh").exists());
+}
@@ -0,0 +1,46 @@
+#[cfg(all(feature = "cli", unix))]
+mod common;
+
+// The exit codes listed in --help: 1 when parsing fails, 3 when fmt --check finds an unformatted
+// document, 4 when a command can't be confirmed
+#[cfg(all(feature = "cli", unix))]
+#[test]
+fn exit_codes_match_the_failure() {
+    use std::process::Stdio;
+
+    let root = common::dir();
+    let write = |name: &str, contents: &str| std::fs::write(root.path().join(name), contents);
+    write("invalid.md", "# Invalid\n<?btxt mode=bogus ?>\n").unwrap();
+    write(
+        "unformatted.md",
+        "# Unformatted
+<?btxt   mode='overwrite'  ?>
+```text   filename='a.txt'
+hi
+```
+",
+    )
+    .unwrap();
+    write(
+        "confirm.md",
+        "# Confirm
+<?btxt mode='overwrite' ?>
+```sh #hi filename='hi.sh' cmd='echo hi'
+```
+",
+    )
+    .unwrap();
+    let code = |args: &[&str]| {
+        common::command(root.path())
+            .args(args)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap()
+            .status
+            .code()
+    };
+    assert_eq!(code(&["invalid.md"]), Some(1));
+    assert_eq!(code(&["fmt", "--check", "unformatted.md"]), Some(3));
+    assert_eq!(code(&["confirm.md", "-e", "hi"]), Some(4));
+    assert_eq!(code(&["confirm.md", "-e", "hi", "--yes"]), Some(0));
+}
@@ -0,0 +1,34 @@
+#[cfg(all(feature = "cli", unix))]
+mod common;
+
+// --fail-on-empty fails when nothing is selected to tangle, and changes nothing otherwise
+#[cfg(all(feature = "cli", unix))]
+#[test]
+fn fail_on_empty_only_fails_without_blocks() {
+    let root = common::dir();
+    std::fs::write(
+        root.path().join("doc.md"),
+        "# Empty
+<?btxt mode='overwrite' ?>
+```text filename='a.txt' tag='docs'
+a
+```
+",
+    )
+    .unwrap();
+    let tangle = |tag: &str| common::betwixt(["doc.md", "-t", tag, "--fail-on-empty"], root.path());
+    let empty = tangle("dcos");
+    assert!(!empty.status.success(), "{:?}", empty);
+    assert!(
+        String::from_utf8_lossy(&empty.stdout).contains("no code blocks are tagged dcos"),
+        "{:?}",
+        empty
+    );
+    assert!(!root.path().join("a.txt").exists());
+    let tangled = tangle("docs");
+    assert_eq!(tangled.status.code(), Some(0), "{:?}", tangled);
+    assert_eq!(
+        std::fs::read_to_string(root.path().join("a.txt")).unwrap(),
+        "a\n"
+    );
+}
@@ -0,0 +1,34 @@
+#[cfg(feature = "cli")]
+mod common;
+
+// `betwixt init` without a template starts a project that tangles as it is
+#[cfg(feature = "cli")]
+#[test]
+fn scaffolded_project_tangles() {
+    let dir = common::dir();
+    let root = dir.path();
+    let betwixt = |args: &[&str]| common::betwixt(args, root);
+    let output = betwixt(&["init"]);
+    assert!(output.status.success(), "{:?}", output);
+    for name in ["README.md", "betwixt.toml", ".github/workflows/check.yml"] {
+        assert!(root.join(name).is_file(), "{} wasn't created", name);
+    }
+    let output = betwixt(&["README.md"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        std::fs::read_to_string(root.join("hello.py")).unwrap(),
+        "def greet(name):
+    return f\"Hello, {name}!\"
+
+if __name__ == \"__main__\":
+    print(greet(\"world\"))
+"
+    );
+    // a project is never started over one already there
+    std::fs::write(root.join("README.md"), "# Mine\n").unwrap();
+    assert!(!betwixt(&["init"]).status.success());
+    assert_eq!(
+        std::fs::read_to_string(root.join("README.md")).unwrap(),
+        "# Mine\n"
+    );
+}
@@ -0,0 +1,51 @@
+#[cfg(all(feature = "cli", unix))]
+mod common;
+
+// An isolated block's command sees the files tangled and nothing else of the output directory
+#[cfg(all(feature = "cli", unix))]
+#[test]
+fn isolated_commands_only_see_tangled_files() {
+    let dir = common::dir();
+    let root = dir.path();
+    std::fs::write(root.join("stray.txt"), "left over\n").unwrap();
+    std::fs::write(
+        root.join("doc.md"),
+        "# Isolated
+<?btxt mode='overwrite' ?>
+```text filename='greeting.txt'
+hello
+```
+
+```sh #isolated filename='isolated.sh' cmd='ls' isolate=true
+```
+
+```sh #shared filename='shared.sh' cmd='ls'
+```
+",
+    )
+    .unwrap();
+    let output = common::betwixt(["doc.md", "-e", "isolated", "-e", "shared", "--yes"], root);
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let listed = |label: &str| -> Vec<String> {
+        stdout
+            .lines()
+            .filter_map(|line| line.strip_prefix(&format!("[{}] ", label)))
+            .map(str::to_owned)
+            .collect()
+    };
+    assert_eq!(
+        listed("isolated"),
+        ["greeting.txt", "isolated.sh", "shared.sh"]
+    );
+    assert_eq!(
+        listed("shared"),
+        [
+            "doc.md",
+            "greeting.txt",
+            "isolated.sh",
+            "shared.sh",
+            "stray.txt"
+        ]
+    );
+}
@@ -0,0 +1,36 @@
+#[cfg(all(feature = "cli", unix))]
+mod common;
+
+// what is shown of a command's output is kept within its block's limits, like its recordings
+#[cfg(all(feature = "cli", unix))]
+#[test]
+fn max_output_limits_what_is_shown() {
+    let root = common::dir();
+    std::fs::write(
+        root.path().join("doc.md"),
+        "# Max output
+<?btxt mode='overwrite' ?>
+```sh #count filename='count.sh' cmd='seq 5' max-output-lines=2
+```
+",
+    )
+    .unwrap();
+    let output = common::betwixt(["doc.md", "-e", "count", "--yes"], root.path());
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.starts_with("[count] 1\n[count] 2\n[count] [truncated 6 bytes]\n"),
+        "{}",
+        stdout
+    );
+    assert!(!stdout.contains("[count] 3"), "{}", stdout);
+
+    let output = common::betwixt(["run", "doc.md", "--yes"], root.path());
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("$ seq 5\n1\n2\n[truncated 6 bytes]\nexecuted"),
+        "{}",
+        stdout
+    );
+}
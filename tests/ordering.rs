@@ -0,0 +1,158 @@
+use std::thread;
+
+#[cfg(feature = "cli")]
+mod common;
+
+use betwixt_parse::tangle::{self, FileOutput, TangleOptions};
+use betwixt_parse::{parse, Document, FlavorBuilder};
+
+// Tangling is deterministic: files are written in the order documents are given, then the order
+// each document first writes to them, and the blocks of a file are in document order, however
+// the documents are parsed and planned
+
+const DOCUMENTS: [&str; 3] = [
+    "# First
+<?btxt mode='append' ?>
+```rust filename='a.rs'
+// a 1
+```
+```python filename='b.py'
+# b 1
+```
+```rust filename='a.rs'
+// a 2
+```
+",
+    "# Second
+<?btxt mode='overwrite' ?>
+```text filename='z.txt'
+z
+```
+```text filename='c.txt'
+c
+```
+",
+    "# Third
+```text filename='d.txt' mode='append'
+d 1
+```
+## Nested
+```python filename='b2.py' mode='append'
+# b2
+```
+```text filename='d.txt' mode='append'
+d 2
+```
+",
+];
+
+fn plan(doc: &Document, selected: &[usize]) -> Vec<(String, String)> {
+    let outputs = tangle::plan(doc, selected, &TangleOptions::default()).unwrap();
+    describe(&outputs)
+}
+
+fn describe(outputs: &[FileOutput]) -> Vec<(String, String)> {
+    outputs
+        .iter()
+        .map(|output| {
+            (
+                String::from_utf8_lossy(&output.filename).into_owned(),
+                String::from_utf8_lossy(&output.contents).into_owned(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn tangle_order_is_stable() {
+    let sequential: Vec<Vec<FileOutput>> = DOCUMENTS
+        .iter()
+        .map(|markdown| {
+            let doc = parse(markdown.as_bytes(), FlavorBuilder::github()).unwrap();
+            let selected: Vec<usize> = (0..doc.code_blocks.len()).collect();
+            tangle::plan(&doc, &selected, &TangleOptions::default()).unwrap()
+        })
+        .collect();
+    let expected = describe(&tangle::merge(sequential).unwrap());
+    assert_eq!(
+        expected
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>(),
+        vec!["a.rs", "b.py", "z.txt", "c.txt", "d.txt", "b2.py"]
+    );
+    assert_eq!(expected[0].1, "// a 1\n// a 2\n");
+    assert_eq!(expected[4].1, "d 1\nd 2\n");
+
+    for _ in 0..20 {
+        // documents planned at once, finishing in whatever order, then merged in the order given
+        let plans: Vec<Vec<(String, String)>> = thread::scope(|scope| {
+            let handles: Vec<_> = DOCUMENTS
+                .iter()
+                .rev()
+                .map(|markdown| {
+                    scope.spawn(move || {
+                        let doc = parse(markdown.as_bytes(), FlavorBuilder::github()).unwrap();
+                        // blocks selected out of order are still taken in document order
+                        let selected: Vec<usize> = (0..doc.code_blocks.len()).rev().collect();
+                        plan(&doc, &selected)
+                    })
+                })
+                .collect();
+            let mut plans: Vec<_> = handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect();
+            plans.reverse();
+            plans
+        });
+        assert_eq!(plans.concat(), expected);
+    }
+}
+
+// The files of a tangle run with documents parsed in parallel, in the order they were written
+#[cfg(feature = "cli")]
+#[test]
+fn parallel_tangle_order_is_stable() {
+    let dir = common::dir();
+    let root = dir.path();
+    let out = root.join("out");
+    std::fs::create_dir_all(&out).unwrap();
+    let mut args = vec!["--porcelain".to_string(), "-j".into(), "3".into()];
+    for (n, markdown) in DOCUMENTS.iter().enumerate() {
+        let path = root.join(format!("{}.md", n));
+        std::fs::write(&path, markdown).unwrap();
+        args.push(path.to_string_lossy().into_owned());
+    }
+    args.extend(["-o".into(), out.to_string_lossy().into_owned()]);
+    for _ in 0..5 {
+        for file in std::fs::read_dir(&out).unwrap() {
+            std::fs::remove_file(file.unwrap().path()).unwrap();
+        }
+        // appended files must exist to be appended to
+        for name in ["a.rs", "b.py", "d.txt", "b2.py"] {
+            std::fs::write(out.join(name), "").unwrap();
+        }
+        let output = common::betwixt(&args, root);
+        assert!(output.status.success(), "{:?}", output);
+        let written: Vec<String> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                Some(
+                    line.strip_prefix("wrote\t")?
+                        .split('\t')
+                        .next()?
+                        .to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            written,
+            vec!["a.rs", "b.py", "z.txt", "c.txt", "d.txt", "b2.py"]
+        );
+        assert_eq!(
+            std::fs::read_to_string(out.join("d.txt")).unwrap(),
+            "d 1\nd 2\n"
+        );
+    }
+}
@@ -0,0 +1,26 @@
+#[cfg(all(feature = "cli", unix))]
+mod common;
+
+// With --porcelain, stdout holds the report and nothing else, what commands write goes to stderr
+#[cfg(all(feature = "cli", unix))]
+#[test]
+fn porcelain_keeps_command_output_off_stdout() {
+    let root = common::dir();
+    std::fs::write(
+        root.path().join("doc.md"),
+        "# Porcelain
+<?btxt mode='overwrite' ?>
+```text #hi filename='hi.txt' cmd='cat hi.txt'
+hi
+```
+",
+    )
+    .unwrap();
+    let output = common::betwixt(["doc.md", "-e", "hi", "--yes", "--porcelain"], root.path());
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "wrote\thi.txt\t3\t+3\nexecuted\thi\tok\n"
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("[hi] hi"));
+}
@@ -0,0 +1,52 @@
+#[cfg(feature = "cli")]
+mod common;
+
+// A document tangled over itself would read what it generated the next time it is tangled
+#[cfg(feature = "cli")]
+#[test]
+fn tangling_over_an_input_fails() {
+    let dir = common::dir();
+    let root = dir.path();
+    let document = "# Cycle
+<?btxt mode='overwrite' ?>
+```text filename='doc.md'
+generated
+```
+";
+    std::fs::write(root.join("doc.md"), document).unwrap();
+    let betwixt = |args: &[&str]| common::betwixt(args, root);
+    let output = betwixt(&["doc.md"]);
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Error: doc.md:3: doc.md is the document it is tangled from, pass --allow-self-write to tangle over it\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(root.join("doc.md")).unwrap(),
+        document
+    );
+    // other documents tangled with it count too, wherever the output directory is
+    std::fs::write(root.join("other.md"), "# Other\n").unwrap();
+    std::fs::create_dir_all(root.join("out")).unwrap();
+    std::fs::write(
+        root.join("out/doc.md"),
+        document.replace("doc.md", "../other.md"),
+    )
+    .unwrap();
+    let out = root.join("out").to_string_lossy().into_owned();
+    let output = betwixt(&["out/doc.md", "other.md", "-o", &out]);
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout)
+        .contains("../other.md is the input document other.md"));
+    // as it is when the output directory is relative
+    let output = betwixt(&["out/doc.md", "other.md", "-o", "out"]);
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout)
+        .contains("../other.md is the input document other.md"));
+    let output = betwixt(&["doc.md", "--allow-self-write"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        std::fs::read_to_string(root.join("doc.md")).unwrap(),
+        "generated\n"
+    );
+}
@@ -0,0 +1,57 @@
+#[cfg(all(feature = "cli", unix))]
+mod common;
+
+// `betwixt selftest` runs the commands of nested documents where they were tangled
+#[cfg(all(feature = "cli", unix))]
+#[test]
+fn selftest_reports_each_command() {
+    let root = common::dir();
+    let docs = root.path().join("docs");
+    std::fs::create_dir_all(docs.join("more")).unwrap();
+    std::fs::write(
+        docs.join("hello.md"),
+        "# Hello
+<?btxt mode='overwrite' ?>
+
+'''text hello filename='hello.txt' cmd='cat {file}'
+hello
+'''
+",
+    )
+    .unwrap();
+    std::fs::write(
+        docs.join("more/missing.md"),
+        "# Missing
+<?btxt mode='overwrite' ?>
+
+'''text filename='there.txt' cmd='cat missing.txt'
+there
+'''
+",
+    )
+    .unwrap();
+    let selftest = |dir: &std::path::Path| {
+        common::command(root.path())
+            .arg("selftest")
+            .arg(dir)
+            .output()
+            .unwrap()
+    };
+    let output = selftest(&docs.join("hello.md"));
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "ok   hello.md hello\n1 passed, 0 failed\n"
+    );
+    let output = selftest(&docs);
+    assert_eq!(output.status.code(), Some(4), "{:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.starts_with("ok   hello.md hello\nFAIL more/missing.md:4: "),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("1 passed, 1 failed\n"), "{}", stdout);
+    // the documents themselves are left alone
+    assert!(!docs.join("hello.txt").exists());
+}